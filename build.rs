@@ -1,4 +1,32 @@
-use std::path::PathBuf;
+use kernel_common::symbols;
+use std::path::{Path, PathBuf};
+use xmas_elf::{
+    sections::SectionData,
+    symbol_table::{Entry, Type},
+    ElfFile,
+};
+
+/// One `start size name` line in hex for every function in the kernel's symbol table, sorted by
+/// address. Empty if the kernel was stripped.
+fn kernel_symbols(kernel: &Path) -> String {
+    let data = std::fs::read(kernel).unwrap();
+    let elf = ElfFile::new(&data).unwrap();
+    let Some(Ok(SectionData::SymbolTable64(entries))) = elf
+        .find_section_by_name(".symtab")
+        .map(|section| section.get_data(&elf))
+    else {
+        return String::new();
+    };
+    let symbols = entries
+        .iter()
+        .filter(|entry| entry.get_type() == Ok(Type::Func) && entry.size() > 0)
+        .filter_map(|entry| {
+            let name = entry.get_name(&elf).ok()?;
+            Some((entry.value(), entry.size(), symbols::demangle(name)))
+        })
+        .collect();
+    symbols::table(symbols)
+}
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -12,12 +40,24 @@ fn main() {
     // let uefi_path = out_dir.join("uefi.img");
     // bootloader::UefiBoot::new(&kernel).create_disk_image(&uefi_path).unwrap();
 
+    // the kernel symbolizes backtraces with this file, if it's copied to the user partition
+    let symbols = kernel_symbols(&kernel);
+    let symbols_path = out_dir.join("kernel.sym");
+    std::fs::write(&symbols_path, &symbols).unwrap();
+
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
-    bootloader::DiskImageBuilder::new(kernel)
-        .set_ramdisk(userspace)
-        .create_bios_image(&bios_path)
-        .unwrap();
+    let mut builder = bootloader::DiskImageBuilder::new(kernel);
+    builder.set_ramdisk(userspace);
+    // the screen size the kernel expects, see `SCREEN_WIDTH` in the kernel's main.rs
+    let mut boot_config = bootloader::BootConfig::default();
+    boot_config.frame_buffer.minimum_framebuffer_width = Some(1024);
+    boot_config.frame_buffer.minimum_framebuffer_height = Some(768);
+    builder.set_boot_config(&boot_config);
+    if !symbols.is_empty() {
+        builder.set_file_contents("kernel.sym".into(), symbols.into_bytes());
+    }
+    builder.create_bios_image(&bios_path).unwrap();
 
     // pass the disk image paths as env variables to the `main.rs`
     // println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
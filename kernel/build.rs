@@ -0,0 +1,10 @@
+fn main() {
+    // the multiboot2 feature links the kernel for grub instead of the bootloader crate, at a
+    // fixed address
+    if std::env::var_os("CARGO_FEATURE_MULTIBOOT2").is_some() {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rustc-link-arg-bins=-T{}/multiboot.ld", dir);
+        println!("cargo:rustc-link-arg-bins=--no-pie");
+        println!("cargo:rerun-if-changed=multiboot.ld");
+    }
+}
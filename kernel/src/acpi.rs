@@ -0,0 +1,346 @@
+use crate::{memory, mmio::Registers, time};
+use alloc::vec::Vec;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+/// An IO APIC described by the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    /// First global system interrupt handled by this IO APIC.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't wired to the global system interrupt with the same number, or doesn't use
+/// the ISA defaults (active high, edge triggered).
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// What the kernel needs from the Multiple APIC Description Table.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    /// The system also has 8259 PICs, which must be masked when using the APIC.
+    pub has_pic: bool,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// The global system interrupt and polarity/trigger mode of ISA IRQ `irq`.
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .find(|entry| entry.irq == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                irq,
+                gsi: irq as u32,
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+}
+
+const SDT_HEADER_SIZE: u64 = 36;
+
+// ACPI tables aren't in RAM the kernel keeps mapped, so they're mapped as they are read. Reading
+// the same page again reuses its mapping.
+fn read<T: Copy>(phys_addr: u64) -> T {
+    let virt = memory::map_physical(PhysAddr::new(phys_addr), core::mem::size_of::<T>())
+        .expect("failed to map ACPI table");
+    unsafe { virt.as_ptr::<T>().read_unaligned() }
+}
+
+fn checksum_ok(phys_addr: u64, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, offset| {
+        sum.wrapping_add(read::<u8>(phys_addr + offset))
+    }) == 0
+}
+
+/// Finds the table with `signature` through the RSDP at `rsdp_addr`. Returns its physical address
+/// and length.
+fn find_table(rsdp_addr: u64, signature: &[u8; 4]) -> Result<(u64, u64), &'static str> {
+    if &read::<[u8; 8]>(rsdp_addr) != b"RSD PTR " || !checksum_ok(rsdp_addr, 20) {
+        return Err("invalid RSDP");
+    }
+    let revision = read::<u8>(rsdp_addr + 15);
+    // ACPI 2.0 and later have a 64-bit XSDT, older versions only the RSDT.
+    let (root, entry_size) = if revision >= 2 && read::<u64>(rsdp_addr + 24) != 0 {
+        (read::<u64>(rsdp_addr + 24), 8)
+    } else {
+        (read::<u32>(rsdp_addr + 16) as u64, 4)
+    };
+    let root_len = read::<u32>(root + 4) as u64;
+    if root_len < SDT_HEADER_SIZE || !checksum_ok(root, root_len) {
+        return Err("invalid root system description table");
+    }
+    let entries = (root_len - SDT_HEADER_SIZE) / entry_size;
+    for index in 0..entries {
+        let entry = root + SDT_HEADER_SIZE + index * entry_size;
+        let table = if entry_size == 8 {
+            read::<u64>(entry)
+        } else {
+            read::<u32>(entry) as u64
+        };
+        if &read::<[u8; 4]>(table) != signature {
+            continue;
+        }
+        let len = read::<u32>(table + 4) as u64;
+        if len < SDT_HEADER_SIZE || !checksum_ok(table, len) {
+            return Err("invalid ACPI table checksum");
+        }
+        return Ok((table, len));
+    }
+    Err("ACPI table not found")
+}
+
+/// Reads the MADT through the RSDP at `rsdp_addr`, as passed by the bootloader.
+pub fn read_madt(rsdp_addr: u64) -> Result<Madt, &'static str> {
+    let (table, len) = find_table(rsdp_addr, b"APIC")?;
+    let mut madt = Madt {
+        local_apic_address: read::<u32>(table + 36) as u64,
+        has_pic: read::<u32>(table + 40) & 1 != 0,
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+    let mut entry = table + 44;
+    while entry + 2 <= table + len {
+        let entry_type = read::<u8>(entry);
+        let entry_len = read::<u8>(entry + 1) as u64;
+        if entry_len < 2 || entry + entry_len > table + len {
+            return Err("invalid MADT entry");
+        }
+        match entry_type {
+            1 => madt.io_apics.push(IoApic {
+                id: read(entry + 2),
+                address: read::<u32>(entry + 4) as u64,
+                gsi_base: read(entry + 8),
+            }),
+            2 => {
+                let flags = read::<u16>(entry + 8);
+                madt.overrides.push(InterruptOverride {
+                    irq: read(entry + 3),
+                    gsi: read(entry + 4),
+                    // 0b00 means "conforms to the bus", which is active high and edge for ISA.
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                });
+            }
+            5 => madt.local_apic_address = read(entry + 4),
+            _ => {}
+        }
+        entry += entry_len;
+    }
+    Ok(madt)
+}
+
+/// Writing SLP_TYP with this bit to a PM1 control register enters that sleep state.
+const SLP_EN: u16 = 1 << 13;
+/// Set in PM1 control once the firmware has handed power management to the OS.
+const SCI_EN: u16 = 1;
+const FADT_RESET_REG_SUPPORTED: u32 = 1 << 10;
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+const ADDRESS_SPACE_IO: u8 = 1;
+
+/// Where a FADT register lives.
+#[derive(Debug, Clone, Copy)]
+struct GenericAddress {
+    space: u8,
+    address: u64,
+}
+
+/// How to power off and reset the machine, from the FADT and the DSDT's `\_S5` object.
+#[derive(Debug)]
+struct PowerControl {
+    smi_command: u32,
+    acpi_enable: u8,
+    pm1a_control: u16,
+    pm1b_control: u16,
+    /// SLP_TYPa and SLP_TYPb of the soft off state.
+    s5: Option<(u16, u16)>,
+    reset: Option<(GenericAddress, u8)>,
+}
+
+static mut POWER_CONTROL: Option<PowerControl> = None;
+/// CMOS index of the RTC's century register, or zero if there isn't one.
+static mut CENTURY_REGISTER: u8 = 0;
+
+/// Finds the sleep type values in the DSDT's `Name (_S5, Package () { a, b, ... })` without a
+/// full AML interpreter.
+fn find_s5(dsdt: u64, len: u64) -> Option<(u16, u16)> {
+    let mut offset = dsdt + SDT_HEADER_SIZE;
+    while offset + 4 < dsdt + len {
+        let at_name = &read::<[u8; 4]>(offset) == b"_S5_"
+            && (read::<u8>(offset - 1) == 0x08
+                || (read::<u8>(offset - 2) == 0x08 && read::<u8>(offset - 1) == b'\\'));
+        if !at_name || read::<u8>(offset + 4) != 0x12 {
+            offset += 1;
+            continue;
+        }
+        // PackageOp, then the package length, whose top two bits count its extra bytes, then
+        // the element count.
+        let mut element = offset + 5;
+        element += (read::<u8>(element) >> 6) as u64 + 1;
+        element += 1;
+        let mut read_element = || {
+            // BytePrefix is followed by the value. Zero and One are their own opcodes.
+            if read::<u8>(element) == 0x0a {
+                element += 1;
+            }
+            let value = read::<u8>(element) as u16;
+            element += 1;
+            value
+        };
+        let slp_typ_a = read_element();
+        let slp_typ_b = read_element();
+        return Some((slp_typ_a, slp_typ_b));
+    }
+    None
+}
+
+fn read_power_control(rsdp_addr: u64) -> Result<PowerControl, &'static str> {
+    let (fadt, len) = find_table(rsdp_addr, b"FACP")?;
+    if len < 116 {
+        return Err("FADT too short");
+    }
+    let mut dsdt = read::<u32>(fadt + 40) as u64;
+    if len >= 148 && read::<u64>(fadt + 140) != 0 {
+        dsdt = read::<u64>(fadt + 140);
+    }
+    let dsdt_len = read::<u32>(dsdt + 4) as u64;
+    let s5 = if &read::<[u8; 4]>(dsdt) == b"DSDT" && checksum_ok(dsdt, dsdt_len) {
+        find_s5(dsdt, dsdt_len)
+    } else {
+        None
+    };
+    // The reset register was added in ACPI 2.0.
+    let reset = if len >= 129 && read::<u32>(fadt + 112) & FADT_RESET_REG_SUPPORTED != 0 {
+        let register = GenericAddress {
+            space: read(fadt + 116),
+            address: read(fadt + 120),
+        };
+        Some((register, read::<u8>(fadt + 128)))
+    } else {
+        None
+    };
+    Ok(PowerControl {
+        smi_command: read(fadt + 48),
+        acpi_enable: read(fadt + 52),
+        pm1a_control: read::<u32>(fadt + 64) as u16,
+        pm1b_control: read::<u32>(fadt + 68) as u16,
+        s5,
+        reset,
+    })
+}
+
+/// Reads what `shutdown`, `reboot` and `century_register` need from the ACPI tables through the RSDP at `rsdp_addr`.
+pub fn init(rsdp_addr: Option<u64>) {
+    let Some(rsdp_addr) = rsdp_addr else {
+        log::warn!("No ACPI tables, shutdown is unsupported");
+        return;
+    };
+    match read_power_control(rsdp_addr) {
+        Ok(power_control) => {
+            if power_control.s5.is_none() || power_control.pm1a_control == 0 {
+                log::warn!("ACPI has no soft off state, shutdown is unsupported");
+            }
+            unsafe { POWER_CONTROL = Some(power_control) };
+        }
+        Err(err) => log::warn!("Can't read the FADT, shutdown is unsupported: {}", err),
+    }
+    if let Ok((fadt, len)) = find_table(rsdp_addr, b"FACP") {
+        if len > 108 {
+            unsafe { CENTURY_REGISTER = read(fadt + 108) };
+        }
+    }
+}
+
+/// The CMOS register holding the RTC's century, if the FADT names one.
+pub fn century_register() -> Option<u8> {
+    Some(unsafe { CENTURY_REGISTER }).filter(|&register| register != 0)
+}
+
+impl PowerControl {
+    /// Enters the soft off state. Only returns if the machine is still running.
+    unsafe fn soft_off(&self) {
+        let Some((slp_typ_a, slp_typ_b)) = self.s5 else {
+            return;
+        };
+        if self.pm1a_control == 0 {
+            return;
+        }
+        let mut pm1a = Port::<u16>::new(self.pm1a_control);
+        // Without SCI_EN the firmware still owns power management and ignores sleep requests.
+        if pm1a.read() & SCI_EN == 0 && self.smi_command != 0 && self.acpi_enable != 0 {
+            Port::<u8>::new(self.smi_command as u16).write(self.acpi_enable);
+            for _ in 0..100 {
+                if pm1a.read() & SCI_EN != 0 {
+                    break;
+                }
+                time::pit_wait_ms(10);
+            }
+        }
+        pm1a.write(((slp_typ_a & 0b111) << 10) | SLP_EN);
+        if self.pm1b_control != 0 {
+            Port::<u16>::new(self.pm1b_control).write(((slp_typ_b & 0b111) << 10) | SLP_EN);
+        }
+        // Powering off can take a moment.
+        time::pit_wait_ms(50);
+    }
+}
+
+/// Turns the machine off through ACPI. Where that isn't possible, reboots instead.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+    log::info!("Powering off");
+    if let Some(power_control) = unsafe { POWER_CONTROL.as_ref() } {
+        unsafe { power_control.soft_off() };
+    }
+    log::warn!("Shutdown is unsupported, rebooting instead");
+    reboot();
+}
+
+/// Resets the machine through the ACPI reset register, the keyboard controller, or failing those
+/// a triple fault.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    log::info!("Rebooting");
+    if let Some((register, value)) = unsafe { POWER_CONTROL.as_ref() }.and_then(|pc| pc.reset) {
+        match register.space {
+            ADDRESS_SPACE_IO => unsafe { Port::<u8>::new(register.address as u16).write(value) },
+            ADDRESS_SPACE_MEMORY => {
+                if let Ok(registers) = Registers::map(PhysAddr::new(register.address), 1) {
+                    registers.write(0, value);
+                }
+            }
+            _ => {}
+        }
+        time::pit_wait_ms(50);
+    }
+    unsafe {
+        // Pulse the CPU reset line once the controller's input buffer is empty.
+        let mut status = Port::<u8>::new(0x64);
+        for _ in 0..0x10000 {
+            if status.read() & 0b10 == 0 {
+                break;
+            }
+        }
+        status.write(0xfe);
+    }
+    time::pit_wait_ms(50);
+    // With an empty IDT the next exception can't be handled, which resets the CPU.
+    unsafe {
+        let empty = x86_64::structures::DescriptorTablePointer {
+            limit: 0,
+            base: x86_64::VirtAddr::zero(),
+        };
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
@@ -0,0 +1,533 @@
+use crate::{
+    memory::{self, PAGE_SIZE},
+    mmio::Registers,
+    pci::{self, PciDevice},
+    time,
+};
+use alloc::{string::String, vec::Vec};
+use ata::{AtaError, BlockDevice};
+use x86_64::{structures::paging::PhysFrame, VirtAddr};
+
+const PCI_CLASS_STORAGE: u8 = 0x01;
+const PCI_SUBCLASS_SATA: u8 = 0x06;
+const PROG_IF_AHCI: u8 = 0x01;
+/// The controller's registers (ABAR) are behind BAR 5.
+const ABAR_INDEX: u8 = 5;
+
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const MAX_PORTS: usize = 32;
+const ABAR_SIZE: usize = PORT_BASE + MAX_PORTS * PORT_SIZE;
+
+// Generic host control registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_PI: usize = 0x0c;
+/// Supports 64-bit addresses in command lists and PRDs.
+const CAP_S64A: u32 = 1 << 31;
+/// Supports staggered spin-up, so ports have to be spun up by hand.
+const CAP_SSS: u32 = 1 << 27;
+/// AHCI enable, as opposed to legacy IDE emulation.
+const GHC_AE: u32 = 1 << 31;
+
+// Port registers
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SCTL: usize = 0x2c;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+/// Task file error: the device failed the command.
+const IS_TFES: u32 = 1 << 30;
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_BSY: u32 = 0x80;
+const SSTS_DET_MASK: u32 = 0xf;
+/// A device is attached and talking to the port.
+const DET_ESTABLISHED: u32 = 3;
+const SCTL_DET_MASK: u32 = 0xf;
+/// Held in DET for at least a millisecond to send COMRESET.
+const SCTL_DET_COMRESET: u32 = 1;
+/// What a SATA disk reports, as opposed to e.g. an ATAPI drive or a port multiplier.
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// Set in a host-to-device FIS that carries a command, rather than device control.
+const FIS_COMMAND: u8 = 1 << 7;
+const DEVICE_LBA: u8 = 1 << 6;
+/// Register FIS length in dwords.
+const FIS_LENGTH: u32 = 5;
+const HEADER_WRITE: u32 = 1 << 6;
+
+const ATA_READ_DMA: u8 = 0xc8;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA: u8 = 0xca;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE: u8 = 0xe7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_IDENTIFY: u8 = 0xec;
+
+const BLOCK_SIZE: usize = 512;
+
+// Where everything the port needs goes in its page. Only command slot 0 is used, but the list has
+// room for all 32 headers and has to be 1 KiB aligned.
+const COMMAND_LIST: usize = 0x000;
+const RECEIVED_FIS: usize = 0x400;
+const COMMAND_TABLE: usize = 0x500;
+const PRDT: usize = COMMAND_TABLE + 0x80;
+/// Data goes through this many pages, one PRD each, so a command moves at most 64 KiB.
+const BOUNCE_PAGES: usize = 16;
+const MAX_BLOCKS: usize = BOUNCE_PAGES * PAGE_SIZE / BLOCK_SIZE;
+
+const STOP_TIMEOUT_MS: u64 = 500;
+/// How long a device gets to bring the link up after COMRESET.
+const LINK_TIMEOUT_MS: u64 = 100;
+/// How long a disk gets to spin up or finish a command.
+const BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// A port with a SATA disk attached, set up by `init`.
+struct Port {
+    registers: Registers,
+    /// The page holding the command list, received FISes and the command table.
+    page: PhysFrame,
+    bounce: Vec<PhysFrame>,
+    lba48: bool,
+    block_count: u64,
+}
+
+/// A SATA disk behind an AHCI controller.
+#[derive(Debug, Clone, Copy)]
+pub struct AhciDisk {
+    /// Index into `PORTS`.
+    port: usize,
+}
+
+#[derive(Debug)]
+pub struct DiskInfo {
+    pub disk: AhciDisk,
+    pub model: String,
+}
+
+impl DiskInfo {
+    pub fn size_in_kib(&self) -> usize {
+        self.disk.block_count() as usize / 2
+    }
+}
+
+static mut PORTS: Vec<Port> = Vec::new();
+static mut DISKS: Vec<DiskInfo> = Vec::new();
+
+/// Spins until `done` returns true, or fails after `timeout_ms`.
+fn wait_until(timeout_ms: u64, mut done: impl FnMut() -> bool) -> bool {
+    let end = time::uptime_ms() + timeout_ms;
+    loop {
+        if done() {
+            return true;
+        }
+        if time::uptime_ms() >= end {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn page_virt(frame: PhysFrame) -> VirtAddr {
+    memory::phys_to_virt(frame.start_address())
+}
+
+impl Port {
+    /// Stops the command list and FIS receive engines, which is the only state the port's
+    /// addresses can be changed in. The firmware may have left them running.
+    fn stop(registers: &Registers) -> Result<(), &'static str> {
+        let cmd = registers.read::<u32>(PX_CMD);
+        registers.write(PX_CMD, cmd & !CMD_ST);
+        if !wait_until(STOP_TIMEOUT_MS, || {
+            registers.read::<u32>(PX_CMD) & CMD_CR == 0
+        }) {
+            return Err("command list won't stop");
+        }
+        let cmd = registers.read::<u32>(PX_CMD);
+        registers.write(PX_CMD, cmd & !CMD_FRE);
+        if !wait_until(STOP_TIMEOUT_MS, || {
+            registers.read::<u32>(PX_CMD) & CMD_FR == 0
+        }) {
+            return Err("FIS receive won't stop");
+        }
+        Ok(())
+    }
+
+    /// Resets the link with COMRESET and waits for a device to come up on it. Returns whether one
+    /// did.
+    fn comreset(registers: &Registers) -> bool {
+        let sctl = registers.read::<u32>(PX_SCTL) & !SCTL_DET_MASK;
+        registers.write(PX_SCTL, sctl | SCTL_DET_COMRESET);
+        time::pit_wait_ms(1);
+        registers.write(PX_SCTL, sctl);
+        let up = wait_until(LINK_TIMEOUT_MS, || {
+            registers.read::<u32>(PX_SSTS) & SSTS_DET_MASK == DET_ESTABLISHED
+        });
+        // The reset leaves errors that would otherwise stop the first command.
+        registers.write(PX_SERR, u32::MAX);
+        up
+    }
+
+    /// Takes over the port, resets it and identifies the disk on it. `None` if there's nothing,
+    /// or nothing that's a disk, attached. Nothing is kept allocated for such ports.
+    fn init(registers: Registers, cap: u32) -> Result<Option<Port>, &'static str> {
+        Port::stop(&registers)?;
+        let mut frames = Vec::with_capacity(1 + BOUNCE_PAGES);
+        for _ in 0..1 + BOUNCE_PAGES {
+            let Some(frame) = memory::allocate_frame() else {
+                frames.into_iter().for_each(memory::free_frame);
+                return Err("out of memory");
+            };
+            frames.push(frame);
+        }
+        let above_4g = frames
+            .iter()
+            .any(|frame| frame.start_address().as_u64() >> 32 != 0);
+        if above_4g && cap & CAP_S64A == 0 {
+            frames.into_iter().for_each(memory::free_frame);
+            return Err("memory above 4 GiB without 64-bit addressing");
+        }
+        let page = frames.remove(0);
+        let mut port = Port {
+            registers,
+            page,
+            bounce: frames,
+            lba48: false,
+            block_count: 0,
+        };
+        match port.attach(cap) {
+            Ok(true) => Ok(Some(port)),
+            Ok(false) => {
+                port.release();
+                Ok(None)
+            }
+            Err(err) => {
+                port.release();
+                Err(err)
+            }
+        }
+    }
+
+    /// Points the port at its page, resets the link and starts the port if a disk answers.
+    fn attach(&mut self, cap: u32) -> Result<bool, &'static str> {
+        let registers = self.registers;
+        unsafe { core::ptr::write_bytes(page_virt(self.page).as_mut_ptr::<u8>(), 0, PAGE_SIZE) };
+        let phys = self.page.start_address().as_u64();
+        registers.write(PX_CLB, (phys + COMMAND_LIST as u64) as u32);
+        registers.write(PX_CLBU, ((phys + COMMAND_LIST as u64) >> 32) as u32);
+        registers.write(PX_FB, (phys + RECEIVED_FIS as u64) as u32);
+        registers.write(PX_FBU, ((phys + RECEIVED_FIS as u64) >> 32) as u32);
+        // Polled, so no interrupts.
+        registers.write(PX_IE, 0u32);
+        registers.write(PX_IS, u32::MAX);
+        // Receiving FISes first, so the signature the disk sends after the reset lands in PxSIG.
+        let mut cmd = registers.read::<u32>(PX_CMD) | CMD_FRE;
+        if cap & CAP_SSS != 0 {
+            cmd |= CMD_SUD;
+        }
+        registers.write(PX_CMD, cmd);
+        if !Port::comreset(&registers) {
+            return Ok(false);
+        }
+        if !wait_until(BUSY_TIMEOUT_MS, || {
+            registers.read::<u32>(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0
+        }) {
+            return Err("device stays busy after reset");
+        }
+        let signature = registers.read::<u32>(PX_SIG);
+        if signature != SIG_ATA {
+            log::debug!("AHCI port has a device with signature {:#x}", signature);
+            return Ok(false);
+        }
+        registers.write(PX_CMD, registers.read::<u32>(PX_CMD) | CMD_ST);
+        Ok(true)
+    }
+
+    /// Stops the port and frees its memory.
+    fn release(self) {
+        let _ = Port::stop(&self.registers);
+        memory::free_frame(self.page);
+        self.bounce.into_iter().for_each(memory::free_frame);
+    }
+
+    /// Reads the disk's IDENTIFY data and returns its model.
+    fn identify(&mut self) -> Result<String, AtaError> {
+        self.issue(ATA_IDENTIFY, 0, 0, false, BLOCK_SIZE)?;
+        let words = unsafe { &*page_virt(self.bounce[0]).as_ptr::<[u16; 256]>() };
+        // Word 83 bit 10 is 48-bit addressing, with the block count in words 100-103 instead of
+        // 60-61.
+        self.lba48 = words[83] & (1 << 10) != 0;
+        self.block_count = if self.lba48 {
+            words[100..104]
+                .iter()
+                .rev()
+                .fold(0, |count, &word| count << 16 | word as u64)
+        } else {
+            (words[61] as u64) << 16 | words[60] as u64
+        };
+        let mut model = String::new();
+        for word in &words[27..47] {
+            for &byte in &word.to_be_bytes() {
+                model.push(byte as char);
+            }
+        }
+        Ok(model.trim().into())
+    }
+
+    /// Runs `command` on `count` blocks at `lba` in slot 0 and waits for it. Data, `len` bytes of
+    /// it, goes through the bounce pages. A failed command leaves the port stopped with an error,
+    /// so it's restarted.
+    fn issue(
+        &mut self,
+        command: u8,
+        lba: u64,
+        count: u16,
+        write: bool,
+        len: usize,
+    ) -> Result<(), AtaError> {
+        let registers = self.registers;
+        if !wait_until(BUSY_TIMEOUT_MS, || {
+            registers.read::<u32>(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0
+        }) {
+            return Err(AtaError::DeviceError);
+        }
+        let base = page_virt(self.page);
+        let prds = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+        let table = self.page.start_address() + COMMAND_TABLE as u64;
+        unsafe {
+            let header = (base + COMMAND_LIST as u64).as_mut_ptr::<u32>();
+            header.write_volatile(
+                FIS_LENGTH | if write { HEADER_WRITE } else { 0 } | (prds as u32) << 16,
+            );
+            // Bytes transferred, which the controller counts up.
+            header.add(1).write_volatile(0);
+            header.add(2).write_volatile(table.as_u64() as u32);
+            header.add(3).write_volatile((table.as_u64() >> 32) as u32);
+
+            let fis = (base + COMMAND_TABLE as u64).as_mut_ptr::<u8>();
+            core::ptr::write_bytes(fis, 0, PRDT - COMMAND_TABLE);
+            let lba = lba.to_le_bytes();
+            let count = count.to_le_bytes();
+            // 28-bit commands take the top 4 bits of the block in the device register.
+            let device = if matches!(command, ATA_READ_DMA | ATA_WRITE_DMA) {
+                DEVICE_LBA | lba[3] & 0xf
+            } else {
+                DEVICE_LBA
+            };
+            let bytes = [
+                FIS_TYPE_REG_H2D,
+                FIS_COMMAND,
+                command,
+                0,
+                lba[0],
+                lba[1],
+                lba[2],
+                device,
+                lba[3],
+                lba[4],
+                lba[5],
+                0,
+                count[0],
+                count[1],
+            ];
+            for (offset, &byte) in bytes.iter().enumerate() {
+                fis.add(offset).write_volatile(byte);
+            }
+
+            let prdt = (base + PRDT as u64).as_mut_ptr::<u32>();
+            for (index, frame) in self.bounce[..prds].iter().enumerate() {
+                let bytes = (len - index * PAGE_SIZE).min(PAGE_SIZE);
+                let entry = prdt.add(index * 4);
+                let addr = frame.start_address().as_u64();
+                entry.write_volatile(addr as u32);
+                entry.add(1).write_volatile((addr >> 32) as u32);
+                entry.add(2).write_volatile(0);
+                // Byte count minus one.
+                entry.add(3).write_volatile(bytes as u32 - 1);
+            }
+        }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        registers.write(PX_IS, u32::MAX);
+        registers.write(PX_CI, 1u32);
+        let mut failed = false;
+        let finished = wait_until(BUSY_TIMEOUT_MS, || {
+            failed = registers.read::<u32>(PX_IS) & IS_TFES != 0;
+            failed || registers.read::<u32>(PX_CI) & 1 == 0
+        });
+        if !finished || failed || registers.read::<u32>(PX_TFD) & TFD_ERR != 0 {
+            self.recover();
+            return Err(AtaError::DeviceError);
+        }
+        Ok(())
+    }
+
+    /// Restarts the port after a failed command, which is how AHCI clears the error.
+    fn recover(&mut self) {
+        let registers = self.registers;
+        if Port::stop(&registers).is_err() {
+            Port::comreset(&registers);
+        }
+        registers.write(PX_SERR, u32::MAX);
+        registers.write(PX_IS, u32::MAX);
+        registers.write(PX_CMD, registers.read::<u32>(PX_CMD) | CMD_FRE);
+        registers.write(PX_CMD, registers.read::<u32>(PX_CMD) | CMD_ST);
+    }
+
+    /// Checks a transfer like an ATA partition does and returns its first block.
+    fn check(&self, buf_len: usize, address: usize, blocks: usize) -> Result<u64, AtaError> {
+        if address % BLOCK_SIZE != 0 {
+            return Err(AtaError::AddressNotAligned);
+        }
+        if buf_len != blocks * BLOCK_SIZE {
+            return Err(AtaError::WrongSizeBuffer);
+        }
+        let lba = (address / BLOCK_SIZE) as u64;
+        if lba + blocks as u64 > self.block_count {
+            return Err(AtaError::OutOfBounds);
+        }
+        Ok(lba)
+    }
+
+    fn read(&mut self, buf: &mut [u8], address: usize, blocks: usize) -> Result<(), AtaError> {
+        let mut lba = self.check(buf.len(), address, blocks)?;
+        let command = if self.lba48 {
+            ATA_READ_DMA_EXT
+        } else {
+            ATA_READ_DMA
+        };
+        for chunk in buf.chunks_mut(MAX_BLOCKS * BLOCK_SIZE) {
+            let count = chunk.len() / BLOCK_SIZE;
+            self.issue(command, lba, count as u16, false, chunk.len())?;
+            for (data, frame) in chunk.chunks_mut(PAGE_SIZE).zip(&self.bounce) {
+                let src = page_virt(*frame).as_ptr::<u8>();
+                unsafe { core::ptr::copy_nonoverlapping(src, data.as_mut_ptr(), data.len()) };
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes and then flushes the disk's cache, like the ATA driver.
+    fn write(&mut self, buf: &[u8], address: usize, blocks: usize) -> Result<(), AtaError> {
+        let mut lba = self.check(buf.len(), address, blocks)?;
+        let (command, flush) = if self.lba48 {
+            (ATA_WRITE_DMA_EXT, ATA_FLUSH_CACHE_EXT)
+        } else {
+            (ATA_WRITE_DMA, ATA_FLUSH_CACHE)
+        };
+        for chunk in buf.chunks(MAX_BLOCKS * BLOCK_SIZE) {
+            let count = chunk.len() / BLOCK_SIZE;
+            for (data, frame) in chunk.chunks(PAGE_SIZE).zip(&self.bounce) {
+                let dest = page_virt(*frame).as_mut_ptr::<u8>();
+                unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len()) };
+            }
+            self.issue(command, lba, count as u16, true, chunk.len())?;
+            lba += count as u64;
+        }
+        self.issue(flush, 0, 0, false, 0)
+    }
+}
+
+impl AhciDisk {
+    fn port(&self) -> &'static mut Port {
+        unsafe { &mut PORTS[self.port] }
+    }
+    pub fn block_count(&self) -> u64 {
+        self.port().block_count
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    const BLOCK_SIZE: u32 = BLOCK_SIZE as u32;
+    type Error = AtaError;
+    fn read(
+        &self,
+        buf: &mut [u8],
+        address: usize,
+        number_of_blocks: usize,
+    ) -> Result<(), Self::Error> {
+        self.port().read(buf, address, number_of_blocks)
+    }
+    fn write(
+        &self,
+        buf: &[u8],
+        address: usize,
+        number_of_blocks: usize,
+    ) -> Result<(), Self::Error> {
+        self.port().write(buf, address, number_of_blocks)
+    }
+}
+
+/// Finds the disks on every AHCI controller.
+pub fn init() {
+    let controllers = pci::find_by_class(PCI_CLASS_STORAGE, PCI_SUBCLASS_SATA)
+        .filter(|device| device.prog_if == PROG_IF_AHCI);
+    for controller in controllers {
+        if let Err(err) = init_controller(controller) {
+            log::warn!("AHCI controller {} unusable: {}", controller, err);
+        }
+    }
+    for info in disks() {
+        log::info!(
+            "Found SATA disk {} size:{}KiB",
+            info.model,
+            info.size_in_kib()
+        );
+    }
+}
+
+fn init_controller(controller: &PciDevice) -> Result<(), &'static str> {
+    let hba = controller.map_bar(ABAR_INDEX, ABAR_SIZE)?;
+    controller.enable_bus_master();
+    hba.write(HBA_GHC, hba.read::<u32>(HBA_GHC) | GHC_AE);
+    let cap = hba.read::<u32>(HBA_CAP);
+    let implemented = hba.read::<u32>(HBA_PI);
+    for index in (0..MAX_PORTS).filter(|index| implemented & 1 << index != 0) {
+        let registers = hba.block(PORT_BASE + index * PORT_SIZE, PORT_SIZE);
+        let mut port = match Port::init(registers, cap) {
+            Ok(Some(port)) => port,
+            Ok(None) => continue,
+            Err(err) => {
+                log::warn!("AHCI port {}: {}", index, err);
+                continue;
+            }
+        };
+        match port.identify() {
+            Ok(model) => unsafe {
+                PORTS.push(port);
+                DISKS.push(DiskInfo {
+                    disk: AhciDisk {
+                        port: PORTS.len() - 1,
+                    },
+                    model,
+                });
+            },
+            Err(err) => {
+                log::warn!("AHCI port {}: identify failed: {:?}", index, err);
+                port.release();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every SATA disk `init` found.
+pub fn disks() -> &'static [DiskInfo] {
+    unsafe { &DISKS }
+}
@@ -0,0 +1,156 @@
+use crate::{acpi, cpu, mmio::Registers, time};
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+/// Delivered when an interrupt goes away before the CPU accepts it. Needs no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// Local APIC registers, as offsets from its base.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_TIMER_PERIODIC: u32 = 1 << 17;
+const LAPIC_TIMER_DIVIDE_BY_16: u32 = 0b0011;
+/// How long the timer is counted against the PIT to find its frequency.
+const CALIBRATION_MS: u64 = 10;
+
+// IO APIC registers are accessed through a select and a data register.
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const IOAPIC_ACTIVE_LOW: u32 = 1 << 13;
+const IOAPIC_LEVEL_TRIGGERED: u32 = 1 << 15;
+const IOAPIC_MASKED: u32 = 1 << 16;
+
+const LAPIC_SIZE: usize = 0x400;
+const IOAPIC_SIZE: usize = 0x20;
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_DATA: usize = 0x10;
+
+// None while the 8259 PIC is in use.
+static mut LOCAL_APIC: Option<Registers> = None;
+
+unsafe fn lapic_read(register: usize) -> u32 {
+    LOCAL_APIC.expect("no local APIC").read(register)
+}
+unsafe fn lapic_write(register: usize, value: u32) {
+    LOCAL_APIC.expect("no local APIC").write(register, value);
+}
+
+struct IoApic {
+    registers: Registers,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        self.registers.write(IOAPIC_SELECT, register);
+        self.registers.read(IOAPIC_DATA)
+    }
+    unsafe fn write(&self, register: u32, value: u32) {
+        self.registers.write(IOAPIC_SELECT, register);
+        self.registers.write(IOAPIC_DATA, value);
+    }
+    unsafe fn set_redirection(&self, gsi: u32, low: u32, destination: u8) {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        self.write(register + 1, (destination as u32) << 24);
+        self.write(register, low);
+    }
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+}
+
+/// Whether interrupts go through the APIC instead of the 8259 PIC.
+pub fn enabled() -> bool {
+    unsafe { LOCAL_APIC.is_some() }
+}
+
+/// Acknowledges the interrupt being handled.
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+/// Switches interrupt delivery to the local APIC and IO APICs described by the MADT. Each ISA IRQ
+/// in `isa_irqs` is routed to its vector, and the local APIC timer is started on `timer_vector`
+/// instead of the PIT. The caller must mask the 8259 PIC. Fails without changing anything if this
+/// machine has no usable APIC.
+pub fn init(
+    rsdp_addr: Option<u64>,
+    isa_irqs: &[(u8, u8)],
+    timer_vector: u8,
+) -> Result<acpi::Madt, &'static str> {
+    if !cpu::has_apic() {
+        return Err("CPU has no APIC");
+    }
+    let madt = acpi::read_madt(rsdp_addr.ok_or("bootloader found no ACPI tables")?)?;
+    if madt.io_apics.is_empty() {
+        return Err("no IO APIC");
+    }
+    let mut io_apics = alloc::vec::Vec::with_capacity(madt.io_apics.len());
+    for io_apic in &madt.io_apics {
+        let registers = Registers::map(PhysAddr::new(io_apic.address), IOAPIC_SIZE)?;
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base: io_apic.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = unsafe { (io_apic.read(IOAPIC_VERSION) >> 16) & 0xff } + 1;
+        io_apics.push(io_apic);
+    }
+    let lapic = Registers::map(PhysAddr::new(madt.local_apic_address), LAPIC_SIZE)?;
+
+    unsafe {
+        let mut apic_base = Msr::new(APIC_BASE_MSR);
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+        LOCAL_APIC = Some(lapic);
+        lapic_write(LAPIC_TASK_PRIORITY, 0);
+        lapic_write(
+            LAPIC_SPURIOUS,
+            LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+        );
+
+        // Everything is delivered to this CPU until there are others.
+        let destination = (lapic_read(LAPIC_ID) >> 24) as u8;
+        for io_apic in &io_apics {
+            for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+                io_apic.set_redirection(gsi, IOAPIC_MASKED, destination);
+            }
+        }
+        for &(irq, vector) in isa_irqs {
+            let route = madt.isa_irq(irq);
+            let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(route.gsi)) else {
+                log::warn!("no IO APIC handles IRQ {} (GSI {})", irq, route.gsi);
+                continue;
+            };
+            let mut low = vector as u32;
+            if route.active_low {
+                low |= IOAPIC_ACTIVE_LOW;
+            }
+            if route.level_triggered {
+                low |= IOAPIC_LEVEL_TRIGGERED;
+            }
+            io_apic.set_redirection(route.gsi, low, destination);
+        }
+
+        // Count how fast the timer runs against the PIT, then fire once per millisecond.
+        lapic_write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_BY_16);
+        lapic_write(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+        time::pit_wait_ms(CALIBRATION_MS);
+        let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT_COUNT);
+        let count_per_ms = (elapsed as u64 / CALIBRATION_MS).max(1) as u32;
+        lapic_write(LAPIC_TIMER, LAPIC_TIMER_PERIODIC | timer_vector as u32);
+        lapic_write(LAPIC_TIMER_INITIAL_COUNT, count_per_ms);
+        time::set_tick_length(1_000_000);
+    }
+    Ok(madt)
+}
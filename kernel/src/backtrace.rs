@@ -0,0 +1,121 @@
+use crate::{
+    filesystem,
+    memory::{self, USER_SPACE_END},
+};
+use core::fmt::{self, Write};
+use kernel_common::symbols;
+use x86_64::VirtAddr;
+
+/// Frames deeper than this aren't printed, in case the chain loops.
+const MAX_DEPTH: usize = 32;
+
+/// Written by the build script from the kernel's symbol table, in the `kernel_common::symbols`
+/// format.
+const SYMBOLS_PATH: &str = "/kernel.sym";
+/// Used to find how far the kernel was moved from its link address.
+const KERNEL_MAIN_SYMBOL: &str = "kernel::kernel_main";
+
+static mut SYMBOLS: &[u8] = &[];
+/// Added to link addresses to get where the code actually is.
+static mut LOAD_OFFSET: u64 = 0;
+
+/// Reads the symbol table from the filesystem so backtraces show function names. Without one
+/// they only have addresses. The table is kept for good, since a panic can't load it.
+pub fn load_symbols() {
+    let Ok(file) = filesystem::open(SYMBOLS_PATH) else {
+        return;
+    };
+    let table: &'static [u8] = match file.read_all() {
+        Ok(data) => data.leak(),
+        Err(err) => {
+            log::warn!("{}: {}", SYMBOLS_PATH, err);
+            return;
+        }
+    };
+    let Some(kernel_main) =
+        symbols::symbols(table).find(|symbol| symbol.name == KERNEL_MAIN_SYMBOL)
+    else {
+        log::warn!(
+            "{} has no {}, ignoring it",
+            SYMBOLS_PATH,
+            KERNEL_MAIN_SYMBOL
+        );
+        return;
+    };
+    unsafe {
+        LOAD_OFFSET = (crate::kernel_main as usize as u64).wrapping_sub(kernel_main.start);
+        SYMBOLS = table;
+    }
+    log::debug!("Loaded {} kernel symbols", symbols::symbols(table).count());
+}
+
+/// The function containing `address` and how far into it the address is. Doesn't allocate.
+fn lookup(address: u64) -> Option<(&'static str, u64)> {
+    let link_address = address.wrapping_sub(unsafe { LOAD_OFFSET });
+    symbols::lookup(unsafe { SYMBOLS }, link_address)
+}
+
+/// Whether the 16 byte frame record at `frame` (saved frame pointer, then return address) can be
+/// read without faulting.
+fn frame_readable(frame: u64) -> bool {
+    if frame == 0 || frame % 8 != 0 {
+        return false;
+    }
+    let (Ok(start), Ok(end)) = (VirtAddr::try_new(frame), VirtAddr::try_new(frame + 15)) else {
+        return false;
+    };
+    memory::is_mapped(start) && memory::is_mapped(end)
+}
+
+/// Calls `f` with the return address of each frame on the frame pointer chain, innermost first.
+/// The kernel is built with `-C force-frame-pointers=yes`, so every function keeps one.
+#[inline(never)]
+pub fn walk(mut f: impl FnMut(u64)) {
+    let mut frame: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack)) };
+    for _ in 0..MAX_DEPTH {
+        if !frame_readable(frame) {
+            break;
+        }
+        let (next, return_address) = unsafe {
+            let record = frame as *const u64;
+            (record.read(), record.add(1).read())
+        };
+        if return_address == 0 {
+            break;
+        }
+        f(return_address);
+        frame = next;
+    }
+}
+
+/// Writes the return addresses on the stack, one per line, with the function names if the symbol
+/// table was loaded. Otherwise they're for `addr2line -e kernel`, and since the kernel may be
+/// loaded at an offset, the address of `kernel_main` is included to work it out.
+pub fn print(out: &mut impl Write) -> fmt::Result {
+    if unsafe { SYMBOLS.is_empty() } {
+        writeln!(
+            out,
+            "Backtrace (kernel_main at {:#x}):",
+            crate::kernel_main as usize
+        )?;
+    } else {
+        writeln!(out, "Backtrace:")?;
+    }
+    let mut result = Ok(());
+    walk(|address| {
+        if result.is_err() {
+            return;
+        }
+        // A return address is just past the call, which may be the last instruction of a
+        // function, so look up the call itself.
+        result = if address < USER_SPACE_END {
+            writeln!(out, "  {:#x} (user)", address)
+        } else if let Some((name, offset)) = lookup(address - 1) {
+            writeln!(out, "  {:#x} {}+{:#x}", address, name, offset + 1)
+        } else {
+            writeln!(out, "  {:#x}", address)
+        };
+    });
+    result
+}
@@ -0,0 +1,130 @@
+use alloc::{boxed::Box, collections::BTreeMap, rc::Rc};
+use ata::{AtaError, BlockDevice, Partition};
+
+pub const BLOCK_SIZE: usize = Partition::BLOCK_SIZE as usize;
+/// 128 KiB of cached blocks.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+pub type Block = [u8; BLOCK_SIZE];
+
+/// What the cache reads blocks from: any `BlockDevice` with the ATA driver's errors, e.g. a
+/// partition or a RAM disk. `BlockDevice` itself can't be boxed because of its `BLOCK_SIZE`
+/// constant, so this passes it on as a method instead.
+pub trait Device {
+    fn block_size(&self) -> usize;
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize)
+        -> Result<(), AtaError>;
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), AtaError>;
+}
+
+impl<T: BlockDevice<Error = AtaError>> Device for T {
+    fn block_size(&self) -> usize {
+        T::BLOCK_SIZE as usize
+    }
+    fn read(
+        &self,
+        buf: &mut [u8],
+        address: usize,
+        number_of_blocks: usize,
+    ) -> Result<(), AtaError> {
+        BlockDevice::read(self, buf, address, number_of_blocks)
+    }
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), AtaError> {
+        BlockDevice::write(self, buf, address, number_of_blocks)
+    }
+}
+
+struct CachedBlock {
+    data: Rc<Block>,
+    last_used: u64,
+}
+
+/// Recently used blocks of a device. Writes go straight to the device and replace the cached copy.
+struct BlockCache {
+    device: Box<dyn Device>,
+    capacity: usize,
+    blocks: BTreeMap<u64, CachedBlock>,
+    // Incremented on every access, to find the least recently used block.
+    clock: u64,
+}
+
+impl BlockCache {
+    fn insert(&mut self, lba: u64, data: Rc<Block>) {
+        self.clock += 1;
+        let last_used = self.clock;
+        self.blocks.insert(lba, CachedBlock { data, last_used });
+        self.evict();
+    }
+
+    /// Drops least recently used blocks until the cache fits its capacity.
+    fn evict(&mut self) {
+        while self.blocks.len() > self.capacity {
+            let oldest = self
+                .blocks
+                .iter()
+                .min_by_key(|(_, block)| block.last_used)
+                .map(|(lba, _)| *lba)
+                .unwrap();
+            self.blocks.remove(&oldest);
+        }
+    }
+}
+
+static mut CACHE: Option<BlockCache> = None;
+
+/// Caches up to `capacity` blocks of `device`. Replaces any previous cache. Panics if the device's
+/// blocks aren't `BLOCK_SIZE` bytes, which the filesystem assumes.
+pub fn init(device: Box<dyn Device>, capacity: usize) {
+    assert_eq!(device.block_size(), BLOCK_SIZE, "unsupported block size");
+    unsafe {
+        CACHE = Some(BlockCache {
+            device,
+            capacity: capacity.max(1),
+            blocks: BTreeMap::new(),
+            clock: 0,
+        });
+    }
+}
+
+fn cache() -> Result<&'static mut BlockCache, AtaError> {
+    unsafe { CACHE.as_mut().ok_or(AtaError::NotInitialized) }
+}
+
+/// Block `lba` of the device, from the cache if it was read recently. The returned buffer is a
+/// snapshot: a later `write_block` doesn't change it.
+pub fn read_block(lba: u64) -> Result<Rc<Block>, AtaError> {
+    let cache = cache()?;
+    cache.clock += 1;
+    let clock = cache.clock;
+    if let Some(block) = cache.blocks.get_mut(&lba) {
+        block.last_used = clock;
+        return Ok(block.data.clone());
+    }
+    let mut data = [0; BLOCK_SIZE];
+    cache.device.read(&mut data, lba as usize * BLOCK_SIZE, 1)?;
+    let data = Rc::new(data);
+    cache.insert(lba, data.clone());
+    Ok(data)
+}
+
+/// Writes block `lba` of the device and updates the cache. If the write fails the cached copy is
+/// dropped, since the device's contents are unknown.
+#[allow(dead_code)]
+pub fn write_block(lba: u64, data: &Block) -> Result<(), AtaError> {
+    let cache = cache()?;
+    if let Err(err) = cache.device.write(data, lba as usize * BLOCK_SIZE, 1) {
+        cache.blocks.remove(&lba);
+        return Err(err);
+    }
+    cache.insert(lba, Rc::new(*data));
+    Ok(())
+}
+
+/// Changes how many blocks are kept, evicting the least recently used ones if there are too many.
+#[allow(dead_code)]
+pub fn set_capacity(capacity: usize) -> Result<(), AtaError> {
+    let cache = cache()?;
+    cache.capacity = capacity.max(1);
+    cache.evict();
+    Ok(())
+}
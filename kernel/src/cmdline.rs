@@ -0,0 +1,126 @@
+use kernel_common::cmdline;
+use x86_64::instructions::port::Port;
+
+/// The bootloader doesn't pass a command line, so it's read from a QEMU firmware config file,
+/// given with `-fw_cfg name=opt/mythos/cmdline,string=...`.
+const FW_CFG_FILE_NAME: &[u8] = b"opt/mythos/cmdline";
+const FW_CFG_SELECTOR: u16 = 0x510;
+const FW_CFG_DATA: u16 = 0x511;
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+const FW_CFG_FILE_NAME_SIZE: usize = 56;
+
+const MAX_CMDLINE: usize = 512;
+/// Keys read by some part of the kernel. Others are reported by `warn_unknown_keys`.
+const KNOWN_KEYS: &[&str] = &[
+    "aslr", "ata", "disk", "elf", "font", "gdb", "init", "log", "loglevel", "physmap", "selftest",
+    "splash", "watchdog", "xmodem",
+];
+
+// Kept in a fixed buffer because the command line is read before the heap exists.
+static mut CMDLINE: [u8; MAX_CMDLINE] = [0; MAX_CMDLINE];
+static mut CMDLINE_LEN: usize = 0;
+
+struct FwCfg {
+    selector: Port<u16>,
+    data: Port<u8>,
+}
+
+impl FwCfg {
+    fn select(&mut self, key: u16) {
+        unsafe { self.selector.write(key) };
+    }
+    fn read(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = unsafe { self.data.read() };
+        }
+    }
+    fn read_u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.read(&mut buf);
+        u32::from_be_bytes(buf)
+    }
+    fn skip(&mut self, count: usize) {
+        for _ in 0..count {
+            unsafe { self.data.read() };
+        }
+    }
+
+    /// The selector and size of the file named `name`.
+    fn find_file(&mut self, name: &[u8]) -> Option<(u16, usize)> {
+        self.select(FW_CFG_FILE_DIR);
+        for _ in 0..self.read_u32() {
+            let size = self.read_u32() as usize;
+            let mut select = [0; 2];
+            self.read(&mut select);
+            self.skip(2);
+            let mut file_name = [0; FW_CFG_FILE_NAME_SIZE];
+            self.read(&mut file_name);
+            let len = file_name
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(file_name.len());
+            if &file_name[..len] == name {
+                return Some((u16::from_be_bytes(select), size));
+            }
+        }
+        None
+    }
+}
+
+/// Reads the command line. Without QEMU or a command line file it's empty, and every part of the
+/// kernel uses its defaults. Must be called before anything uses `get`.
+pub fn init() {
+    let mut fw_cfg = FwCfg {
+        selector: Port::new(FW_CFG_SELECTOR),
+        data: Port::new(FW_CFG_DATA),
+    };
+    // Reading a port nothing answers gives 0xff, which can't be mistaken for the signature.
+    let mut signature = [0; 4];
+    fw_cfg.select(FW_CFG_SIGNATURE);
+    fw_cfg.read(&mut signature);
+    if &signature != b"QEMU" {
+        return;
+    }
+    let Some((select, size)) = fw_cfg.find_file(FW_CFG_FILE_NAME) else {
+        return;
+    };
+    let len = size.min(MAX_CMDLINE);
+    fw_cfg.select(select);
+    unsafe {
+        fw_cfg.read(&mut CMDLINE[..len]);
+        CMDLINE_LEN = len;
+    }
+}
+
+/// Uses `cmdline` instead of reading it, for loaders that pass one. Anything past `MAX_CMDLINE`
+/// bytes is dropped.
+#[cfg(feature = "multiboot2")]
+pub fn set(cmdline: &str) {
+    let len = cmdline.len().min(MAX_CMDLINE);
+    unsafe {
+        CMDLINE[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+        CMDLINE_LEN = len;
+    }
+}
+
+/// The whole command line. Empty if it wasn't valid UTF-8.
+pub fn raw() -> &'static str {
+    cmdline::from_bytes(unsafe { &CMDLINE[..CMDLINE_LEN] })
+}
+
+/// The value of the last `key=value` on the command line, or `Some("")` if `key` is a bare flag.
+pub fn get(key: &str) -> Option<&'static str> {
+    cmdline::get(raw(), key)
+}
+
+/// Logs the command line and any keys nothing reads, which are otherwise ignored.
+pub fn warn_unknown_keys() {
+    if raw().is_empty() {
+        return;
+    }
+    log::info!("Command line: {}", raw());
+    for key in cmdline::unknown_keys(raw(), KNOWN_KEYS) {
+        log::warn!("Unknown command line option {}", key);
+    }
+}
@@ -0,0 +1,614 @@
+use crate::graphics::{self, Color};
+use crate::{
+    cmdline,
+    filesystem::{self, FsError},
+    screen,
+};
+use x86_64::instructions::interrupts;
+
+const MAX_LINES: usize = 256;
+const MAX_COLUMNS: usize = 160;
+
+const ESC: char = '\x1b';
+/// Parameters of a control sequence past this many are ignored.
+const MAX_PARAMS: usize = 4;
+/// The 8 colors of SGR 30-37 and 40-47, then their bold versions.
+const PALETTE: [Color; 16] = [
+    Color::new(0, 0, 0),
+    Color::new(170, 0, 0),
+    Color::new(0, 170, 0),
+    Color::new(170, 85, 0),
+    Color::new(0, 0, 170),
+    Color::new(170, 0, 170),
+    Color::new(0, 170, 170),
+    Color::new(170, 170, 170),
+    Color::new(85, 85, 85),
+    Color::new(255, 85, 85),
+    Color::new(85, 255, 85),
+    Color::new(255, 255, 85),
+    Color::new(85, 85, 255),
+    Color::new(255, 85, 255),
+    Color::new(85, 255, 255),
+    Color::new(255, 255, 255),
+];
+
+#[derive(Clone, Copy)]
+struct Cell {
+    ch: char,
+    fg: Color,
+    bg: Color,
+}
+
+impl Cell {
+    const BLANK: Cell = Cell::new(' ', Color::WHITE);
+
+    const fn new(ch: char, fg: Color) -> Self {
+        Cell {
+            ch,
+            fg,
+            bg: Color::BLACK,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    // Only the first `len` are shown, so the rest start zeroed, which keeps the console out of the
+    // kernel image.
+    cells: [Cell; MAX_COLUMNS],
+    len: usize,
+}
+
+impl Line {
+    const fn empty() -> Self {
+        Line {
+            cells: [Cell::new('\0', Color::BLACK); MAX_COLUMNS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, cell: Cell) {
+        self.cells[self.len] = cell;
+        self.len += 1;
+    }
+}
+
+/// How the text `write` adds looks, as set by SGR escape sequences. Colors are indices into
+/// `PALETTE`; without one, text has the writer's color on black.
+#[derive(Clone, Copy)]
+struct Style {
+    fg: Option<usize>,
+    bg: Option<usize>,
+    bold: bool,
+}
+
+impl Style {
+    const DEFAULT: Style = Style {
+        fg: None,
+        bg: None,
+        bold: false,
+    };
+
+    fn cell(&self, ch: char, color: Color) -> Cell {
+        Cell {
+            ch,
+            fg: self
+                .fg
+                .map_or(color, |fg| PALETTE[fg + 8 * self.bold as usize]),
+            bg: self.bg.map_or(Color::BLACK, |bg| PALETTE[bg]),
+        }
+    }
+}
+
+/// Where `write` is in an escape sequence. Kept between calls, since a program may write one in
+/// pieces.
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /// After ESC.
+    Start,
+    /// After ESC [, collecting the numbers separated by `;` until the final letter.
+    Csi {
+        params: [u16; MAX_PARAMS],
+        // Index of the parameter being read.
+        index: usize,
+        // Sequences starting with `?` and the like aren't standard ones, and are ignored.
+        private: bool,
+    },
+}
+
+/// A UTF-8 sequence `write` is in the middle of, also kept between calls.
+#[derive(Clone, Copy)]
+struct PartialChar {
+    // The bits read so far.
+    code: u32,
+    // Continuation bytes still to come.
+    missing: u8,
+    // The smallest code point a sequence this long encodes, since longer ones than needed are
+    // invalid.
+    min: u32,
+}
+
+/// A ring buffer of screen rows. Long lines are wrapped when they are pushed, so every entry is
+/// exactly one row on screen.
+struct Console {
+    lines: [Line; MAX_LINES],
+    // Index of the oldest line.
+    start: usize,
+    len: usize,
+    // Number of rows scrolled back from the newest line.
+    scroll: usize,
+    visible: bool,
+    // Whether `write` continues at `cursor` and `column`. Otherwise it starts a new row first.
+    open: bool,
+    // Line `write` is at, counted from the oldest one, and the column in it.
+    cursor: usize,
+    column: usize,
+    style: Style,
+    escape: Escape,
+    partial: PartialChar,
+    // Line being typed, shown in the bottom row below the scrollback.
+    input: Option<Line>,
+}
+
+impl Console {
+    const fn new() -> Self {
+        Console {
+            lines: [Line::empty(); MAX_LINES],
+            start: 0,
+            len: 0,
+            scroll: 0,
+            visible: true,
+            open: false,
+            cursor: 0,
+            column: 0,
+            style: Style::DEFAULT,
+            escape: Escape::None,
+            partial: PartialChar {
+                code: 0,
+                missing: 0,
+                min: 0,
+            },
+            input: None,
+        }
+    }
+
+    fn line(&self, index: usize) -> &Line {
+        &self.lines[(self.start + index) % MAX_LINES]
+    }
+    fn line_mut(&mut self, index: usize) -> &mut Line {
+        &mut self.lines[(self.start + index) % MAX_LINES]
+    }
+    fn push_row(&mut self, row: Line) {
+        if self.len == MAX_LINES {
+            self.lines[self.start] = row;
+            self.start = (self.start + 1) % MAX_LINES;
+            self.cursor = self.cursor.saturating_sub(1);
+        } else {
+            self.lines[(self.start + self.len) % MAX_LINES] = row;
+            self.len += 1;
+        }
+    }
+    fn push_line(&mut self, text: &str, color: Color) {
+        self.open = false;
+        let columns = columns();
+        for text in text.split('\n') {
+            let mut row = Line::empty();
+            for ch in text.chars() {
+                if row.len == columns {
+                    self.push_row(row);
+                    row = Line::empty();
+                }
+                row.push(Cell::new(ch, color));
+            }
+            self.push_row(row);
+        }
+    }
+    fn history_rows(&self) -> usize {
+        if self.input.is_some() {
+            rows().saturating_sub(1)
+        } else {
+            rows()
+        }
+    }
+    /// Index of the line in the top row when not scrolled back. Adds empty rows until the lines
+    /// fill the screen, so every row of it can be moved to.
+    fn screen_top(&mut self) -> usize {
+        let rows = self.history_rows();
+        while self.len < rows {
+            self.push_row(Line::empty());
+        }
+        self.len - rows
+    }
+    /// Makes `cursor` and `column` where the next character goes, starting a new row if the last
+    /// line was ended.
+    fn open_cursor(&mut self) {
+        if !self.open {
+            self.push_row(Line::empty());
+            self.cursor = self.len - 1;
+            self.column = 0;
+            self.open = true;
+        }
+    }
+    /// Moves to the start of the next line, or only ends the newest one, so a trailing newline
+    /// doesn't leave an empty row.
+    fn new_line(&mut self) {
+        if self.open && self.cursor + 1 < self.len {
+            self.cursor += 1;
+            self.column = 0;
+        } else {
+            self.open = false;
+        }
+    }
+    fn put_char(&mut self, ch: char, color: Color) {
+        self.open_cursor();
+        if self.column >= columns() {
+            self.new_line();
+            self.open_cursor();
+        }
+        let cell = self.style.cell(ch, color);
+        let column = self.column;
+        let line = self.line_mut(self.cursor);
+        while line.len < column {
+            line.push(Cell::BLANK);
+        }
+        line.cells[column] = cell;
+        line.len = line.len.max(column + 1);
+        self.column += 1;
+    }
+    /// Writes UTF-8 text at the cursor, which is at the end of the newest line unless an escape
+    /// sequence moved it. Bytes that aren't valid UTF-8 are written as U+FFFD.
+    fn write(&mut self, bytes: &[u8], color: Color) {
+        for &byte in bytes {
+            if self.partial.missing > 0 {
+                if byte & 0xc0 == 0x80 {
+                    let partial = &mut self.partial;
+                    partial.code = partial.code << 6 | (byte & 0x3f) as u32;
+                    partial.missing -= 1;
+                    if partial.missing == 0 {
+                        let ch = char::from_u32(partial.code)
+                            .filter(|_| partial.code >= partial.min)
+                            .unwrap_or(char::REPLACEMENT_CHARACTER);
+                        self.write_char(ch, color);
+                    }
+                    continue;
+                }
+                // The sequence was cut short, and `byte` starts the next one.
+                self.partial.missing = 0;
+                self.write_char(char::REPLACEMENT_CHARACTER, color);
+            }
+            let (code, missing, min) = match byte {
+                0x00..=0x7f => {
+                    self.write_char(byte as char, color);
+                    continue;
+                }
+                0xc0..=0xdf => (byte & 0x1f, 1, 0x80),
+                0xe0..=0xef => (byte & 0x0f, 2, 0x800),
+                0xf0..=0xf7 => (byte & 0x07, 3, 0x10000),
+                // A continuation byte without a start, or one that no sequence starts with.
+                _ => {
+                    self.write_char(char::REPLACEMENT_CHARACTER, color);
+                    continue;
+                }
+            };
+            self.partial = PartialChar {
+                code: code as u32,
+                missing,
+                min,
+            };
+        }
+    }
+    /// Writes `ch`, or takes it as part of an escape sequence. Understands SGR colors and bold,
+    /// cursor movement, and erasing the line or screen.
+    fn write_char(&mut self, ch: char, color: Color) {
+        self.escape = match (self.escape, ch) {
+            (Escape::None, ESC) => Escape::Start,
+            (Escape::None, '\n') => {
+                self.new_line();
+                Escape::None
+            }
+            (Escape::None, '\r') => {
+                self.column = 0;
+                Escape::None
+            }
+            (Escape::None, ch) => {
+                self.put_char(ch, color);
+                Escape::None
+            }
+            (Escape::Start, '[') => Escape::Csi {
+                params: [0; MAX_PARAMS],
+                index: 0,
+                private: false,
+            },
+            // Other escapes aren't supported, and are dropped.
+            (Escape::Start, _) => Escape::None,
+            (
+                Escape::Csi {
+                    mut params,
+                    index,
+                    private,
+                },
+                ch,
+            ) => match ch {
+                '0'..='9' => {
+                    if let Some(param) = params.get_mut(index) {
+                        *param = param
+                            .saturating_mul(10)
+                            .saturating_add(ch as u16 - '0' as u16);
+                    }
+                    Escape::Csi {
+                        params,
+                        index,
+                        private,
+                    }
+                }
+                ';' => Escape::Csi {
+                    params,
+                    index: index + 1,
+                    private,
+                },
+                '<'..='?' => Escape::Csi {
+                    params,
+                    index,
+                    private: true,
+                },
+                '@'..='~' => {
+                    if !private {
+                        self.control(ch, &params[..(index + 1).min(MAX_PARAMS)]);
+                    }
+                    Escape::None
+                }
+                // Intermediate bytes, which no supported sequence has.
+                ' '..='/' => Escape::Csi {
+                    params,
+                    index,
+                    private: true,
+                },
+                // Not part of a sequence, so it was broken off.
+                _ => Escape::None,
+            },
+        };
+    }
+    /// Runs the control sequence ending in `command`. Rows are counted from the top of the screen,
+    /// and rows and columns from 1.
+    fn control(&mut self, command: char, params: &[u16]) {
+        let param = |index: usize, default: u16| match params.get(index) {
+            Some(0) | None => default as usize,
+            Some(&param) => param as usize,
+        };
+        match command {
+            // Cursor position.
+            'H' | 'f' => {
+                let top = self.screen_top();
+                let rows = self.history_rows();
+                self.cursor = top + param(0, 1).min(rows) - 1;
+                self.column = param(1, 1).min(columns()) - 1;
+                self.open = true;
+            }
+            'A' => {
+                self.open_cursor();
+                let top = self.screen_top();
+                self.cursor = self.cursor.saturating_sub(param(0, 1)).max(top);
+            }
+            'B' => {
+                self.open_cursor();
+                self.cursor = (self.cursor + param(0, 1)).min(self.len - 1);
+            }
+            'C' => {
+                self.open_cursor();
+                self.column = (self.column + param(0, 1)).min(columns() - 1);
+            }
+            'D' => {
+                self.open_cursor();
+                self.column = self.column.saturating_sub(param(0, 1));
+            }
+            // Erase in display: from the cursor to the end, from the top to the cursor, or all of
+            // it. The cursor stays where it is.
+            'J' => {
+                self.open_cursor();
+                let top = self.screen_top();
+                let (cursor, len) = (self.cursor, self.len);
+                let lines = match params[0] {
+                    0 => cursor + 1..len,
+                    1 => top..cursor,
+                    _ => top..len,
+                };
+                for index in lines {
+                    self.line_mut(index).len = 0;
+                }
+                if params[0] < 2 {
+                    self.erase_line(params[0]);
+                }
+            }
+            // Erase in line, the same way.
+            'K' => {
+                self.open_cursor();
+                self.erase_line(params[0]);
+            }
+            'm' => params.iter().for_each(|&param| self.select_graphic(param)),
+            _ => (),
+        }
+    }
+    fn erase_line(&mut self, mode: u16) {
+        let column = self.column;
+        let line = self.line_mut(self.cursor);
+        match mode {
+            0 => line.len = line.len.min(column),
+            1 => line.cells[..(column + 1).min(line.len)].fill(Cell::BLANK),
+            _ => line.len = 0,
+        }
+    }
+    fn select_graphic(&mut self, param: u16) {
+        match param {
+            0 => self.style = Style::DEFAULT,
+            1 => self.style.bold = true,
+            22 => self.style.bold = false,
+            30..=37 => self.style.fg = Some((param - 30) as usize),
+            39 => self.style.fg = None,
+            40..=47 => self.style.bg = Some((param - 40) as usize),
+            49 => self.style.bg = None,
+            _ => (),
+        }
+    }
+    fn max_scroll(&self) -> usize {
+        self.len.saturating_sub(self.history_rows())
+    }
+
+    fn render(&self) {
+        if !self.visible {
+            return;
+        }
+        let rows = self.history_rows();
+        let columns = columns();
+        let (width, height) = (screen::char_width(), screen::char_height());
+        // Index of the line shown in the top row, which may be negative while the buffer is
+        // still shorter than the screen.
+        let first = self.len as isize - self.scroll as isize - rows as isize;
+        for row in 0..rows {
+            let y = (row as u32 * height) as i32;
+            let index = first + row as isize;
+            let line = if index >= 0 {
+                Some(self.line(index as usize))
+            } else {
+                None
+            };
+            for column in 0..columns {
+                let x = (column as u32 * width) as i32;
+                let cell = match line {
+                    Some(line) if column < line.len => line.cells[column],
+                    _ => Cell::BLANK,
+                };
+                screen::draw_char(x, y, cell.ch, cell.fg, cell.bg);
+            }
+        }
+        self.render_input();
+    }
+    fn render_input(&self) {
+        if !self.visible {
+            return;
+        }
+        if let Some(input) = self.input.as_ref() {
+            let y = ((rows() - 1) as u32 * screen::char_height()) as i32;
+            for column in 0..columns() {
+                let x = (column as u32 * screen::char_width()) as i32;
+                let cell = if column < input.len {
+                    input.cells[column]
+                } else {
+                    Cell::BLANK
+                };
+                screen::draw_char(x, y, cell.ch, cell.fg, cell.bg);
+            }
+        }
+        graphics::present();
+    }
+}
+
+// Interrupt handlers log to the console, so it's only used with interrupts disabled.
+static mut CONSOLE: Console = Console::new();
+
+fn columns() -> usize {
+    let (width, _) = graphics::dimensions();
+    if width == 0 {
+        // Graphics isn't set up yet, keep lines whole.
+        return MAX_COLUMNS;
+    }
+    ((width / screen::char_width()) as usize).clamp(1, MAX_COLUMNS)
+}
+fn rows() -> usize {
+    let (_, height) = graphics::dimensions();
+    ((height / screen::char_height()) as usize).max(1)
+}
+
+/// Appends a line to the console and shows the newest lines. Lines wider than the screen wrap
+/// onto the next row.
+pub fn push_line(text: &str) {
+    push_colored_line(text, Color::WHITE);
+}
+pub fn push_colored_line(text: &str, color: Color) {
+    interrupts::without_interrupts(|| unsafe {
+        CONSOLE.push_line(text, color);
+        CONSOLE.scroll = 0;
+        CONSOLE.render();
+    });
+}
+
+/// Writes UTF-8 text that may contain any number of newlines, continuing the last line written
+/// this way. ANSI escape sequences in it set colors, move the cursor and erase; text without a
+/// color set is `color`. A character or escape sequence cut off at the end is finished by the next
+/// call.
+pub fn write(bytes: &[u8], color: Color) {
+    interrupts::without_interrupts(|| unsafe {
+        CONSOLE.write(bytes, color);
+        CONSOLE.scroll = 0;
+        CONSOLE.render();
+    });
+}
+
+/// Scrolls back through older lines (positive `delta`) or forward towards the newest ones
+/// (negative `delta`).
+#[allow(dead_code)]
+pub fn scroll(delta: isize) {
+    interrupts::without_interrupts(|| unsafe {
+        let max = CONSOLE.max_scroll() as isize;
+        CONSOLE.scroll = (CONSOLE.scroll as isize + delta).clamp(0, max) as usize;
+        CONSOLE.render();
+    });
+}
+
+/// Switches to the PSF font `font=path` names on the user partition, now that the filesystem is
+/// up, and fits the rows and columns to its size. The built-in font stays if there is none or it
+/// can't be loaded.
+pub fn load_font() {
+    let Some(path) = cmdline::get("font").filter(|path| !path.is_empty()) else {
+        return;
+    };
+    let font = filesystem::open(path)
+        .and_then(|file| file.read_all())
+        .map_err(FsError::as_str)
+        .and_then(|data| screen::load_font(&data));
+    match font {
+        Ok(font) => interrupts::without_interrupts(|| unsafe {
+            screen::set_font(font);
+            CONSOLE.scroll = CONSOLE.scroll.min(CONSOLE.max_scroll());
+            graphics::invalidate();
+            CONSOLE.render();
+        }),
+        Err(err) => log::warn!("{}: {}, using the built-in font", path, err),
+    }
+}
+
+/// Stops the console from drawing, e.g. while a userspace program owns the screen. Lines are still
+/// recorded and shown again once the console is made visible.
+pub fn set_visible(visible: bool) {
+    interrupts::without_interrupts(|| unsafe {
+        CONSOLE.visible = visible;
+        if visible {
+            // Whatever was drawn while hidden has to be covered up.
+            graphics::invalidate();
+            CONSOLE.render();
+        }
+    });
+}
+
+/// Shows `text` in the bottom row as the line being edited, or removes the input row when `None`.
+/// Text that doesn't fit shows its end, so the cursor stays visible.
+pub fn set_input(text: Option<&str>) {
+    interrupts::without_interrupts(|| unsafe {
+        let had_input = CONSOLE.input.is_some();
+        CONSOLE.input = text.map(|text| {
+            let mut line = Line::empty();
+            let count = text.chars().count();
+            let skip = count.saturating_sub(columns());
+            for ch in text.chars().skip(skip) {
+                line.push(Cell::new(ch, Color::WHITE));
+            }
+            line
+        });
+        if had_input == CONSOLE.input.is_some() {
+            CONSOLE.render_input();
+        } else {
+            CONSOLE.scroll = CONSOLE.scroll.min(CONSOLE.max_scroll());
+            CONSOLE.render();
+        }
+    });
+}
@@ -0,0 +1,121 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+const LEAF_1_EDX_APIC: u32 = 1 << 9;
+const LEAF_1_EDX_FXSR: u32 = 1 << 24;
+const LEAF_1_EDX_SSE: u32 = 1 << 25;
+const LEAF_1_ECX_X2APIC: u32 = 1 << 21;
+const LEAF_1_ECX_RDRAND: u32 = 1 << 30;
+const LEAF_7_EBX_RDSEED: u32 = 1 << 18;
+const EXT_1_EDX_NX: u32 = 1 << 20;
+const EXT_7_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+const EXT_BASE: u32 = 0x8000_0000;
+const EXT_FEATURES: u32 = 0x8000_0001;
+const EXT_BRAND: u32 = 0x8000_0002;
+const EXT_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+/// The CPUID registers the feature checks read.
+struct Features {
+    leaf_1_ecx: u32,
+    leaf_1_edx: u32,
+    leaf_7_ebx: u32,
+    ext_1_edx: u32,
+    ext_7_edx: u32,
+}
+
+static mut FEATURES: Features = Features {
+    leaf_1_ecx: 0,
+    leaf_1_edx: 0,
+    leaf_7_ebx: 0,
+    ext_1_edx: 0,
+    ext_7_edx: 0,
+};
+static mut VENDOR: [u8; 12] = [0; 12];
+static mut BRAND: [u8; 48] = [0; 48];
+
+/// Reads the CPU's features and names. Everything here reports no features until this is called,
+/// so it has to run before anything checks them.
+pub fn init() {
+    unsafe {
+        let leaf_0 = __cpuid(0);
+        for (chunk, register) in VENDOR
+            .chunks_mut(4)
+            .zip([leaf_0.ebx, leaf_0.edx, leaf_0.ecx])
+        {
+            chunk.copy_from_slice(&register.to_le_bytes());
+        }
+        let leaf_1 = __cpuid(1);
+        FEATURES.leaf_1_ecx = leaf_1.ecx;
+        FEATURES.leaf_1_edx = leaf_1.edx;
+        if leaf_0.eax >= 7 {
+            FEATURES.leaf_7_ebx = __cpuid_count(7, 0).ebx;
+        }
+        let max_ext = __cpuid(EXT_BASE).eax;
+        if max_ext >= EXT_FEATURES {
+            FEATURES.ext_1_edx = __cpuid(EXT_FEATURES).edx;
+        }
+        if max_ext >= EXT_POWER_MANAGEMENT {
+            FEATURES.ext_7_edx = __cpuid(EXT_POWER_MANAGEMENT).edx;
+        }
+        if max_ext >= EXT_BRAND + 2 {
+            for (index, chunk) in BRAND.chunks_mut(16).enumerate() {
+                let leaf = __cpuid(EXT_BRAND + index as u32);
+                for (bytes, register) in chunk
+                    .chunks_mut(4)
+                    .zip([leaf.eax, leaf.ebx, leaf.ecx, leaf.edx])
+                {
+                    bytes.copy_from_slice(&register.to_le_bytes());
+                }
+            }
+        }
+    }
+    log::info!("CPU {} ({})", brand(), vendor());
+}
+
+fn name(bytes: &'static [u8]) -> &'static str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).unwrap_or("").trim()
+}
+
+/// The vendor ID, e.g. "GenuineIntel" or "AuthenticAMD".
+pub fn vendor() -> &'static str {
+    name(unsafe { &VENDOR })
+}
+/// The processor's marketing name. Empty on CPUs too old to report one.
+pub fn brand() -> &'static str {
+    name(unsafe { &BRAND })
+}
+
+fn features() -> &'static Features {
+    unsafe { &FEATURES }
+}
+
+/// Whether pages can be mapped no-execute.
+pub fn has_nx() -> bool {
+    features().ext_1_edx & EXT_1_EDX_NX != 0
+}
+pub fn has_sse() -> bool {
+    features().leaf_1_edx & LEAF_1_EDX_SSE != 0
+}
+/// Whether FXSAVE and FXRSTOR can save and restore the x87 and SSE registers.
+pub fn has_fxsr() -> bool {
+    features().leaf_1_edx & LEAF_1_EDX_FXSR != 0
+}
+/// Whether the TSC ticks at a constant rate, whatever the CPU's power state and clock speed.
+pub fn has_invariant_tsc() -> bool {
+    features().ext_7_edx & EXT_7_EDX_INVARIANT_TSC != 0
+}
+pub fn has_rdrand() -> bool {
+    features().leaf_1_ecx & LEAF_1_ECX_RDRAND != 0
+}
+pub fn has_rdseed() -> bool {
+    features().leaf_7_ebx & LEAF_7_EBX_RDSEED != 0
+}
+/// Whether there's a local APIC, which the kernel uses instead of the 8259 PIC when it can.
+pub fn has_apic() -> bool {
+    features().leaf_1_edx & LEAF_1_EDX_APIC != 0
+}
+#[allow(dead_code)]
+pub fn has_x2apic() -> bool {
+    features().leaf_1_ecx & LEAF_1_ECX_X2APIC != 0
+}
@@ -0,0 +1,174 @@
+use core::{arch::asm, fmt};
+use x86_64::{
+    registers::{
+        control::{Cr2, Cr3},
+        debug::{
+            BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber,
+            Dr0, Dr1, Dr2, Dr3, Dr6, Dr6Flags, Dr7, Dr7Flags,
+        },
+        rflags::RFlags,
+    },
+    structures::idt::InterruptStackFrame,
+    VirtAddr,
+};
+
+/// What a hardware breakpoint triggers on.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub enum BreakpointKind {
+    /// Executing the instruction at the address.
+    Execute,
+    /// Writes to the given number of bytes at the address.
+    Write(usize),
+    /// Reads or writes of the given number of bytes at the address.
+    Access(usize),
+}
+
+const NUMBERS: [DebugAddressRegisterNumber; 4] = [
+    DebugAddressRegisterNumber::Dr0,
+    DebugAddressRegisterNumber::Dr1,
+    DebugAddressRegisterNumber::Dr2,
+    DebugAddressRegisterNumber::Dr3,
+];
+
+/// Programs a free one of DR0-DR3 to raise a debug exception on `kind` at `addr`, and returns its
+/// number for `clear_hw_breakpoint`. It's global, so it triggers in every address space. Watched
+/// ranges must be 1, 2, 4 or 8 bytes long and aligned to their length.
+#[allow(dead_code)]
+pub fn set_hw_breakpoint(addr: VirtAddr, kind: BreakpointKind) -> Result<u8, &'static str> {
+    let (condition, len) = match kind {
+        BreakpointKind::Execute => (BreakpointCondition::InstructionExecution, 1),
+        BreakpointKind::Write(len) => (BreakpointCondition::DataWrites, len),
+        BreakpointKind::Access(len) => (BreakpointCondition::DataReadsWrites, len),
+    };
+    let size = BreakpointSize::new(len).ok_or("length must be 1, 2, 4 or 8")?;
+    if !addr.is_aligned(len as u64) {
+        return Err("address not aligned to length");
+    }
+    let mut dr7 = Dr7::read();
+    let number = NUMBERS
+        .into_iter()
+        .find(|&n| !dr7.flags().contains(Dr7Flags::global_breakpoint_enable(n)))
+        .ok_or("all debug registers in use")?;
+    match number {
+        DebugAddressRegisterNumber::Dr0 => Dr0::write(addr.as_u64()),
+        DebugAddressRegisterNumber::Dr1 => Dr1::write(addr.as_u64()),
+        DebugAddressRegisterNumber::Dr2 => Dr2::write(addr.as_u64()),
+        DebugAddressRegisterNumber::Dr3 => Dr3::write(addr.as_u64()),
+    }
+    dr7.set_condition(number, condition);
+    dr7.set_size(number, size);
+    dr7.insert_flags(Dr7Flags::global_breakpoint_enable(number));
+    Dr7::write(dr7);
+    Ok(number.get())
+}
+
+/// Disables the hardware breakpoint `set_hw_breakpoint` returned `number` for.
+#[allow(dead_code)]
+pub fn clear_hw_breakpoint(number: u8) {
+    let Some(number) = DebugAddressRegisterNumber::new(number) else {
+        return;
+    };
+    let mut dr7 = Dr7::read();
+    dr7.remove_flags(Dr7Flags::global_breakpoint_enable(number));
+    Dr7::write(dr7);
+}
+
+/// Reads DR6 and clears it. The CPU never clears it, so without this the next debug exception
+/// would look like it had this one's causes too.
+pub fn take_status() -> Dr6Flags {
+    let status = Dr6::read();
+    unsafe { asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack, preserves_flags)) };
+    status
+}
+
+/// Logs what caused a debug exception. A hardware breakpoint on an instruction triggers before it
+/// runs, so the resume flag is set to let it run once instead of triggering again.
+pub fn handle_debug_exception(stack_frame: &mut InterruptStackFrame) {
+    let status = take_status();
+    let rip = stack_frame.instruction_pointer.as_u64();
+    if status.contains(Dr6Flags::STEP) {
+        log::info!("Single step at RIP={:#x}", rip);
+    }
+    let mut hit = false;
+    for number in NUMBERS {
+        if status.contains(Dr6Flags::trap(number)) {
+            log::info!("Hardware breakpoint {} at RIP={:#x}", number.get(), rip);
+            hit = true;
+        }
+    }
+    if hit {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.cpu_flags |= RFlags::RESUME_FLAG.bits());
+        }
+    }
+}
+
+/// Like `assert!`, but logs the registers before panicking, and is only checked in debug builds,
+/// so it can guard invariants on hot paths.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if cfg!(debug_assertions) && !$cond {
+            $crate::debug::assertion_failed(stringify!($cond), None, format_args!(""));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if cfg!(debug_assertions) && !$cond {
+            $crate::debug::assertion_failed(stringify!($cond), None, format_args!($($arg)+));
+        }
+    };
+}
+
+/// Like `assert_eq!`, but logs the registers before panicking, and is only checked in debug
+/// builds.
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        $crate::kassert_eq!($left, $right, "")
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        if cfg!(debug_assertions) {
+            match (&$left, &$right) {
+                (left, right) if *left != *right => $crate::debug::assertion_failed(
+                    concat!(stringify!($left), " == ", stringify!($right)),
+                    Some(format_args!("left: {:?}, right: {:?}", left, right)),
+                    format_args!($($arg)+),
+                ),
+                _ => {}
+            }
+        }
+    };
+}
+
+/// Called by `kassert!` and `kassert_eq!` when their check fails. Panics at the caller's location,
+/// so the panic screen shows where the assertion is and a backtrace.
+#[cold]
+#[track_caller]
+pub fn assertion_failed(
+    expression: &str,
+    values: Option<fmt::Arguments>,
+    message: fmt::Arguments,
+) -> ! {
+    x86_64::instructions::interrupts::disable();
+    let (rsp, rbp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) rbp, options(nomem, nostack, preserves_flags))
+    };
+    log::error!(
+        "Registers: RSP={:#x} RBP={:#x} RFLAGS={:#x} CR2={:#x} CR3={:#x}",
+        rsp,
+        rbp,
+        x86_64::registers::rflags::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read().0.start_address().as_u64()
+    );
+    match (values, message.as_str()) {
+        (Some(values), Some("")) => panic!("assertion failed: {}\n{}", expression, values),
+        (Some(values), _) => panic!("assertion failed: {}: {}\n{}", expression, message, values),
+        (None, Some("")) => panic!("assertion failed: {}", expression),
+        (None, _) => panic!("assertion failed: {}: {}", expression, message),
+    }
+}
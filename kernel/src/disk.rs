@@ -0,0 +1,366 @@
+use crate::{
+    ahci, block_cache, cmdline, memory,
+    pci::{self, PciDevice},
+    scheduler::WaitQueue,
+    time,
+};
+use alloc::{boxed::Box, vec, vec::Vec};
+use ata::{AtaError, BlockDevice, DmaRegion, Drive, DriveInfo, Partition};
+use mbr::{
+    gpt::{self, GptEntry, GptHeader},
+    ErrorCause, MasterBootRecord, PartitionType,
+};
+
+/// Name of the GPT partition that holds the user filesystem.
+pub const USER_PARTITION_NAME: &str = "user";
+const PCI_CLASS_STORAGE: u8 = 0x01;
+const PCI_SUBCLASS_IDE: u8 = 0x01;
+const IDE_BUS_MASTER: u8 = 1 << 7;
+/// The partition entry array is usually 32 blocks (128 entries of 128 bytes).
+const MAX_GPT_ENTRIES_BLOCKS: usize = 256;
+/// How long an ATA drive gets to finish a command.
+const ATA_TIMEOUT_MS: u64 = 5000;
+
+// Every drive found at boot, in bus/drive order.
+static mut DRIVES: Vec<DriveInfo> = Vec::new();
+// Processes waiting for a transfer on the primary and the secondary ATA bus.
+static ATA_WAITERS: [WaitQueue; 2] = [WaitQueue::new(), WaitQueue::new()];
+// Set by `ata=poll`, which spins on the drive's status instead of waiting for its IRQ.
+static mut ATA_POLL: bool = false;
+
+/// Finds the ATA drives on both buses, using DMA for reads if the controller supports it, then the
+/// SATA disks on AHCI controllers.
+pub fn init() {
+    init_ata();
+    ahci::init();
+}
+
+fn init_ata() {
+    unsafe {
+        ata::init();
+        ATA_POLL = cmdline::get("ata") == Some("poll");
+        ata::set_waiter(wait_for_ata);
+    }
+    init_dma();
+    let drives = match ata::list() {
+        Ok(drives) => drives,
+        Err(err) => {
+            log::warn!("ATA unavailable: {:?}", err);
+            Vec::new()
+        }
+    };
+    for info in &drives {
+        log::info!("Found drive {} size:{}KiB", info.model, info.size_in_kib());
+    }
+    unsafe {
+        DRIVES = drives;
+    }
+}
+
+/// Finds an IDE controller capable of bus mastering (programming interface bit 7) with its bus
+/// master registers in I/O space.
+fn find_bus_master() -> Option<(&'static PciDevice, u16)> {
+    pci::find_by_class(PCI_CLASS_STORAGE, PCI_SUBCLASS_IDE)
+        .filter(|device| device.prog_if & IDE_BUS_MASTER != 0)
+        .find_map(|device| {
+            let bar4 = device.bar(4);
+            (bar4 & 1 != 0).then_some((device, (bar4 & 0xfffc) as u16))
+        })
+}
+
+fn init_dma() {
+    let Some((controller, bus_master_base)) = find_bus_master() else {
+        return;
+    };
+    let Some(frame) = memory::allocate_frame() else {
+        return;
+    };
+    memory::pin_frame(frame);
+    let region = DmaRegion {
+        virt: memory::phys_to_virt(frame.start_address()).as_mut_ptr(),
+        phys: frame.start_address().as_u64(),
+        len: memory::PAGE_SIZE,
+    };
+    controller.enable_bus_master();
+    match unsafe { ata::init_dma(region, bus_master_base) } {
+        Ok(()) => log::debug!("ATA reads use DMA"),
+        Err(err) => log::warn!("ATA DMA unavailable: {:?}", err),
+    }
+}
+
+/// Blocks the current process while a transfer on ATA bus `bus` runs, so others can run
+/// meanwhile. It's woken by the bus' IRQ, and on every timer tick so a lost IRQ ends in a timeout
+/// rather than a hang. Before the scheduler runs, e.g. for the MBR, this polls. The timeout is
+/// kept with `time::now_ns`, so it also runs out while polling with interrupts disabled, as long as
+/// the TSC was calibrated or the timer has started.
+fn wait_for_ata(bus: u8, done: &mut dyn FnMut() -> bool) -> bool {
+    let end = time::now_ns() + ATA_TIMEOUT_MS * 1_000_000;
+    let mut timed_out = false;
+    let mut check = || {
+        if done() {
+            return true;
+        }
+        timed_out = time::clock_running() && time::now_ns() >= end;
+        timed_out
+    };
+    if unsafe { ATA_POLL } {
+        while !check() {
+            core::hint::spin_loop();
+        }
+    } else {
+        ATA_WAITERS[bus as usize].wait_until(check);
+    }
+    if timed_out {
+        log::warn!("ATA bus {} timed out", bus);
+    }
+    !timed_out
+}
+
+/// Called from the IRQ handler of ATA bus `bus`.
+pub fn handle_ata_interrupt(bus: u8) {
+    ata::acknowledge_interrupt(bus);
+    wake_ata_waiters(bus);
+}
+
+/// Wakes the processes waiting for ATA bus `bus`. Called for both buses from the timer's
+/// interrupt handler.
+pub fn wake_ata_waiters(bus: u8) {
+    ATA_WAITERS[bus as usize].wake_all();
+}
+
+pub fn drives() -> &'static [DriveInfo] {
+    unsafe { &DRIVES }
+}
+
+/// Whether `init` found any ATA or SATA disk.
+pub fn any_drive() -> bool {
+    !drives().is_empty() || !ahci::disks().is_empty()
+}
+
+/// Part of a disk that isn't an ATA drive, which `ata::Partition` can't hold.
+#[derive(Debug, Clone, Copy)]
+pub struct DevicePartition<D> {
+    device: D,
+    start_byte: usize,
+    num_bytes: usize,
+}
+
+impl<D: BlockDevice<Error = AtaError>> DevicePartition<D> {
+    fn check(&self, address: usize, number_of_blocks: usize) -> Result<usize, AtaError> {
+        let end = number_of_blocks
+            .checked_mul(D::BLOCK_SIZE as usize)
+            .and_then(|len| address.checked_add(len));
+        match end {
+            Some(end) if end <= self.num_bytes => Ok(self.start_byte + address),
+            _ => Err(AtaError::OutOfBounds),
+        }
+    }
+}
+
+impl<D: BlockDevice<Error = AtaError>> BlockDevice for DevicePartition<D> {
+    const BLOCK_SIZE: u32 = D::BLOCK_SIZE;
+    type Error = AtaError;
+    fn read(
+        &self,
+        buf: &mut [u8],
+        address: usize,
+        number_of_blocks: usize,
+    ) -> Result<(), Self::Error> {
+        let address = self.check(address, number_of_blocks)?;
+        self.device.read(buf, address, number_of_blocks)
+    }
+    fn write(
+        &self,
+        buf: &[u8],
+        address: usize,
+        number_of_blocks: usize,
+    ) -> Result<(), Self::Error> {
+        let address = self.check(address, number_of_blocks)?;
+        self.device.write(buf, address, number_of_blocks)
+    }
+}
+
+/// A partition found by `find_user_partition`, on whichever kind of disk it is.
+pub struct UserPartition {
+    pub device: Box<dyn block_cache::Device>,
+    pub size: usize,
+}
+
+/// The first user partition on any drive, trying ATA drives before SATA disks: a FAT32 partition
+/// in any of the MBR's entries, preferring one marked bootable, or for GPT disks the partition
+/// named `USER_PARTITION_NAME`, else the first basic data partition. Drives without one, or whose
+/// partition table can't be read, are skipped.
+pub fn find_user_partition() -> Option<UserPartition> {
+    let ata = drives().iter().find_map(|info| {
+        let (lba, sector_count) =
+            find_on(&info.drive, info.drive.block_count() as u64, &info.model)?;
+        let partition = Partition::new(info.drive, lba as usize, sector_count as usize);
+        Some(UserPartition {
+            device: Box::new(partition),
+            size: partition.size(),
+        })
+    });
+    ata.or_else(|| {
+        ahci::disks().iter().find_map(|info| {
+            let (lba, sector_count) = find_on(&info.disk, info.disk.block_count(), &info.model)?;
+            let partition = DevicePartition {
+                device: info.disk,
+                start_byte: lba as usize * ahci::AhciDisk::BLOCK_SIZE as usize,
+                num_bytes: sector_count as usize * ahci::AhciDisk::BLOCK_SIZE as usize,
+            };
+            Some(UserPartition {
+                device: Box::new(partition),
+                size: partition.num_bytes,
+            })
+        })
+    })
+}
+
+/// The user partition's first block and block count on `drive`, logging where it is, or why
+/// there's none.
+fn find_on<D: BlockDevice<Error = AtaError>>(
+    drive: &D,
+    block_count: u64,
+    model: &str,
+) -> Option<(u64, u64)> {
+    match user_partition(drive, block_count) {
+        Ok((lba, sector_count, entry)) => {
+            log::info!(
+                "User partition on {} is {} ({} blocks at {})",
+                model,
+                entry,
+                sector_count,
+                lba
+            );
+            Some((lba, sector_count))
+        }
+        Err(err) => {
+            log::warn!("Skipping drive {}: {}", model, err);
+            None
+        }
+    }
+}
+
+/// Which partition table entry `user_partition` picked, for the log.
+enum PartitionEntry {
+    Mbr(usize),
+    /// The GPT partition named `USER_PARTITION_NAME`.
+    GptNamed,
+    /// The first basic data partition in the GPT.
+    GptBasicData,
+}
+
+impl core::fmt::Display for PartitionEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            PartitionEntry::Mbr(index) => write!(f, "MBR entry {}", index),
+            PartitionEntry::GptNamed => {
+                write!(f, "the GPT partition named {}", USER_PARTITION_NAME)
+            }
+            PartitionEntry::GptBasicData => write!(f, "the first basic data partition in the GPT"),
+        }
+    }
+}
+
+/// The user partition's first block, block count and entry.
+fn user_partition<D: BlockDevice<Error = AtaError>>(
+    drive: &D,
+    block_count: u64,
+) -> Result<(u64, u64, PartitionEntry), &'static str> {
+    let mut mbr_bytes = [0u8; 512];
+    drive
+        .read(&mut mbr_bytes, 0, 1)
+        .map_err(|_| "failed to read the MBR")?;
+    let mbr = MasterBootRecord::from_bytes(&mbr_bytes).map_err(|_| "no MBR")?;
+    // The first bootable FAT32 partition, else the first FAT32 one, in whichever slot, so a
+    // single unmarked partition works too.
+    let fat32 = mbr
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| {
+            matches!(entry.partition_type, PartitionType::Fat32(_)) && entry.sector_count != 0
+        })
+        .min_by_key(|(_, entry)| !entry.bootable);
+    let (lba, sector_count, entry) = if let Some((index, entry)) = fat32 {
+        (
+            entry.logical_block_address as u64,
+            entry.sector_count as u64,
+            PartitionEntry::Mbr(index),
+        )
+    } else if mbr
+        .entries
+        .iter()
+        .any(|entry| entry.partition_type == PartitionType::GptProtective)
+    {
+        gpt_user_partition(drive, block_count)?
+    } else {
+        return Err("no FAT32 partition in the MBR");
+    };
+    // Partitions past the 28-bit limit are read with 48-bit commands, but can't extend past the
+    // end of the drive.
+    if lba + sector_count > block_count {
+        return Err("partition extends past the end of the drive");
+    }
+    Ok((lba, sector_count, entry))
+}
+
+/// Finds the user partition in the GPT, using the backup copy at the end of the drive if the
+/// primary header or its entries are corrupt. Returns its first block, block count and entry.
+fn gpt_user_partition<D: BlockDevice<Error = AtaError>>(
+    drive: &D,
+    block_count: u64,
+) -> Result<(u64, u64, PartitionEntry), &'static str> {
+    let entries = match read_gpt(drive, gpt::PRIMARY_HEADER_LBA) {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::warn!("Primary GPT unusable ({}), trying the backup", err);
+            read_gpt(drive, block_count.saturating_sub(1))?
+        }
+    };
+    let named = entries
+        .iter()
+        .find(|entry| entry.name_is(USER_PARTITION_NAME))
+        .map(|entry| (entry, PartitionEntry::GptNamed));
+    let (entry, found) = named
+        .or_else(|| {
+            entries
+                .iter()
+                .find(|entry| entry.type_guid == gpt::BASIC_DATA_PARTITION)
+                .map(|entry| (entry, PartitionEntry::GptBasicData))
+        })
+        .ok_or("no user or basic data partition in the GPT")?;
+    Ok((entry.first_lba, entry.sector_count(), found))
+}
+
+/// Reads and checks the GPT header at `lba` and its partition entries.
+fn read_gpt<D: BlockDevice<Error = AtaError>>(
+    drive: &D,
+    lba: u64,
+) -> Result<Vec<GptEntry>, &'static str> {
+    const BLOCK_SIZE: usize = Drive::BLOCK_SIZE as usize;
+    let mut header_bytes = [0u8; BLOCK_SIZE];
+    drive
+        .read(&mut header_bytes, lba as usize * BLOCK_SIZE, 1)
+        .map_err(|_| "failed to read the header")?;
+    let header = GptHeader::from_bytes(&header_bytes).map_err(|err| match err.cause {
+        ErrorCause::InvalidGptSignature => "bad signature",
+        ErrorCause::GptChecksumMismatch { .. } => "header checksum mismatch",
+        _ => "invalid header",
+    })?;
+    if header.entries_blocks() > MAX_GPT_ENTRIES_BLOCKS {
+        return Err("partition entry array too large");
+    }
+    let mut entries_bytes = vec![0u8; header.entries_blocks() * BLOCK_SIZE];
+    drive
+        .read(
+            &mut entries_bytes,
+            header.entries_lba as usize * BLOCK_SIZE,
+            header.entries_blocks(),
+        )
+        .map_err(|_| "failed to read the partition entries")?;
+    let entries = header
+        .entries(&entries_bytes)
+        .map_err(|_| "partition entries checksum mismatch")?;
+    Ok(entries.collect())
+}
@@ -1,6 +1,12 @@
-use crate::memory::{user_memory_mapper, UserMemoryMapper, PAGE_SIZE};
+use crate::memory::{self, AddressSpace, PAGE_SIZE};
+use alloc::{rc::Rc, vec::Vec};
 use bootloader_api::info::TlsTemplate;
-use core::{cmp, iter::Step, mem::size_of, ops::Add};
+use core::{
+    cmp,
+    iter::Step,
+    mem::size_of,
+    ops::{Add, Range},
+};
 
 use x86_64::{
     align_up,
@@ -44,41 +50,122 @@ impl Add<u64> for VirtualAddressOffset {
     }
 }
 
+/// Why a file was rejected by the loader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file is shorter than an ELF header.
+    Truncated,
+    /// The file doesn't start with `0x7F ELF`.
+    BadMagic,
+    /// The file isn't a 64-bit ELF file.
+    NotElf64,
+    /// The file isn't little-endian.
+    NotLittleEndian,
+    /// The file isn't built for x86-64.
+    WrongMachine,
+    /// The file is neither an executable nor a position independent executable.
+    UnsupportedType,
+    /// The header was fine, but the rest of the file couldn't be loaded.
+    Invalid(&'static str),
+}
+
+impl ElfError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ElfError::Truncated => "ELF file is truncated",
+            ElfError::BadMagic => "not an ELF file",
+            ElfError::NotElf64 => "ELF file is not 64-bit",
+            ElfError::NotLittleEndian => "ELF file is not little-endian",
+            ElfError::WrongMachine => "ELF file is not for x86-64",
+            ElfError::UnsupportedType => "ELF file is not an executable",
+            ElfError::Invalid(err) => err,
+        }
+    }
+}
+
+impl From<&'static str> for ElfError {
+    fn from(err: &'static str) -> Self {
+        ElfError::Invalid(err)
+    }
+}
+
+impl core::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+const ELF64_HEADER_SIZE: usize = 64;
+const PT_DYNAMIC: u32 = 2;
+
+/// Checks the ELF identification and header fields we rely on before anything else looks at the
+/// file.
+fn check_header(bytes: &[u8]) -> Result<(), ElfError> {
+    if bytes.len() < ELF64_HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if bytes[0..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if bytes[4] != ELFCLASS64 {
+        return Err(ElfError::NotElf64);
+    }
+    if bytes[5] != ELFDATA2LSB {
+        return Err(ElfError::NotLittleEndian);
+    }
+    let file_type = u16::from_le_bytes([bytes[16], bytes[17]]);
+    let machine = u16::from_le_bytes([bytes[18], bytes[19]]);
+    if machine != EM_X86_64 {
+        return Err(ElfError::WrongMachine);
+    }
+    if file_type != ET_EXEC && file_type != ET_DYN {
+        return Err(ElfError::UnsupportedType);
+    }
+    Ok(())
+}
+
 /// Used by [`Inner::make_mut`] and [`Inner::clean_copied_flag`].
 const COPIED: Flags = Flags::BIT_9;
 
 struct Loader<'a> {
     elf_file: ElfFile<'a>,
-    inner: Inner,
+    inner: Inner<'a>,
 }
 
-struct Inner {
+struct Inner<'a> {
     phys_addr: PhysAddr,
     virt_offset: VirtualAddressOffset,
-    memory_mapper: &'static mut UserMemoryMapper,
+    memory_mapper: &'a mut AddressSpace,
 }
 
 impl<'a> Loader<'a> {
     fn new(
         phys_addr: PhysAddr,
         len: usize,
-        memory_mapper: &'static mut UserMemoryMapper,
-    ) -> Result<Self, &'static str> {
+        load_base: impl FnOnce(u64) -> u64,
+        memory_mapper: &'a mut AddressSpace,
+    ) -> Result<Self, ElfError> {
         if !phys_addr.is_aligned(PAGE_SIZE as u64) {
-            return Err("ELF file is not sufficiently aligned");
+            return Err("ELF file is not sufficiently aligned".into());
         }
         let bytes_ptr = memory_mapper.phys_offset(phys_addr).as_ptr();
-        let elf_file = ElfFile::new(unsafe { core::slice::from_raw_parts(bytes_ptr, len) })?;
+        let bytes = unsafe { core::slice::from_raw_parts(bytes_ptr, len) };
+        check_header(bytes)?;
+        let elf_file = ElfFile::new(bytes)?;
         for program_header in elf_file.program_iter() {
             program::sanity_check(program_header, &elf_file)?;
         }
 
         let virt_offset = match elf_file.header.pt2.type_().as_type() {
-            header::Type::None => unimplemented!(),
-            header::Type::Relocatable => unimplemented!(),
-            header::Type::Executable => unimplemented!(),
+            header::Type::Executable => VirtualAddressOffset::new(0),
             header::Type::SharedObject => {
-                // Find the highest virtual memory address and the biggest alignment.
+                // Find the lowest and highest virtual memory address and the biggest alignment.
                 let mut min_addr = u64::MAX;
                 let mut max_addr = 0;
                 let mut align = 1;
@@ -87,22 +174,30 @@ impl<'a> Loader<'a> {
                     .filter(|h| matches!(h.get_type(), Ok(Type::Load)))
                 {
                     min_addr = min_addr.min(header.virtual_addr());
-                    max_addr = max_addr.max(header.virtual_addr() + header.mem_size());
+                    max_addr =
+                        max_addr.max(header.virtual_addr().saturating_add(header.mem_size()));
                     align = align.max(header.align());
                 }
                 if min_addr == u64::MAX {
                     min_addr = 0;
                 }
+                if !align.is_power_of_two() {
+                    return Err("segment alignment is not a power of two".into());
+                }
 
-                //let size = max_addr - min_addr;
-                let offset: i128 = 0x2000_0000_0000; // TODO!!!
-                VirtualAddressOffset::new(i128::from(offset) - i128::from(min_addr))
+                // Keep the lowest segment at the same offset within its alignment, so every
+                // segment stays aligned after the move.
+                let image_size = (max_addr - min_addr).saturating_add(align);
+                let base = align_up(load_base(image_size), align) + (min_addr & (align - 1));
+                VirtualAddressOffset::new(i128::from(base) - i128::from(min_addr))
             }
-            header::Type::Core => unimplemented!(),
-            header::Type::ProcessorSpecific(_) => unimplemented!(),
+            // Ruled out by `check_header`.
+            _ => return Err(ElfError::UnsupportedType),
         };
 
         header::sanity_check(&elf_file)?;
+        check_in_user_space(&elf_file, virt_offset, memory_mapper.stack())?;
+        check_load_order(&elf_file)?;
         let loader = Loader {
             elf_file,
             inner: Inner {
@@ -123,7 +218,7 @@ impl<'a> Loader<'a> {
                 Type::Load => self.inner.handle_load_segment(program_header)?,
                 Type::Tls => {
                     if tls_template.is_none() {
-                        tls_template = Some(self.inner.handle_tls_segment(program_header)?);
+                        tls_template = Some(self.inner.tls_template(program_header));
                     } else {
                         return Err("multiple TLS segments not supported");
                     }
@@ -161,37 +256,151 @@ impl<'a> Loader<'a> {
         Ok(tls_template)
     }
 
-    fn entry_point(&self) -> VirtAddr {
-        VirtAddr::new(self.inner.virt_offset + self.elf_file.header.pt2.entry_point())
+    /// Like `load_segments`, but only records where each page of the Load segments comes from,
+    /// for `LazyImage::read_page` to read it on the first access. The file can't have relocations,
+    /// which would need its pages right away.
+    fn lazy_segments(
+        &self,
+        reader: Reader,
+    ) -> Result<(LazyImage, Option<TlsTemplate>), &'static str> {
+        let mut image = LazyImage {
+            reader,
+            segments: Vec::new(),
+            read_only: Vec::new(),
+        };
+        let mut tls_template = None;
+        for program_header in self.elf_file.program_iter() {
+            match program_header.get_type()? {
+                Type::Load if program_header.mem_size() > 0 => {
+                    let segment = LazySegment {
+                        start: self.inner.virt_offset + program_header.virtual_addr(),
+                        file_offset: program_header.offset(),
+                        file_size: program_header.file_size(),
+                        mem_size: program_header.mem_size(),
+                        flags: segment_page_flags(program_header.flags())?,
+                    };
+                    // Refuse a shared page with impossible flags now rather than when it's used.
+                    if let Some(previous) = image.segments.last() {
+                        if segment.first_page() <= previous.last_page() {
+                            merge_page_flags(previous.flags, segment.flags)?;
+                        }
+                    }
+                    image.segments.push(segment);
+                }
+                Type::Tls => {
+                    if tls_template.is_none() {
+                        tls_template = Some(self.inner.tls_template(program_header));
+                    } else {
+                        return Err("multiple TLS segments not supported");
+                    }
+                }
+                Type::GnuRelro => {
+                    let start = self.inner.virt_offset + program_header.virtual_addr();
+                    image
+                        .read_only
+                        .push(start..start + program_header.mem_size());
+                }
+                Type::Dynamic => return Err("relocations need the segments loaded"),
+                Type::Load
+                | Type::Null
+                | Type::Interp
+                | Type::Note
+                | Type::ShLib
+                | Type::Phdr
+                | Type::OsSpecific(_)
+                | Type::ProcessorSpecific(_) => {}
+            }
+        }
+        Ok((image, tls_template))
+    }
+
+    /// The entry point, which the file picks, so it's checked to be in an executable segment.
+    fn entry_point(&self) -> Result<VirtAddr, &'static str> {
+        let offset = self.inner.virt_offset.virtual_address_offset;
+        let entry = offset + i128::from(self.elf_file.header.pt2.entry_point());
+        let in_code = self.elf_file.program_iter().any(|header| {
+            let start = offset + i128::from(header.virtual_addr());
+            matches!(header.get_type(), Ok(Type::Load))
+                && header.flags().is_execute()
+                && (start..start + i128::from(header.mem_size())).contains(&entry)
+        });
+        if !in_code || entry >= i128::from(memory::USER_SPACE_END) {
+            return Err("entry point is outside of the program's code");
+        }
+        u64::try_from(entry)
+            .ok()
+            .and_then(|entry| VirtAddr::try_new(entry).ok())
+            .ok_or("entry point is not a valid address")
     }
 }
 
-impl Inner {
+/// Translates the flags of a Load segment to page table flags. Pages are never mapped both
+/// writable and executable, so segments asking for that are rejected. Without NX every mapped
+/// page is executable, so this only stops writes to code.
+fn segment_page_flags(segment_flags: program::Flags) -> Result<Flags, &'static str> {
+    if segment_flags.is_write() && segment_flags.is_execute() {
+        return Err("segment is both writable and executable");
+    }
+    let mut flags = Flags::PRESENT;
+    if !segment_flags.is_execute() {
+        flags |= memory::no_execute();
+    }
+    if segment_flags.is_write() {
+        flags |= Flags::WRITABLE;
+    }
+    Ok(flags)
+}
+
+/// The flags for a page shared by two segments, allowing what either of them allows.
+fn merge_page_flags(a: Flags, b: Flags) -> Result<Flags, &'static str> {
+    let no_execute = a & b & memory::no_execute();
+    let flags = ((a | b) & !memory::no_execute()) | no_execute;
+    if flags.contains(Flags::WRITABLE) && !flags.contains(memory::no_execute()) {
+        return Err("segments sharing a page are writable and executable");
+    }
+    Ok(flags)
+}
+
+impl<'a> Inner<'a> {
     fn handle_load_segment(&mut self, segment: ProgramHeader) -> Result<(), &'static str> {
         let phys_start_addr = self.phys_addr + segment.offset();
         let start_frame: PhysFrame = PhysFrame::containing_address(phys_start_addr);
         let end_frame: PhysFrame =
-            PhysFrame::containing_address(phys_start_addr + segment.file_size() - 1u64);
+            PhysFrame::containing_address(phys_start_addr + segment.file_size().saturating_sub(1));
 
         let virt_start_addr = VirtAddr::new(self.virt_offset + segment.virtual_addr());
         let start_page: Page = Page::containing_address(virt_start_addr);
 
-        let mut segment_flags = Flags::PRESENT;
-        if !segment.flags().is_execute() {
-            segment_flags |= Flags::NO_EXECUTE;
-        }
-        if segment.flags().is_write() {
-            segment_flags |= Flags::WRITABLE;
-        }
+        let segment_flags = segment_page_flags(segment.flags())?;
 
         // map all frames of the segment at the desired virtual address
-        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-            let offset = frame - start_frame;
-            let page = start_page + offset;
-            unsafe {
-                self.memory_mapper
-                    .map_page(page, frame, segment_flags)
-                    .map_err(|_err| "map_to failed")?;
+        if segment.file_size() > 0 {
+            for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+                let offset = frame - start_frame;
+                let page = start_page + offset;
+                if let Some(flags) = self.mapped_flags(page) {
+                    // The previous segment ends in this page, which then needs the data of both.
+                    self.share_page(page, flags, segment_flags)?;
+                    let start = cmp::max(virt_start_addr, page.start_address());
+                    let end = cmp::min(
+                        virt_start_addr + segment.file_size(),
+                        page.start_address() + Size4KiB::SIZE,
+                    );
+                    let src = phys_start_addr + (start - virt_start_addr);
+                    unsafe {
+                        let bytes = core::slice::from_raw_parts(
+                            self.memory_mapper.phys_offset(src).as_ptr::<u8>(),
+                            (end - start) as usize,
+                        );
+                        self.copy_to(start, bytes);
+                    }
+                    continue;
+                }
+                memory::inc_ref(frame)?;
+                if unsafe { self.memory_mapper.map_page(page, frame, segment_flags) }.is_err() {
+                    memory::dec_ref(frame);
+                    return Err("map_to failed");
+                }
             }
         }
 
@@ -224,7 +433,7 @@ impl Inner {
         // In some cases, `zero_start` might not be page-aligned. This requires some
         // special treatment because we can't safely zero a frame of the original file.
         let data_bytes_before_zero = zero_start.as_u64() & 0xfff;
-        if data_bytes_before_zero != 0 {
+        if data_bytes_before_zero != 0 && file_size != 0 {
             // The last non-bss frame of the segment consists partly of data and partly of bss
             // memory, which must be zeroed. Unfortunately, the file representation might have
             // reused the part of the frame that should be zeroed to store the next segment. This
@@ -269,11 +478,29 @@ impl Inner {
             }
         }
 
-        // map additional frames for `.bss` memory that is not present in source file
-        let start_page: Page =
-            Page::containing_address(VirtAddr::new(align_up(zero_start.as_u64(), Size4KiB::SIZE)));
-        let end_page = Page::containing_address(zero_end);
+        // map additional frames for `.bss` memory that is not present in source file. A segment
+        // without any file data has no partial page to reuse, so it starts on a fresh frame.
+        let start_page: Page = if file_size == 0 {
+            Page::containing_address(zero_start)
+        } else {
+            Page::containing_address(VirtAddr::new(align_up(zero_start.as_u64(), Size4KiB::SIZE)))
+        };
+        let end_page = Page::containing_address(zero_end - 1u64);
         for page in Page::range_inclusive(start_page, end_page) {
+            if let Some(flags) = self.mapped_flags(page) {
+                // Only the first page can be shared, when the previous segment ends in it.
+                self.share_page(page, flags, segment_flags)?;
+                let frame = unsafe { self.make_mut(page) };
+                let start = zero_start.as_u64() & 0xfff;
+                let len = cmp::min(Size4KiB::SIZE - start, zero_end - zero_start);
+                let ptr: *mut u8 = self
+                    .memory_mapper
+                    .phys_offset(frame.start_address())
+                    .as_mut_ptr();
+                unsafe { core::ptr::write_bytes(ptr.add(start as usize), 0, len as usize) };
+                continue;
+            }
+
             // allocate a new unused frame
             let frame = self.memory_mapper.allocate_frame().unwrap();
 
@@ -284,10 +511,10 @@ impl Inner {
                 .as_mut_ptr();
             unsafe { frame_ptr.write(ZERO_ARRAY) };
 
-            // map frame
+            // map frame, which is already private to this segment and can be written to
             unsafe {
                 self.memory_mapper
-                    .map_page(page, frame, segment_flags)
+                    .map_page(page, frame, segment_flags | COPIED)
                     .map_err(|_err| "Failed to map new frame for bss memory")?;
             }
         }
@@ -295,6 +522,39 @@ impl Inner {
         Ok(())
     }
 
+    /// The flags `page` is mapped with, if an earlier segment mapped it.
+    fn mapped_flags(&self, page: Page) -> Option<Flags> {
+        match self
+            .memory_mapper
+            .page_table()
+            .translate(page.start_address())
+        {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    }
+
+    /// Gives a page that an earlier segment mapped with `flags` a private frame, so another segment
+    /// can write its data into it, and lets it be used as both segments allow.
+    fn share_page(
+        &mut self,
+        page: Page,
+        flags: Flags,
+        segment_flags: Flags,
+    ) -> Result<(), &'static str> {
+        let merged = merge_page_flags(flags & !COPIED, segment_flags)?;
+        unsafe {
+            // SAFETY: The earlier segment mapped the page.
+            self.make_mut(page);
+            self.memory_mapper
+                .page_table_mut()
+                .update_flags(page, merged | Flags::USER_ACCESSIBLE | COPIED)
+                .map_err(|_err| "update_flags failed")?
+                .ignore();
+        }
+        Ok(())
+    }
+
     /// Copy from the kernel address space.
     ///
     /// ## Panics
@@ -484,7 +744,8 @@ impl Inner {
         }
 
         // Replace the underlying frame and update the flags.
-        self.memory_mapper.unmap_page(page).unwrap();
+        let old_frame = self.memory_mapper.unmap_page(page).unwrap();
+        memory::free_frame(old_frame);
         let new_flags = flags | COPIED;
         unsafe {
             self.memory_mapper
@@ -538,12 +799,12 @@ impl Inner {
         Ok(())
     }
 
-    fn handle_tls_segment(&mut self, segment: ProgramHeader) -> Result<TlsTemplate, &'static str> {
-        Ok(TlsTemplate {
+    fn tls_template(&self, segment: ProgramHeader) -> TlsTemplate {
+        TlsTemplate {
             start_addr: self.virt_offset + segment.virtual_addr(),
             mem_size: segment.mem_size(),
             file_size: segment.file_size(),
-        })
+        }
     }
 
     fn handle_dynamic_segment(
@@ -555,7 +816,7 @@ impl Inner {
         let data = if let SegmentData::Dynamic64(data) = data {
             data
         } else {
-            panic!("expected Dynamic64 segment")
+            return Err("expected Dynamic64 segment");
         };
 
         // Find the `Rela`, `RelaSize` and `RelaEnt` entries.
@@ -604,11 +865,9 @@ impl Inner {
         let entry_size = rela_ent.ok_or("RelaEnt entry is missing")?;
 
         // Make sure that the reported size matches our `Rela<u64>`.
-        assert_eq!(
-            entry_size,
-            size_of::<Rela<u64>>() as u64,
-            "unsupported entry size: {entry_size}"
-        );
+        if entry_size != size_of::<Rela<u64>>() as u64 {
+            return Err("unsupported RelaEnt size");
+        }
 
         // Apply the relocations.
         let num_entries = total_size / entry_size;
@@ -644,13 +903,13 @@ impl Inner {
         rela: Rela<u64>,
         elf_file: &ElfFile,
     ) -> Result<(), &'static str> {
-        let symbol_idx = rela.get_symbol_table_index();
-        assert_eq!(
-            symbol_idx, 0,
-            "relocations using the symbol table are not supported"
-        );
+        if rela.get_symbol_table_index() != 0 {
+            return Err("relocations using the symbol table are not supported");
+        }
 
         match rela.get_type() {
+            // R_AMD64_NONE
+            0 => {}
             // R_AMD64_RELATIVE
             8 => {
                 // Make sure that the relocation happens in memory mapped
@@ -670,7 +929,10 @@ impl Inner {
                     self.copy_to(addr, &value.to_ne_bytes());
                 }
             }
-            ty => unimplemented!("relocation type {:x} not supported", ty),
+            ty => {
+                log::warn!("Unsupported relocation type {:#x}", ty);
+                return Err("unsupported relocation type");
+            }
         }
 
         Ok(())
@@ -719,6 +981,48 @@ impl Inner {
     }
 }
 
+/// Check that every load segment ends up below the kernel's half of the address space.
+fn check_in_user_space(
+    elf_file: &ElfFile,
+    virt_offset: VirtualAddressOffset,
+    stack: memory::VirtMemRange,
+) -> Result<(), &'static str> {
+    for program_header in elf_file.program_iter() {
+        if let Type::Load = program_header.get_type()? {
+            let start =
+                virt_offset.virtual_address_offset + i128::from(program_header.virtual_addr());
+            let end = start + i128::from(program_header.mem_size());
+            if start < 0 || end > i128::from(memory::USER_SPACE_END) {
+                return Err("segment is outside of user space");
+            }
+            let reserved = memory::USER_MEMORY.reserved();
+            if start < i128::from(reserved.end()) && end > i128::from(reserved.start().as_u64()) {
+                return Err("segment overlaps the user stack or heap");
+            }
+            let stack_start = i128::from(stack.guard_page().start().as_u64());
+            if start < i128::from(stack.end()) && end > stack_start {
+                return Err("segment overlaps the user stack");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that load segments are sorted by address and don't overlap, as the ELF format requires.
+/// Segments may still share a page, which [`Inner::share_page`] handles.
+fn check_load_order(elf_file: &ElfFile) -> Result<(), &'static str> {
+    let mut end = 0;
+    for program_header in elf_file.program_iter() {
+        if let Type::Load = program_header.get_type()? {
+            if program_header.virtual_addr() < end {
+                return Err("load segments overlap or are not sorted by address");
+            }
+            end = program_header.virtual_addr() + program_header.mem_size();
+        }
+    }
+    Ok(())
+}
+
 /// Check that the virtual offset belongs to a load segment.
 fn check_is_in_load(elf_file: &ElfFile, virt_offset: u64) -> Result<(), &'static str> {
     for program_header in elf_file.program_iter() {
@@ -734,6 +1038,82 @@ fn check_is_in_load(elf_file: &ElfFile, virt_offset: u64) -> Result<(), &'static
     Err("offset is not in load segment")
 }
 
+/// Fills a buffer from a file offset and returns how many bytes it read.
+pub type Reader = Rc<dyn Fn(&mut [u8], usize) -> Result<usize, &'static str>>;
+
+/// The Load segments of a program whose pages are read from its file as they are first used.
+pub struct LazyImage {
+    reader: Reader,
+    /// Sorted by address, like the program headers.
+    segments: Vec<LazySegment>,
+    /// RELRO ranges, whose pages are mapped read-only.
+    read_only: Vec<Range<u64>>,
+}
+
+struct LazySegment {
+    start: u64,
+    file_offset: u64,
+    file_size: u64,
+    mem_size: u64,
+    flags: Flags,
+}
+
+impl LazySegment {
+    fn first_page(&self) -> u64 {
+        self.start & !(Size4KiB::SIZE - 1)
+    }
+    fn last_page(&self) -> u64 {
+        (self.start + self.mem_size - 1) & !(Size4KiB::SIZE - 1)
+    }
+}
+
+impl LazyImage {
+    /// Whether `addr` is on a page of one of the segments.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        let page = addr.as_u64() & !(Size4KiB::SIZE - 1);
+        self.segments
+            .iter()
+            .any(|segment| (segment.first_page()..=segment.last_page()).contains(&page))
+    }
+
+    /// Reads the data of `page` into `buf`, which must be zeroed, and returns the flags to map it
+    /// with. Parts of the page beyond the file data, like `.bss`, stay zeroed. A page shared by two
+    /// segments gets the data of both.
+    pub fn read_page(&self, page: Page, buf: &mut [u8; PAGE_SIZE]) -> Result<Flags, &'static str> {
+        let page_start = page.start_address().as_u64();
+        let page_end = page_start + Size4KiB::SIZE;
+        let mut flags = None;
+        for segment in self
+            .segments
+            .iter()
+            .filter(|segment| (segment.first_page()..=segment.last_page()).contains(&page_start))
+        {
+            flags = Some(match flags {
+                Some(flags) => merge_page_flags(flags, segment.flags)?,
+                None => segment.flags,
+            });
+            let start = cmp::max(segment.start, page_start);
+            let end = cmp::min(segment.start + segment.file_size, page_end);
+            if start < end {
+                let offset = segment.file_offset + (start - segment.start);
+                let range = (start - page_start) as usize..(end - page_start) as usize;
+                if (self.reader)(&mut buf[range], offset as usize)? < (end - start) as usize {
+                    return Err("program file is shorter than its segments");
+                }
+            }
+        }
+        let mut flags = flags.ok_or("page is not part of the program")?;
+        if self
+            .read_only
+            .iter()
+            .any(|range| range.start < page_end && page_start < range.end)
+        {
+            flags.remove(Flags::WRITABLE);
+        }
+        Ok(flags)
+    }
+}
+
 enum File {
     Empty,
     Partial {
@@ -741,22 +1121,24 @@ enum File {
         start_addr: PhysAddr,
         phys_addr: PhysAddr,
         file_size: usize,
+        /// Set if only the headers were read, to read the segments as they are used.
+        reader: Option<Reader>,
     },
 }
 
 static mut LOAD_FILE: File = File::Empty;
 
 pub fn start_load() -> Result<(), &'static str> {
-    let mapper = user_memory_mapper();
     match unsafe { &LOAD_FILE } {
         File::Empty => {
-            let phys_frame = mapper.allocate_frame().unwrap();
+            let phys_frame = memory::allocate_frame().ok_or("out of memory")?;
             let start_addr = phys_frame.start_address();
             let file = File::Partial {
                 phys_frame,
                 start_addr,
                 phys_addr: start_addr,
                 file_size: 0,
+                reader: None,
             };
             unsafe { LOAD_FILE = file };
             Ok(())
@@ -766,26 +1148,25 @@ pub fn start_load() -> Result<(), &'static str> {
 }
 
 fn load_bytes_subpage(bytes: &[u8]) -> Result<(), &'static str> {
-    let mapper = user_memory_mapper();
     match unsafe { &mut LOAD_FILE } {
         File::Empty => Err("load not started"),
         File::Partial {
             phys_frame,
-            start_addr: _,
             phys_addr,
             file_size,
+            ..
         } => {
             unsafe {
                 core::ptr::copy(
                     bytes.as_ptr(),
-                    mapper.phys_offset(*phys_addr).as_mut_ptr(),
+                    memory::phys_to_virt(*phys_addr).as_mut_ptr(),
                     bytes.len(),
                 );
             }
             *file_size += bytes.len();
             *phys_addr += bytes.len();
             if *phys_addr >= phys_frame.start_address() + phys_frame.size() {
-                *phys_frame = mapper.allocate_frame().unwrap();
+                *phys_frame = memory::allocate_frame().ok_or("out of memory")?;
                 assert_eq!(phys_frame.start_address(), *phys_addr);
             }
             Ok(())
@@ -799,20 +1180,148 @@ pub fn load_bytes(bytes: &[u8]) -> Result<(), &'static str> {
     Ok(())
 }
 
-pub fn finish_load() -> Result<(VirtAddr, Option<TlsTemplate>), &'static str> {
-    let mapper = user_memory_mapper();
+/// Loads a file of `size` bytes through `read_at`, which fills a buffer from a file offset and
+/// returns how many bytes it read. Only the pages holding the ELF header, the program headers and
+/// the segments are read, the rest of the file is left zeroed. Like `load_bytes`, this goes
+/// between `start_load` and `finish_load`. The load is abandoned if a read fails.
+pub fn load_file(
+    size: usize,
+    read_at: impl FnMut(&mut [u8], usize) -> Result<usize, &'static str>,
+) -> Result<(), &'static str> {
+    read_ranges(size, read_at, false).map(|_| ())
+}
+
+/// Like `load_file`, but only reads the headers and leaves the segments to be read through
+/// `reader` when the program first uses their pages. Files with relocations are read like with
+/// `load_file`, since relocating touches their pages right away.
+pub fn load_file_lazily(size: usize, reader: Reader) -> Result<(), &'static str> {
+    if read_ranges(size, |buf, offset| reader(buf, offset), true)? {
+        if let File::Partial { reader: lazy, .. } = unsafe { &mut LOAD_FILE } {
+            *lazy = Some(reader);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the parts of the file `needed_ranges` asks for and returns whether the segments were left
+/// out.
+fn read_ranges(
+    size: usize,
+    mut read_at: impl FnMut(&mut [u8], usize) -> Result<usize, &'static str>,
+    lazy: bool,
+) -> Result<bool, &'static str> {
+    let result = (|| {
+        let (ranges, lazy) = needed_ranges(size, &mut read_at, lazy)?;
+        let mut page = alloc::vec![0; PAGE_SIZE];
+        for start in (0..size).step_by(PAGE_SIZE) {
+            let end = (start + PAGE_SIZE).min(size);
+            page.fill(0);
+            if ranges
+                .iter()
+                .any(|range| range.start < end && start < range.end)
+            {
+                read_at(&mut page[..end - start], start)?;
+            }
+            load_bytes_subpage(&page[..end - start])?;
+        }
+        Ok(lazy)
+    })();
+    if result.is_err() {
+        abandon_load();
+    }
+    result
+}
+
+/// The byte ranges of the file that the loader looks at: the headers and each segment's data,
+/// which is left out if `lazy` is set and the file has no dynamic segment, and whether it was.
+/// Offsets are checked against the file later, by `Loader::new`.
+fn needed_ranges(
+    size: usize,
+    read_at: &mut impl FnMut(&mut [u8], usize) -> Result<usize, &'static str>,
+    lazy: bool,
+) -> Result<(Vec<Range<usize>>, bool), &'static str> {
+    let mut header = [0; ELF64_HEADER_SIZE];
+    read_at(&mut header, 0)?;
+    let ph_offset = u64::from_le_bytes(header[32..40].try_into().unwrap()) as usize;
+    let ph_entry_size = u16::from_le_bytes([header[54], header[55]]) as usize;
+    let ph_count = u16::from_le_bytes([header[56], header[57]]) as usize;
+    let ph_end = ph_offset.saturating_add(ph_entry_size * ph_count).min(size);
+    let mut ranges = alloc::vec![0..ELF64_HEADER_SIZE, ph_offset.min(ph_end)..ph_end];
+    // Only the type, offset and file size of each 64-bit program header are needed.
+    let mut entry = [0; 40];
+    let mut segments = Vec::new();
+    let mut dynamic = false;
+    for index in 0..ph_count {
+        if ph_entry_size < entry.len() {
+            break;
+        }
+        read_at(&mut entry, ph_offset.saturating_add(index * ph_entry_size))?;
+        dynamic |= u32::from_le_bytes(entry[0..4].try_into().unwrap()) == PT_DYNAMIC;
+        let offset = u64::from_le_bytes(entry[8..16].try_into().unwrap()) as usize;
+        let file_size = u64::from_le_bytes(entry[32..40].try_into().unwrap()) as usize;
+        segments.push(offset.min(size)..offset.saturating_add(file_size).min(size));
+    }
+    let lazy = lazy && !dynamic;
+    if !lazy {
+        ranges.extend(segments);
+    }
+    Ok((ranges, lazy))
+}
+
+/// Frees the frames of a load that won't be finished.
+fn abandon_load() {
+    if let File::Partial {
+        phys_frame,
+        start_addr,
+        ..
+    } = unsafe { core::mem::replace(&mut LOAD_FILE, File::Empty) }
+    {
+        free_file_frames(start_addr, phys_frame);
+    }
+}
+
+fn free_file_frames(start_addr: PhysAddr, last_frame: PhysFrame) {
+    let first_frame = PhysFrame::containing_address(start_addr);
+    for frame in PhysFrame::range_inclusive(first_frame, last_frame) {
+        memory::free_frame(frame);
+    }
+}
+
+/// Maps the loaded file's segments into `address_space`, which doesn't have to be the active one.
+/// Position independent executables are placed at the first suitably aligned address from the one
+/// `load_base` returns for the size of their image, other executables at the addresses they were
+/// linked for.
+pub fn finish_load(
+    address_space: &mut AddressSpace,
+    load_base: impl FnOnce(u64) -> u64,
+) -> Result<(VirtAddr, Option<TlsTemplate>), ElfError> {
     match unsafe { core::mem::replace(&mut LOAD_FILE, File::Empty) } {
-        File::Empty => Err("nothing to load"),
+        File::Empty => Err("nothing to load".into()),
         File::Partial {
-            phys_frame: _,
+            phys_frame,
             start_addr,
             phys_addr: _,
             file_size,
+            reader,
         } => {
-            let mut loader = Loader::new(start_addr, file_size, mapper)?;
-            let tls_template = loader.load_segments()?;
-            loader.inner.memory_mapper.finish_load();
-            Ok((loader.entry_point(), tls_template))
+            let result = Loader::new(start_addr, file_size, load_base, address_space).and_then(
+                |mut loader| {
+                    let tls_template = match reader {
+                        Some(reader) => {
+                            let (image, tls_template) = loader.lazy_segments(reader)?;
+                            loader.inner.memory_mapper.set_lazy_image(image);
+                            tls_template
+                        }
+                        None => loader.load_segments()?,
+                    };
+                    loader.inner.memory_mapper.finish_load();
+                    Ok((loader.entry_point()?, tls_template))
+                },
+            );
+            // Drop the file's own reference to its frames. The ones mapped by a Load segment
+            // stay alive through the address space.
+            free_file_frames(start_addr, phys_frame);
+            result
         }
     }
 }
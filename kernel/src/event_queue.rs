@@ -0,0 +1,34 @@
+/// A fixed-size FIFO of input events, filled by an interrupt handler. Kept in an `IrqSafeSpinLock`,
+/// so readers can't be interrupted while popping.
+pub struct EventQueue<T: Copy, const N: usize> {
+    events: [Option<T>; N],
+    start: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        EventQueue {
+            events: [None; N],
+            start: 0,
+            len: 0,
+        }
+    }
+    pub fn push(&mut self, event: T) {
+        if self.len == N {
+            // Nobody is reading, drop the new event.
+            return;
+        }
+        self.events[(self.start + self.len) % N] = Some(event);
+        self.len += 1;
+    }
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.start].take();
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        event
+    }
+}
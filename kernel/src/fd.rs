@@ -0,0 +1,92 @@
+use crate::{
+    filesystem::File,
+    graphics::Color,
+    pipe::{PipeReader, PipeWriter},
+};
+use alloc::{rc::Rc, vec::Vec};
+use core::cell::RefCell;
+
+/// Text written to stderr is shown in this color.
+const STDERR_COLOR: Color = Color::new(255, 64, 64);
+/// File descriptor numbers go up to one less than this.
+pub const MAX_FDS: usize = 64;
+
+/// What a file descriptor refers to.
+#[derive(Clone)]
+pub enum FileDescriptor {
+    /// Writes go to the console in this color. It can't be read from.
+    Console(Color),
+    /// A file opened for reading. Duplicates share the position, like on Unix.
+    File(Rc<RefCell<File>>),
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+}
+
+/// A process' open file descriptors, indexed by their numbers.
+#[derive(Clone)]
+pub struct FdTable(Vec<Option<FileDescriptor>>);
+
+impl FdTable {
+    /// A table with stdin, stdout and stderr on the console.
+    pub fn new() -> Self {
+        FdTable(alloc::vec![
+            Some(FileDescriptor::Console(Color::WHITE)),
+            Some(FileDescriptor::Console(Color::WHITE)),
+            Some(FileDescriptor::Console(STDERR_COLOR)),
+        ])
+    }
+
+    /// An empty table, for a process that exited.
+    pub fn empty() -> Self {
+        FdTable(Vec::new())
+    }
+
+    pub fn get(&self, fd: u64) -> Option<&FileDescriptor> {
+        self.0.get(usize::try_from(fd).ok()?)?.as_ref()
+    }
+
+    /// Adds `descriptor` at the lowest free number and returns it, or None if all `MAX_FDS` are
+    /// in use.
+    pub fn insert(&mut self, descriptor: FileDescriptor) -> Option<usize> {
+        match self.0.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.0[fd] = Some(descriptor);
+                Some(fd)
+            }
+            None if self.0.len() < MAX_FDS => {
+                self.0.push(Some(descriptor));
+                Some(self.0.len() - 1)
+            }
+            None => None,
+        }
+    }
+
+    /// Makes the lowest free number refer to the same thing as `fd`, and returns it.
+    pub fn dup(&mut self, fd: u64) -> Option<usize> {
+        let descriptor = self.get(fd)?.clone();
+        self.insert(descriptor)
+    }
+
+    /// Makes `new_fd` refer to the same thing as `fd`, first closing what it referred to. Returns
+    /// that, for the caller to drop like `close`, or None if either number is invalid. Does
+    /// nothing if both are the same.
+    pub fn dup2(&mut self, fd: u64, new_fd: u64) -> Option<Option<FileDescriptor>> {
+        let descriptor = self.get(fd)?.clone();
+        let new_fd = usize::try_from(new_fd)
+            .ok()
+            .filter(|&new_fd| new_fd < MAX_FDS)?;
+        if fd == new_fd as u64 {
+            return Some(None);
+        }
+        if new_fd >= self.0.len() {
+            self.0.resize_with(new_fd + 1, || None);
+        }
+        Some(self.0[new_fd].replace(descriptor))
+    }
+
+    /// Removes `fd` and returns what it referred to, or None if it wasn't open. The caller drops
+    /// it, which may wake processes blocked on a pipe.
+    pub fn close(&mut self, fd: u64) -> Option<FileDescriptor> {
+        self.0.get_mut(usize::try_from(fd).ok()?)?.take()
+    }
+}
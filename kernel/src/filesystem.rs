@@ -0,0 +1,852 @@
+use crate::{
+    block_cache::{self, BLOCK_SIZE},
+    kassert, rtc,
+    scheduler::{SleepLock, SleepLockGuard},
+};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use ata::AtaError;
+use kernel_common::fat::{self, ATTR_ARCHIVE, DIR_ENTRY_SIZE, ENTRY_DELETED, ENTRY_END};
+
+#[derive(Debug, Clone, Copy)]
+pub enum FsError {
+    /// `init_fs` hasn't found a filesystem.
+    NotInitialized,
+    Disk(AtaError),
+    /// The partition doesn't hold a FAT32 filesystem this driver can read.
+    NotFat32,
+    NotFound,
+    NotADirectory,
+    NotAFile,
+    AlreadyExists,
+    /// The name can't be stored in a FAT directory.
+    InvalidName,
+    /// There aren't enough free clusters. Nothing was changed on the disk.
+    DiskFull,
+    /// FAT file sizes are 32-bit.
+    FileTooLarge,
+    /// The filesystem's metadata doesn't make sense, e.g. a broken cluster chain.
+    Corrupt(&'static str),
+}
+
+impl FsError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FsError::NotInitialized => "no filesystem",
+            FsError::Disk(AtaError::ReadOnly) => "read-only disk",
+            FsError::Disk(_) => "disk error",
+            FsError::NotFat32 => "not a FAT32 filesystem",
+            FsError::NotFound => "file not found",
+            FsError::NotADirectory => "not a directory",
+            FsError::NotAFile => "not a file",
+            FsError::AlreadyExists => "file already exists",
+            FsError::InvalidName => "invalid file name",
+            FsError::DiskFull => "disk full",
+            FsError::FileTooLarge => "file too large",
+            FsError::Corrupt(err) => err,
+        }
+    }
+}
+
+impl From<AtaError> for FsError {
+    fn from(err: AtaError) -> Self {
+        FsError::Disk(err)
+    }
+}
+
+impl core::fmt::Display for FsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+const BAD_CLUSTER: u32 = 0x0fff_fff7;
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+// What this driver writes to end a chain.
+const END_OF_CHAIN_MARK: u32 = 0x0fff_ffff;
+const FREE_CLUSTER: u32 = 0;
+// The top 4 bits of a FAT entry are reserved and must be kept.
+const RESERVED_BITS: u32 = 0xf000_0000;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+const UNKNOWN: u32 = 0xffff_ffff;
+
+/// The parts of the BIOS parameter block needed to find clusters.
+struct Fat32 {
+    sectors_per_cluster: u64,
+    /// First block of the first FAT, relative to the partition.
+    fat_start: u64,
+    /// First block of cluster 2.
+    data_start: u64,
+    root_cluster: u32,
+    cluster_count: u32,
+    num_fats: u64,
+    fat_size: u64,
+    /// The FSInfo block, which holds hints for finding free clusters.
+    fsinfo_block: Option<u64>,
+    /// Number of free clusters, if known.
+    free_count: Option<u32>,
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+}
+
+static mut FILESYSTEM: Option<Fat32> = None;
+// Held by every operation, since one can leave the FAT and the block cache half updated while it
+// waits for the disk and other processes run.
+static LOCK: SleepLock = SleepLock::new();
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Fat32 {
+    fn from_boot_sector(bpb: &[u8; BLOCK_SIZE]) -> Result<Fat32, FsError> {
+        let bytes_per_sector = read_u16(bpb, 11) as usize;
+        let sectors_per_cluster = bpb[13] as u64;
+        let reserved_sectors = read_u16(bpb, 14) as u64;
+        let num_fats = bpb[16] as u64;
+        let root_entry_count = read_u16(bpb, 17);
+        let fat_size_16 = read_u16(bpb, 22);
+        let total_sectors = read_u32(bpb, 32) as u64;
+        let fat_size = read_u32(bpb, 36) as u64;
+        // FAT12/16 have a fixed root directory and 16-bit FAT sizes instead.
+        if bpb[510..512] != [0x55, 0xaa]
+            || bytes_per_sector != BLOCK_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || num_fats == 0
+            || root_entry_count != 0
+            || fat_size_16 != 0
+            || fat_size == 0
+        {
+            return Err(FsError::NotFat32);
+        }
+        let data_start = reserved_sectors + num_fats * fat_size;
+        let cluster_count = (total_sectors.saturating_sub(data_start) / sectors_per_cluster) as u32;
+        let root_cluster = read_u32(bpb, 44);
+        if root_cluster < 2 || root_cluster - 2 >= cluster_count {
+            return Err(FsError::Corrupt("invalid root directory cluster"));
+        }
+        let fsinfo_block = match read_u16(bpb, 48) {
+            0 | 0xffff => None,
+            block => Some(block as u64),
+        };
+        Ok(Fat32 {
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            data_start,
+            root_cluster,
+            cluster_count,
+            num_fats,
+            fat_size,
+            fsinfo_block,
+            free_count: None,
+            next_free: 2,
+        })
+    }
+
+    /// Reads the free cluster hints from the FSInfo block. They are only hints, so a missing or
+    /// invalid block is ignored.
+    fn read_fsinfo(&mut self) -> Result<(), FsError> {
+        let Some(block) = self.fsinfo_block else {
+            return Ok(());
+        };
+        let fsinfo = block_cache::read_block(block)?;
+        if read_u32(&fsinfo[..], 0) != FSINFO_LEAD_SIGNATURE
+            || read_u32(&fsinfo[..], 484) != FSINFO_STRUCT_SIGNATURE
+        {
+            self.fsinfo_block = None;
+            return Ok(());
+        }
+        let free_count = read_u32(&fsinfo[..], FSINFO_FREE_COUNT);
+        if free_count <= self.cluster_count {
+            self.free_count = Some(free_count);
+        }
+        let next_free = read_u32(&fsinfo[..], FSINFO_NEXT_FREE);
+        if self.check_cluster(next_free).is_ok() {
+            self.next_free = next_free;
+        }
+        Ok(())
+    }
+
+    fn write_fsinfo(&self) -> Result<(), FsError> {
+        let Some(block) = self.fsinfo_block else {
+            return Ok(());
+        };
+        let mut fsinfo = *block_cache::read_block(block)?;
+        let free_count = self.free_count.unwrap_or(UNKNOWN);
+        fsinfo[FSINFO_FREE_COUNT..FSINFO_FREE_COUNT + 4].copy_from_slice(&free_count.to_le_bytes());
+        fsinfo[FSINFO_NEXT_FREE..FSINFO_NEXT_FREE + 4]
+            .copy_from_slice(&self.next_free.to_le_bytes());
+        block_cache::write_block(block, &fsinfo)?;
+        Ok(())
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), FsError> {
+        if cluster < 2 || cluster - 2 >= self.cluster_count {
+            return Err(FsError::Corrupt("cluster number out of range"));
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, or None at the end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        match self.fat_entry(cluster)? {
+            BAD_CLUSTER => Err(FsError::Corrupt("bad cluster in chain")),
+            next if next >= END_OF_CHAIN => Ok(None),
+            next => {
+                self.check_cluster(next)?;
+                Ok(Some(next))
+            }
+        }
+    }
+
+    /// The block and offset of `cluster`'s entry in FAT number `fat`.
+    fn fat_entry_location(&self, fat: u64, cluster: u32) -> (u64, usize) {
+        let offset = cluster as u64 * 4;
+        let block = self.fat_start + fat * self.fat_size + offset / BLOCK_SIZE as u64;
+        (block, (offset % BLOCK_SIZE as u64) as usize)
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let (block, offset) = self.fat_entry_location(0, cluster);
+        Ok(read_u32(&block_cache::read_block(block)?[..], offset) & CLUSTER_MASK)
+    }
+
+    /// Sets `cluster`'s entry in every copy of the FAT.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for fat in 0..self.num_fats {
+            let (block, offset) = self.fat_entry_location(fat, cluster);
+            let mut data = *block_cache::read_block(block)?;
+            let old = read_u32(&data, offset);
+            let new = (old & RESERVED_BITS) | (value & CLUSTER_MASK);
+            data[offset..offset + 4].copy_from_slice(&new.to_le_bytes());
+            block_cache::write_block(block, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Finds `count` free clusters without claiming them. Fails with `DiskFull` if there aren't
+    /// enough.
+    fn find_free_clusters(&self, count: usize) -> Result<Vec<u32>, FsError> {
+        if matches!(self.free_count, Some(free) if (free as usize) < count) {
+            return Err(FsError::DiskFull);
+        }
+        let mut clusters = Vec::with_capacity(count);
+        let start = self.next_free - 2;
+        for index in 0..self.cluster_count {
+            if clusters.len() == count {
+                break;
+            }
+            let cluster = (start + index) % self.cluster_count + 2;
+            if self.fat_entry(cluster)? == FREE_CLUSTER {
+                clusters.push(cluster);
+            }
+        }
+        if clusters.len() < count {
+            return Err(FsError::DiskFull);
+        }
+        Ok(clusters)
+    }
+
+    /// Chains `clusters` together, ends the chain after the last one and appends them to the chain
+    /// ending at `last`, if any.
+    fn link_clusters(&mut self, last: Option<u32>, clusters: &[u32]) -> Result<(), FsError> {
+        for pair in clusters.windows(2) {
+            self.set_fat_entry(pair[0], pair[1])?;
+        }
+        if let Some(&end) = clusters.last() {
+            self.set_fat_entry(end, END_OF_CHAIN_MARK)?;
+            self.next_free = if end - 2 + 1 >= self.cluster_count {
+                2
+            } else {
+                end + 1
+            };
+        }
+        // Linked last, so the new clusters only become part of the chain once they are set up.
+        if let (Some(last), Some(&first)) = (last, clusters.first()) {
+            self.set_fat_entry(last, first)?;
+        }
+        self.free_count = self
+            .free_count
+            .map(|free| free.saturating_sub(clusters.len() as u32));
+        Ok(())
+    }
+
+    /// Marks every cluster of `chain` free again.
+    fn free_clusters(&mut self, chain: &[u32]) -> Result<(), FsError> {
+        for &cluster in chain {
+            self.set_fat_entry(cluster, FREE_CLUSTER)?;
+        }
+        if let Some(&first) = chain.first() {
+            self.next_free = self.next_free.min(first);
+        }
+        self.free_count = self
+            .free_count
+            .map(|free| (free + chain.len() as u32).min(self.cluster_count));
+        Ok(())
+    }
+
+    fn cluster_block(&self, cluster: u32) -> u64 {
+        kassert!(
+            (2..self.cluster_count + 2).contains(&cluster),
+            "cluster {} outside the partition",
+            cluster
+        );
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    /// Writes `data` at byte `offset` of the clusters in `chain`, which must be long enough.
+    fn write_chain(&self, chain: &[u32], offset: usize, data: &[u8]) -> Result<(), FsError> {
+        kassert!(offset + data.len() <= chain.len() * self.cluster_size());
+        let mut written = 0;
+        while written < data.len() {
+            let position = offset + written;
+            let cluster = chain[position / self.cluster_size()];
+            let block = self.cluster_block(cluster)
+                + ((position % self.cluster_size()) / BLOCK_SIZE) as u64;
+            let block_offset = position % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - block_offset).min(data.len() - written);
+            // Whole blocks don't need to be read first.
+            let mut buffer = if len == BLOCK_SIZE {
+                [0; BLOCK_SIZE]
+            } else {
+                *block_cache::read_block(block)?
+            };
+            buffer[block_offset..block_offset + len].copy_from_slice(&data[written..written + len]);
+            block_cache::write_block(block, &buffer)?;
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// All clusters of the chain starting at `first`.
+    fn cluster_chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        self.check_cluster(first)?;
+        let mut chain = Vec::new();
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            // A chain can't be longer than the filesystem, so anything longer has a loop.
+            if chain.len() >= self.cluster_count as usize {
+                return Err(FsError::Corrupt("cluster chain loops"));
+            }
+            chain.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(chain)
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * BLOCK_SIZE
+    }
+
+    /// Appends cluster `cluster` to `data`, at most `limit` bytes of it.
+    fn read_cluster(&self, cluster: u32, data: &mut Vec<u8>, limit: usize) -> Result<(), FsError> {
+        let first_block = self.cluster_block(cluster);
+        for block in first_block..first_block + self.sectors_per_cluster {
+            let len = limit.saturating_sub(data.len()).min(BLOCK_SIZE);
+            if len == 0 {
+                break;
+            }
+            data.extend_from_slice(&block_cache::read_block(block)?[..len]);
+        }
+        Ok(())
+    }
+
+    /// Fills `buf` from byte `offset` of the chain starting at `first`. Only the clusters up to the
+    /// end of the range are followed.
+    fn read_range(&self, first: u32, offset: usize, buf: &mut [u8]) -> Result<(), FsError> {
+        let next = |cluster| {
+            self.next_cluster(cluster)?
+                .ok_or(FsError::Corrupt("cluster chain shorter than the file"))
+        };
+        self.check_cluster(first)?;
+        let mut cluster = first;
+        for _ in 0..offset / self.cluster_size() {
+            cluster = next(cluster)?;
+        }
+        let mut read = 0;
+        while read < buf.len() {
+            let position = offset + read;
+            if read > 0 && position % self.cluster_size() == 0 {
+                cluster = next(cluster)?;
+            }
+            let block = self.cluster_block(cluster)
+                + ((position % self.cluster_size()) / BLOCK_SIZE) as u64;
+            let block_offset = position % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - block_offset).min(buf.len() - read);
+            buf[read..read + len].copy_from_slice(
+                &block_cache::read_block(block)?[block_offset..block_offset + len],
+            );
+            read += len;
+        }
+        Ok(())
+    }
+
+    /// The clusters of the directory starting at `cluster`, and their contents.
+    fn read_dir_raw(&self, cluster: u32) -> Result<(Vec<u32>, Vec<u8>), FsError> {
+        // Directories have no size, they end with their cluster chain or an end marker.
+        let chain = self.cluster_chain(cluster)?;
+        let mut data = Vec::new();
+        for cluster in &chain {
+            self.read_cluster(*cluster, &mut data, usize::MAX)?;
+        }
+        Ok((chain, data))
+    }
+
+    fn read_dir(&self, cluster: u32) -> Result<Vec<DirEntry>, FsError> {
+        let (chain, data) = self.read_dir_raw(cluster)?;
+        let entries = fat::parse_dir(&data).into_iter().map(|entry| DirEntry {
+            location: self.entry_location(&chain, entry.index),
+            name: entry.name,
+            size: entry.size,
+            is_dir: entry.is_dir,
+            short_name: entry.short_name,
+            first_cluster: entry.first_cluster,
+        });
+        Ok(entries.collect())
+    }
+
+    /// Where entry `index` of the directory made of `chain` is on the disk.
+    fn entry_location(&self, chain: &[u32], index: usize) -> EntryLocation {
+        let offset = index * DIR_ENTRY_SIZE;
+        let cluster = chain[offset / self.cluster_size()];
+        EntryLocation {
+            block: self.cluster_block(cluster)
+                + ((offset % self.cluster_size()) / BLOCK_SIZE) as u64,
+            offset: offset % BLOCK_SIZE,
+        }
+    }
+
+    fn write_entry(
+        &self,
+        location: EntryLocation,
+        entry: &[u8; DIR_ENTRY_SIZE],
+    ) -> Result<(), FsError> {
+        let mut data = *block_cache::read_block(location.block)?;
+        data[location.offset..location.offset + DIR_ENTRY_SIZE].copy_from_slice(entry);
+        block_cache::write_block(location.block, &data)?;
+        Ok(())
+    }
+
+    /// Adds a zeroed cluster to the end of the directory made of `chain`.
+    fn grow_dir(&mut self, chain: &mut Vec<u32>) -> Result<(), FsError> {
+        let cluster = self.find_free_clusters(1)?[0];
+        let zeros = [0; BLOCK_SIZE];
+        let first_block = self.cluster_block(cluster);
+        for block in first_block..first_block + self.sectors_per_cluster {
+            block_cache::write_block(block, &zeros)?;
+        }
+        self.link_clusters(chain.last().copied(), &[cluster])?;
+        chain.push(cluster);
+        Ok(())
+    }
+}
+
+/// A file or directory in a directory.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// The long filename if there is one, otherwise the 8.3 name.
+    pub name: String,
+    pub size: u32,
+    pub is_dir: bool,
+    short_name: String,
+    first_cluster: u32,
+    /// Where the short entry is, to update the size and first cluster.
+    location: EntryLocation,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EntryLocation {
+    block: u64,
+    offset: usize,
+}
+
+impl DirEntry {
+    /// Names are compared case-insensitively, like FAT does.
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.short_name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// A regular file, found with `open` or made with `create`. Writes go to the disk right away, but
+/// the size in the directory entry is only updated by `flush` or when the file is dropped.
+#[derive(Debug)]
+pub struct File {
+    entry: DirEntry,
+    /// Where the next `read` or `write` goes.
+    position: usize,
+    /// The directory entry is out of date.
+    dirty: bool,
+}
+
+impl File {
+    fn new(entry: DirEntry) -> File {
+        File {
+            entry,
+            position: 0,
+            dirty: false,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.entry.size as usize
+    }
+
+    /// Reads the whole file, following its cluster chain.
+    pub fn read_all(&self) -> Result<Vec<u8>, FsError> {
+        let mut data = alloc::vec![0; self.size()];
+        self.read_at(&mut data, 0)?;
+        Ok(data)
+    }
+
+    /// Reads into `buf` from byte `offset` of the file, without reading the clusters before or
+    /// after that range. Returns how many bytes were read, which is less than `buf.len()` if the
+    /// file ends first.
+    pub fn read_at(&self, buf: &mut [u8], offset: usize) -> Result<usize, FsError> {
+        let len = buf.len().min(self.size().saturating_sub(offset));
+        if len == 0 {
+            return Ok(0);
+        }
+        let _lock = lock();
+        filesystem()?.read_range(self.entry.first_cluster, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    /// Reads into `buf` from the current position and moves past what was read. Returns 0 at the
+    /// end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let read = self.read_at(buf, self.position)?;
+        self.position += read;
+        Ok(read)
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Another handle on the file at the same position. Only the original updates the directory
+    /// entry.
+    pub fn duplicate(&self) -> File {
+        File {
+            entry: self.entry.clone(),
+            position: self.position,
+            dirty: false,
+        }
+    }
+
+    /// Moves where the next `read` or `write` goes, at most to the end of the file.
+    pub fn seek(&mut self, position: usize) {
+        self.position = position.min(self.size());
+    }
+
+    /// Writes `data` at the current position, growing the file past its end if needed. All new
+    /// clusters are found before anything is written, so a full disk leaves the file unchanged.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, FsError> {
+        let _lock = lock();
+        let filesystem = filesystem_mut()?;
+        let end = self.position + data.len();
+        if end > u32::MAX as usize {
+            return Err(FsError::FileTooLarge);
+        }
+        let mut chain = match self.entry.first_cluster {
+            0 => Vec::new(),
+            first => filesystem.cluster_chain(first)?,
+        };
+        let needed = (end + filesystem.cluster_size() - 1) / filesystem.cluster_size();
+        let new_clusters = if needed > chain.len() {
+            filesystem.find_free_clusters(needed - chain.len())?
+        } else {
+            Vec::new()
+        };
+        let last = chain.last().copied();
+        chain.extend_from_slice(&new_clusters);
+        // The data goes into the new clusters before they are linked into the file.
+        filesystem.write_chain(&chain, self.position, data)?;
+        if !new_clusters.is_empty() {
+            filesystem.link_clusters(last, &new_clusters)?;
+            if last.is_none() {
+                self.entry.first_cluster = new_clusters[0];
+            }
+        }
+        self.position = end;
+        if end > self.size() {
+            self.entry.size = end as u32;
+        }
+        self.dirty = true;
+        Ok(data.len())
+    }
+
+    /// Empties the file and frees its clusters. The directory entry is updated first, so it never
+    /// points at freed clusters.
+    pub fn truncate(&mut self) -> Result<(), FsError> {
+        let first = self.entry.first_cluster;
+        self.entry.first_cluster = 0;
+        self.entry.size = 0;
+        self.position = 0;
+        self.dirty = true;
+        self.flush()?;
+        if first != 0 {
+            let _lock = lock();
+            let filesystem = filesystem_mut()?;
+            let chain = filesystem.cluster_chain(first)?;
+            filesystem.free_clusters(&chain)?;
+            filesystem.write_fsinfo()?;
+        }
+        Ok(())
+    }
+
+    /// Updates the directory entry with the file's size, first cluster and modification time,
+    /// and the free cluster hints.
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let _lock = lock();
+        let filesystem = filesystem()?;
+        let location = self.entry.location;
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        entry.copy_from_slice(
+            &block_cache::read_block(location.block)?
+                [location.offset..location.offset + DIR_ENTRY_SIZE],
+        );
+        let (date, time) = timestamp();
+        let cluster = self.entry.first_cluster;
+        entry[18..20].copy_from_slice(&date.to_le_bytes());
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[22..24].copy_from_slice(&time.to_le_bytes());
+        entry[24..26].copy_from_slice(&date.to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&self.entry.size.to_le_bytes());
+        entry[11] |= ATTR_ARCHIVE;
+        filesystem.write_entry(location, &entry)?;
+        filesystem.write_fsinfo()?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::warn!("Failed to update {}: {}", self.entry.name, err);
+        }
+    }
+}
+
+/// FAT date and time of the current moment, from the RTC. FAT can't store dates before 1980, so
+/// those become 1980-01-01 00:00.
+fn timestamp() -> (u16, u16) {
+    let now = rtc::now();
+    if now.year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    let date = (now.year - 1980) << 9 | (now.month as u16) << 5 | now.day as u16;
+    let time = (now.hour as u16) << 11 | (now.minute as u16) << 5 | (now.second / 2) as u16;
+    (date, time)
+}
+
+fn lock() -> SleepLockGuard<'static> {
+    LOCK.lock()
+}
+
+/// Whether another process is in the middle of an operation.
+pub fn busy() -> bool {
+    LOCK.is_locked()
+}
+
+fn filesystem() -> Result<&'static Fat32, FsError> {
+    unsafe { FILESYSTEM.as_ref().ok_or(FsError::NotInitialized) }
+}
+fn filesystem_mut() -> Result<&'static mut Fat32, FsError> {
+    unsafe { FILESYSTEM.as_mut().ok_or(FsError::NotInitialized) }
+}
+
+/// Puts `device`, e.g. a partition or a RAM disk, behind `block_cache` and reads its FAT32 boot
+/// sector.
+pub fn init_fs(device: Box<dyn block_cache::Device>) -> Result<(), FsError> {
+    block_cache::init(device, block_cache::DEFAULT_CAPACITY);
+    let boot_sector = block_cache::read_block(0)?;
+    let mut filesystem = Fat32::from_boot_sector(&boot_sector)?;
+    filesystem.read_fsinfo()?;
+    log::debug!(
+        "FAT32 with {} clusters of {} bytes",
+        filesystem.cluster_count,
+        filesystem.cluster_size()
+    );
+    unsafe {
+        FILESYSTEM = Some(filesystem);
+    }
+    Ok(())
+}
+
+/// The entry `path` names, or None for the root directory.
+///
+/// Components are separated by `/` and matched against both long and 8.3 names, ignoring case.
+/// `.` is the current directory and `..` its parent, with the root being its own parent. A leading
+/// `/` starts at the root; there is no working directory, so paths without one start there too.
+/// Walking into or through a file, including a trailing `/` after one, is `NotADirectory`.
+fn resolve(filesystem: &Fat32, path: &str) -> Result<Option<DirEntry>, FsError> {
+    // The directories walked into so far, so `..` can go back up.
+    let mut stack: Vec<DirEntry> = Vec::new();
+    let mut components = path.split('/').peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() && component.is_empty() && path.ends_with('/') {
+            // A trailing `/` only makes sense after a directory.
+            if matches!(stack.last(), Some(entry) if !entry.is_dir) {
+                return Err(FsError::NotADirectory);
+            }
+            break;
+        }
+        if component.is_empty() {
+            continue;
+        }
+        let dir = match stack.last() {
+            None => filesystem.root_cluster,
+            Some(entry) if entry.is_dir => entry.first_cluster,
+            Some(_) => return Err(FsError::NotADirectory),
+        };
+        match component {
+            "." => {}
+            ".." => {
+                stack.pop();
+            }
+            name => {
+                let found = filesystem
+                    .read_dir(dir)?
+                    .into_iter()
+                    .find(|entry| entry.matches(name))
+                    .ok_or(FsError::NotFound)?;
+                stack.push(found);
+            }
+        }
+    }
+    Ok(stack.pop())
+}
+
+/// Opens the file at `path`.
+pub fn open(path: &str) -> Result<File, FsError> {
+    let _lock = lock();
+    match resolve(filesystem()?, path)? {
+        Some(entry) if !entry.is_dir => Ok(File::new(entry)),
+        _ => Err(FsError::NotAFile),
+    }
+}
+
+/// Lists the directory at `path`, the root directory for `/` or an empty path. Deleted entries
+/// and the volume label are left out, and so are `.` and `..`.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let _lock = lock();
+    let filesystem = filesystem()?;
+    match resolve(filesystem, path)? {
+        None => filesystem.read_dir(filesystem.root_cluster),
+        Some(entry) if entry.is_dir => filesystem.read_dir(entry.first_cluster),
+        Some(_) => Err(FsError::NotADirectory),
+    }
+}
+
+/// Creates an empty file at `path`, in a directory that must exist. Names that don't fit 8.3 get a
+/// long filename.
+pub fn create(path: &str) -> Result<File, FsError> {
+    let _lock = lock();
+    let filesystem = filesystem_mut()?;
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if !fat::valid_name(name) {
+        return Err(FsError::InvalidName);
+    }
+    let dir = match resolve(filesystem, parent)? {
+        None => filesystem.root_cluster,
+        Some(entry) if entry.is_dir => entry.first_cluster,
+        Some(_) => return Err(FsError::NotADirectory),
+    };
+    let existing = filesystem.read_dir(dir)?;
+    if existing.iter().any(|entry| entry.matches(name)) {
+        return Err(FsError::AlreadyExists);
+    }
+
+    let mut short_entry = [0; DIR_ENTRY_SIZE];
+    let long_entries = match fat::exact_short_name(name) {
+        Some((raw, case)) => {
+            short_entry[..11].copy_from_slice(&raw);
+            short_entry[12] = case;
+            Vec::new()
+        }
+        None => {
+            let taken = |short_name: &str| {
+                existing
+                    .iter()
+                    .any(|entry| entry.short_name.eq_ignore_ascii_case(short_name))
+            };
+            let raw = fat::generate_short_name(name, taken).ok_or(FsError::AlreadyExists)?;
+            short_entry[..11].copy_from_slice(&raw);
+            fat::long_name_entries(name, fat::short_name_checksum(&short_entry))
+        }
+    };
+    let (date, time) = timestamp();
+    short_entry[11] = ATTR_ARCHIVE;
+    for offset in [14, 22] {
+        short_entry[offset..offset + 2].copy_from_slice(&time.to_le_bytes());
+    }
+    for offset in [16, 18, 24] {
+        short_entry[offset..offset + 2].copy_from_slice(&date.to_le_bytes());
+    }
+
+    let count = long_entries.len() + 1;
+    let (mut chain, data) = filesystem.read_dir_raw(dir)?;
+    let total = data.len() / DIR_ENTRY_SIZE;
+    let end_index = data
+        .chunks_exact(DIR_ENTRY_SIZE)
+        .position(|entry| entry[0] == ENTRY_END);
+    let index = match fat::find_free_entries(&data, count) {
+        Some(index) => index,
+        None => {
+            // Free entries at the end continue into the new clusters.
+            let start = end_index.unwrap_or_else(|| {
+                total
+                    - data
+                        .chunks_exact(DIR_ENTRY_SIZE)
+                        .rev()
+                        .take_while(|entry| entry[0] == ENTRY_DELETED)
+                        .count()
+            });
+            let per_cluster = filesystem.cluster_size() / DIR_ENTRY_SIZE;
+            while chain.len() * per_cluster - start < count {
+                filesystem.grow_dir(&mut chain)?;
+            }
+            start
+        }
+    };
+    for (i, entry) in long_entries.iter().enumerate() {
+        filesystem.write_entry(filesystem.entry_location(&chain, index + i), entry)?;
+    }
+    let location = filesystem.entry_location(&chain, index + count - 1);
+    filesystem.write_entry(location, &short_entry)?;
+    // Entries past an end marker are free but not necessarily zeroed, so the directory needs a
+    // new end marker after the new entries.
+    let next = index + count;
+    if matches!(end_index, Some(end_index) if next > end_index)
+        && next < total
+        && data[next * DIR_ENTRY_SIZE] != ENTRY_END
+    {
+        filesystem.write_entry(
+            filesystem.entry_location(&chain, next),
+            &[0; DIR_ENTRY_SIZE],
+        )?;
+    }
+    filesystem.write_fsinfo()?;
+
+    Ok(File::new(DirEntry {
+        name: String::from(name),
+        size: 0,
+        is_dir: false,
+        short_name: fat::short_name(&short_entry),
+        first_cluster: 0,
+        location,
+    }))
+}
@@ -0,0 +1,74 @@
+use crate::cpu;
+use core::arch::asm;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+/// Default x87 control word: all exceptions masked, 64 bit precision, round to nearest.
+const DEFAULT_FCW: u16 = 0x037f;
+/// Default SSE control and status: all exceptions masked, round to nearest.
+const DEFAULT_MXCSR: u32 = 0x1f80;
+const FXSAVE_MXCSR_OFFSET: usize = 24;
+
+static mut ENABLED: bool = false;
+
+/// The x87 and SSE registers of a process, in the layout FXSAVE writes.
+#[derive(Clone)]
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// The state after FNINIT, which a new process starts with.
+    pub fn new() -> Self {
+        let mut data = [0; 512];
+        data[..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        data[FXSAVE_MXCSR_OFFSET..FXSAVE_MXCSR_OFFSET + 4]
+            .copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        FpuState(data)
+    }
+    /// Saves the registers into this state. The FPU must be enabled and not trapping.
+    pub unsafe fn save(&mut self) {
+        asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack));
+    }
+    /// Loads the registers from this state. The FPU must be enabled and not trapping.
+    pub unsafe fn restore(&self) {
+        asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack, readonly));
+    }
+}
+
+/// Turns on the x87 FPU and SSE for userspace. The kernel itself is built without them. Without
+/// SSE and FXSAVE they stay off, and programs using floating point are killed by the invalid
+/// opcode or device not available fault they cause.
+pub fn init() {
+    if !cpu::has_sse() || !cpu::has_fxsr() {
+        log::warn!("CPU has no SSE, floating point is disabled");
+        return;
+    }
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        });
+        ENABLED = true;
+    }
+    // The first process to use the FPU loads its state in the trap.
+    trap_next_use();
+}
+
+pub fn enabled() -> bool {
+    unsafe { ENABLED }
+}
+
+/// Makes the next FPU or SSE instruction raise device not available, so the scheduler can switch
+/// the FPU state only when a process actually uses it.
+pub fn trap_next_use() {
+    if enabled() {
+        unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+    }
+}
+
+/// Stops trapping FPU instructions, after the right state was loaded.
+pub fn allow_use() {
+    unsafe { asm!("clts", options(nomem, nostack)) };
+}
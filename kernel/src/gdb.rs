@@ -0,0 +1,459 @@
+use crate::{debug, interrupt, memory, serial, watchdog};
+use core::arch::{asm, global_asm};
+use x86_64::{
+    registers::segmentation::{Segment, DS, ES, FS, GS},
+    VirtAddr,
+};
+
+/// The largest packet either side sends, as told to GDB in `qSupported`.
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+/// The trap flag, which raises a debug exception after the next instruction.
+const RFLAGS_TF: u64 = 1 << 8;
+/// Every stop is reported to GDB as SIGTRAP.
+const STOP_REPLY: &[u8] = b"S05";
+/// EFAULT, for memory that isn't mapped.
+const MEMORY_ERROR: &[u8] = b"E0e";
+/// Registers in the `g` packet, each 64 bit: rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15 and
+/// rip. Then come eflags, cs, ss, ds, es, fs and gs, each 32 bit. GDB treats the floating point
+/// registers after them as unavailable, since the reply stops there.
+const GENERAL_REGISTERS: usize = 17;
+const SEGMENT_REGISTERS: usize = 7;
+
+/// The registers `gdb_trap_entry` pushes, below the frame the CPU pushed for the exception.
+#[repr(C)]
+struct TrapFrame {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+impl TrapFrame {
+    /// The 64 bit registers in `g` packet order.
+    fn general(&mut self) -> [&mut u64; GENERAL_REGISTERS] {
+        [
+            &mut self.rax,
+            &mut self.rbx,
+            &mut self.rcx,
+            &mut self.rdx,
+            &mut self.rsi,
+            &mut self.rdi,
+            &mut self.rbp,
+            &mut self.rsp,
+            &mut self.r8,
+            &mut self.r9,
+            &mut self.r10,
+            &mut self.r11,
+            &mut self.r12,
+            &mut self.r13,
+            &mut self.r14,
+            &mut self.r15,
+            &mut self.rip,
+        ]
+    }
+}
+
+/// What to do after a packet.
+enum Action {
+    Reply,
+    Continue,
+    Step,
+    /// Continue without GDB waiting for it to stop again.
+    Detach,
+}
+
+/// A packet being built, without the framing.
+struct Reply {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    /// Anything past `PACKET_SIZE` is dropped. Memory reads are limited so it doesn't happen.
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(PACKET_SIZE - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(&[hex_digit(byte >> 4), hex_digit(byte & 0xf)]);
+        }
+    }
+}
+
+// Fixed buffers, because the stub can stop the kernel anywhere, including inside the allocator.
+static mut PACKET: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+static mut REPLY: Reply = Reply {
+    data: [0; PACKET_SIZE],
+    len: 0,
+};
+/// Inserted breakpoints, with the byte each replaced.
+static mut BREAKPOINTS: [Option<(u64, u8)>; MAX_BREAKPOINTS] = [None; MAX_BREAKPOINTS];
+/// Set while GDB is waiting for a stop reply, after it continued or stepped.
+static mut RESUMED: bool = false;
+
+extern "C" {
+    fn gdb_trap_entry();
+}
+
+// Entered through an interrupt gate, so interrupts stay off until the stub returns: nothing else
+// runs while the kernel is stopped, and no interrupt can find the stub holding COM1. Neither
+// exception pushes an error code, so 15 registers on top of the CPU's 5 keep the stack aligned.
+// The pushes land below the interrupted code's RSP, which is only safe because the kernel has no
+// red zone.
+global_asm!(
+    ".global gdb_trap_entry",
+    "gdb_trap_entry:",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rbp",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rbx",
+    "push rax",
+    "mov rdi, rsp",
+    "call {handle}",
+    "pop rax",
+    "pop rbx",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rbp",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "iretq",
+    handle = sym handle_trap,
+);
+
+/// Sends breakpoint and debug exceptions to the stub, then stops until GDB attaches over COM1.
+pub fn init() {
+    // Logging only sets up the port when it uses it.
+    serial::init_serial();
+    unsafe { interrupt::set_debug_handler(VirtAddr::new(gdb_trap_entry as usize as u64)) };
+    log::info!("Waiting for GDB on COM1");
+    breakpoint();
+}
+
+/// Stops in the debugger, if there's one.
+pub fn breakpoint() {
+    unsafe { asm!("int3") };
+}
+
+extern "sysv64" fn handle_trap(frame: &mut TrapFrame) {
+    frame.rflags &= !RFLAGS_TF;
+    debug::take_status();
+    // Text output would end up in the middle of packets.
+    serial::set_raw(true);
+    unsafe {
+        // GDB asks with `?` when it attaches, and isn't expecting a reply before then.
+        if RESUMED {
+            send_packet(STOP_REPLY);
+            RESUMED = false;
+        }
+        loop {
+            REPLY.len = 0;
+            let packet = receive_packet();
+            match handle_packet(frame, packet) {
+                Action::Reply => send_packet(&REPLY.data[..REPLY.len]),
+                Action::Continue => {
+                    RESUMED = true;
+                    break;
+                }
+                Action::Step => {
+                    frame.rflags |= RFLAGS_TF;
+                    RESUMED = true;
+                    break;
+                }
+                Action::Detach => break,
+            }
+        }
+    }
+    serial::set_raw(false);
+    // The clock stood still while stopped, but the deadline didn't move.
+    watchdog::pet();
+}
+
+/// Handles one packet, leaving any reply in `REPLY`. Unsupported packets get an empty reply, which
+/// tells GDB so.
+unsafe fn handle_packet(frame: &mut TrapFrame, packet: &[u8]) -> Action {
+    let Some((&command, args)) = packet.split_first() else {
+        return Action::Reply;
+    };
+    let reply = &mut REPLY;
+    match command {
+        b'?' => reply.push(STOP_REPLY),
+        b'g' => {
+            for register in frame.general() {
+                reply.push_hex(&register.to_le_bytes());
+            }
+            let segments: [u64; SEGMENT_REGISTERS] = [
+                frame.rflags,
+                frame.cs,
+                frame.ss,
+                DS::get_reg().0 as u64,
+                ES::get_reg().0 as u64,
+                FS::get_reg().0 as u64,
+                GS::get_reg().0 as u64,
+            ];
+            for register in segments {
+                reply.push_hex(&(register as u32).to_le_bytes());
+            }
+        }
+        b'G' => {
+            let mut values = args.chunks(16);
+            for register in frame.general() {
+                if let Some(value) = values.next().and_then(parse_le_hex) {
+                    *register = value;
+                }
+            }
+            // Only eflags of the 32 bit ones: a new cs or ss could make the return fault.
+            if let Some(value) = args.get(GENERAL_REGISTERS * 16..GENERAL_REGISTERS * 16 + 8) {
+                if let Some(value) = parse_le_hex(value) {
+                    frame.rflags = value;
+                }
+            }
+            reply.push(b"OK");
+        }
+        b'm' => match parse_range(args) {
+            // Two hex digits for each byte.
+            Some((addr, len)) => {
+                let len = len.min(PACKET_SIZE as u64 / 2);
+                for offset in 0..len {
+                    let Some(byte) = read_memory(addr.wrapping_add(offset)) else {
+                        reply.len = 0;
+                        reply.push(MEMORY_ERROR);
+                        break;
+                    };
+                    reply.push_hex(&[byte]);
+                }
+            }
+            None => reply.push(b"E01"),
+        },
+        b'M' => {
+            let mut parts = args.splitn(2, |&b| b == b':');
+            let range = parts.next().and_then(parse_range);
+            match (range, parts.next()) {
+                (Some((addr, len)), Some(data)) if data.len() as u64 == len * 2 => {
+                    let written = data.chunks(2).enumerate().all(|(offset, digits)| {
+                        parse_hex(digits).map_or(false, |byte| {
+                            write_memory(addr.wrapping_add(offset as u64), byte as u8)
+                        })
+                    });
+                    reply.push(if written { b"OK" } else { MEMORY_ERROR });
+                }
+                _ => reply.push(b"E01"),
+            }
+        }
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                frame.rip = addr;
+            }
+            return if command == b's' {
+                Action::Step
+            } else {
+                Action::Continue
+            };
+        }
+        // Software breakpoints. The kind is always 1 on x86, the length of INT3.
+        b'Z' | b'z' if args.first() == Some(&b'0') => {
+            let addr = args
+                .get(2..)
+                .and_then(|args| args.split(|&b| b == b',').next())
+                .and_then(parse_hex);
+            let done = match addr {
+                Some(addr) if command == b'Z' => insert_breakpoint(addr),
+                Some(addr) => remove_breakpoint(addr),
+                None => false,
+            };
+            reply.push(if done { b"OK" } else { b"E01" });
+        }
+        // There's only one thread to pick.
+        b'H' => reply.push(b"OK"),
+        b'q' if args.starts_with(b"Supported") => reply.push(b"PacketSize=1000"),
+        b'q' if args.starts_with(b"Attached") => reply.push(b"1"),
+        // Detaching takes the breakpoints out and lets the kernel run on. Killing the kernel isn't
+        // possible, so it does the same.
+        b'D' | b'k' => {
+            for breakpoint in BREAKPOINTS.iter_mut() {
+                if let Some((addr, byte)) = breakpoint.take() {
+                    write_memory(addr, byte);
+                }
+            }
+            if command == b'D' {
+                send_packet(b"OK");
+            }
+            return Action::Detach;
+        }
+        _ => {}
+    }
+    Action::Reply
+}
+
+/// The byte at `addr`, if it's mapped.
+fn read_memory(addr: u64) -> Option<u8> {
+    let addr = VirtAddr::try_new(addr).ok()?;
+    memory::is_mapped(addr).then(|| unsafe { addr.as_ptr::<u8>().read_volatile() })
+}
+
+/// Writes through the physical memory mapping, which is writable even where the kernel's code is
+/// mapped read only. A copy-on-write page is changed for every address space sharing it.
+fn write_memory(addr: u64, byte: u8) -> bool {
+    let Some(phys) = VirtAddr::try_new(addr).ok().and_then(memory::translate) else {
+        return false;
+    };
+    let virt = memory::phys_to_virt(phys);
+    if !memory::is_mapped(virt) {
+        return false;
+    }
+    unsafe { virt.as_mut_ptr::<u8>().write_volatile(byte) };
+    true
+}
+
+unsafe fn insert_breakpoint(addr: u64) -> bool {
+    if BREAKPOINTS.iter().flatten().any(|&(a, _)| a == addr) {
+        return true;
+    }
+    let Some(slot) = BREAKPOINTS.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+    let Some(byte) = read_memory(addr) else {
+        return false;
+    };
+    if !write_memory(addr, INT3) {
+        return false;
+    }
+    *slot = Some((addr, byte));
+    true
+}
+
+unsafe fn remove_breakpoint(addr: u64) -> bool {
+    let Some(slot) = BREAKPOINTS
+        .iter_mut()
+        .find(|slot| matches!(slot, Some((a, _)) if *a == addr))
+    else {
+        return false;
+    };
+    let (_, byte) = slot.take().unwrap();
+    write_memory(addr, byte)
+}
+
+/// Waits for a packet with a good checksum, acknowledging it, and returns what's between `$` and
+/// `#`.
+unsafe fn receive_packet() -> &'static [u8] {
+    loop {
+        // Everything before the start of a packet, like acknowledgements, is skipped.
+        while serial::wait_byte() != b'$' {}
+        let mut len = 0;
+        let mut checksum: u8 = 0;
+        let mut overflow = false;
+        loop {
+            match serial::wait_byte() {
+                b'#' => break,
+                // GDB gave up on the last one and started again.
+                b'$' => {
+                    len = 0;
+                    checksum = 0;
+                    overflow = false;
+                }
+                byte => {
+                    if len < PACKET_SIZE {
+                        PACKET[len] = byte;
+                        len += 1;
+                    } else {
+                        overflow = true;
+                    }
+                    checksum = checksum.wrapping_add(byte);
+                }
+            }
+        }
+        let expected = parse_hex(&[serial::wait_byte(), serial::wait_byte()]);
+        if !overflow && expected == Some(checksum as u64) {
+            serial::write_byte(b'+');
+            return &PACKET[..len];
+        }
+        serial::write_byte(b'-');
+    }
+}
+
+/// Sends `data` as a packet until GDB acknowledges it.
+fn send_packet(data: &[u8]) {
+    loop {
+        serial::write_byte(b'$');
+        let mut checksum: u8 = 0;
+        for &byte in data {
+            serial::write_byte(byte);
+            checksum = checksum.wrapping_add(byte);
+        }
+        serial::write_byte(b'#');
+        serial::write_byte(hex_digit(checksum >> 4));
+        serial::write_byte(hex_digit(checksum & 0xf));
+        if serial::wait_byte() != b'-' {
+            return;
+        }
+    }
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[value as usize]
+}
+
+/// A big-endian hex number, as GDB sends addresses and lengths.
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| {
+        Some(value << 4 | (digit as char).to_digit(16)? as u64)
+    })
+}
+
+/// Hex bytes in memory order, as GDB sends register values.
+fn parse_le_hex(digits: &[u8]) -> Option<u64> {
+    if digits.len() % 2 != 0 || digits.len() > 16 {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .rev()
+        .try_fold(0, |value, byte| Some(value << 8 | parse_hex(byte)?))
+}
+
+/// `addr,length`
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, |&b| b == b',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
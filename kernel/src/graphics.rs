@@ -1,24 +1,126 @@
-use crate::memory::VirtMemRange;
+use crate::memory::{self, VirtMemRange};
+use crate::screen;
+use alloc::vec::Vec;
+use core::fmt::{Arguments, Display, Write};
+use core::panic::PanicInfo;
+use kernel_common::bmp;
 
 pub use kernel_common::graphics::*;
 
+pub mod cursor;
+
+pub use bmp::BmpError;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Mode {
+    pub width: u32,
+    pub height: u32,
+    pub stride: usize,
+    pub bytes_per_pixel: usize,
+}
+
+impl Mode {
+    pub const fn const_default() -> Self {
+        Mode {
+            width: 0,
+            height: 0,
+            stride: 0,
+            bytes_per_pixel: 0,
+        }
+    }
+}
+
 static mut FRAMEBUFFER: Option<FrameBuffer> = None;
+static mut BACK_BUFFER: Option<FrameBuffer> = None;
+static mut BACK_BUFFER_FAILED: bool = false;
 static mut GRAPHICS_CONTEXT: GraphicsContext = GraphicsContext::const_default();
+static mut PHYSICAL_MODE: Mode = Mode::const_default();
+static mut MODE: Mode = Mode::const_default();
 
 pub fn init_graphics(framebuffer: &'static mut bootloader_api::info::FrameBuffer) -> VirtMemRange {
+    let info = framebuffer.info();
     let data = framebuffer.buffer_mut();
     let fb_memory = VirtMemRange::new(data.as_ptr() as u64, data.len());
     data.fill(0);
     let context = GraphicsContext::from_framebuffer(framebuffer);
+    if !context.is_supported() {
+        log::warn!(
+            "Unsupported framebuffer format {:?} with {} bytes per pixel, graphics disabled",
+            info.pixel_format,
+            info.bytes_per_pixel
+        );
+    }
     let buffer = FrameBuffer::from_framebuffer(framebuffer);
     load_system_font(&context, [255, 64, 64]);
+    let mode = Mode {
+        width: info.width as u32,
+        height: info.height as u32,
+        stride: info.stride,
+        bytes_per_pixel: info.bytes_per_pixel,
+    };
     unsafe {
         FRAMEBUFFER = Some(buffer);
         GRAPHICS_CONTEXT = context;
+        PHYSICAL_MODE = mode;
+        MODE = mode;
     }
     fb_memory
 }
 
+/// Restricts drawing to a `width` x `height` region in the top-left corner of the framebuffer.
+///
+/// The bootloader picks the real video mode, so this can only select a mode that fits inside it
+/// and uses the same pixel size.
+pub fn set_mode(width: u32, height: u32, bytes_per_pixel: usize) -> Result<Mode, &'static str> {
+    let physical = unsafe { PHYSICAL_MODE };
+    if bytes_per_pixel != physical.bytes_per_pixel {
+        return Err("unsupported bytes per pixel");
+    }
+    if width == 0 || height == 0 || width > physical.width || height > physical.height {
+        return Err("mode does not fit in framebuffer");
+    }
+    let framebuffer = unsafe { FRAMEBUFFER.take().ok_or("graphics not initialized")? };
+    let mode = Mode {
+        width,
+        height,
+        stride: physical.stride,
+        bytes_per_pixel,
+    };
+    unsafe {
+        FRAMEBUFFER = Some(FrameBuffer::new(
+            width,
+            height,
+            mode.stride,
+            framebuffer.into_data(),
+        ));
+        if let Some(back_buffer) = BACK_BUFFER.take() {
+            BACK_BUFFER = Some(FrameBuffer::new(
+                width,
+                height,
+                mode.stride,
+                back_buffer.into_data(),
+            ));
+        }
+        MODE = mode;
+        DIRTY_RECTS.invalidate();
+    }
+    log::info!(
+        "Graphics mode set to {}x{}x{}",
+        width,
+        height,
+        bytes_per_pixel * 8
+    );
+    Ok(mode)
+}
+
+pub fn mode() -> Mode {
+    unsafe { MODE }
+}
+pub fn dimensions() -> (u32, u32) {
+    let mode = mode();
+    (mode.width, mode.height)
+}
+
 pub fn context() -> GraphicsContext {
     unsafe { GRAPHICS_CONTEXT.clone() }
 }
@@ -29,3 +131,360 @@ pub unsafe fn framebuffer() -> Option<FrameBuffer> {
     core::ptr::copy_nonoverlapping(&FRAMEBUFFER as *const _, &mut framebuffer as *mut _, 1);
     framebuffer
 }
+
+/// Allocates the back buffer the first time it is needed. This can't happen in `init_graphics`
+/// because the framebuffer is set up before the kernel heap.
+unsafe fn init_back_buffer() {
+    if BACK_BUFFER.is_some() || BACK_BUFFER_FAILED || !memory::heap_initialized() {
+        return;
+    }
+    let mode = MODE;
+    if mode.width == 0 {
+        return;
+    }
+    let len = mode.stride * mode.height as usize * mode.bytes_per_pixel;
+    let mut data = Vec::new();
+    if data.try_reserve_exact(len).is_err() {
+        BACK_BUFFER_FAILED = true;
+        log::warn!("Not enough memory for a back buffer, drawing directly to the framebuffer");
+        return;
+    }
+    data.resize(len, 0);
+    BACK_BUFFER = Some(FrameBuffer::new(
+        mode.width,
+        mode.height,
+        mode.stride,
+        data.leak(),
+    ));
+}
+
+/// Returns the buffer that kernel drawing should target: the back buffer if it could be allocated,
+/// otherwise the framebuffer itself. Call `present` to make the changes visible.
+///
+/// UNSAFE: same as `framebuffer`.
+pub unsafe fn target() -> Option<FrameBuffer> {
+    init_back_buffer();
+    let mut target = None;
+    core::ptr::copy_nonoverlapping(&BACK_BUFFER as *const _, &mut target as *mut _, 1);
+    if target.is_none() {
+        framebuffer()
+    } else {
+        target
+    }
+}
+
+static mut DIRTY_RECTS: DirtyRects = DirtyRects::new();
+
+fn screen_rect() -> Rect {
+    let (width, height) = dimensions();
+    Rect::new(0, 0, width, height)
+}
+
+/// Records that `rect` was drawn to, so the next `present` copies it to the framebuffer.
+pub fn mark_dirty(rect: Rect) {
+    let rect = rect.intersect(&screen_rect());
+    unsafe {
+        DIRTY_RECTS.add(rect);
+    }
+}
+
+/// Makes the next `present` copy the whole back buffer, e.g. after something else drew to the
+/// framebuffer.
+pub fn invalidate() {
+    unsafe {
+        DIRTY_RECTS.invalidate();
+    }
+}
+
+/// The bounding box of everything drawn since the last present, or `None` if nothing was drawn.
+#[allow(dead_code)]
+pub fn dirty_region() -> Option<Rect> {
+    unsafe {
+        if DIRTY_RECTS.is_full() {
+            Some(screen_rect())
+        } else if DIRTY_RECTS.as_slice().is_empty() {
+            None
+        } else {
+            Some(DIRTY_RECTS.bounds())
+        }
+    }
+}
+
+unsafe fn copy_rect(back_buffer: &FrameBuffer, framebuffer: &mut FrameBuffer, rect: Rect) {
+    let bytes_per_pixel = MODE.bytes_per_pixel;
+    let row_stride = back_buffer.stride() * bytes_per_pixel;
+    let row_bytes = rect.width() as usize * bytes_per_pixel;
+    let mut offset = (rect.y() as usize * row_stride) + (rect.x() as usize * bytes_per_pixel);
+    let source = back_buffer.data().as_ptr();
+    let dest = framebuffer.data_mut().as_mut_ptr();
+    for _row in 0..rect.height() {
+        core::ptr::copy_nonoverlapping(source.add(offset), dest.add(offset), row_bytes);
+        offset += row_stride;
+    }
+}
+
+/// Copies the parts of the back buffer that changed since the last present to the framebuffer,
+/// and draws the mouse cursor over them. The first present after startup or a mode change copies
+/// everything.
+pub fn present() {
+    unsafe {
+        let full = [screen_rect()];
+        let dirty = if DIRTY_RECTS.is_full() {
+            &full[..]
+        } else {
+            DIRTY_RECTS.as_slice()
+        };
+        if let Some(framebuffer) = FRAMEBUFFER.as_mut() {
+            let context = context();
+            cursor::restore(&context, framebuffer, dirty, BACK_BUFFER.is_some());
+            if let Some(back_buffer) = BACK_BUFFER.as_ref() {
+                for rect in dirty {
+                    copy_rect(back_buffer, framebuffer, *rect);
+                }
+            }
+            cursor::draw(&context, framebuffer);
+        }
+        DIRTY_RECTS.clear();
+    }
+}
+
+/// Runs `draw` on the drawing target with `color` encoded for it, then marks `bounds` dirty.
+fn draw_shape(
+    bounds: Rect,
+    color: Color,
+    draw: impl FnOnce(&GraphicsContext, &mut FrameBuffer, u32),
+) {
+    let context = context();
+    let (Some(mut target), Some(color)) = (unsafe { target() }, context.encode_color(color)) else {
+        return;
+    };
+    draw(&context, &mut target, color);
+    mark_dirty(bounds);
+}
+
+/// The rect covering a circle, for marking it dirty.
+fn circle_bounds(center: Point, radius: u32) -> Rect {
+    let radius = radius.min(i32::MAX as u32 / 2) as i32;
+    let size = radius as u32 * 2 + 1;
+    Rect::new(
+        center.x().saturating_sub(radius),
+        center.y().saturating_sub(radius),
+        size,
+        size,
+    )
+}
+
+// Drawing functions for the kernel's own screen. They draw to `target` and mark what they touch
+// dirty, clipped to the screen.
+
+#[allow(dead_code)]
+pub fn draw_line(from: Point, to: Point, color: Color) {
+    let (x, y) = (from.x().min(to.x()), from.y().min(to.y()));
+    let width = from.x().abs_diff(to.x()).saturating_add(1);
+    let height = from.y().abs_diff(to.y()).saturating_add(1);
+    draw_shape(
+        Rect::new(x, y, width, height),
+        color,
+        |context, target, color| context.draw_line(target, from, to, color),
+    );
+}
+pub fn draw_rect(rect: Rect, color: Color) {
+    draw_shape(rect, color, |context, target, color| {
+        context.draw_rect(target, rect, color)
+    });
+}
+pub fn fill_rect(rect: Rect, color: Color) {
+    draw_shape(rect, color, |context, target, color| {
+        context.fill_rect(target, rect, color)
+    });
+}
+#[allow(dead_code)]
+pub fn draw_circle(center: Point, radius: u32, color: Color) {
+    draw_shape(
+        circle_bounds(center, radius),
+        color,
+        |context, target, color| context.draw_circle(target, center, radius, color),
+    );
+}
+#[allow(dead_code)]
+pub fn fill_circle(center: Point, radius: u32, color: Color) {
+    draw_shape(
+        circle_bounds(center, radius),
+        color,
+        |context, target, color| context.fill_circle(target, center, radius, color),
+    );
+}
+
+/// Alpha-blends an RGBA image of `dst_rect`'s size onto the screen, see
+/// `GraphicsContext::blit_blend`. `src_stride` is in pixels.
+#[allow(dead_code)]
+pub fn blit_blend(src: &[u8], src_stride: usize, dst_rect: Rect, alpha: u8) {
+    let context = context();
+    let Some(mut target) = (unsafe { target() }) else {
+        return;
+    };
+    context.blit_blend(src, src_stride, &mut target, dst_rect, alpha);
+    mark_dirty(dst_rect);
+}
+
+/// Copies an image of `rect`'s size in the screen's pixel format, with rows `rect.width()` pixels
+/// apart, to `rect` on the screen. `rect` must be inside the screen and `src` large enough.
+pub fn blit(src: &[u8], rect: Rect) {
+    let context = context();
+    let Some(mut target) = (unsafe { target() }) else {
+        return;
+    };
+    let bytes_per_pixel = context.bytes_per_pixel();
+    let row_bytes = rect.width() as usize * bytes_per_pixel;
+    let stride = target.stride();
+    for (row, line) in src
+        .chunks_exact(row_bytes)
+        .take(rect.height() as usize)
+        .enumerate()
+    {
+        let offset = ((rect.y() as usize + row) * stride + rect.x() as usize) * bytes_per_pixel;
+        target.data_mut()[offset..offset + row_bytes].copy_from_slice(line);
+    }
+    mark_dirty(rect);
+}
+
+/// Decodes a BMP file into a texture in the framebuffer's pixel format, for `draw_image`.
+pub fn load_bmp(data: &[u8]) -> Result<VecBuffer, BmpError> {
+    bmp::decode(&context(), data)
+}
+
+/// Draws an image from `load_bmp` with its top-left corner at `x`, `y`, clipped to the screen.
+pub fn draw_image(image: &VecBuffer, x: i32, y: i32) {
+    let context = context();
+    let Some(mut target) = (unsafe { target() }) else {
+        return;
+    };
+    let rect = Rect::new(0, 0, image.width(), image.height());
+    context.blit(image, rect, &mut target, Point::new(x, y));
+    mark_dirty(Rect::new(x, y, image.width(), image.height()));
+}
+
+/// A copy of what's on the screen, including the mouse cursor, in the framebuffer's pixel format.
+pub fn capture() -> Option<VecBuffer> {
+    let context = context();
+    let framebuffer = unsafe { framebuffer() }?;
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let mut image = VecBuffer::alloc(&context, width, height);
+    context.blit(
+        &framebuffer,
+        Rect::new(0, 0, width, height),
+        &mut image,
+        Point::new(0, 0),
+    );
+    Some(image)
+}
+
+/// Encodes an image from `capture` or `load_bmp` as a 24 bit BMP file.
+pub fn encode_bmp(image: &VecBuffer) -> Result<Vec<u8>, BmpError> {
+    bmp::encode(&context(), image)
+}
+
+const PANIC_BACKGROUND: Color = Color::new(128, 0, 0);
+const PANIC_MARGIN: u32 = 2;
+
+/// Draws formatted text straight into a framebuffer, wrapping at the right edge.
+struct PanicWriter<'a> {
+    context: &'a GraphicsContext,
+    framebuffer: &'a mut FrameBuffer,
+    fg: u32,
+    bg: u32,
+    column: u32,
+    row: u32,
+    columns: u32,
+}
+
+impl<'a> PanicWriter<'a> {
+    fn new_line(&mut self) {
+        self.column = 0;
+        self.row += 1;
+    }
+}
+
+impl<'a> Write for PanicWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for ch in s.chars() {
+            if ch == '\n' {
+                self.new_line();
+                continue;
+            }
+            if self.column == self.columns {
+                self.new_line();
+            }
+            let x = ((PANIC_MARGIN + self.column) * screen::char_width()) as i32;
+            let y = ((PANIC_MARGIN + self.row) * screen::char_height()) as i32;
+            screen::draw_char_to(self.context, self.framebuffer, x, y, ch, self.fg, self.bg);
+            self.column += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Fills the screen and draws the text written by `write`. This draws directly to the framebuffer
+/// and doesn't allocate, so it works when the heap or the back buffer is broken.
+fn error_screen(write: impl FnOnce(&mut PanicWriter) -> core::fmt::Result) {
+    let context = context();
+    let Some(mut framebuffer) = (unsafe { framebuffer() }) else {
+        return;
+    };
+    let (Some(fg), Some(bg)) = (
+        context.encode_color(Color::WHITE),
+        context.encode_color(PANIC_BACKGROUND),
+    ) else {
+        return;
+    };
+    let screen = Rect::new(0, 0, framebuffer.width(), framebuffer.height());
+    context.fill_rect(&mut framebuffer, screen, bg);
+    let columns = (framebuffer.width() / screen::char_width()).saturating_sub(PANIC_MARGIN * 2);
+    let mut writer = PanicWriter {
+        context: &context,
+        framebuffer: &mut framebuffer,
+        fg,
+        bg,
+        column: 0,
+        row: 0,
+        columns: columns.max(1),
+    };
+    let _ = write(&mut writer);
+}
+
+/// Shows the panic message and location on a full screen.
+pub fn panic_screen(info: &PanicInfo) {
+    error_screen(|writer| {
+        write!(writer, "{} panicked!\n\n", crate::OS_NAME)?;
+        match info.message() {
+            Some(message) => writer.write_fmt(*message)?,
+            None => writer.write_str("no message")?,
+        }
+        if let Some(location) = info.location() {
+            write!(
+                writer,
+                "\n\nat {}:{}:{}",
+                location.file(),
+                location.line(),
+                location.column()
+            )?;
+        }
+        writer.write_str("\n\n")?;
+        crate::backtrace::print(writer)
+    });
+}
+
+/// Shows a fatal error and a backtrace on a full screen. Used by `fatal_error!`, which also runs
+/// when allocating failed.
+pub fn fatal_error_screen(message: Arguments) {
+    error_screen(|writer| {
+        writer.write_fmt(message)?;
+        writer.write_str("\n\n")?;
+        crate::backtrace::print(writer)
+    });
+}
+
+/// Shows why the kernel couldn't start on a full screen.
+pub fn init_error_screen(error: &dyn Display) {
+    error_screen(|writer| write!(writer, "{} failed to start\n\n{}", crate::OS_NAME, error));
+}
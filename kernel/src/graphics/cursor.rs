@@ -0,0 +1,159 @@
+use super::{dimensions, Rect};
+use kernel_common::graphics::{FrameBuffer, GraphicsContext, Texture};
+
+const WIDTH: u32 = 12;
+const HEIGHT: u32 = 19;
+const SIZE: usize = (WIDTH * HEIGHT * 4) as usize;
+
+/// The arrow, with its hotspot in the top-left corner. `X` is the outline, `.` the fill, and
+/// spaces are transparent.
+const SPRITE: [&str; HEIGHT as usize] = [
+    "X           ",
+    "XX          ",
+    "X.X         ",
+    "X..X        ",
+    "X...X       ",
+    "X....X      ",
+    "X.....X     ",
+    "X......X    ",
+    "X.......X   ",
+    "X........X  ",
+    "X.........X ",
+    "X..........X",
+    "X......XXXXX",
+    "X...X..X    ",
+    "X..XX..X    ",
+    "X.X  X..X   ",
+    "XX   X..X   ",
+    "      X..X  ",
+    "      XXXX  ",
+];
+
+/// `SPRITE` as RGBA pixels for `GraphicsContext::blit_blend`.
+const fn sprite_rgba() -> [u8; SIZE] {
+    let mut pixels = [0; SIZE];
+    let mut y = 0;
+    while y < HEIGHT as usize {
+        let row = SPRITE[y].as_bytes();
+        let mut x = 0;
+        while x < WIDTH as usize {
+            let (value, alpha) = match row[x] {
+                b'X' => (0, 255),
+                b'.' => (255, 255),
+                _ => (0, 0),
+            };
+            let offset = (y * WIDTH as usize + x) * 4;
+            pixels[offset] = value;
+            pixels[offset + 1] = value;
+            pixels[offset + 2] = value;
+            pixels[offset + 3] = alpha;
+            x += 1;
+        }
+        y += 1;
+    }
+    pixels
+}
+
+static SPRITE_RGBA: [u8; SIZE] = sprite_rgba();
+
+struct Cursor {
+    x: i32,
+    y: i32,
+    visible: bool,
+    /// Where the cursor was last drawn, clipped to the screen, and the framebuffer pixels it
+    /// covers, packed row after row.
+    saved_rect: Option<Rect>,
+    saved_pixels: [u8; SIZE],
+}
+
+static mut CURSOR: Cursor = Cursor {
+    x: 0,
+    y: 0,
+    visible: false,
+    saved_rect: None,
+    saved_pixels: [0; SIZE],
+};
+
+fn sprite_rect(x: i32, y: i32) -> Rect {
+    Rect::new(x, y, WIDTH, HEIGHT)
+}
+
+/// Moves the cursor's hotspot to `x`, `y`, clamped to the screen, and shows it. The sprite may
+/// hang off the right and bottom edges. Takes effect on the next `present`.
+pub fn set_position(x: i32, y: i32) {
+    let (width, height) = dimensions();
+    unsafe {
+        CURSOR.x = x.clamp(0, (width as i32 - 1).max(0));
+        CURSOR.y = y.clamp(0, (height as i32 - 1).max(0));
+        CURSOR.visible = true;
+    }
+}
+
+/// Moves the cursor by a mouse movement.
+pub fn move_by(dx: i32, dy: i32) {
+    let (x, y) = position();
+    set_position(x.saturating_add(dx), y.saturating_add(dy));
+}
+
+pub fn position() -> (i32, i32) {
+    unsafe { (CURSOR.x, CURSOR.y) }
+}
+
+#[allow(dead_code)]
+pub fn set_visible(visible: bool) {
+    unsafe {
+        CURSOR.visible = visible;
+    }
+}
+
+/// Byte offset of `x`, `y` and the byte length of `rect`'s rows in `framebuffer`.
+fn row_layout(framebuffer: &FrameBuffer, bpp: usize, rect: Rect) -> (usize, usize, usize) {
+    let row_stride = framebuffer.stride() * bpp;
+    let start = rect.y() as usize * row_stride + rect.x() as usize * bpp;
+    (start, row_stride, rect.width() as usize * bpp)
+}
+
+/// Puts back the pixels the cursor covered. When drawing goes straight to the framebuffer, parts
+/// of the screen drawn since then already hold new content and must not be overwritten, so the
+/// cursor is only erased if it doesn't touch them.
+pub(super) unsafe fn restore(
+    context: &GraphicsContext,
+    framebuffer: &mut FrameBuffer,
+    dirty: &[Rect],
+    back_buffered: bool,
+) {
+    let Some(rect) = CURSOR.saved_rect.take() else {
+        return;
+    };
+    if !back_buffered && dirty.iter().any(|dirty| dirty.overlaps(&rect)) {
+        return;
+    }
+    let (start, row_stride, row_bytes) = row_layout(framebuffer, context.bytes_per_pixel(), rect);
+    let data = framebuffer.data_mut();
+    for row in 0..rect.height() as usize {
+        let offset = start + row * row_stride;
+        data[offset..offset + row_bytes]
+            .copy_from_slice(&CURSOR.saved_pixels[row * row_bytes..(row + 1) * row_bytes]);
+    }
+}
+
+/// Saves the pixels under the cursor and draws it on top.
+pub(super) unsafe fn draw(context: &GraphicsContext, framebuffer: &mut FrameBuffer) {
+    if !CURSOR.visible {
+        return;
+    }
+    let sprite = sprite_rect(CURSOR.x, CURSOR.y);
+    let rect = sprite.intersect(&Rect::new(0, 0, framebuffer.width(), framebuffer.height()));
+    if rect.is_empty() {
+        return;
+    }
+    let (start, row_stride, row_bytes) = row_layout(framebuffer, context.bytes_per_pixel(), rect);
+    let data = framebuffer.data();
+    for row in 0..rect.height() as usize {
+        let offset = start + row * row_stride;
+        CURSOR.saved_pixels[row * row_bytes..(row + 1) * row_bytes]
+            .copy_from_slice(&data[offset..offset + row_bytes]);
+    }
+    CURSOR.saved_rect = Some(rect);
+    context.blit_blend(&SPRITE_RGBA, WIDTH as usize, framebuffer, sprite, 255);
+}
@@ -1,18 +1,45 @@
-use crate::fatal_error;
-use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+use crate::{
+    apic, debug, disk, fatal_error, keyboard, memory, mouse, scheduler, time,
+    userspace::{DOUBLE_FAULT_IST_INDEX, EXCEPTION_IST_INDEX},
+    watchdog,
+};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel_common::{FAULT_EXIT_CODE, PAGE_FAULT_EXIT_CODE};
 use pic8259::ChainedPics;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::instructions::port::Port;
+use x86_64::set_general_handler;
+use x86_64::structures::idt::{
+    Entry, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::VirtAddr;
 
+/// Interrupts taken in ring 0 stay on the current stack, and the CPU pushes their frame right
+/// below RSP, so the kernel must be built without the red zone (see `.cargo/config.toml`). Ring 3
+/// code is safe either way, since the CPU switches to the kernel stack first.
 static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
 const PIC_OFFSET: u8 = 32;
 static mut PICS: ChainedPics = unsafe { ChainedPics::new(PIC_OFFSET, PIC_OFFSET + 8) };
+const PRIMARY_PIC_COMMAND: u16 = 0x20;
+const SECONDARY_PIC_COMMAND: u16 = 0xa0;
+/// OCW3 that makes the next read of the command port return the in-service register.
+const PIC_READ_ISR: u8 = 0x0b;
+/// The secondary PIC is connected to this line of the primary one.
+const PIC_CASCADE_IRQ: u8 = 2;
+
+/// Unexpected interrupts are logged at most once in this long, so a storm of them can't flood the
+/// log.
+const UNEXPECTED_LOG_INTERVAL_MS: u64 = 1000;
+static LAST_UNEXPECTED_LOG: AtomicU64 = AtomicU64::new(u64::MAX);
+static UNEXPECTED_SUPPRESSED: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_OFFSET + 0,
     Keyboard = PIC_OFFSET + 1,
+    Mouse = PIC_OFFSET + 12,
     PrimaryAta = PIC_OFFSET + 14,
     SecondaryAta = PIC_OFFSET + 15,
 }
@@ -20,140 +47,281 @@ pub enum InterruptIndex {
 impl InterruptIndex {
     #[inline(always)]
     fn end_interrupt(self) {
-        unsafe {
-            PICS.notify_end_of_interrupt(self as u8);
+        if apic::enabled() {
+            apic::end_of_interrupt();
+        } else {
+            unsafe {
+                PICS.notify_end_of_interrupt(self as u8);
+            }
         }
     }
 }
 
-static mut KEYBOARD: Keyboard<layouts::Us104Key, ScancodeSet1> = Keyboard::new(
-    ScancodeSet1::new(),
-    layouts::Us104Key,
-    HandleControl::Ignore,
-);
-
 pub fn init_idt() {
     unsafe {
+        // Every vector that isn't an exception gets a handler, so a stray interrupt is logged
+        // instead of causing a general protection fault. The handlers below replace it.
+        set_general_handler!(&mut IDT, unexpected_interrupt_handler, PIC_OFFSET..=255);
+
         // Exceptions
         IDT.divide_error
             .set_handler_fn(divide_error_handler)
-            .set_stack_index(0);
-        IDT.breakpoint
-            .set_handler_fn(breakpoint_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
+        // These two return to the code they interrupted, so they run on its stack: one inside
+        // another exception handler would otherwise overwrite its frame on the exception stack.
+        IDT.debug.set_handler_fn(debug_handler);
+        IDT.breakpoint.set_handler_fn(breakpoint_handler);
         IDT.overflow
             .set_handler_fn(overflow_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.bound_range_exceeded
             .set_handler_fn(bound_range_exceeded_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.invalid_opcode
             .set_handler_fn(invalid_opcode_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.device_not_available
             .set_handler_fn(device_not_available_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.double_fault
             .set_handler_fn(double_fault_handler)
-            .set_stack_index(1);
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
         IDT.invalid_tss
             .set_handler_fn(invalid_tss_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.segment_not_present
             .set_handler_fn(segment_not_present_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.stack_segment_fault
             .set_handler_fn(stack_segment_fault_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.general_protection_fault
             .set_handler_fn(general_protection_fault_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.page_fault
             .set_handler_fn(page_fault_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.alignment_check
             .set_handler_fn(alignment_check_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
         IDT.simd_floating_point
             .set_handler_fn(simd_floating_point_handler)
-            .set_stack_index(0);
-
-        // Interrupts
-        IDT[InterruptIndex::Timer as usize]
-            .set_handler_fn(timer_interrupt_handler)
-            .set_stack_index(0);
-        IDT[InterruptIndex::Keyboard as usize]
-            .set_handler_fn(keyboard_interrupt_handler)
-            .set_stack_index(0);
-        IDT[InterruptIndex::PrimaryAta as usize]
-            .set_handler_fn(primary_ata_interrupt_handler)
-            .set_stack_index(0);
-        IDT[InterruptIndex::SecondaryAta as usize]
-            .set_handler_fn(secondary_ata_interrupt_handler)
-            .set_stack_index(0);
+            .set_stack_index(EXCEPTION_IST_INDEX);
+
+        // Interrupts run on the current stack, which for userspace is the process' kernel stack,
+        // so the scheduler can switch processes from inside them.
+        IDT[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
+        IDT[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
+        IDT[InterruptIndex::Mouse as usize].set_handler_fn(mouse_interrupt_handler);
+        IDT[InterruptIndex::PrimaryAta as usize].set_handler_fn(primary_ata_interrupt_handler);
+        IDT[InterruptIndex::SecondaryAta as usize].set_handler_fn(secondary_ata_interrupt_handler);
+        IDT[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
 
         IDT.load();
     }
 }
-pub fn init_interrupts() {
-    use x86_64::instructions::port::Port;
+/// Starts the timer and device interrupts, through the APIC if the ACPI tables at `rsdp_addr`
+/// describe one and the 8259 PIC otherwise.
+pub fn init_interrupts(rsdp_addr: Option<u64>) {
+    // Remapped even when it ends up masked, so stray PIC interrupts don't look like exceptions.
     unsafe {
         PICS.initialize();
     }
 
-    // Configure timer.
-    let timer_rate = 19853_u16; // 60.1 Hz
-    let mut timer_command_port = Port::new(0x43);
-    let mut timer_data_port = Port::new(0x40);
-    unsafe {
-        timer_command_port.write(0b00110100_u8); // channel 0, lobyte/hibyte, rate generator
-        timer_data_port.write((timer_rate & 0xFF) as u8); // divider lobyte
-        timer_data_port.write(((timer_rate >> 8) & 0xFF) as u8); // divider hibyte
+    if let Err(err) = mouse::init() {
+        log::warn!("No PS/2 mouse: {}", err);
+    }
+
+    let isa_irqs = [
+        (1, InterruptIndex::Keyboard as u8),
+        (12, InterruptIndex::Mouse as u8),
+        (14, InterruptIndex::PrimaryAta as u8),
+        (15, InterruptIndex::SecondaryAta as u8),
+    ];
+    match apic::init(rsdp_addr, &isa_irqs, InterruptIndex::Timer as u8) {
+        Ok(madt) => {
+            if madt.has_pic {
+                unsafe {
+                    PICS.disable();
+                }
+            }
+            log::info!(
+                "Using APIC at {:#x} with {} IO APIC(s)",
+                madt.local_apic_address,
+                madt.io_apics.len()
+            );
+        }
+        Err(err) => {
+            log::warn!("APIC unavailable ({}), using the PIC", err);
+            time::init_timer();
+            // The firmware may leave the mouse and the cascade to the secondary PIC masked.
+            unsafe {
+                let [primary, secondary] = PICS.read_masks();
+                PICS.write_masks(primary & !(1 << 2), secondary & !(1 << 4));
+            }
+        }
     }
 
     x86_64::instructions::interrupts::enable();
 
     // The keyboard won't send new interrupts if there is a scancode pending. Read and discard the
     // scancode here in case the user was mashing keys during setup.
-    unsafe {
-        Port::<u8>::new(0x60).read();
+    keyboard::flush();
+}
+
+/// Logs whether locals below RSP survive an interrupt, which they only do if nothing in the kernel
+/// uses the red zone. Needs interrupts enabled.
+pub fn check_red_zone() {
+    if red_zone_survives_interrupt() {
+        log::info!("Red zone self-test: PASS");
+    } else {
+        log::error!("Red zone self-test: FAIL");
+    }
+}
+
+/// With the red zone, this leaf function keeps `words` below RSP, where the interrupt that ends the
+/// `hlt` pushes its frame. Without it, the function moves RSP past them first.
+#[inline(never)]
+fn red_zone_survives_interrupt() -> bool {
+    let seed = core::hint::black_box(0x0123_4567_89ab_cdef_u64);
+    let mut words = [0u64; 16];
+    for (i, word) in words.iter_mut().enumerate() {
+        unsafe { (word as *mut u64).write_volatile(seed.rotate_left(i as u32)) };
     }
+    // `nostack` promises the asm doesn't push, so the function stays a leaf.
+    unsafe { core::arch::asm!("hlt", options(nostack)) };
+    words.iter().enumerate().all(|(i, word)| {
+        let word = unsafe { (word as *const u64).read_volatile() };
+        word == seed.rotate_left(i as u32)
+    })
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // unsafe {
-    //     crate::game::WAIT_FRAME = false;
-    // }
-    // TODO
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    time::tick();
+    watchdog::tick();
+    disk::wake_ata_waiters(0);
+    disk::wake_ata_waiters(1);
     InterruptIndex::Timer.end_interrupt();
+    // Only preempt userspace. The kernel can't switch processes at arbitrary points.
+    if stack_frame.code_segment & 3 == 3 {
+        // Getting back to userspace means the kernel isn't stuck.
+        watchdog::pet();
+        scheduler::yield_now();
+        scheduler::exit_if_terminated();
+    }
 }
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = unsafe { KEYBOARD.add_byte(scancode) } {
-        if let Some(key) = unsafe { KEYBOARD.process_keyevent(key_event) } {
-            match key {
-                // DecodedKey::Unicode(character) => log::trace!("Keyboard:{}", character),
-                // DecodedKey::RawKey(key) => log::trace!("Keyboard:{:?}", key),
-                _ => (),
-            }
-        }
-    }
+    keyboard::handle_interrupt();
     InterruptIndex::Keyboard.end_interrupt();
 }
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    mouse::handle_interrupt();
+    InterruptIndex::Mouse.end_interrupt();
+}
 extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    disk::handle_ata_interrupt(0);
     InterruptIndex::PrimaryAta.end_interrupt();
 }
 extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    if spurious_pic_irq(InterruptIndex::SecondaryAta as u8) {
+        return;
+    }
+    disk::handle_ata_interrupt(1);
     InterruptIndex::SecondaryAta.end_interrupt();
 }
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Logs an unexpected interrupt, unless one was logged less than `UNEXPECTED_LOG_INTERVAL_MS`
+/// ago. The ones skipped are counted in the next message.
+fn log_unexpected(what: &str, vector: u8) {
+    let now = time::uptime_ms();
+    let last = LAST_UNEXPECTED_LOG.load(Ordering::Relaxed);
+    if last != u64::MAX && now.saturating_sub(last) < UNEXPECTED_LOG_INTERVAL_MS {
+        UNEXPECTED_SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    LAST_UNEXPECTED_LOG.store(now, Ordering::Relaxed);
+    match UNEXPECTED_SUPPRESSED.swap(0, Ordering::Relaxed) {
+        0 => log::warn!("{} interrupt {}", what, vector),
+        suppressed => log::warn!(
+            "{} interrupt {} ({} more not logged)",
+            what,
+            vector,
+            suppressed
+        ),
+    }
+}
 
-extern "x86-interrupt" fn divide_error_handler(_stack_frame: InterruptStackFrame) {
-    fatal_error!("EXCEPTION: {}", "DIVIDE BY 0");
+/// Handles a spurious interrupt from the 8259 PIC, which raises IRQ 7 or 15 without setting it in
+/// service if an interrupt goes away before the CPU acknowledges it. Those must not get an EOI,
+/// or a real interrupt in service would be ended instead. Returns whether `vector` was one.
+fn spurious_pic_irq(vector: u8) -> bool {
+    if apic::enabled() {
+        return false;
+    }
+    let command = match vector.wrapping_sub(PIC_OFFSET) {
+        7 => PRIMARY_PIC_COMMAND,
+        15 => SECONDARY_PIC_COMMAND,
+        _ => return false,
+    };
+    let in_service = unsafe {
+        let mut port = Port::<u8>::new(command);
+        port.write(PIC_READ_ISR);
+        port.read()
+    };
+    if in_service & 0x80 != 0 {
+        return false;
+    }
+    if command == SECONDARY_PIC_COMMAND {
+        // The primary PIC doesn't know the secondary's interrupt was spurious, so its cascade line
+        // still needs an EOI.
+        unsafe { PICS.notify_end_of_interrupt(PIC_OFFSET + PIC_CASCADE_IRQ) };
+    }
+    log_unexpected("Spurious PIC", vector);
+    true
 }
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
-    fatal_error!("EXCEPTION: {}", "BREAKPOINT");
+
+/// Handles any vector nothing else does. The interrupt is logged and ended, so it doesn't block
+/// the ones after it.
+fn unexpected_interrupt_handler(
+    _stack_frame: InterruptStackFrame,
+    vector: u8,
+    _error_code: Option<u64>,
+) {
+    if spurious_pic_irq(vector) {
+        return;
+    }
+    log_unexpected("Unexpected", vector);
+    if apic::enabled() {
+        apic::end_of_interrupt();
+    } else if (PIC_OFFSET..PIC_OFFSET + 16).contains(&vector) {
+        unsafe { PICS.notify_end_of_interrupt(vector) };
+    }
+}
+
+/// Sends breakpoint and debug exceptions to the handler at `addr`, which must take the CPU's frame
+/// without an error code. It runs on the stack it interrupted, not the exception stack, so a
+/// breakpoint inside an exception handler doesn't overwrite its frame.
+pub unsafe fn set_debug_handler(addr: VirtAddr) {
+    IDT.breakpoint = Entry::missing();
+    IDT.breakpoint.set_handler_addr(addr);
+    IDT.debug = Entry::missing();
+    IDT.debug.set_handler_addr(addr);
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    fault("divide error", &stack_frame, format_args!(""));
+}
+extern "x86-interrupt" fn debug_handler(mut stack_frame: InterruptStackFrame) {
+    debug::handle_debug_exception(&mut stack_frame);
+}
+/// Logs and carries on, so `int3` can mark places in a trace.
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    // RIP is past the one byte INT3.
+    log::info!(
+        "Breakpoint at RIP={:#x}",
+        stack_frame.instruction_pointer - 1u64
+    );
 }
 extern "x86-interrupt" fn overflow_handler(_stack_frame: InterruptStackFrame) {
     fatal_error!("EXCEPTION: {}", "OVERFLOW");
@@ -161,17 +329,24 @@ extern "x86-interrupt" fn overflow_handler(_stack_frame: InterruptStackFrame) {
 extern "x86-interrupt" fn bound_range_exceeded_handler(_stack_frame: InterruptStackFrame) {
     fatal_error!("EXCEPTION: {}", "BOUND RANGE EXCEEDED");
 }
-extern "x86-interrupt" fn invalid_opcode_handler(_stack_frame: InterruptStackFrame) {
-    fatal_error!("EXCEPTION: {}", "INVALID OPCODE");
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    fault("invalid opcode", &stack_frame, format_args!(""));
 }
-extern "x86-interrupt" fn device_not_available_handler(_stack_frame: InterruptStackFrame) {
-    fatal_error!("EXCEPTION: {}", "DEVICE NOT AVAILABLE");
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    if !scheduler::handle_fpu_trap() {
+        fault("device not available", &stack_frame, format_args!(""));
+    }
 }
+/// Runs on its own stack, so it works even if the fault was caused by an overflowing stack. Goes
+/// through the panic handler, which draws straight to the framebuffer without touching the heap.
 extern "x86-interrupt" fn double_fault_handler(
-    _stack_frame: InterruptStackFrame,
+    stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    fatal_error!("EXCEPTION: {}", "DOUBLE FAULT");
+    panic!(
+        "double fault at RIP={:#x}, RSP={:#x}",
+        stack_frame.instruction_pointer, stack_frame.stack_pointer
+    );
 }
 extern "x86-interrupt" fn invalid_tss_handler(_stack_frame: InterruptStackFrame, error_code: u64) {
     fatal_error!("EXCEPTION: {}({})", "INVALID TSS", error_code);
@@ -189,22 +364,146 @@ extern "x86-interrupt" fn stack_segment_fault_handler(
     fatal_error!("EXCEPTION: {}({})", "STACK SEGMENT FAULT", error_code);
 }
 extern "x86-interrupt" fn general_protection_fault_handler(
-    _stack_frame: InterruptStackFrame,
+    stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
-    fatal_error!("EXCEPTION: {}({})", "GENERAL PROTECTION FAULT", error_code);
+    fault(
+        "general protection fault",
+        &stack_frame,
+        format_args!(", {}", SelectorErrorCode(error_code)),
+    );
 }
+
+/// Logs an exception with the interrupted context and ends the current process if it came from
+/// user mode. Exceptions in the kernel panic.
+fn fault(name: &str, stack_frame: &InterruptStackFrame, detail: fmt::Arguments) -> ! {
+    let user_mode = stack_frame.code_segment & 3 == 3;
+    if user_mode {
+        log::error!(
+            "{} at RIP={:#x} CS={:#x} RFLAGS={:#x} RSP={:#x} SS={:#x}{}, killing process",
+            name,
+            stack_frame.instruction_pointer,
+            stack_frame.code_segment,
+            stack_frame.cpu_flags,
+            stack_frame.stack_pointer,
+            stack_frame.stack_segment,
+            detail
+        );
+        scheduler::exit(FAULT_EXIT_CODE);
+    }
+    panic!(
+        "{} at RIP={:#x} CS={:#x} RFLAGS={:#x} RSP={:#x} SS={:#x}{}",
+        name,
+        stack_frame.instruction_pointer,
+        stack_frame.code_segment,
+        stack_frame.cpu_flags,
+        stack_frame.stack_pointer,
+        stack_frame.stack_segment,
+        detail
+    );
+}
+
+/// Describes a selector error code, as pushed by a general protection fault.
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        if code == 0 {
+            return f.write_str("not caused by a segment");
+        }
+        let table = match (code >> 1) & 0b11 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{} selector {}", table, (code >> 3) & 0x1fff)?;
+        if code & 1 != 0 {
+            f.write_str(" (external event)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Describes a page fault error code, e.g. "write to protected page from user mode".
+struct PageFaultDescription(PageFaultErrorCode);
+
+impl fmt::Display for PageFaultDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protected"
+        } else {
+            "non-present"
+        };
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(f, "{} {} page from {} mode", access, page, mode)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            f.write_str(", reserved bit set")?;
+        }
+        Ok(())
+    }
+}
+
 extern "x86-interrupt" fn page_fault_handler(
-    _stack_frame: InterruptStackFrame,
+    stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     let fault_address = x86_64::registers::control::Cr2::read();
-    fatal_error!(
-        "EXCEPTION: {}({:06b}) {:#x}",
-        "PAGE FAULT",
-        error_code,
-        fault_address
-    );
+    if memory::handle_user_page_fault(
+        fault_address,
+        error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+        error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+        error_code.contains(PageFaultErrorCode::USER_MODE),
+    ) {
+        return;
+    }
+    let description = PageFaultDescription(error_code);
+    let overflowed_stack = memory::stack_guard_hit(fault_address);
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        match overflowed_stack {
+            Some("user") => log::error!(
+                "User stack overflow in pid {} at RIP={:#x}: {}, killing process",
+                scheduler::current_id(),
+                stack_frame.instruction_pointer,
+                description
+            ),
+            Some(stack) => log::error!(
+                "Stack overflow ({} stack) at RIP={:#x}: {}, killing process",
+                stack,
+                stack_frame.instruction_pointer,
+                description
+            ),
+            None => log::error!(
+                "Page fault at {:#x}, RIP={:#x}: {}, killing process",
+                fault_address,
+                stack_frame.instruction_pointer,
+                description
+            ),
+        }
+        scheduler::exit(PAGE_FAULT_EXIT_CODE);
+    }
+    match overflowed_stack {
+        Some(stack) => panic!(
+            "stack overflow ({} stack) at RIP={:#x}: {}",
+            stack, stack_frame.instruction_pointer, description
+        ),
+        None => panic!(
+            "page fault at {:#x}, RIP={:#x}: {}",
+            fault_address, stack_frame.instruction_pointer, description
+        ),
+    }
 }
 extern "x86-interrupt" fn alignment_check_handler(
     _stack_frame: InterruptStackFrame,
@@ -212,6 +511,10 @@ extern "x86-interrupt" fn alignment_check_handler(
 ) {
     fatal_error!("EXCEPTION: {}", "ALIGNMENT CHECK");
 }
-extern "x86-interrupt" fn simd_floating_point_handler(_stack_frame: InterruptStackFrame) {
-    fatal_error!("EXCEPTION: {}", "SIMD FLOATING POINT");
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    fault(
+        "SIMD floating point exception",
+        &stack_frame,
+        format_args!(""),
+    );
 }
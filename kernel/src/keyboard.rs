@@ -0,0 +1,67 @@
+use crate::{event_queue::EventQueue, scheduler, sync::IrqSafeSpinLock};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyState, Keyboard, ScancodeSet1};
+use x86_64::instructions::port::Port;
+
+pub use pc_keyboard::KeyCode;
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+    /// The character typed by this key press, taking Shift and Caps Lock into account.
+    pub character: Option<char>,
+}
+
+const QUEUE_SIZE: usize = 64;
+
+// The decoder tracks the 0xE0 prefix and modifier state between scancodes.
+static mut KEYBOARD: Keyboard<layouts::Us104Key, ScancodeSet1> = Keyboard::new(
+    ScancodeSet1::new(),
+    layouts::Us104Key,
+    HandleControl::Ignore,
+);
+// Tracked here because the decoder doesn't tell, and Ctrl+C is handled before decoding.
+static mut CTRL_DOWN: bool = false;
+static QUEUE: IrqSafeSpinLock<EventQueue<KeyEvent, QUEUE_SIZE>> =
+    IrqSafeSpinLock::new(EventQueue::new());
+
+/// Reads a scancode from the controller and queues the resulting key event, if any. Ctrl+C
+/// terminates the program the shell is running instead, if there is one. Called from the IRQ1
+/// handler.
+pub fn handle_interrupt() {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    unsafe {
+        if let Ok(Some(event)) = KEYBOARD.add_byte(scancode) {
+            let key = event.code;
+            let pressed = event.state != KeyState::Up;
+            if matches!(key, KeyCode::LControl | KeyCode::RControl) {
+                CTRL_DOWN = pressed;
+            }
+            if pressed && CTRL_DOWN && key == KeyCode::C && scheduler::terminate_foreground() {
+                return;
+            }
+            let character = match KEYBOARD.process_keyevent(event) {
+                Some(DecodedKey::Unicode(character)) => Some(character),
+                _ => None,
+            };
+            QUEUE.lock().push(KeyEvent {
+                key,
+                pressed,
+                character,
+            });
+        }
+    }
+}
+
+/// Takes the oldest key event from the queue.
+pub fn poll_event() -> Option<KeyEvent> {
+    QUEUE.lock().pop()
+}
+
+/// Discards a scancode left in the controller.
+pub fn flush() {
+    unsafe {
+        Port::<u8>::new(0x60).read();
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{backtrace, cmdline, console, graphics::Color, serial, time};
+use core::fmt::Write;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// A string formatted into a fixed-size buffer, for formatting without the heap. Text that doesn't
+/// fit is cut off.
+pub struct StackString<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> StackString<N> {
+    pub const fn new() -> Self {
+        StackString {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        // Writes only ever append whole characters, so this can't fail.
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Write for StackString<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for ch in s.chars() {
+            let len = ch.len_utf8();
+            if self.len + len > N {
+                return Err(core::fmt::Error);
+            }
+            ch.encode_utf8(&mut self.buf[self.len..]);
+            self.len += len;
+        }
+        Ok(())
+    }
+}
+
+/// Where log records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogBackend {
+    Screen,
+    Serial,
+    Both,
+}
+
+impl LogBackend {
+    fn from_str(name: &str) -> Option<Self> {
+        match name {
+            "screen" => Some(LogBackend::Screen),
+            "serial" => Some(LogBackend::Serial),
+            "both" => Some(LogBackend::Both),
+            _ => None,
+        }
+    }
+    fn screen(self) -> bool {
+        self != LogBackend::Serial
+    }
+    fn serial(self) -> bool {
+        self != LogBackend::Screen
+    }
+}
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+static mut BACKEND: LogBackend = LogBackend::Screen;
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+fn level_color(level: Level) -> Color {
+    match level {
+        Level::Error => Color::new(255, 64, 64),
+        Level::Warn => Color::new(255, 200, 64),
+        Level::Info => Color::WHITE,
+        Level::Debug | Level::Trace => Color::new(160, 160, 160),
+    }
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let backend = unsafe { BACKEND };
+        let uptime = time::uptime_ms();
+        let (seconds, millis) = (uptime / 1000, uptime % 1000);
+        if backend.serial() {
+            serial::write_fmt(format_args!(
+                "[{:>5}.{:03}] [{:5}] {}\n",
+                seconds,
+                millis,
+                record.level(),
+                record.args()
+            ));
+        }
+        if backend.screen() {
+            let mut line = StackString::<256>::new();
+            let _ = write!(
+                line,
+                "[{:>5}.{:03}] [{:5}] {}",
+                seconds,
+                millis,
+                record.level(),
+                record.args()
+            );
+            console::push_colored_line(line.as_str(), level_color(record.level()));
+        }
+    }
+    fn flush(&self) {}
+}
+
+/// Starts logging to `backend` at the default level. The `log=screen|serial|both` and
+/// `loglevel=...` command line options override them.
+pub fn init(backend: LogBackend) {
+    let log_option = cmdline::get("log");
+    let level_option = cmdline::get("loglevel");
+    let backend = log_option.and_then(LogBackend::from_str).unwrap_or(backend);
+    let level = level_option.and_then(|level| level.parse().ok());
+    if backend.serial() {
+        serial::init_serial();
+    }
+    unsafe {
+        BACKEND = backend;
+    }
+    log::set_logger(&LOGGER).expect("logger already initialized");
+    set_level(level.unwrap_or(DEFAULT_LEVEL));
+    if let Some(option) = log_option.filter(|option| LogBackend::from_str(option).is_none()) {
+        log::warn!(
+            "Unknown log backend {}, expected screen, serial or both",
+            option
+        );
+    }
+    if let Some(option) = level_option.filter(|_| level.is_none()) {
+        log::warn!("Unknown log level {}", option);
+    }
+}
+
+/// Changes which records are logged from now on. Can be called at any time, also before `init`.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Writes the panic message and a backtrace to serial, if enabled. The screen gets its own panic
+/// screen.
+pub fn log_panic(info: &core::panic::PanicInfo) {
+    if unsafe { BACKEND }.serial() {
+        serial::write_fmt(format_args!("[PANIC] {}\n", info));
+    }
+    log_backtrace();
+}
+
+/// Writes a backtrace of the caller to serial, if enabled.
+pub fn log_backtrace() {
+    if unsafe { BACKEND }.serial() {
+        let _ = backtrace::print(&mut serial::SerialWriter);
+    }
+}
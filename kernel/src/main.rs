@@ -1,135 +1,389 @@
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 #![feature(asm_const)]
+#![feature(panic_info_message)]
 #![feature(step_trait)]
 #![no_std]
 #![no_main]
 extern crate alloc;
 
+mod acpi;
+mod ahci;
+mod apic;
+mod backtrace;
+mod block_cache;
+mod cmdline;
+mod console;
+mod cpu;
+mod debug;
+mod disk;
 mod elf_loader;
+mod event_queue;
+mod fd;
+mod filesystem;
+mod fpu;
+mod gdb;
 mod graphics;
 mod interrupt;
+mod keyboard;
+mod logger;
 mod memory;
+mod mmio;
+mod mouse;
+#[cfg(feature = "multiboot2")]
+mod multiboot;
+mod pci;
+mod pipe;
+mod profile;
+mod program;
+mod ramdisk;
+mod rand;
+mod rtc;
+mod scheduler;
+mod screen;
+mod screenshot;
+mod serial;
+mod shell;
+mod shm;
+mod speaker;
+mod splash;
+mod sync;
+mod time;
 mod userspace;
+mod watchdog;
+mod xmodem;
 
-use alloc::{format, string::String};
-use bootloader_api::{config::Mapping, entry_point, BootInfo, BootloaderConfig};
+use alloc::{boxed::Box, format, string::String};
+use bootloader_api::{
+    config::{ApiVersion, Mapping},
+    entry_point,
+    info::{FrameBuffer, MemoryRegions},
+    BootInfo, BootloaderConfig,
+};
+use memory::VirtMemRange;
 
 static OS_NAME: &str = "Mythos";
 static OS_VERSION: &str = env!("CARGO_PKG_VERSION");
 static mut BOOTLOADER_VERSION: Option<String> = None;
 
+/// The screen size the disk image asks the bootloader for, in build.rs. It picks at least this much
+/// if the firmware has such a mode. 1024x768 at 32 bits per pixel is what's tested, in QEMU.
+const SCREEN_WIDTH: u32 = 1024;
+const SCREEN_HEIGHT: u32 = 768;
+
 static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::FixedAddress(0xf000_0000_0000));
+    config.mappings.dynamic_range_start = Some(memory::DYNAMIC_RANGE_START);
     config
 };
 
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
+/// Why the kernel couldn't finish starting up.
+#[derive(Debug)]
+enum KernelInitError {
+    NoFramebuffer,
+    PhysicalMemoryNotMapped,
+    NoRamdisk,
+    FramebufferNotUserAccessible,
+    // The rest only mean there are no programs on disk, so they don't stop the kernel.
+    NoDrive,
+    NoUserPartition,
+    NoFilesystem(filesystem::FsError),
+}
+
+impl core::fmt::Display for KernelInitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            KernelInitError::NoFramebuffer => {
+                write!(f, "The bootloader found no framebuffer to draw to.")
+            }
+            KernelInitError::PhysicalMemoryNotMapped => {
+                write!(f, "The bootloader didn't map physical memory.")
+            }
+            KernelInitError::NoRamdisk => write!(
+                f,
+                "The bootloader didn't load a ramdisk. It should hold userspace.elf."
+            ),
+            KernelInitError::FramebufferNotUserAccessible => {
+                write!(f, "Couldn't make the framebuffer accessible to userspace.")
+            }
+            KernelInitError::NoDrive => {
+                write!(f, "No ATA or SATA drive found. Is a disk attached?")
+            }
+            KernelInitError::NoUserPartition => write!(
+                f,
+                "No user partition found. It should be a FAT32 partition in the MBR, \
+                 preferably marked bootable, or named \"{}\" in a GPT.",
+                disk::USER_PARTITION_NAME
+            ),
+            KernelInitError::NoFilesystem(err) => {
+                write!(f, "No filesystem on the user partition: {}.", err)
+            }
+        }
+    }
+}
+
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    // Save the framebuffer info from the bootloader.
-    let framebuffer_memory =
-        graphics::init_graphics(boot_info.framebuffer.as_mut().expect("no framebuffer"));
-
-    // Configure core hardware.
-    userspace::init_gdt();
-    interrupt::init_idt();
-    memory::init_memory(
-        boot_info
-            .physical_memory_offset
-            .into_option()
-            .expect("physical memory not mapped"),
+    cmdline::init();
+    start(boot_info, None);
+}
+
+/// Starts the kernel with what the loader passed, once the command line is read. `kernel_main`
+/// and the Multiboot2 entry point both end up here. `bootloader` names the loader, if it isn't
+/// the bootloader crate.
+fn start(boot_info: &'static mut BootInfo, bootloader: Option<&'static str>) -> ! {
+    // Start logging first so serial captures everything. Screen output shows up once graphics is
+    // initialized. The command line can change where logs go.
+    logger::init(logger::LogBackend::Both);
+    log::info!("{} v{}", OS_NAME, OS_VERSION);
+    cmdline::warn_unknown_keys();
+    if let Err(err) = init(boot_info, bootloader) {
+        splash::hide();
+        log::error!("{}", err);
+        graphics::init_error_screen(&err);
+        hlt_loop();
+    }
+    splash::hide();
+    launch_init();
+}
+
+/// How many times `init` calls `splash::progress`.
+const INIT_STEPS: u32 = 5;
+
+/// Runs the init stages in order, and fails if one the kernel can't run without does. Storage and
+/// the filesystem only mean there are no programs on disk, so their errors are just logged.
+fn init(
+    boot_info: &'static mut BootInfo,
+    bootloader: Option<&'static str>,
+) -> Result<(), KernelInitError> {
+    let framebuffer_memory = init_graphics(boot_info.framebuffer.as_mut())?;
+    init_memory_stage(
+        boot_info.physical_memory_offset.into_option(),
         &boot_info.memory_regions,
+        framebuffer_memory,
+    )?;
+    // The heap is up now, so the splash can draw to the back buffer.
+    splash::show(INIT_STEPS);
+    init_interrupts_stage(boot_info.rsdp_addr.into_option());
+    splash::progress(2);
+    save_bootloader_version(boot_info.api_version, bootloader);
+    splash::progress(3);
+    add_ramdisk_program(boot_info.ramdisk_addr.into_option(), boot_info.ramdisk_len)?;
+    splash::progress(4);
+    // Disk timeouts and copying a partition to a RAM disk can add up to more than a watchdog
+    // timeout.
+    watchdog::suspend();
+    let result = init_storage().and_then(init_filesystem);
+    watchdog::resume();
+    if let Err(err) = result {
+        log::warn!("{}", err);
+    }
+    console::load_font();
+    splash::load_logo();
+    splash::progress(5);
+    profile::log_summary();
+    Ok(())
+}
+
+/// Draws to the framebuffer from the bootloader, and returns its memory.
+fn init_graphics(
+    framebuffer: Option<&'static mut FrameBuffer>,
+) -> Result<VirtMemRange, KernelInitError> {
+    let framebuffer = framebuffer.ok_or(KernelInitError::NoFramebuffer)?;
+    let framebuffer_memory = graphics::init_graphics(framebuffer);
+    // A larger mode is cut down to the requested size, so the screen looks the same everywhere.
+    // A smaller one is all the firmware had, so it's used as it is.
+    let physical = graphics::mode();
+    if physical.width < SCREEN_WIDTH || physical.height < SCREEN_HEIGHT {
+        log::warn!(
+            "Asked for a {}x{} framebuffer, got {}x{}",
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT,
+            physical.width,
+            physical.height
+        );
+    } else if physical.width > SCREEN_WIDTH || physical.height > SCREEN_HEIGHT {
+        if let Err(err) = graphics::set_mode(SCREEN_WIDTH, SCREEN_HEIGHT, physical.bytes_per_pixel)
+        {
+            log::warn!(
+                "Couldn't set {}x{} mode: {}",
+                SCREEN_WIDTH,
+                SCREEN_HEIGHT,
+                err
+            );
+        }
+    }
+    let mode = graphics::mode();
+    log::info!(
+        "Framebuffer {}x{} stride:{} bpp:{}",
+        mode.width,
+        mode.height,
+        mode.stride,
+        mode.bytes_per_pixel * 8
     );
-    interrupt::init_interrupts();
-
-    // Save bootloader version
-    let api_version = boot_info.api_version;
-    let bootloader_version = format!(
-        "{}.{}.{}",
-        api_version.version_major(),
-        api_version.version_minor(),
-        api_version.version_patch()
-    );
+    Ok(framebuffer_memory)
+}
+
+/// Sets up the CPU, the GDT and IDT, and memory, up to the heap. Userspace can access the
+/// framebuffer afterwards.
+fn init_memory_stage(
+    physical_memory_offset: Option<u64>,
+    memory_regions: &'static MemoryRegions,
+    framebuffer_memory: VirtMemRange,
+) -> Result<(), KernelInitError> {
+    cpu::init();
+    time::calibrate_tsc();
+    fpu::init();
+    profile::measure("GDT", userspace::init_gdt);
+    profile::measure("IDT", interrupt::init_idt);
+    let physical_memory_offset =
+        physical_memory_offset.ok_or(KernelInitError::PhysicalMemoryNotMapped)?;
+    profile::measure("memory", || {
+        memory::init_memory(physical_memory_offset, memory_regions);
+        // `physmap=full` keeps all of physical memory mapped, for debugging.
+        if cmdline::get("physmap") != Some("full") {
+            memory::trim_physical_mapping();
+        }
+    });
+    // `gdb=on` stops here until GDB attaches over COM1, once memory can be inspected.
+    if cmdline::get("gdb") == Some("on") {
+        gdb::init();
+    }
+    // The framebuffer memory must never be reused.
+    memory::make_range_user_accessible(framebuffer_memory)
+        .map_err(|_| KernelInitError::FramebufferNotUserAccessible)?;
+    memory::pin_range(framebuffer_memory);
+    Ok(())
+}
+
+/// Starts the scheduler, ACPI, the interrupt controllers and the timer, and the watchdog.
+fn init_interrupts_stage(rsdp_addr: Option<u64>) {
+    scheduler::init();
+    acpi::init(rsdp_addr);
+    log::info!("Time {}", rtc::now());
+    rand::init();
+    splash::progress(1);
+    profile::measure("interrupts", || interrupt::init_interrupts(rsdp_addr));
+    // Needs the timer running.
+    watchdog::init();
+    if cmdline::get("selftest") == Some("on") {
+        interrupt::check_red_zone();
+    }
+}
+
+fn save_bootloader_version(api_version: ApiVersion, bootloader: Option<&'static str>) {
+    let bootloader_version = match bootloader {
+        Some(name) => String::from(name),
+        None => format!(
+            "{}.{}.{}",
+            api_version.version_major(),
+            api_version.version_minor(),
+            api_version.version_patch()
+        ),
+    };
     unsafe {
         BOOTLOADER_VERSION = Some(bootloader_version);
     }
+}
 
-    // Allow userspace to directly access the framebuffer memory.
-    memory::user_memory_mapper()
-        .make_range_user_accessible(framebuffer_memory)
-        .unwrap();
-
-    // Start the userspace program, which loads drivers and other programs from the filesystem.
-    let ramdisk = unsafe {
-        core::slice::from_raw_parts(
-            boot_info
-                .ramdisk_addr
-                .into_option()
-                .expect("bootloader did not load ramdisk") as *const u8,
-            boot_info.ramdisk_len as usize,
-        )
-    };
-    elf_loader::start_load().unwrap();
-    elf_loader::load_bytes(ramdisk).unwrap();
-    let (entry_point, _tls_template) = elf_loader::finish_load().unwrap();
-    userspace::enter_userspace(entry_point);
-
-    // log::info!("Initializing ATA");
-    // let drive_info = get_first_ata_drive().unwrap();
-    // log::debug!(
-    //     "Found drive {} size:{}KiB",
-    //     drive_info.model,
-    //     drive_info.size_in_kib()
-    // );
-    // let user_partition = get_user_partition(drive_info.drive).unwrap();
-    // log::debug!("  user partition size:{}KiB", user_partition.size_in_kib());
-    // filesystem::init_fs(user_partition);
-    // let entry_point = program::load_program("raytrace.elf").unwrap();
-    // userspace::enter_userspace(entry_point);
-}
-
-// fn get_first_ata_drive() -> ata::DriveInfo {
-//     ata::list()
-//         .unwrap()
-//         .into_iter()
-//         .next()
-//         .expect("no connected drives")
-// }
-
-// fn get_user_partition(drive: ata::Drive) -> ata::Partition {
-//     let mut mbr_bytes = alloc::vec![0u8; 512];
-//     drive.read(&mut mbr_bytes, 0, 1).unwrap();
-//     let mbr = mbr::MasterBootRecord::from_bytes(&mbr_bytes).unwrap();
-//     if mbr.entries[2].partition_type != mbr::PartitionType::Fat32(0x0c) || !mbr.entries[2].bootable {
-//         panic!("invalid filesystem partition");
-//     }
-//     ata::Partition::new(
-//         drive,
-//         mbr.entries[2].logical_block_address as usize,
-//         mbr.entries[2].sector_count as usize,
-//     )
-// }
+/// The ramdisk holds the userspace program, which loads drivers and other programs from the
+/// filesystem.
+fn add_ramdisk_program(ramdisk_addr: Option<u64>, len: u64) -> Result<(), KernelInitError> {
+    let ramdisk_addr = ramdisk_addr.ok_or(KernelInitError::NoRamdisk)?;
+    let ramdisk = unsafe { core::slice::from_raw_parts(ramdisk_addr as *const u8, len as usize) };
+    program::add_program("userspace.elf", ramdisk);
+    Ok(())
+}
+
+/// Finds the drives and the user partition on them, and returns the device the filesystem should
+/// run on.
+fn init_storage() -> Result<Box<dyn block_cache::Device>, KernelInitError> {
+    profile::measure("PCI", pci::init);
+    log::info!("Initializing disks");
+    profile::measure("disks", disk::init);
+    if !disk::any_drive() {
+        return Err(KernelInitError::NoDrive);
+    }
+    let user_partition = disk::find_user_partition().ok_or(KernelInitError::NoUserPartition)?;
+    log::debug!("  user partition size:{}KiB", user_partition.size / 1024);
+    // `disk=ram` runs the filesystem on a copy of the partition in memory, so nothing is written
+    // to the disk, and `disk=ram-ro` on a copy that can't be written at all.
+    match cmdline::get("disk") {
+        Some(mode @ ("ram" | "ram-ro")) => {
+            let disk = profile::measure("RAM disk", || {
+                ramdisk::RamDisk::copy_of(
+                    &*user_partition.device,
+                    user_partition.size,
+                    mode == "ram-ro",
+                )
+            })
+            .map_err(|err| KernelInitError::NoFilesystem(err.into()))?;
+            log::info!("Using a {}KiB RAM disk", disk.size_in_kib());
+            Ok(Box::new(disk))
+        }
+        _ => Ok(user_partition.device),
+    }
+}
+
+/// Mounts the filesystem on `device`, and loads the kernel symbols from it.
+fn init_filesystem(device: Box<dyn block_cache::Device>) -> Result<(), KernelInitError> {
+    profile::measure("filesystem", || filesystem::init_fs(device))
+        .map_err(KernelInitError::NoFilesystem)?;
+    profile::measure("symbols", backtrace::load_symbols);
+    Ok(())
+}
+
+/// Runs what the command line asks for, then the shell.
+fn launch_init() -> ! {
+    // `xmodem=path` receives a file over serial, e.g. a program to try without rebuilding the disk.
+    if let Some(path) = cmdline::get("xmodem").filter(|path| !path.is_empty()) {
+        shell::receive_file(path);
+    }
+    // `init=program` runs a program before the shell starts.
+    if let Some(program) = cmdline::get("init").filter(|program| !program.is_empty()) {
+        shell::run_program(program);
+    }
+    shell::run();
+}
 
 #[macro_export]
 macro_rules! fatal_error {
     ($($arg:tt)*) => {{
-        use core::fmt::Write;
-        if let Some(mut framebuffer) = unsafe { $crate::graphics::framebuffer() } {
-            let context = $crate::graphics::context();
-            let mut error_writer = $crate::graphics::TextWriter::new(&context, &mut framebuffer, 0, 0);
-            error_writer.write_fmt(format_args!($($arg)*)).ok();
-        }
-        loop {
-            x86_64::instructions::hlt();
-        }
+        $crate::graphics::fatal_error_screen(format_args!($($arg)*));
+        $crate::logger::log_backtrace();
+        $crate::hlt_loop();
     }}
 }
 
+pub fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Halts until the next interrupt, then lets the scheduler run anything it made ready, forever. The
+/// scheduler's idle task runs this when no process is ready, and frees orphaned processes here.
+pub fn idle_loop() -> ! {
+    loop {
+        sync::assert_no_locks_held("halting");
+        watchdog::pet();
+        scheduler::reap_orphans();
+        // Enabled right before halting, so an interrupt can't come in between and be slept
+        // through.
+        x86_64::instructions::interrupts::enable_and_hlt();
+        scheduler::yield_now();
+    }
+}
+
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    fatal_error!("{}", info);
+    x86_64::instructions::interrupts::disable();
+    logger::log_panic(info);
+    graphics::panic_screen(info);
+    speaker::panic_beep();
+    hlt_loop();
 }
 
 #[alloc_error_handler]
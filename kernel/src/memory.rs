@@ -1,8 +1,16 @@
+use crate::{cmdline, cpu, elf_loader::LazyImage, filesystem, kassert, kassert_eq, rand, shm};
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
-use linked_list_allocator::LockedHeap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+};
+use linked_list_allocator::{Heap, LockedHeap};
 use x86_64::{
+    align_up,
     structures::paging::{
-        mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError},
+        mapper::{CleanUp, FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError},
+        page_table::PageTableEntry,
         *,
     },
     PhysAddr, VirtAddr,
@@ -10,8 +18,76 @@ use x86_64::{
 
 pub const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
 
+/// `NO_EXECUTE`, or nothing on CPUs without NX, where setting the bit would fault.
+pub fn no_execute() -> PageTableFlags {
+    if cpu::has_nx() {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelHeap = KernelHeap(LockedHeap::empty());
+
+/// The smallest amount the kernel heap grows by, so a run of small allocations doesn't map pages
+/// one at a time.
+const MIN_HEAP_GROWTH: usize = 64;
+
+/// The kernel heap. When an allocation doesn't fit, more pages are mapped at its top before giving
+/// up.
+struct KernelHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.0.lock().allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        // Enough for the allocation even if the new space ends up badly aligned.
+        let pages =
+            ((layout.size() + layout.align() + PAGE_SIZE - 1) / PAGE_SIZE).max(MIN_HEAP_GROWTH);
+        if let Err(err) = grow_heap(pages) {
+            log::error!("Failed to grow kernel heap: {}", err);
+            return core::ptr::null_mut();
+        }
+        let mut heap = self.0.lock();
+        match heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(()) => {
+                let largest = largest_free_block(&mut heap);
+                drop(heap);
+                log::error!(
+                    "Kernel heap is fragmented: {:?} doesn't fit, largest free block is {} bytes",
+                    layout,
+                    largest
+                );
+                core::ptr::null_mut()
+            }
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0
+            .lock()
+            .deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+/// Finds the largest allocation that would currently succeed, by trying them.
+fn largest_free_block(heap: &mut Heap) -> usize {
+    let (mut low, mut high) = (0, heap.free());
+    while low < high {
+        let size = (low + high + 1) / 2;
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        match heap.allocate_first_fit(layout) {
+            Ok(ptr) => {
+                unsafe { heap.deallocate(ptr, layout) };
+                low = size;
+            }
+            Err(()) => high = size - 1,
+        }
+    }
+    low
+}
 
 #[derive(Debug, Copy, Clone)]
 pub struct VirtMemRange(u64, u64);
@@ -27,6 +103,16 @@ impl VirtMemRange {
         // Stacks grow upward and must be 16-byte aligned.
         VirtAddr::new(self.0 + self.1 - 16)
     }
+    pub const fn end(&self) -> u64 {
+        self.0 + self.1
+    }
+    /// The unmapped page directly below a stack allocated with a guard page.
+    pub const fn guard_page(&self) -> VirtMemRange {
+        VirtMemRange(self.0 - GUARD_SIZE, GUARD_SIZE)
+    }
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        (self.0..self.end()).contains(&addr.as_u64())
+    }
     pub fn last_addr(&self) -> VirtAddr {
         VirtAddr::new(self.0 + self.1 - 1)
     }
@@ -38,15 +124,52 @@ impl VirtMemRange {
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 struct BootInfoFrameAllocator {
     memory_regions: &'static MemoryRegions,
+    phys_offset: VirtAddr,
     next: usize,
+    total: usize,
+    // Freed frames, each holding the address of the next one. Handed out before new ones.
+    free_list: Option<PhysFrame>,
+    free_count: usize,
+    // Frames outside the usable regions that were given to the allocator with `adopt_frame`.
+    adopted: usize,
 }
 
 impl BootInfoFrameAllocator {
-    fn new(memory_regions: &'static MemoryRegions) -> BootInfoFrameAllocator {
-        BootInfoFrameAllocator {
+    fn new(
+        memory_regions: &'static MemoryRegions,
+        phys_offset: VirtAddr,
+    ) -> BootInfoFrameAllocator {
+        let mut allocator = BootInfoFrameAllocator {
             memory_regions,
+            phys_offset,
             next: 0,
-        }
+            total: 0,
+            free_list: None,
+            free_count: 0,
+            adopted: 0,
+        };
+        allocator.total = allocator.usable_frames().count();
+        allocator
+    }
+    fn used(&self) -> usize {
+        self.next.min(self.total) + self.adopted - self.free_count
+    }
+    fn next_free_ptr(&self, frame: PhysFrame) -> *mut u64 {
+        (self.phys_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self
+            .free_list
+            .map_or(0, |next| next.start_address().as_u64());
+        unsafe { self.next_free_ptr(frame).write(next) };
+        self.free_list = Some(frame);
+        self.free_count += 1;
+    }
+    /// Takes over a frame this allocator didn't hand out, e.g. a page table the bootloader
+    /// allocated, and frees it.
+    fn adopt_frame(&mut self, frame: PhysFrame) {
+        self.adopted += 1;
+        self.deallocate_frame(frame);
     }
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
@@ -63,9 +186,31 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let frame = if let Some(frame) = self.free_list {
+            // Frame 0 is never usable, so it marks the end of the list.
+            let next = unsafe { self.next_free_ptr(frame).read() };
+            self.free_list =
+                (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            self.free_count -= 1;
+            frame
+        } else {
+            let frame = self.usable_frames().nth(self.next)?;
+            self.next += 1;
+            frame
+        };
+        if let Some(count) = frame_ref(frame) {
+            *count = 1;
+        }
+        Some(frame)
+    }
+}
+
+/// Gives the page tables `CleanUp` frees to the frame allocator, which didn't allocate them.
+struct Adopter<'a>(&'a mut BootInfoFrameAllocator);
+
+impl FrameDeallocator<Size4KiB> for Adopter<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.0.adopt_frame(frame);
     }
 }
 
@@ -78,52 +223,128 @@ unsafe fn active_level_4_table(phys_offset: VirtAddr) -> &'static mut PageTable
     &mut *page_table_ptr // unsafe
 }
 
-// TODO secure against stack overflows
-// TODO allow heaps to map more memory as needed
+/// Marks user pages that are shared after a fork and copied on the first write.
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_10;
+/// Marks pages of a shared memory region, which a forked process keeps sharing instead of copying.
+const SHARED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Left unmapped below every stack, so an overflow faults instead of running into the memory
+/// below it.
+const GUARD_SIZE: u64 = PAGE_SIZE as u64;
+
+// TODO allow user heaps to map more memory as needed
 pub struct KernelMemory {
     pub privilege_stack: VirtMemRange,
     pub interrupt_stack: VirtMemRange,
     pub double_fault_stack: VirtMemRange,
+    pub syscall_stack: VirtMemRange,
     heap: VirtMemRange,
 }
 
 impl KernelMemory {
     const STACK_SIZE: usize = PAGE_SIZE;
-    const HEAP_SIZE: usize = PAGE_SIZE * 8;
+    // Enough to format and draw a panic.
+    const DOUBLE_FAULT_STACK_SIZE: usize = PAGE_SIZE * 4;
+    const SYSCALL_STACK_SIZE: usize = PAGE_SIZE * 4;
+    // Large enough for a back buffer at common framebuffer resolutions.
+    const HEAP_SIZE: usize = PAGE_SIZE * 2048;
+    // Virtual space kept free above the heap for `grow_heap`.
+    const HEAP_MAX_SIZE: usize = PAGE_SIZE * 65536;
     const fn new(base_addr: u64) -> Self {
-        let offset = Self::STACK_SIZE as u64;
+        let privilege_stack = VirtMemRange::new(base_addr + GUARD_SIZE, Self::STACK_SIZE);
+        let interrupt_stack =
+            VirtMemRange::new(privilege_stack.end() + GUARD_SIZE, Self::STACK_SIZE);
+        let double_fault_stack = VirtMemRange::new(
+            interrupt_stack.end() + GUARD_SIZE,
+            Self::DOUBLE_FAULT_STACK_SIZE,
+        );
+        let syscall_stack = VirtMemRange::new(
+            double_fault_stack.end() + GUARD_SIZE,
+            Self::SYSCALL_STACK_SIZE,
+        );
         KernelMemory {
-            privilege_stack: VirtMemRange::new(base_addr, Self::STACK_SIZE),
-            interrupt_stack: VirtMemRange::new(base_addr + offset, Self::STACK_SIZE),
-            double_fault_stack: VirtMemRange::new(base_addr + (offset * 2), Self::STACK_SIZE),
-            heap: VirtMemRange::new(base_addr + (offset * 3), Self::HEAP_SIZE),
+            privilege_stack,
+            interrupt_stack,
+            double_fault_stack,
+            syscall_stack,
+            heap: VirtMemRange::new(syscall_stack.end(), Self::HEAP_SIZE),
         }
     }
-    const fn len() -> usize {
-        (Self::STACK_SIZE * 3) + Self::HEAP_SIZE
+
+    fn stacks(&self) -> [(&'static str, VirtMemRange); 4] {
+        [
+            ("privilege", self.privilege_stack),
+            ("interrupt", self.interrupt_stack),
+            ("double fault", self.double_fault_stack),
+            ("syscall", self.syscall_stack),
+        ]
     }
 }
 
 pub struct UserMemory {
     pub stack: VirtMemRange,
-    heap: VirtMemRange,
+    pub heap: VirtMemRange,
+    /// Grown with the `brk` syscall and mapped a page at a time as it is touched.
+    pub brk: VirtMemRange,
+    /// Where shared memory regions are mapped, each after a guard page.
+    pub shared: VirtMemRange,
 }
 
 impl UserMemory {
     const STACK_SIZE: usize = PAGE_SIZE * 4;
     const HEAP_SIZE: usize = PAGE_SIZE * 64;
+    const BRK_MAX_SIZE: usize = PAGE_SIZE * 262144;
+    const SHARED_SIZE: usize = PAGE_SIZE * 262144;
     const fn new(base_addr: u64) -> Self {
+        let stack = VirtMemRange::new(base_addr + GUARD_SIZE, Self::STACK_SIZE);
+        let heap = VirtMemRange::new(stack.end(), Self::HEAP_SIZE);
+        let brk = VirtMemRange::new(heap.end(), Self::BRK_MAX_SIZE);
         UserMemory {
-            stack: VirtMemRange::new(base_addr, Self::STACK_SIZE),
-            heap: VirtMemRange::new(base_addr + (Self::STACK_SIZE as u64), Self::HEAP_SIZE),
+            stack,
+            heap,
+            brk,
+            shared: VirtMemRange::new(brk.end(), Self::SHARED_SIZE),
         }
     }
+
+    /// Everything the kernel places in user space for every process, including the guard page.
+    pub const fn reserved(&self) -> VirtMemRange {
+        let start = self.stack.guard_page().0;
+        VirtMemRange(start, self.shared.end() - start)
+    }
 }
 
 const EXECUTION_MEMORY_START: u64 = 0xc000_0000_0000;
+const USER_MEMORY_START: u64 = 0x1000_0000_0000;
 pub const KERNEL_MEMORY: KernelMemory = KernelMemory::new(EXECUTION_MEMORY_START);
-pub const USER_MEMORY: UserMemory =
-    UserMemory::new(EXECUTION_MEMORY_START + (KernelMemory::len() as u64));
+pub const USER_MEMORY: UserMemory = UserMemory::new(USER_MEMORY_START);
+
+/// Kernel stacks of processes, one per scheduler slot, each with a guard page below it. Far enough
+/// above the kernel heap to leave it room to grow.
+const PROCESS_KERNEL_STACKS_START: u64 = EXECUTION_MEMORY_START + 0x10_0000_0000;
+pub const PROCESS_KERNEL_STACK_SIZE: usize = PAGE_SIZE * 4;
+/// Physical memory mapped with `map_physical` and `map_mmio`, above the process kernel stacks.
+const MMIO_START: u64 = EXECUTION_MEMORY_START + 0x20_0000_0000;
+static mut NEXT_MMIO: u64 = MMIO_START;
+
+/// A range mapped with `map_physical` or `map_mmio`.
+struct PhysicalMapping {
+    start: PhysFrame,
+    frames: u64,
+    virt: VirtAddr,
+    cached: bool,
+}
+
+static mut PHYSICAL_MAPPINGS: Vec<PhysicalMapping> = Vec::new();
+
+/// Where the bootloader starts placing its own mappings (kernel, boot info, framebuffer). This
+/// keeps the lower half free for userspace.
+pub const DYNAMIC_RANGE_START: u64 = 0xd000_0000_0000;
+/// Where position independent programs are loaded, between the user stack/heap and the end of
+/// user space. With ASLR it's the lowest address they are loaded at.
+pub const PROGRAM_LOAD_BASE: u64 = 0x2000_0000_0000;
+/// Userspace addresses are in the lower half, everything above belongs to the kernel.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
 
 struct KernelMemoryMapper {
     frame_allocator: BootInfoFrameAllocator,
@@ -141,17 +362,18 @@ impl KernelMemoryMapper {
             let level_4_table = active_level_4_table(phys_offset);
             OffsetPageTable::new(level_4_table, phys_offset)
         };
-        let frame_allocator = BootInfoFrameAllocator::new(memory_regions);
+        let frame_allocator = BootInfoFrameAllocator::new(memory_regions, phys_offset);
 
         let mut kernel_mapper = KernelMemoryMapper {
             frame_allocator,
             mapper,
             phys_offset,
         };
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
         kernel_mapper.alloc_and_map_range(memory_layout.privilege_stack, flags)?;
         kernel_mapper.alloc_and_map_range(memory_layout.interrupt_stack, flags)?;
         kernel_mapper.alloc_and_map_range(memory_layout.double_fault_stack, flags)?;
+        kernel_mapper.alloc_and_map_range(memory_layout.syscall_stack, flags)?;
         kernel_mapper.alloc_and_map_range(memory_layout.heap, flags)?;
         x86_64::instructions::tlb::flush_all();
         Ok(kernel_mapper)
@@ -191,49 +413,114 @@ impl KernelMemoryMapper {
     }
 }
 
-pub struct UserMemoryMapper {
-    kernel_mapper: &'static mut KernelMemoryMapper,
+/// A process' page tables. The kernel half is shared with every other address space, the user
+/// half is private.
+pub struct AddressSpace {
+    page_table_frame: PhysFrame<Size4KiB>,
+    page_table: OffsetPageTable<'static>,
+    phys_offset: VirtAddr,
     allocator: LockedHeap,
+    // Current end of the `brk` heap.
+    brk: u64,
+    // The shared memory regions this address space keeps alive, see `shm`.
+    shared: Vec<SharedHold>,
+    // Where the next shared memory mapping goes, after a guard page.
+    next_shared: u64,
+    // The user stack, `UserMemory::stack` or somewhere else with ASLR.
+    stack: VirtMemRange,
+    // The program's segments that are read from its file as they are used, see `elf_loader`.
+    lazy_image: Option<Rc<LazyImage>>,
+}
+
+/// A reference to a shared memory region, from creating it or from one mapping of it.
+#[derive(Clone)]
+struct SharedHold {
+    id: u64,
+    /// First page and number of pages, if it's a mapping.
+    mapping: Option<(Page, usize)>,
 }
 
-impl UserMemoryMapper {
-    pub fn init(memory_layout: UserMemory) -> Result<UserMemoryMapper, MapToError<Size4KiB>> {
-        let kernel_mapper = unsafe {
-            KERNEL_MEMORY_MAPPER
-                .as_mut()
-                .expect("no kernel memory mapper")
+impl AddressSpace {
+    /// An address space with only the kernel half mapped.
+    fn empty(
+        memory_layout: &UserMemory,
+        stack: VirtMemRange,
+    ) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let kernel_mapper = kernel_memory_mapper();
+        let phys_offset = kernel_mapper.phys_offset;
+        let page_table_frame = kernel_mapper
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let level_4_table = unsafe {
+            let virt = phys_offset + page_table_frame.start_address().as_u64();
+            let table: *mut PageTable = virt.as_mut_ptr();
+            table.write(PageTable::new());
+            &mut *table
         };
-        let flags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
-        kernel_mapper.alloc_and_map_range(memory_layout.stack, flags)?;
-        kernel_mapper.alloc_and_map_range(memory_layout.heap, flags)?;
-        Ok(UserMemoryMapper {
-            kernel_mapper,
+        // Share the kernel half, which holds the kernel itself, its stacks and heap, the IDT and
+        // the physical memory mapping. Without these the next interrupt would triple fault.
+        let kernel_level_4_table = kernel_mapper.mapper.level_4_table();
+        for index in 256..512 {
+            level_4_table[index] = kernel_level_4_table[index].clone();
+        }
+
+        Ok(AddressSpace {
+            page_table_frame,
+            page_table: unsafe { OffsetPageTable::new(level_4_table, phys_offset) },
+            phys_offset,
             allocator: unsafe {
                 LockedHeap::new(
                     memory_layout.heap.start().as_mut_ptr(),
                     memory_layout.heap.size(),
                 )
             },
+            brk: memory_layout.brk.start().as_u64(),
+            shared: Vec::new(),
+            next_shared: memory_layout.shared.start().as_u64(),
+            stack,
+            lazy_image: None,
         })
     }
 
+    fn new(
+        memory_layout: UserMemory,
+        stack: VirtMemRange,
+    ) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let mut address_space = AddressSpace::empty(&memory_layout, stack)?;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | no_execute();
+        address_space.alloc_and_map_range(stack, flags)?;
+        address_space.alloc_and_map_range(memory_layout.heap, flags)?;
+        Ok(address_space)
+    }
+
+    pub fn stack(&self) -> VirtMemRange {
+        self.stack
+    }
+
     pub fn phys_offset(&self, phys_addr: PhysAddr) -> VirtAddr {
-        VirtAddr::new(phys_addr.as_u64() + self.kernel_mapper.phys_offset.as_u64())
+        VirtAddr::new(phys_addr.as_u64() + self.phys_offset.as_u64())
     }
 
     pub fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        self.kernel_mapper.frame_allocator.allocate_frame()
+        kernel_memory_mapper().allocate_frame()
     }
+    /// Lets pages of `image` that aren't mapped yet be read from the program's file on a fault.
+    pub fn set_lazy_image(&mut self, image: LazyImage) {
+        self.lazy_image = Some(Rc::new(image));
+    }
+
     pub fn finish_load(&mut self) {
         x86_64::instructions::tlb::flush_all();
     }
 
     pub fn page_table(&self) -> &OffsetPageTable<'static> {
-        &self.kernel_mapper.mapper
+        &self.page_table
     }
     pub fn page_table_mut(&mut self) -> &mut OffsetPageTable<'static> {
-        &mut self.kernel_mapper.mapper
+        &mut self.page_table
     }
 
     pub unsafe fn map_page(
@@ -243,48 +530,394 @@ impl UserMemoryMapper {
         mut flags: PageTableFlags,
     ) -> Result<(), MapToError<Size4KiB>> {
         flags |= PageTableFlags::USER_ACCESSIBLE;
-        self.kernel_mapper
-            .mapper
-            .map_to(page, frame, flags, &mut self.kernel_mapper.frame_allocator)?
+        self.page_table
+            .map_to(
+                page,
+                frame,
+                flags,
+                &mut kernel_memory_mapper().frame_allocator,
+            )?
             .ignore();
         Ok(())
     }
-    pub fn unmap_page(&mut self, page: Page<Size4KiB>) -> Result<(), UnmapError> {
-        self.kernel_mapper.mapper.unmap(page)?.1.ignore();
+    /// Unmaps `page` and returns the frame it was mapped to. The caller decides whether to free
+    /// it.
+    pub fn unmap_page(&mut self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, UnmapError> {
+        let (frame, flush) = self.page_table.unmap(page)?;
+        flush.ignore();
+        Ok(frame)
+    }
+
+    /// Calls `leaf` for every mapped page in the user half, then `table` for each of the user
+    /// half's page tables once its entries were visited. Stops at the first error from `leaf`.
+    fn walk_user_tables(
+        &mut self,
+        mut leaf: impl FnMut(Page, &mut PageTableEntry) -> Result<(), &'static str>,
+        mut table: impl FnMut(PhysFrame),
+    ) -> Result<(), &'static str> {
+        let phys_offset = self.phys_offset;
+        let next_table = |entry: &PageTableEntry| -> Option<(PhysFrame, &'static mut PageTable)> {
+            let frame = entry.frame().ok()?;
+            let table =
+                unsafe { &mut *(phys_offset + frame.start_address().as_u64()).as_mut_ptr() };
+            Some((frame, table))
+        };
+        let level_4_table = self.page_table.level_4_table();
+        for p4 in 0..256 {
+            let Some((level_3_frame, level_3_table)) = next_table(&level_4_table[p4]) else {
+                continue;
+            };
+            for p3 in 0..512 {
+                let Some((level_2_frame, level_2_table)) = next_table(&level_3_table[p3]) else {
+                    continue;
+                };
+                for p2 in 0..512 {
+                    let Some((level_1_frame, level_1_table)) = next_table(&level_2_table[p2])
+                    else {
+                        continue;
+                    };
+                    for p1 in 0..512 {
+                        let entry = &mut level_1_table[p1];
+                        if entry.frame().is_err() {
+                            continue;
+                        }
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(p4 as u16),
+                            PageTableIndex::new(p3 as u16),
+                            PageTableIndex::new(p2 as u16),
+                            PageTableIndex::new(p1 as u16),
+                        );
+                        leaf(page, entry)?;
+                    }
+                    table(level_1_frame);
+                }
+                table(level_2_frame);
+            }
+            table(level_3_frame);
+        }
+        Ok(())
+    }
+
+    /// Copies this address space for a forked process. Both share every user frame; writable
+    /// pages are made read-only in both and copied by `handle_cow_fault` on the first write.
+    pub fn fork(&mut self) -> Result<Box<AddressSpace>, &'static str> {
+        let mut child =
+            AddressSpace::empty(&USER_MEMORY, self.stack).map_err(|_| "out of memory")?;
+        // The allocator only holds pointers into the heap, which the child sees at the same
+        // addresses.
+        child.allocator = unsafe { core::ptr::read(&self.allocator) };
+        child.brk = self.brk;
+        child.next_shared = self.next_shared;
+        child.lazy_image = self.lazy_image.clone();
+        for hold in &self.shared {
+            shm::retain(hold.id);
+        }
+        child.shared = self.shared.clone();
+
+        let result = self.walk_user_tables(
+            |page, entry| {
+                let frame = entry.frame().unwrap();
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                }
+                inc_ref(frame)?;
+                if unsafe { child.map_page(page, frame, flags) }.is_err() {
+                    dec_ref(frame);
+                    return Err("failed to map page in child");
+                }
+                Ok(())
+            },
+            |_| {},
+        );
+        // The parent lost write access to its pages.
+        x86_64::instructions::tlb::flush_all();
+        result.map(|()| Box::new(child))
+    }
+
+    /// Gives the page at `addr` a private, writable frame if it's a copy-on-write page. Returns
+    /// whether the write can be retried.
+    pub fn handle_cow_fault(&mut self, addr: VirtAddr) -> bool {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } = self.page_table.translate(page.start_address())
+        else {
+            return false;
+        };
+        if !flags.contains(COPY_ON_WRITE) {
+            return false;
+        }
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        if ref_count(frame) <= 1 {
+            // Every other process let go of the frame already.
+            match unsafe { self.page_table.update_flags(page, flags) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => return false,
+            }
+            return true;
+        }
+        let Some(new_frame) = self.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.phys_offset(frame.start_address()).as_ptr::<u8>(),
+                self.phys_offset(new_frame.start_address())
+                    .as_mut_ptr::<u8>(),
+                PAGE_SIZE,
+            );
+        }
+        if self.unmap_page(page).is_err()
+            || unsafe { self.map_page(page, new_frame, flags) }.is_err()
+        {
+            return false;
+        }
+        x86_64::instructions::tlb::flush(page.start_address());
+        free_frame(frame);
+        true
+    }
+
+    /// Moves the end of the `brk` heap to `new_break` and returns the new end. Requests outside
+    /// the `brk` range, such as 0, leave it unchanged, so they return the current end. Pages are
+    /// only mapped once touched; shrinking unmaps the pages that are no longer covered.
+    pub fn set_brk(&mut self, new_break: u64) -> u64 {
+        let range = USER_MEMORY.brk;
+        if new_break < range.start().as_u64() || new_break > range.end() {
+            return self.brk;
+        }
+        if new_break < self.brk {
+            let start = Page::<Size4KiB>::containing_address(VirtAddr::new(align_up(
+                new_break,
+                PAGE_SIZE as u64,
+            )));
+            let end = Page::containing_address(VirtAddr::new(align_up(self.brk, PAGE_SIZE as u64)));
+            for page in Page::range(start, end) {
+                if let Ok(frame) = self.unmap_page(page) {
+                    x86_64::instructions::tlb::flush(page.start_address());
+                    free_frame(frame);
+                }
+            }
+        }
+        self.brk = new_break;
+        self.brk
+    }
+
+    /// Maps a zeroed page at `addr` if it's part of the `brk` heap and not mapped yet. Returns
+    /// whether `addr` can be accessed now.
+    pub fn handle_brk_fault(&mut self, addr: VirtAddr) -> bool {
+        let start = USER_MEMORY.brk.start();
+        if addr < start || addr.as_u64() >= self.brk {
+            return false;
+        }
+        let page = Page::<Size4KiB>::containing_address(addr);
+        if self.page_table.translate_page(page).is_ok() {
+            return true;
+        }
+        let Some(frame) = self.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            core::ptr::write_bytes(
+                self.phys_offset(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                PAGE_SIZE,
+            );
+        }
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
+        if unsafe { self.map_page(page, frame, flags) }.is_err() {
+            return false;
+        }
+        x86_64::instructions::tlb::flush(page.start_address());
+        true
+    }
+
+    /// Reads the page at `addr` from the program's file and maps it if it's part of a lazily loaded
+    /// segment and not mapped yet. Returns whether `addr` can be accessed now. `from_user` tells
+    /// whether the access was made in ring 3.
+    pub fn handle_lazy_fault(&mut self, addr: VirtAddr, from_user: bool) -> bool {
+        let Some(image) = self.lazy_image.clone() else {
+            return false;
+        };
+        if !image.contains(addr) {
+            return false;
+        }
+        let page = Page::<Size4KiB>::containing_address(addr);
+        if self.page_table.translate_page(page).is_ok() {
+            return true;
+        }
+        // Faults run on the exception stack, where this process can't wait for the one using the
+        // filesystem. A fault from ring 3 comes back until that one is done, but the kernel isn't
+        // preempted, so its access would fault forever. It prefaults what it touches instead.
+        if filesystem::busy() && on_exception_stack() {
+            return from_user;
+        }
+        let Some(frame) = self.allocate_frame() else {
+            return false;
+        };
+        let buf = unsafe {
+            let buf = &mut *self
+                .phys_offset(frame.start_address())
+                .as_mut_ptr::<[u8; PAGE_SIZE]>();
+            buf.fill(0);
+            buf
+        };
+        let flags = match image.read_page(page, buf) {
+            Ok(flags) => flags,
+            Err(err) => {
+                log::warn!("Failed to read {:?} of the program: {}", page, err);
+                free_frame(frame);
+                return false;
+            }
+        };
+        if unsafe { self.map_page(page, frame, flags) }.is_err() {
+            free_frame(frame);
+            return false;
+        }
+        x86_64::instructions::tlb::flush(page.start_address());
+        true
+    }
+
+    /// Copies `bytes` to `addr` in this address space, which doesn't have to be the active one.
+    pub fn write_bytes(&mut self, addr: VirtAddr, bytes: &[u8]) -> Result<(), &'static str> {
+        let mut addr = addr;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let TranslateResult::Mapped { frame, offset, .. } = self.page_table.translate(addr)
+            else {
+                return Err("address is not mapped");
+            };
+            let phys_addr = frame.start_address() + offset;
+            let len = bytes.len().min((frame.size() - offset) as usize);
+            let dest = self.phys_offset(phys_addr).as_mut_ptr::<u8>();
+            unsafe {
+                core::ptr::copy_nonoverlapping(bytes.as_ptr(), dest, len);
+            }
+            addr += len;
+            bytes = &bytes[len..];
+        }
         Ok(())
     }
 
-    pub fn make_range_user_accessible(
+    /// Records that this address space keeps shared memory region `id` alive until it's dropped.
+    pub fn hold_shared(&mut self, id: u64) {
+        self.shared.push(SharedHold { id, mapping: None });
+    }
+
+    /// Maps the frames of shared memory region `id` at a new address and returns it. Each frame
+    /// gets another reference, which unmapping it gives back.
+    pub fn map_shared(&mut self, id: u64, frames: &[PhysFrame]) -> Result<VirtAddr, &'static str> {
+        let start = self.next_shared + GUARD_SIZE;
+        kassert_eq!(
+            start % PAGE_SIZE as u64,
+            0,
+            "shared memory start isn't page aligned"
+        );
+        let end = start + (frames.len() * PAGE_SIZE) as u64;
+        if end > USER_MEMORY.shared.end() {
+            return Err("no room to map shared memory");
+        }
+        let first_page = Page::from_start_address(VirtAddr::new(start)).unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute() | SHARED;
+        for (index, &frame) in frames.iter().enumerate() {
+            let page = first_page + index as u64;
+            let mapped = inc_ref(frame).and_then(|()| {
+                unsafe { self.map_page(page, frame, flags) }.map_err(|_| {
+                    dec_ref(frame);
+                    "failed to map shared memory"
+                })
+            });
+            if let Err(err) = mapped {
+                // Take back what was mapped so far.
+                for page in Page::range(first_page, page) {
+                    if let Ok(frame) = self.unmap_page(page) {
+                        free_frame(frame);
+                    }
+                }
+                return Err(err);
+            }
+        }
+        self.next_shared = end;
+        self.shared.push(SharedHold {
+            id,
+            mapping: Some((first_page, frames.len())),
+        });
+        Ok(first_page.start_address())
+    }
+
+    /// Unmaps the shared memory mapping starting at `addr`, and returns the id of its region.
+    pub fn unmap_shared(&mut self, addr: VirtAddr) -> Result<u64, &'static str> {
+        let index = self
+            .shared
+            .iter()
+            .position(
+                |hold| matches!(hold.mapping, Some((page, _)) if page.start_address() == addr),
+            )
+            .ok_or("no shared memory mapped there")?;
+        let hold = self.shared.swap_remove(index);
+        let (first_page, pages) = hold.mapping.unwrap();
+        for page in Page::range(first_page, first_page + pages as u64) {
+            if let Ok(frame) = self.unmap_page(page) {
+                x86_64::instructions::tlb::flush(page.start_address());
+                free_frame(frame);
+            }
+        }
+        Ok(hold.id)
+    }
+
+    fn alloc_and_map_range(
         &mut self,
         range: VirtMemRange,
-    ) -> Result<(), FlagUpdateError> {
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
         let range_start = Page::from_start_address(range.start()).unwrap();
         let range_end = Page::containing_address(range.last_addr());
-        for page in Page::<Size4KiB>::range_inclusive(range_start, range_end) {
-            // Translate the page.
-            let res = self.kernel_mapper.mapper.translate(page.start_address());
-            let (frame, flags) = match res {
-                TranslateResult::Mapped {
-                    frame: MappedFrame::Size4KiB(frame),
-                    offset: _,
-                    flags,
-                } => (frame, flags),
-                _ => {
-                    return Err(FlagUpdateError::PageNotMapped);
-                }
-            };
-            // Remap the page with USER_ACCESSIBLE enabled. This also enables it for parent pages.
-            self.unmap_page(page).unwrap();
+        for page in Page::range_inclusive(range_start, range_end) {
+            let frame = self
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
             unsafe {
-                self.map_page(page, frame, flags).unwrap();
+                self.map_page(page, frame, flags)?;
             }
         }
         Ok(())
     }
 }
 
+impl Drop for AddressSpace {
+    /// Frees the user half's frames and page tables. Shared frames are only freed once no other
+    /// address space uses them.
+    fn drop(&mut self) {
+        let _ = self.walk_user_tables(
+            |_, entry| {
+                free_frame(entry.frame().unwrap());
+                Ok(())
+            },
+            free_frame,
+        );
+        free_frame(self.page_table_frame);
+        // The mappings were freed with the other pages, now the regions can go too.
+        for hold in &self.shared {
+            shm::release(hold.id);
+        }
+    }
+}
+
 static mut KERNEL_MEMORY_MAPPER: Option<KernelMemoryMapper> = None;
-static mut USER_MEMORY_MAPPER: Option<UserMemoryMapper> = None;
+static mut KERNEL_PAGE_TABLE: Option<PhysFrame<Size4KiB>> = None;
+// The address space of the running process, or null while the kernel's own is active.
+static mut CURRENT_ADDRESS_SPACE: *mut AddressSpace = core::ptr::null_mut();
+
+fn kernel_memory_mapper() -> &'static mut KernelMemoryMapper {
+    unsafe {
+        KERNEL_MEMORY_MAPPER
+            .as_mut()
+            .expect("no kernel memory mapper")
+    }
+}
 
 pub fn init_memory(phys_offset: u64, memory_regions: &'static MemoryRegions) {
     // Create kernel mapper and map kernel heap and interrupt stack.
@@ -293,26 +926,499 @@ pub fn init_memory(phys_offset: u64, memory_regions: &'static MemoryRegions) {
         .expect("failed to map kernel memory");
     unsafe {
         KERNEL_MEMORY_MAPPER = Some(kernel_mapper);
+        KERNEL_PAGE_TABLE = Some(x86_64::registers::control::Cr3::read().0);
     }
 
     // Setup the allocator to use the newly-mapped heap.
     unsafe {
-        ALLOCATOR.lock().init(
+        ALLOCATOR.0.lock().init(
             KERNEL_MEMORY.heap.start().as_mut_ptr(),
             KERNEL_MEMORY.heap.size(),
         );
     }
 
-    // Map user stack and heap, and create a separate allocator for the user heap.
-    let user_mapper = UserMemoryMapper::init(USER_MEMORY).expect("failed to map user memory");
+    // Track frames allocated from now on. Sized for the highest usable address.
+    let frames = memory_regions
+        .iter()
+        .filter(|region| region.kind == MemoryRegionKind::Usable)
+        .map(|region| region.end / Size4KiB::SIZE)
+        .max()
+        .unwrap_or(0);
+    unsafe {
+        FRAME_REFS = Some(alloc::vec![0; frames as usize].into_boxed_slice());
+    }
+    // Everything allocated so far (kernel stacks, heap and page tables) stays.
+    let frame_allocator = &kernel_memory_mapper().frame_allocator;
+    for frame in frame_allocator.usable_frames().take(frame_allocator.next) {
+        pin_frame(frame);
+    }
+
+    // Make the kernel respect read-only pages too, so its writes to copy-on-write pages fault.
+    unsafe {
+        use x86_64::registers::control::{Cr0, Cr0Flags};
+        Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+    }
+
+    let stats = stats();
+    log::info!(
+        "Memory: {} of {} frames used, {} KiB heap",
+        stats.used_frames,
+        stats.total_frames,
+        stats.heap_size / 1024
+    );
+}
+
+fn process_kernel_stack_range(slot: usize) -> VirtMemRange {
+    let stride = GUARD_SIZE + PROCESS_KERNEL_STACK_SIZE as u64;
+    VirtMemRange::new(
+        PROCESS_KERNEL_STACKS_START + slot as u64 * stride + GUARD_SIZE,
+        PROCESS_KERNEL_STACK_SIZE,
+    )
+}
+
+/// The kernel stack for scheduler slot `slot`, mapped the first time it's used and kept for the
+/// next process in that slot.
+pub fn process_kernel_stack(slot: usize) -> Result<VirtMemRange, &'static str> {
+    let range = process_kernel_stack_range(slot);
+    let kernel_mapper = kernel_memory_mapper();
+    if kernel_mapper.mapper.translate_addr(range.start()).is_none() {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
+        kernel_mapper
+            .alloc_and_map_range(range, flags)
+            .map_err(|_| "failed to map kernel stack")?;
+        pin_range(range);
+    }
+    Ok(range)
+}
+
+/// Whether the CPU is on the stack exceptions or double faults switch to, which every exception of
+/// that kind starts at the top of again.
+pub fn on_exception_stack() -> bool {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    let rsp = VirtAddr::new(rsp);
+    KERNEL_MEMORY.interrupt_stack.contains(rsp) || KERNEL_MEMORY.double_fault_stack.contains(rsp)
+}
+
+/// Names the stack whose guard page contains `addr`, if any.
+pub fn stack_guard_hit(addr: VirtAddr) -> Option<&'static str> {
+    if let Some((name, _)) = KERNEL_MEMORY
+        .stacks()
+        .into_iter()
+        .find(|(_, stack)| stack.guard_page().contains(addr))
+    {
+        return Some(name);
+    }
+    if (0..crate::scheduler::MAX_PROCESSES)
+        .any(|slot| process_kernel_stack_range(slot).guard_page().contains(addr))
+    {
+        return Some("process kernel");
+    }
+    let user_stack = unsafe { CURRENT_ADDRESS_SPACE.as_ref() }
+        .map_or(USER_MEMORY.stack, |address_space| address_space.stack);
+    if user_stack.guard_page().contains(addr) {
+        return Some("user");
+    }
+    None
+}
+
+/// A snapshot of physical memory and kernel heap usage.
+#[derive(Debug, Clone, Copy)]
+pub struct MemStats {
+    pub total_frames: usize,
+    pub used_frames: usize,
+    pub free_frames: usize,
+    pub heap_size: usize,
+    pub heap_used: usize,
+}
+
+pub fn stats() -> MemStats {
+    let frame_allocator = &kernel_memory_mapper().frame_allocator;
+    let heap = ALLOCATOR.0.lock();
+    MemStats {
+        total_frames: frame_allocator.total + frame_allocator.adopted,
+        used_frames: frame_allocator.used(),
+        free_frames: frame_allocator.total + frame_allocator.adopted - frame_allocator.used(),
+        heap_size: heap.size(),
+        heap_used: heap.used(),
+    }
+}
+
+pub fn heap_initialized() -> bool {
+    ALLOCATOR.0.lock().size() > 0
+}
+
+/// Maps `pages` more pages at the top of the kernel heap. If physical memory runs out part way, the
+/// pages mapped so far are still added.
+pub fn grow_heap(pages: usize) -> Result<(), &'static str> {
+    let mut heap = ALLOCATOR.0.lock();
+    if heap.size() + pages * PAGE_SIZE > KernelMemory::HEAP_MAX_SIZE {
+        return Err("kernel heap is at its maximum size");
+    }
+    let kernel_mapper = kernel_memory_mapper();
+    kassert!(
+        VirtAddr::from_ptr(heap.top()).is_aligned(PAGE_SIZE as u64),
+        "heap top {:p} isn't page aligned",
+        heap.top()
+    );
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(heap.top()));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
+    let mut mapped = 0;
+    let result = loop {
+        if mapped == pages {
+            break Ok(());
+        }
+        let Some(frame) = kernel_mapper.allocate_frame() else {
+            break Err("out of physical memory");
+        };
+        pin_frame(frame);
+        if unsafe { kernel_mapper.map_page(start + mapped as u64, frame, flags) }.is_err() {
+            break Err("failed to map heap page");
+        }
+        mapped += 1;
+    };
+    // The kernel half is shared, so the new pages are visible in every address space.
+    for page in Page::range(start, start + mapped as u64) {
+        x86_64::instructions::tlb::flush(page.start_address());
+    }
     unsafe {
-        USER_MEMORY_MAPPER = Some(user_mapper);
+        heap.extend(mapped * PAGE_SIZE);
     }
+    result
 }
 
-pub fn user_memory_mapper() -> &'static mut UserMemoryMapper {
-    unsafe { USER_MEMORY_MAPPER.as_mut().expect("no user memory mapper") }
+/// Reference counts of physical frames, indexed by frame number. 0 means the frame isn't tracked
+/// (it's free, or was never handed out by the frame allocator), so it's never freed.
+static mut FRAME_REFS: Option<Box<[u16]>> = None;
+/// Count of frames that are never given back, like the kernel heap and the framebuffer.
+const FRAME_PINNED: u16 = u16::MAX;
+
+fn frame_ref(frame: PhysFrame) -> Option<&'static mut u16> {
+    let index = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize;
+    unsafe { FRAME_REFS.as_mut()?.get_mut(index) }
+}
+fn ref_count(frame: PhysFrame) -> u16 {
+    frame_ref(frame).map_or(0, |count| *count)
+}
+/// Records another user of `frame`. Fails if the count can't go higher, since a user that isn't
+/// counted would have the frame freed under it.
+pub fn inc_ref(frame: PhysFrame) -> Result<(), &'static str> {
+    match frame_ref(frame) {
+        Some(count) if *count == FRAME_PINNED - 1 => Err("frame has too many references"),
+        Some(count) if *count != 0 && *count != FRAME_PINNED => {
+            *count += 1;
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+/// Records that a user of `frame` is gone and returns how many are left, without freeing it even
+/// when none are.
+pub fn dec_ref(frame: PhysFrame) -> u16 {
+    match frame_ref(frame) {
+        Some(count) if *count != 0 && *count != FRAME_PINNED => {
+            *count -= 1;
+            *count
+        }
+        Some(count) => *count,
+        None => 0,
+    }
+}
+/// Drops a reference to `frame` and gives it back to the frame allocator if it was the last one.
+/// Pinned and untracked frames are left alone.
+pub fn free_frame(frame: PhysFrame) {
+    match frame_ref(frame) {
+        Some(count) if *count == 1 => {
+            *count = 0;
+            kernel_memory_mapper()
+                .frame_allocator
+                .deallocate_frame(frame);
+        }
+        Some(_) => {
+            dec_ref(frame);
+        }
+        None => {}
+    }
+}
+/// Makes sure `frame` is never freed.
+pub fn pin_frame(frame: PhysFrame) {
+    if let Some(count) = frame_ref(frame) {
+        *count = FRAME_PINNED;
+    }
+}
+/// Pins the frames behind an already mapped kernel range, e.g. the framebuffer.
+pub fn pin_range(range: VirtMemRange) {
+    let kernel_mapper = kernel_memory_mapper();
+    let range_start = Page::<Size4KiB>::containing_address(range.start());
+    let range_end = Page::containing_address(range.last_addr());
+    for page in Page::range_inclusive(range_start, range_end) {
+        if let Ok(frame) = kernel_mapper.mapper.translate_page(page) {
+            pin_frame(frame);
+        }
+    }
+}
+
+pub fn allocate_frame() -> Option<PhysFrame<Size4KiB>> {
+    kernel_memory_mapper().allocate_frame()
+}
+/// Where RAM at `phys_addr` is mapped in the kernel half. Anything else is only mapped there with
+/// `physmap=full`, and has to be mapped with `map_physical` or `map_mmio`.
+pub fn phys_to_virt(phys_addr: PhysAddr) -> VirtAddr {
+    kernel_memory_mapper().phys_offset + phys_addr.as_u64()
+}
+
+/// Unmaps the parts of the bootloader's mapping of all physical memory that aren't RAM, and gives
+/// the page tables that leaves empty to the frame allocator. The rest is still needed to reach
+/// page tables and frames through `phys_to_virt`. The bootloader maps it with 2 MiB pages, and
+/// pages of other sizes are left alone. Must run before any address space is created.
+pub fn trim_physical_mapping() {
+    let kernel_mapper = kernel_memory_mapper();
+    let regions = kernel_mapper.frame_allocator.memory_regions;
+    let is_ram = |start: u64, end: u64| {
+        regions.iter().any(|region| {
+            matches!(
+                region.kind,
+                MemoryRegionKind::Usable | MemoryRegionKind::Bootloader
+            ) && region.start < end
+                && start < region.end
+        })
+    };
+    // The bootloader maps at least the first 4 GiB, where the APIC registers are.
+    let limit = regions
+        .iter()
+        .map(|region| region.end)
+        .max()
+        .unwrap_or(0)
+        .max(4 << 30);
+    let mut unmapped = 0;
+    for addr in (0..limit).step_by(Size2MiB::SIZE as usize) {
+        let virt = kernel_mapper.phys_offset + addr;
+        let huge = matches!(
+            kernel_mapper.mapper.translate(virt),
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            }
+        );
+        if huge && !is_ram(addr, addr + Size2MiB::SIZE) {
+            if let Ok((_, flush)) = kernel_mapper
+                .mapper
+                .unmap(Page::<Size2MiB>::containing_address(virt))
+            {
+                // The whole TLB is flushed below.
+                flush.ignore();
+                unmapped += 1;
+            }
+        }
+    }
+    let adopted = kernel_mapper.frame_allocator.adopted;
+    let window = Page::range_inclusive(
+        Page::containing_address(kernel_mapper.phys_offset),
+        Page::containing_address(kernel_mapper.phys_offset + (limit - 1)),
+    );
+    unsafe {
+        kernel_mapper
+            .mapper
+            .clean_up_addr_range(window, &mut Adopter(&mut kernel_mapper.frame_allocator));
+    }
+    x86_64::instructions::tlb::flush_all();
+    log::info!(
+        "Unmapped {} MiB of physical memory that isn't RAM, freed {} page tables",
+        unmapped * 2,
+        kernel_mapper.frame_allocator.adopted - adopted
+    );
+}
+
+/// Maps `size` bytes of physical memory at `phys_addr` that isn't RAM, e.g. firmware tables, into
+/// the kernel half. A range that's already mapped isn't mapped again. Mappings are never removed.
+pub fn map_physical(phys_addr: PhysAddr, size: usize) -> Result<VirtAddr, &'static str> {
+    map_window(phys_addr, size, true)
+}
+
+/// Like `map_physical`, but uncached, for device registers.
+pub fn map_mmio(phys_addr: PhysAddr, size: usize) -> Result<VirtAddr, &'static str> {
+    map_window(phys_addr, size, false)
+}
+
+fn map_window(phys_addr: PhysAddr, size: usize, cached: bool) -> Result<VirtAddr, &'static str> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+    let last_frame = PhysFrame::containing_address(phys_addr + (size.max(1) - 1) as u64);
+    let offset = phys_addr - first_frame.start_address();
+    let mappings = unsafe { &mut PHYSICAL_MAPPINGS };
+    for mapping in mappings.iter() {
+        let end = mapping.start + mapping.frames;
+        if last_frame < mapping.start || end <= first_frame {
+            continue;
+        }
+        // Mapping the same memory cached and uncached is undefined.
+        if mapping.cached != cached {
+            return Err("memory is already mapped with other caching");
+        }
+        if mapping.start <= first_frame && last_frame < end {
+            return Ok(mapping.virt + (first_frame - mapping.start) * Size4KiB::SIZE + offset);
+        }
+    }
+    let kernel_mapper = kernel_memory_mapper();
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
+    if !cached {
+        flags |= PageTableFlags::NO_CACHE;
+    }
+    let virt_start = VirtAddr::new(unsafe { NEXT_MMIO });
+    let mut page = Page::<Size4KiB>::containing_address(virt_start);
+    for frame in PhysFrame::range_inclusive(first_frame, last_frame) {
+        unsafe {
+            kernel_mapper
+                .map_page(page, frame, flags)
+                .map_err(|_| "failed to map physical memory")?;
+        }
+        page += 1;
+    }
+    unsafe {
+        NEXT_MMIO = page.start_address().as_u64();
+    }
+    mappings.push(PhysicalMapping {
+        start: first_frame,
+        frames: last_frame - first_frame + 1,
+        virt: virt_start,
+        cached,
+    });
+    Ok(virt_start + offset)
+}
+
+/// Whether user stacks and position independent programs are placed at random addresses.
+/// `aslr=off` places them at fixed ones, so runs can be reproduced while debugging.
+pub fn aslr() -> bool {
+    cmdline::get("aslr") != Some("off")
+}
+
+/// A random page aligned address in `start..end`, or `start` if the range has no page.
+pub fn random_page(start: u64, end: u64) -> u64 {
+    let size = end.saturating_sub(start);
+    if size < PAGE_SIZE as u64 {
+        return start;
+    }
+    start + ((rand::u64() % size) & !(PAGE_SIZE as u64 - 1))
+}
+
+/// The size of a user stack unless the program asks for another.
+pub const DEFAULT_USER_STACK_SIZE: usize = UserMemory::STACK_SIZE;
+/// User stacks are mapped in full when the address space is created, so they can't be huge.
+pub const MAX_USER_STACK_SIZE: usize = PAGE_SIZE * 256;
+
+/// A user stack of at least `size` bytes, rounded up to whole pages, for a new address space. With
+/// ASLR it goes anywhere between the fixed user memory and `PROGRAM_LOAD_BASE`, so it can't collide
+/// with a position independent program. Without, a stack of the default size is at its place in
+/// the fixed user memory and others just above it. Either way the page below it stays unmapped.
+fn user_stack(size: usize) -> VirtMemRange {
+    let size = align_up(size.max(1) as u64, PAGE_SIZE as u64);
+    let lowest = USER_MEMORY.reserved().end() + GUARD_SIZE;
+    let start = if aslr() {
+        random_page(lowest, PROGRAM_LOAD_BASE - size)
+    } else if size == UserMemory::STACK_SIZE as u64 {
+        USER_MEMORY.stack.start().as_u64()
+    } else {
+        lowest
+    };
+    VirtMemRange::new(start, size as usize)
+}
+
+/// Creates an address space with a user stack of at least `stack_size` bytes and the heap mapped,
+/// and nothing else in the user half. `stack_size` must not be more than `MAX_USER_STACK_SIZE`.
+pub fn new_address_space(stack_size: usize) -> Result<Box<AddressSpace>, MapToError<Size4KiB>> {
+    kassert!(stack_size <= MAX_USER_STACK_SIZE);
+    AddressSpace::new(USER_MEMORY, user_stack(stack_size)).map(Box::new)
+}
+
+/// Makes `address_space` the active one. It must stay alive until another one is switched to.
+pub fn switch_address_space(address_space: &mut AddressSpace) {
+    use x86_64::registers::control::{Cr3, Cr3Flags};
+    unsafe {
+        if Cr3::read().0 != address_space.page_table_frame {
+            Cr3::write(address_space.page_table_frame, Cr3Flags::empty());
+        }
+        CURRENT_ADDRESS_SPACE = address_space;
+    }
+}
+/// Switches back to the kernel's own address space, which has no user mappings.
+pub fn switch_to_kernel_address_space() {
+    use x86_64::registers::control::{Cr3, Cr3Flags};
+    unsafe {
+        let frame = KERNEL_PAGE_TABLE.expect("memory not initialized");
+        if Cr3::read().0 != frame {
+            Cr3::write(frame, Cr3Flags::empty());
+        }
+        CURRENT_ADDRESS_SPACE = core::ptr::null_mut();
+    }
+}
+
+/// Whether `addr` is mapped in the active page table. Doesn't allocate, so it's safe in the panic
+/// handler, and is false for everything before memory is set up.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    translate(addr).is_some()
+}
+
+/// The physical address `addr` is mapped to in the active page table.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    let mapper = unsafe { KERNEL_MEMORY_MAPPER.as_ref() }?;
+    let phys_offset = mapper.phys_offset;
+    let table = unsafe { OffsetPageTable::new(active_level_4_table(phys_offset), phys_offset) };
+    table.translate_addr(addr)
+}
+
+/// The address space of the running process.
+pub fn current_address_space() -> &'static mut AddressSpace {
+    unsafe {
+        CURRENT_ADDRESS_SPACE
+            .as_mut()
+            .expect("no user address space active")
+    }
+}
+/// Lets the active user address space handle a fault at `addr`, by mapping a `brk` heap page,
+/// reading a page of the program or copying a copy-on-write page. Returns whether the access can
+/// be retried. `from_user` tells whether the access was made in ring 3.
+pub fn handle_user_page_fault(addr: VirtAddr, present: bool, write: bool, from_user: bool) -> bool {
+    if addr.as_u64() >= USER_SPACE_END {
+        return false;
+    }
+    match unsafe { CURRENT_ADDRESS_SPACE.as_mut() } {
+        Some(address_space) if !present => {
+            address_space.handle_brk_fault(addr) || address_space.handle_lazy_fault(addr, from_user)
+        }
+        Some(address_space) if write => address_space.handle_cow_fault(addr),
+        _ => false,
+    }
 }
 pub fn user_allocator() -> &'static LockedHeap {
-    &user_memory_mapper().allocator
+    &current_address_space().allocator
+}
+
+/// Makes an already mapped kernel range, e.g. the framebuffer, accessible from userspace in every
+/// address space created afterwards.
+pub fn make_range_user_accessible(range: VirtMemRange) -> Result<(), FlagUpdateError> {
+    let kernel_mapper = kernel_memory_mapper();
+    let range_start = Page::from_start_address(range.start()).unwrap();
+    let range_end = Page::containing_address(range.last_addr());
+    for page in Page::<Size4KiB>::range_inclusive(range_start, range_end) {
+        // Translate the page.
+        let res = kernel_mapper.mapper.translate(page.start_address());
+        let (frame, flags) = match res {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                offset: _,
+                flags,
+            } => (frame, flags),
+            _ => {
+                return Err(FlagUpdateError::PageNotMapped);
+            }
+        };
+        // Remap the page with USER_ACCESSIBLE enabled. This also enables it for parent pages.
+        kernel_mapper.mapper.unmap(page).unwrap().1.ignore();
+        unsafe {
+            kernel_mapper
+                .map_page(page, frame, flags | PageTableFlags::USER_ACCESSIBLE)
+                .unwrap();
+        }
+    }
+    x86_64::instructions::tlb::flush_all();
+    Ok(())
 }
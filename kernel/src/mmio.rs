@@ -0,0 +1,88 @@
+use crate::memory;
+use core::marker::PhantomData;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The register widths devices use.
+pub trait Width: Copy {}
+impl Width for u8 {}
+impl Width for u16 {}
+impl Width for u32 {}
+impl Width for u64 {}
+
+/// A device register at a fixed address. Every access is volatile, so the compiler neither merges
+/// nor drops them, and is the full width of `T`, as devices expect.
+#[derive(Clone, Copy)]
+pub struct Mmio<T: Width> {
+    addr: VirtAddr,
+    width: PhantomData<T>,
+}
+
+impl<T: Width> Mmio<T> {
+    /// Fails if `addr` isn't aligned to the register's width, which devices don't allow. `addr`
+    /// must be where device memory is mapped, e.g. by `memory::map_mmio`.
+    pub unsafe fn new(addr: VirtAddr) -> Result<Self, &'static str> {
+        if !addr.is_aligned(core::mem::size_of::<T>() as u64) {
+            return Err("unaligned register");
+        }
+        Ok(Mmio {
+            addr,
+            width: PhantomData,
+        })
+    }
+    pub fn read(&self) -> T {
+        unsafe { self.addr.as_ptr::<T>().read_volatile() }
+    }
+    pub fn write(&self, value: T) {
+        unsafe { self.addr.as_mut_ptr::<T>().write_volatile(value) }
+    }
+}
+
+/// A block of device registers, accessed by their offset from its start.
+#[derive(Clone, Copy)]
+pub struct Registers {
+    base: VirtAddr,
+    size: usize,
+}
+
+impl Registers {
+    /// `size` bytes of device memory must be mapped at `base`.
+    pub const unsafe fn new(base: VirtAddr, size: usize) -> Self {
+        Registers { base, size }
+    }
+    /// Maps the `size` bytes of registers at `phys_addr` with `memory::map_mmio`.
+    pub fn map(phys_addr: PhysAddr, size: usize) -> Result<Self, &'static str> {
+        let base = memory::map_mmio(phys_addr, size)?;
+        Ok(unsafe { Registers::new(base, size) })
+    }
+    /// The `size` bytes at `offset` as a block of their own, e.g. one port of a controller.
+    pub fn block(&self, offset: usize, size: usize) -> Registers {
+        assert!(
+            offset + size <= self.size,
+            "block {:#x}+{:#x} outside block of {:#x} bytes",
+            offset,
+            size,
+            self.size
+        );
+        Registers {
+            base: self.base + offset,
+            size,
+        }
+    }
+    /// The register at `offset`. Panics if it's outside the block or unaligned, which is a bug in
+    /// the driver's register offsets.
+    pub fn at<T: Width>(&self, offset: usize) -> Mmio<T> {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.size,
+            "register {:#x} outside block of {:#x} bytes",
+            offset,
+            self.size
+        );
+        unsafe { Mmio::new(self.base + offset) }.expect("unaligned register offset")
+    }
+    pub fn read<T: Width>(&self, offset: usize) -> T {
+        self.at(offset).read()
+    }
+    pub fn write<T: Width>(&self, offset: usize, value: T) {
+        self.at(offset).write(value)
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{event_queue::EventQueue, sync::IrqSafeSpinLock};
+use x86_64::instructions::port::Port;
+
+pub const LEFT_BUTTON: u8 = 1 << 0;
+pub const RIGHT_BUTTON: u8 = 1 << 1;
+pub const MIDDLE_BUTTON: u8 = 1 << 2;
+
+/// Movement since the previous event and the buttons held now.
+#[derive(Debug, Clone, Copy)]
+pub struct MouseEvent {
+    /// Positive to the right.
+    pub dx: i16,
+    /// Positive downwards, like screen coordinates.
+    pub dy: i16,
+    /// `LEFT_BUTTON`, `RIGHT_BUTTON` and `MIDDLE_BUTTON`.
+    pub buttons: u8,
+    /// Wheel clicks, positive towards the user. Always 0 for mice without a wheel.
+    pub scroll: i8,
+}
+
+const QUEUE_SIZE: usize = 64;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// Controller commands, written to the status port.
+const CONTROLLER_READ_CONFIG: u8 = 0x20;
+const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+const CONTROLLER_ENABLE_AUX: u8 = 0xa8;
+const CONTROLLER_WRITE_AUX: u8 = 0xd4;
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+// Mouse commands, sent through `CONTROLLER_WRITE_AUX`.
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xf3;
+const MOUSE_GET_ID: u8 = 0xf2;
+const MOUSE_ACK: u8 = 0xfa;
+/// Reported by `MOUSE_GET_ID` once the wheel is enabled.
+const MOUSE_ID_WHEEL: u8 = 3;
+
+// Bits of the first packet byte.
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// How many status polls to wait for the controller before giving up.
+const TIMEOUT: usize = 100_000;
+
+struct PacketDecoder {
+    bytes: [u8; 4],
+    len: usize,
+    /// 4 with a wheel, 3 otherwise.
+    packet_size: usize,
+}
+
+impl PacketDecoder {
+    /// Adds a byte from the mouse, returning the event once a packet is complete.
+    fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // A first byte without the always-one bit means a byte was lost. Drop bytes until the
+        // stream lines up with a packet again.
+        if self.len == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_size {
+            return None;
+        }
+        self.len = 0;
+        let flags = self.bytes[0];
+        // Movement is 9 bit two's complement, with the sign bit in the first byte. On overflow the
+        // value is meaningless, so take the largest movement in the sign's direction.
+        let axis = |value: u8, sign: u8, overflow: u8| -> i16 {
+            let negative = flags & sign != 0;
+            match (flags & overflow != 0, negative) {
+                (true, true) => -256,
+                (true, false) => 255,
+                (false, true) => value as i16 - 256,
+                (false, false) => value as i16,
+            }
+        };
+        let dx = axis(self.bytes[1], PACKET_X_SIGN, PACKET_X_OVERFLOW);
+        let dy = axis(self.bytes[2], PACKET_Y_SIGN, PACKET_Y_OVERFLOW);
+        // The wheel is a 4 bit two's complement value.
+        let scroll = if self.packet_size == 4 {
+            ((self.bytes[3] << 4) as i8) >> 4
+        } else {
+            0
+        };
+        Some(MouseEvent {
+            dx,
+            dy: -dy,
+            buttons: flags & (LEFT_BUTTON | RIGHT_BUTTON | MIDDLE_BUTTON),
+            scroll,
+        })
+    }
+}
+
+static mut DECODER: PacketDecoder = PacketDecoder {
+    bytes: [0; 4],
+    len: 0,
+    packet_size: 3,
+};
+static QUEUE: IrqSafeSpinLock<EventQueue<MouseEvent, QUEUE_SIZE>> =
+    IrqSafeSpinLock::new(EventQueue::new());
+
+fn status() -> u8 {
+    unsafe { Port::new(STATUS_PORT).read() }
+}
+
+fn wait_for_input() -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err("PS/2 controller timed out")
+}
+
+fn read_data() -> Result<u8, &'static str> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            return Ok(unsafe { Port::new(DATA_PORT).read() });
+        }
+    }
+    Err("PS/2 controller timed out")
+}
+
+fn write_controller(command: u8) -> Result<(), &'static str> {
+    wait_for_input()?;
+    unsafe { Port::new(STATUS_PORT).write(command) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), &'static str> {
+    wait_for_input()?;
+    unsafe { Port::new(DATA_PORT).write(byte) };
+    Ok(())
+}
+
+fn mouse_command(command: u8) -> Result<(), &'static str> {
+    write_controller(CONTROLLER_WRITE_AUX)?;
+    write_data(command)?;
+    match read_data()? {
+        MOUSE_ACK => Ok(()),
+        _ => Err("mouse didn't acknowledge a command"),
+    }
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), &'static str> {
+    mouse_command(MOUSE_SET_SAMPLE_RATE)?;
+    mouse_command(rate)
+}
+
+/// Enables the PS/2 auxiliary device and its interrupt, and turns on the scroll wheel if there is
+/// one. Must be called with interrupts disabled, before IRQ12 is unmasked.
+pub fn init() -> Result<(), &'static str> {
+    write_controller(CONTROLLER_ENABLE_AUX)?;
+    write_controller(CONTROLLER_READ_CONFIG)?;
+    let config = read_data()?;
+    write_controller(CONTROLLER_WRITE_CONFIG)?;
+    write_data((config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED)?;
+
+    mouse_command(MOUSE_SET_DEFAULTS)?;
+    // This sequence of sample rates is the signal to enable the wheel. Mice without one ignore it
+    // and keep reporting ID 0.
+    for rate in [200, 100, 80] {
+        set_sample_rate(rate)?;
+    }
+    mouse_command(MOUSE_GET_ID)?;
+    let wheel = read_data()? == MOUSE_ID_WHEEL;
+    set_sample_rate(100)?;
+    mouse_command(MOUSE_ENABLE_REPORTING)?;
+    unsafe {
+        DECODER.packet_size = if wheel { 4 } else { 3 };
+    }
+    log::debug!("PS/2 mouse{}", if wheel { " with wheel" } else { "" });
+    Ok(())
+}
+
+/// Reads a byte from the mouse and queues an event once a packet is complete. Called from the
+/// IRQ12 handler.
+pub fn handle_interrupt() {
+    let byte: u8 = unsafe { Port::new(DATA_PORT).read() };
+    unsafe {
+        if let Some(event) = DECODER.add_byte(byte) {
+            QUEUE.lock().push(event);
+        }
+    }
+}
+
+/// Takes the oldest mouse event from the queue.
+pub fn poll_event() -> Option<MouseEvent> {
+    QUEUE.lock().pop()
+}
@@ -0,0 +1,432 @@
+use crate::memory::DYNAMIC_RANGE_START;
+use bootloader_api::{
+    info::{FrameBuffer, FrameBufferInfo, MemoryRegion, MemoryRegionKind, PixelFormat},
+    BootInfo,
+};
+use core::arch::global_asm;
+use x86_64::{
+    registers::{
+        control::Cr3,
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+/// In the header, so GRUB knows the kernel can be loaded with Multiboot2.
+const HEADER_MAGIC: u32 = 0xe852_50d6;
+/// In EAX when GRUB jumps to the entry point.
+const LOADER_MAGIC: u32 = 0x36d7_6289;
+
+/// The kernel is loaded at this physical address and linked this far above it, in the top 2 GiB
+/// (see multiboot.ld).
+const KERNEL_LOAD_ADDR: u64 = 0x10_0000;
+const KERNEL_OFFSET: u64 = 0xffff_ffff_8000_0000;
+/// Where the entry code maps physical memory, the same place `BOOTLOADER_CONFIG` asks the
+/// bootloader crate for.
+const PHYSICAL_MEMORY_OFFSET: u64 = 0xf000_0000_0000;
+/// How much of physical memory the entry code maps, with 2 MiB pages. RAM above it isn't used.
+const MAPPED_MEMORY: u64 = 4 << 30;
+
+const STACK_SIZE: usize = 128 * 1024;
+
+const TAG_CMDLINE: u32 = 1;
+const TAG_LOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+const MEMORY_AVAILABLE: u32 = 1;
+const FRAMEBUFFER_DIRECT_RGB: u8 = 1;
+
+/// Regions past this many aren't passed on, and modules past `MAX_MODULES` aren't kept out of
+/// the usable memory.
+const MAX_REGIONS: usize = 64;
+const MAX_MODULES: usize = 8;
+/// Page tables for mapping the framebuffer, enough for 16 MiB.
+const FRAMEBUFFER_PAGE_TABLES: usize = 8;
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+static mut STACK: Stack = Stack([0; STACK_SIZE]);
+// Fixed arrays, because they're filled before the heap exists.
+static mut MEMORY_REGIONS: [MemoryRegion; MAX_REGIONS] = [MemoryRegion::empty(); MAX_REGIONS];
+static mut BOOT_INFO: Option<BootInfo> = None;
+const EMPTY_TABLE: PageTable = PageTable::new();
+/// A PDPT, a page directory and the page tables under it.
+static mut FRAMEBUFFER_TABLES: [PageTable; FRAMEBUFFER_PAGE_TABLES + 2] =
+    [EMPTY_TABLE; FRAMEBUFFER_PAGE_TABLES + 2];
+
+// GRUB runs the entry point in 32-bit protected mode without paging, so it's linked at its
+// physical address. It identity maps the first 4 GiB, during the switch to long mode, and maps
+// it again at the physical memory offset and, for the kernel, at -2 GiB, all with the same page
+// directories. The kernel half of the page tables is shared with every address space, so the
+// identity mapping is removed by `multiboot_main` before anything runs in the lower half.
+global_asm!(
+    r#"
+.pushsection .multiboot_header, "a"
+.align 8
+multiboot_header:
+    .long {header_magic}
+    .long 0
+    .long multiboot_header_end - multiboot_header
+    .long 0x100000000 - {header_magic} - (multiboot_header_end - multiboot_header)
+    // Entry address
+    .align 8
+    .word 3, 0
+    .long 12
+    .long multiboot_start
+    // Framebuffer, of the same size the bootloader crate is asked for. GRUB picks one close to it.
+    .align 8
+    .word 5, 0
+    .long 20
+    .long {width}, {height}, 32
+    // End
+    .align 8
+    .word 0, 0
+    .long 8
+multiboot_header_end:
+.popsection
+
+.pushsection .boot.text, "ax"
+.code32
+.global multiboot_start
+multiboot_start:
+    cli
+    mov edi, eax
+    mov esi, ebx
+    xor ecx, ecx
+2:
+    mov eax, ecx
+    shl eax, 21
+    or eax, 0x83
+    mov dword ptr [ecx * 8 + boot_pd], eax
+    inc ecx
+    cmp ecx, 2048
+    jne 2b
+    xor ecx, ecx
+    mov eax, offset boot_pd + 3
+3:
+    mov dword ptr [ecx * 8 + boot_pdpt], eax
+    add eax, 4096
+    inc ecx
+    cmp ecx, 4
+    jne 3b
+    mov eax, offset boot_pd + 3
+    mov dword ptr [boot_pdpt_kernel + 510 * 8], eax
+    mov eax, offset boot_pdpt + 3
+    mov dword ptr [boot_pml4], eax
+    mov dword ptr [boot_pml4 + {physical_index} * 8], eax
+    mov eax, offset boot_pdpt_kernel + 3
+    mov dword ptr [boot_pml4 + 511 * 8], eax
+    mov eax, offset boot_pml4
+    mov cr3, eax
+    // PAE, then long mode in EFER, then paging.
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, 1 << 8
+    wrmsr
+    mov eax, cr0
+    or eax, 1 << 31
+    mov cr0, eax
+    lgdt [boot_gdt_pointer]
+    mov eax, offset multiboot_long
+    push 8
+    push eax
+    retf
+.code64
+multiboot_long:
+    lgdt [rip + boot_gdt_pointer_high]
+    movabs rax, offset multiboot_high
+    jmp rax
+.popsection
+
+.pushsection .boot.data, "aw"
+.align 8
+boot_gdt:
+    .quad 0
+    // 64-bit code
+    .quad 0x00af9a000000ffff
+boot_gdt_pointer:
+    .word 15
+    .long boot_gdt
+// The identity mapping of the GDT goes away, so it's loaded again from the physical memory
+// mapping.
+boot_gdt_pointer_high:
+    .word 15
+    .quad boot_gdt + {physical_offset}
+.popsection
+
+.pushsection .boot.bss, "aw", @nobits
+.align 4096
+boot_pml4:
+    .skip 4096
+boot_pdpt:
+    .skip 4096
+boot_pdpt_kernel:
+    .skip 4096
+boot_pd:
+    .skip 4096 * 4
+.popsection
+
+.pushsection .text.multiboot_high, "ax"
+multiboot_high:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    lea rsp, [rip + {stack} + {stack_size}]
+    xor ebp, ebp
+    call {main}
+    ud2
+.popsection
+"#,
+    header_magic = const HEADER_MAGIC,
+    width = const crate::SCREEN_WIDTH,
+    height = const crate::SCREEN_HEIGHT,
+    physical_index = const (PHYSICAL_MEMORY_OFFSET >> 39) & 511,
+    physical_offset = const PHYSICAL_MEMORY_OFFSET,
+    stack = sym STACK,
+    stack_size = const STACK_SIZE,
+    main = sym multiboot_main,
+);
+
+extern "C" {
+    /// The end of the kernel image, from multiboot.ld.
+    static kernel_end: u8;
+}
+
+/// One tag of the boot information GRUB passes.
+struct Tag {
+    kind: u32,
+    /// Physical address of `data`.
+    addr: u64,
+    /// What follows the type and size.
+    data: &'static [u8],
+}
+
+fn phys_ptr<T>(addr: u64) -> *mut T {
+    (PHYSICAL_MEMORY_OFFSET + addr) as *mut T
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// The string up to the first NUL. Empty if it isn't UTF-8.
+fn c_str(data: &'static [u8]) -> &'static str {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    core::str::from_utf8(&data[..len]).unwrap_or("")
+}
+
+/// The tags of the boot information at physical address `info`, up to the end tag.
+fn tags(info: u64) -> impl Iterator<Item = Tag> {
+    let total_size = unsafe { phys_ptr::<u32>(info).read() } as u64;
+    let mut offset = 8;
+    core::iter::from_fn(move || {
+        if offset + 8 > total_size {
+            return None;
+        }
+        let addr = info + offset;
+        let (kind, size) = unsafe {
+            (
+                phys_ptr::<u32>(addr).read(),
+                phys_ptr::<u32>(addr + 4).read(),
+            )
+        };
+        let size = size as u64;
+        if kind == 0 || size < 8 || offset + size > total_size {
+            return None;
+        }
+        // Tags start 8 byte aligned.
+        offset += (size + 7) & !7;
+        let data = unsafe { core::slice::from_raw_parts(phys_ptr(addr + 8), size as usize - 8) };
+        Some(Tag {
+            kind,
+            addr: addr + 8,
+            data,
+        })
+    })
+}
+
+/// Turns the memory map into `MemoryRegions` like the bootloader crate's. The kernel image, the
+/// boot information and modules are marked as the bootloader's, so they're kept, and so is the
+/// first MiB, where the BIOS keeps its data.
+fn memory_regions(info: u64, modules: &[(u64, u64)]) -> &'static mut [MemoryRegion] {
+    let info_size = unsafe { phys_ptr::<u32>(info).read() } as u64;
+    let image_end = unsafe { &kernel_end as *const u8 as u64 } - KERNEL_OFFSET;
+    let mut reserved = [(0, 0); MAX_MODULES + 3];
+    reserved[0] = (0, KERNEL_LOAD_ADDR);
+    reserved[1] = (KERNEL_LOAD_ADDR, image_end);
+    reserved[2] = (info, info + info_size);
+    for (slot, &module) in reserved[3..].iter_mut().zip(modules) {
+        *slot = module;
+    }
+    for range in &mut reserved {
+        *range = (range.0 & !0xfff, (range.1 + 0xfff) & !0xfff);
+    }
+    reserved.sort_unstable();
+
+    let regions = unsafe { &mut MEMORY_REGIONS };
+    let mut count = 0;
+    let mut push = |start: u64, end: u64, kind: MemoryRegionKind| {
+        if start < end && count < MAX_REGIONS {
+            regions[count] = MemoryRegion { start, end, kind };
+            count += 1;
+        }
+    };
+    let Some(map) = tags(info).find(|tag| tag.kind == TAG_MEMORY_MAP) else {
+        return &mut [];
+    };
+    let entry_size = u32_at(map.data, 0) as usize;
+    if entry_size < 20 {
+        return &mut [];
+    }
+    for entry in map.data[8..].chunks_exact(entry_size) {
+        let (start, len, kind) = (u64_at(entry, 0), u64_at(entry, 8), u32_at(entry, 16));
+        if kind != MEMORY_AVAILABLE {
+            push(start, start + len, MemoryRegionKind::UnknownBios(kind));
+            continue;
+        }
+        // Only whole frames are usable.
+        let end = (start + len).min(MAPPED_MEMORY) & !0xfff;
+        let start = (start + 0xfff) & !0xfff;
+        let mut next = start;
+        for &(reserved_start, reserved_end) in &reserved {
+            if reserved_end <= next || end <= reserved_start {
+                continue;
+            }
+            push(next, reserved_start, MemoryRegionKind::Usable);
+            next = reserved_start.max(next);
+            push(next, reserved_end.min(end), MemoryRegionKind::Bootloader);
+            next = reserved_end.min(end);
+        }
+        push(next, end, MemoryRegionKind::Usable);
+    }
+    &mut regions[..count]
+}
+
+/// Maps the framebuffer with 4 KiB pages at the start of the dynamic range, where the bootloader
+/// crate maps it too. Its memory isn't RAM, so it isn't kept in the physical memory mapping, and
+/// that has 2 MiB pages, which `memory::make_range_user_accessible` can't handle.
+fn map_framebuffer(phys_addr: PhysAddr, len: usize) -> Option<VirtAddr> {
+    let first_frame = phys_addr.align_down(4096u64);
+    let offset = phys_addr - first_frame;
+    let pages = (offset as usize + len + 4095) / 4096;
+    if pages > FRAMEBUFFER_PAGE_TABLES * 512 {
+        return None;
+    }
+    let start = VirtAddr::new(DYNAMIC_RANGE_START);
+    let table_addr = |table: &PageTable| PhysAddr::new(table as *const _ as u64 - KERNEL_OFFSET);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        let level_4_table = &mut *phys_ptr::<PageTable>(Cr3::read().0.start_address().as_u64());
+        let [pdpt, directory, page_tables @ ..] = &mut FRAMEBUFFER_TABLES;
+        level_4_table[start.p4_index()].set_addr(table_addr(pdpt), flags);
+        pdpt[start.p3_index()].set_addr(table_addr(directory), flags);
+        for (index, table) in page_tables.iter_mut().enumerate().take((pages + 511) / 512) {
+            directory[usize::from(start.p2_index()) + index].set_addr(table_addr(table), flags);
+        }
+        for page in 0..pages {
+            page_tables[page / 512][page % 512].set_addr(first_frame + (page * 4096) as u64, flags);
+        }
+    }
+    Some(start + offset)
+}
+
+fn framebuffer(tag: &Tag) -> Option<FrameBuffer> {
+    let data = tag.data;
+    if data.len() < 30 || data[21] != FRAMEBUFFER_DIRECT_RGB || data[20] % 8 != 0 {
+        return None;
+    }
+    let pitch = u32_at(data, 8) as usize;
+    let height = u32_at(data, 16) as usize;
+    let bytes_per_pixel = data[20] as usize / 8;
+    // Bit positions of red, green and blue.
+    let pixel_format = match (data[24], data[26], data[28]) {
+        (0, 8, 16) => PixelFormat::Rgb,
+        (16, 8, 0) => PixelFormat::Bgr,
+        (red_position, green_position, blue_position) => PixelFormat::Unknown {
+            red_position,
+            green_position,
+            blue_position,
+        },
+    };
+    let byte_len = pitch * height;
+    let start = map_framebuffer(PhysAddr::new(u64_at(data, 0)), byte_len)?;
+    let info = FrameBufferInfo {
+        byte_len,
+        width: u32_at(data, 12) as usize,
+        height,
+        pixel_format,
+        bytes_per_pixel,
+        stride: pitch / bytes_per_pixel,
+    };
+    Some(unsafe { FrameBuffer::new(start.as_u64(), info) })
+}
+
+/// Where the entry code calls into Rust, on the kernel stack, with the magic value and physical
+/// address of the boot information GRUB passed. Builds the `BootInfo` the bootloader crate would
+/// have, then starts the kernel like `kernel_main`.
+extern "C" fn multiboot_main(magic: u32, info: u32) -> ! {
+    if magic != LOADER_MAGIC {
+        crate::hlt_loop();
+    }
+    let info = info as u64;
+    unsafe {
+        let level_4_table = &mut *phys_ptr::<PageTable>(Cr3::read().0.start_address().as_u64());
+        level_4_table[0].set_unused();
+        x86_64::instructions::tlb::flush_all();
+        // The bootloader crate enables it, and the kernel expects it if the CPU has it.
+        if core::arch::x86_64::__cpuid(0x8000_0001).edx & (1 << 20) != 0 {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+    }
+
+    let mut modules = [(0, 0); MAX_MODULES];
+    let mut module_count = 0;
+    for tag in tags(info).filter(|tag| tag.kind == TAG_MODULE) {
+        if module_count < MAX_MODULES {
+            modules[module_count] = (u32_at(tag.data, 0) as u64, u32_at(tag.data, 4) as u64);
+            module_count += 1;
+        }
+    }
+    let mut boot_info = BootInfo::new(memory_regions(info, &modules[..module_count]).into());
+    boot_info.physical_memory_offset = Some(PHYSICAL_MEMORY_OFFSET).into();
+    // The first module is the userspace program, like the bootloader crate's ramdisk.
+    if module_count > 0 {
+        let (start, end) = modules[0];
+        boot_info.ramdisk_addr = Some(PHYSICAL_MEMORY_OFFSET + start).into();
+        boot_info.ramdisk_len = end - start;
+    }
+    let mut loader_name = "Multiboot2";
+    let mut cmdline = None;
+    for tag in tags(info) {
+        match tag.kind {
+            TAG_CMDLINE => cmdline = Some(c_str(tag.data)),
+            TAG_LOADER_NAME => loader_name = c_str(tag.data),
+            TAG_FRAMEBUFFER => boot_info.framebuffer = framebuffer(&tag).into(),
+            // GRUB copies the RSDP into the tag. The new one is preferred, it has the XSDT.
+            TAG_ACPI_NEW => boot_info.rsdp_addr = Some(tag.addr).into(),
+            TAG_ACPI_OLD if boot_info.rsdp_addr.into_option().is_none() => {
+                boot_info.rsdp_addr = Some(tag.addr).into()
+            }
+            _ => {}
+        }
+    }
+    match cmdline.filter(|cmdline| !cmdline.is_empty()) {
+        Some(cmdline) => crate::cmdline::set(cmdline),
+        None => crate::cmdline::init(),
+    }
+    let boot_info = unsafe { BOOT_INFO.insert(boot_info) };
+    crate::start(boot_info, Some(loader_name));
+}
@@ -0,0 +1,173 @@
+use crate::mmio::Registers;
+use alloc::vec::Vec;
+use x86_64::{
+    instructions::port::{Port, PortWriteOnly},
+    PhysAddr,
+};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const REG_ID: u8 = 0x00;
+const REG_COMMAND: u8 = 0x04;
+const REG_CLASS: u8 = 0x08;
+const REG_HEADER_TYPE: u8 = 0x0c;
+const REG_BAR0: u8 = 0x10;
+
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b110;
+const BAR_TYPE_64: u32 = 0b100;
+const BAR_ADDRESS_MASK: u32 = !0xf;
+const HEADER_TYPE_MULTIFUNCTION: u8 = 1 << 7;
+/// Read from the vendor ID of a function that doesn't exist.
+const NO_VENDOR: u16 = 0xffff;
+
+/// A PCI function found by `init`.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    0x8000_0000
+        | (bus as u32) << 16
+        | (device as u32) << 11
+        | (function as u32) << 8
+        | (offset as u32 & 0xfc)
+}
+
+fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    unsafe {
+        PortWriteOnly::<u32>::new(CONFIG_ADDRESS)
+            .write(config_address(bus, device, function, offset));
+        Port::<u32>::new(CONFIG_DATA).read()
+    }
+}
+
+fn write_config(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    unsafe {
+        PortWriteOnly::<u32>::new(CONFIG_ADDRESS)
+            .write(config_address(bus, device, function, offset));
+        Port::<u32>::new(CONFIG_DATA).write(value);
+    }
+}
+
+impl PciDevice {
+    /// The 32-bit configuration register at `offset`, which is rounded down to a multiple of 4.
+    pub fn read_config(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.device, self.function, offset)
+    }
+    pub fn write_config(&self, offset: u8, value: u32) {
+        write_config(self.bus, self.device, self.function, offset, value)
+    }
+
+    /// Base address register `index`, 0 to 5, as stored: bit 0 is set for I/O space.
+    pub fn bar(&self, index: u8) -> u32 {
+        self.read_config(REG_BAR0 + index * 4)
+    }
+
+    /// Maps the first `size` bytes of the memory space behind base address register `index`. A
+    /// 64-bit BAR takes `index` and the next one.
+    pub fn map_bar(&self, index: u8, size: usize) -> Result<Registers, &'static str> {
+        let low = self.bar(index);
+        if low & BAR_IO != 0 {
+            return Err("BAR is in I/O space");
+        }
+        let mut address = (low & BAR_ADDRESS_MASK) as u64;
+        if low & BAR_TYPE_MASK == BAR_TYPE_64 {
+            if index >= 5 {
+                return Err("64-bit BAR has no upper half");
+            }
+            address |= (self.bar(index + 1) as u64) << 32;
+        }
+        if address == 0 {
+            return Err("BAR isn't assigned");
+        }
+        Registers::map(PhysAddr::new(address), size)
+    }
+
+    /// Lets the device start DMA transfers.
+    pub fn enable_bus_master(&self) {
+        let command = self.read_config(REG_COMMAND);
+        self.write_config(REG_COMMAND, command | COMMAND_BUS_MASTER);
+    }
+}
+
+impl core::fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}:{:02x}:{:02x}",
+            self.bus,
+            self.device,
+            self.function,
+            self.vendor_id,
+            self.device_id,
+            self.class,
+            self.subclass,
+            self.prog_if
+        )
+    }
+}
+
+fn probe(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+    let id = read_config(bus, device, function, REG_ID);
+    if id as u16 == NO_VENDOR {
+        return None;
+    }
+    let class = read_config(bus, device, function, REG_CLASS);
+    Some(PciDevice {
+        bus,
+        device,
+        function,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+    })
+}
+
+static mut DEVICES: Vec<PciDevice> = Vec::new();
+
+/// Finds every function on every PCI bus through the configuration ports.
+pub fn init() {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let Some(first) = probe(bus, device, 0) else {
+                continue;
+            };
+            devices.push(first);
+            // Functions other than 0 only exist on multifunction devices.
+            let header_type = (read_config(bus, device, 0, REG_HEADER_TYPE) >> 16) as u8;
+            if header_type & HEADER_TYPE_MULTIFUNCTION != 0 {
+                devices.extend((1..8).filter_map(|function| probe(bus, device, function)));
+            }
+        }
+    }
+    for device in &devices {
+        log::debug!("PCI {}", device);
+    }
+    unsafe { DEVICES = devices };
+}
+
+/// All functions found by `init`.
+#[allow(dead_code)]
+pub fn devices() -> &'static [PciDevice] {
+    unsafe { &DEVICES }
+}
+
+/// The functions with the given class and subclass codes.
+pub fn find_by_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+    unsafe { DEVICES.iter() }
+        .filter(move |device| device.class == class && device.subclass == subclass)
+}
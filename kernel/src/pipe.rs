@@ -0,0 +1,142 @@
+use crate::scheduler;
+use alloc::{collections::VecDeque, rc::Rc, vec::Vec};
+use core::cell::RefCell;
+
+/// How many bytes a pipe holds before writers have to wait for a reader.
+const PIPE_CAPACITY: usize = 4096;
+
+struct Pipe {
+    buffer: VecDeque<u8>,
+    /// Open read and write ends, counting every process' copy.
+    readers: usize,
+    writers: usize,
+    /// Processes blocked on the pipe, woken whenever something changes so they can check again.
+    waiting: Vec<usize>,
+}
+
+impl Pipe {
+    fn wake_all(&mut self) {
+        for id in self.waiting.drain(..) {
+            scheduler::wake(id);
+        }
+    }
+}
+
+/// Blocks the current process until something changes in `pipe`.
+fn wait(pipe: &RefCell<Pipe>) {
+    pipe.borrow_mut().waiting.push(scheduler::current_id());
+    scheduler::block();
+}
+
+/// The read end of a pipe.
+pub struct PipeReader(Rc<RefCell<Pipe>>);
+/// The write end of a pipe.
+pub struct PipeWriter(Rc<RefCell<Pipe>>);
+
+/// Every read end of the pipe was closed, so nothing written would ever be read.
+#[derive(Debug)]
+pub struct BrokenPipe;
+
+/// Creates a pipe, returning its two ends.
+pub fn new() -> (PipeReader, PipeWriter) {
+    let pipe = Rc::new(RefCell::new(Pipe {
+        buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+        readers: 1,
+        writers: 1,
+        waiting: Vec::new(),
+    }));
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+impl PipeReader {
+    /// Reads up to `buf.len()` bytes, blocking until there are any. Returns 0 once the pipe is
+    /// empty and every write end is closed.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        loop {
+            {
+                let mut pipe = self.0.borrow_mut();
+                if !pipe.buffer.is_empty() {
+                    let len = buf.len().min(pipe.buffer.len());
+                    for (dest, byte) in buf.iter_mut().zip(pipe.buffer.drain(..len)) {
+                        *dest = byte;
+                    }
+                    pipe.wake_all();
+                    return len;
+                }
+                // Returns to the syscall so a terminated process can exit.
+                if pipe.writers == 0 || scheduler::terminated() {
+                    return 0;
+                }
+            }
+            wait(&self.0);
+        }
+    }
+}
+
+impl PipeWriter {
+    /// Writes all of `data`, blocking while the pipe is full. Fails if every read end is closed
+    /// before anything was written; if that happens partway, returns how much was.
+    pub fn write(&self, data: &[u8]) -> Result<usize, BrokenPipe> {
+        let mut written = 0;
+        loop {
+            {
+                let mut pipe = self.0.borrow_mut();
+                if pipe.readers == 0 {
+                    return if written > 0 {
+                        Ok(written)
+                    } else {
+                        Err(BrokenPipe)
+                    };
+                }
+                let len = (PIPE_CAPACITY - pipe.buffer.len()).min(data.len() - written);
+                pipe.buffer.extend(&data[written..written + len]);
+                written += len;
+                if len > 0 {
+                    pipe.wake_all();
+                }
+                if written == data.len() || scheduler::terminated() {
+                    return Ok(written);
+                }
+            }
+            wait(&self.0);
+        }
+    }
+}
+
+impl Clone for PipeReader {
+    fn clone(&self) -> Self {
+        self.0.borrow_mut().readers += 1;
+        PipeReader(self.0.clone())
+    }
+}
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        self.0.borrow_mut().writers += 1;
+        PipeWriter(self.0.clone())
+    }
+}
+
+impl Drop for PipeReader {
+    /// Wakes blocked writers once the last read end is gone, so they fail instead of waiting
+    /// forever.
+    fn drop(&mut self) {
+        let mut pipe = self.0.borrow_mut();
+        pipe.readers -= 1;
+        if pipe.readers == 0 {
+            pipe.wake_all();
+        }
+    }
+}
+impl Drop for PipeWriter {
+    /// Wakes blocked readers once the last write end is gone, so they see the end of the data.
+    fn drop(&mut self) {
+        let mut pipe = self.0.borrow_mut();
+        pipe.writers -= 1;
+        if pipe.writers == 0 {
+            pipe.wake_all();
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use crate::time;
+
+/// Phases after this many aren't kept for the summary, but are still logged.
+const MAX_PHASES: usize = 16;
+
+// A fixed array, because the first phases run before the heap exists.
+static mut PHASES: [(&str, u64); MAX_PHASES] = [("", 0); MAX_PHASES];
+static mut PHASE_COUNT: usize = 0;
+
+/// Logs how long it was alive when dropped, and keeps it for `log_summary`. Does nothing if it was
+/// created before the clock was running, since it would report 0.
+pub struct ScopedTimer {
+    name: &'static str,
+    start: Option<u64>,
+}
+
+impl ScopedTimer {
+    pub fn new(name: &'static str) -> Self {
+        ScopedTimer {
+            name,
+            start: time::clock_running().then(time::now_ns),
+        }
+    }
+}
+
+impl Drop for ScopedTimer {
+    fn drop(&mut self) {
+        let Some(start) = self.start else {
+            return;
+        };
+        let nanos = time::now_ns().saturating_sub(start);
+        log::debug!("{} took {}", self.name, Millis(nanos));
+        unsafe {
+            if PHASE_COUNT < MAX_PHASES {
+                PHASES[PHASE_COUNT] = (self.name, nanos);
+                PHASE_COUNT += 1;
+            }
+        }
+    }
+}
+
+/// Runs `f` inside a `ScopedTimer` named `name`.
+pub fn measure<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let _timer = ScopedTimer::new(name);
+    f()
+}
+
+/// Formats nanoseconds as milliseconds with three decimals.
+struct Millis(u64);
+
+impl core::fmt::Display for Millis {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}.{:03} ms", self.0 / 1_000_000, self.0 / 1000 % 1000)
+    }
+}
+
+fn log_row(name: &str, nanos: u64, width: usize) {
+    log::info!(
+        "  {:width$} {:>6}.{:03} ms",
+        name,
+        nanos / 1_000_000,
+        nanos / 1000 % 1000
+    );
+}
+
+/// Logs a table of how long each timed phase took.
+pub fn log_summary() {
+    let phases = unsafe { &PHASES[..PHASE_COUNT] };
+    if phases.is_empty() {
+        return;
+    }
+    let width = phases
+        .iter()
+        .map(|(name, _)| name.len())
+        .fold("total".len(), usize::max);
+    log::info!("Init times:");
+    for (name, nanos) in phases {
+        log_row(name, *nanos, width);
+    }
+    log_row("total", phases.iter().map(|(_, nanos)| nanos).sum(), width);
+}
@@ -0,0 +1,170 @@
+use crate::{
+    cmdline,
+    elf_loader::{self, ElfError},
+    filesystem::{self, FsError},
+    memory::{self, AddressSpace, PAGE_SIZE, PROGRAM_LOAD_BASE, USER_SPACE_END},
+};
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+use x86_64::VirtAddr;
+
+/// How much of the user stack the argument strings and pointers may take up, and never more than
+/// half of it.
+const MAX_ARGS_SIZE: usize = PAGE_SIZE;
+const AT_NULL: u64 = 0;
+
+struct Program {
+    name: &'static str,
+    data: &'static [u8],
+}
+
+static mut PROGRAMS: Vec<Program> = Vec::new();
+
+enum Source {
+    Memory(&'static [u8]),
+    File(filesystem::File),
+}
+
+/// A program loaded into its own address space, ready to be started by
+/// `userspace::enter_userspace`.
+pub struct LoadedProgram {
+    pub address_space: Box<AddressSpace>,
+    pub entry_point: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    /// argc, argv and envp, passed in `rdi`, `rsi` and `rdx`.
+    pub args: [u64; 3],
+}
+
+/// Makes an ELF file that is already in memory available to `load_program`.
+pub fn add_program(name: &'static str, data: &'static [u8]) {
+    unsafe {
+        PROGRAMS.push(Program { name, data });
+    }
+}
+
+/// Names of all programs that can be loaded.
+pub fn program_names() -> impl Iterator<Item = &'static str> {
+    unsafe { PROGRAMS.iter().map(|program| program.name) }
+}
+
+/// Loads the named program into a new address space, with its name as the only argument and a
+/// stack of the default size.
+pub fn load_program(name: &str) -> Result<LoadedProgram, &'static str> {
+    load_program_with_args(name, &[name], memory::DEFAULT_USER_STACK_SIZE)
+}
+
+/// Loads the named program into a new address space and puts `args` on its stack, which has at
+/// least `stack_size` bytes and at least a page. Programs added with `add_program` are found
+/// first, then files on the filesystem.
+pub fn load_program_with_args(
+    name: &str,
+    args: &[&str],
+    stack_size: usize,
+) -> Result<LoadedProgram, &'static str> {
+    if stack_size > memory::MAX_USER_STACK_SIZE {
+        return Err("stack size too large");
+    }
+    let source = match unsafe { PROGRAMS.iter().find(|program| program.name == name) } {
+        Some(program) => Source::Memory(program.data),
+        None => Source::File(filesystem::open(name).map_err(fs_error_str)?),
+    };
+    let mut address_space =
+        memory::new_address_space(stack_size).map_err(|_| "failed to create address space")?;
+    elf_loader::start_load()?;
+    match source {
+        Source::Memory(data) => elf_loader::load_bytes(data)?,
+        // Only the parts of the file that get mapped are read from the disk.
+        Source::File(file) if cmdline::get("elf") == Some("eager") => {
+            elf_loader::load_file(file.size(), |buf, offset| {
+                file.read_at(buf, offset).map_err(fs_error_str)
+            })?
+        }
+        // The segments are read when the program first touches their pages.
+        Source::File(file) => {
+            let size = file.size();
+            let reader: elf_loader::Reader = Rc::new(move |buf: &mut [u8], offset| {
+                file.read_at(buf, offset).map_err(fs_error_str)
+            });
+            elf_loader::load_file_lazily(size, reader)?
+        }
+    }
+    let (entry_point, _tls_template) =
+        elf_loader::finish_load(&mut address_space, load_base).map_err(ElfError::as_str)?;
+    let (stack_pointer, args) = build_initial_stack(&mut address_space, args)?;
+    Ok(LoadedProgram {
+        address_space,
+        entry_point,
+        stack_pointer,
+        args,
+    })
+}
+
+/// Where a position independent program needing `image_size` bytes goes: `PROGRAM_LOAD_BASE`, or
+/// with ASLR a random address above it that leaves room for the whole image.
+fn load_base(image_size: u64) -> u64 {
+    if !memory::aslr() {
+        return PROGRAM_LOAD_BASE;
+    }
+    memory::random_page(PROGRAM_LOAD_BASE, USER_SPACE_END.saturating_sub(image_size))
+}
+
+fn fs_error_str(err: FsError) -> &'static str {
+    match err {
+        FsError::NotFound | FsError::NotInitialized => "program not found",
+        err => err.as_str(),
+    }
+}
+
+/// Writes `args` to the top of the user stack in the System V layout and returns the stack
+/// pointer to start with, along with argc, argv and envp:
+///
+/// ```text
+/// stack top      argument strings, NUL terminated
+///                padding to 16 bytes
+///                auxv: AT_NULL, 0
+///                envp: NULL
+///                argv: argc pointers followed by NULL
+/// 16 aligned ->  argc
+/// stack pointer  0
+/// ```
+///
+/// The zero below argc takes the place of a return address, so an `extern "C"` entry function
+/// sees the stack alignment it expects. Entry code written in assembly finds argc at `rsp + 8`.
+fn build_initial_stack(
+    address_space: &mut AddressSpace,
+    args: &[&str],
+) -> Result<(VirtAddr, [u64; 3]), &'static str> {
+    let stack = address_space.stack();
+    let top = stack.stack_start().as_u64();
+    let strings_size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    // argc, argv with its NULL, envp's NULL and the AT_NULL pair.
+    let words = 1 + args.len() + 1 + 1 + 2;
+    if strings_size + words * 8 + 16 > MAX_ARGS_SIZE.min(stack.size() / 2) {
+        return Err("arguments too long");
+    }
+
+    let mut string_addr = top - strings_size as u64;
+    let mut block = Vec::with_capacity(words);
+    block.push(args.len() as u64);
+    for arg in args {
+        address_space.write_bytes(VirtAddr::new(string_addr), arg.as_bytes())?;
+        address_space.write_bytes(VirtAddr::new(string_addr + arg.len() as u64), &[0])?;
+        block.push(string_addr);
+        string_addr += arg.len() as u64 + 1;
+    }
+    block.push(0);
+    block.push(0);
+    block.extend_from_slice(&[AT_NULL, 0]);
+
+    let block_addr = (top - strings_size as u64 - (words * 8) as u64) & !0xf;
+    let bytes: Vec<u8> = block.iter().flat_map(|word| word.to_ne_bytes()).collect();
+    address_space.write_bytes(VirtAddr::new(block_addr), &bytes)?;
+    let stack_pointer = block_addr - 8;
+    address_space.write_bytes(VirtAddr::new(stack_pointer), &0u64.to_ne_bytes())?;
+
+    let argv = block_addr + 8;
+    let envp = argv + (args.len() as u64 + 1) * 8;
+    Ok((
+        VirtAddr::new(stack_pointer),
+        [args.len() as u64, argv, envp],
+    ))
+}
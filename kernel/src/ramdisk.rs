@@ -0,0 +1,113 @@
+use crate::block_cache::Device;
+use alloc::vec;
+use ata::{AtaError, BlockDevice};
+use core::ops::Range;
+
+const BLOCK_SIZE: usize = 512;
+/// Blocks read at a time when copying a partition.
+const COPY_BLOCKS: usize = 128;
+
+/// A disk in memory, for running the filesystem without a real one.
+pub struct RamDisk {
+    data: *mut u8,
+    len: usize,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// A writable disk holding `data`, which writes change. Trailing bytes past the last whole
+    /// block aren't used.
+    pub fn new(data: &'static mut [u8]) -> Self {
+        RamDisk {
+            data: data.as_mut_ptr(),
+            len: data.len() / BLOCK_SIZE * BLOCK_SIZE,
+            read_only: false,
+        }
+    }
+
+    /// A disk image that can't be changed, e.g. one linked into the kernel. Writes fail with
+    /// `AtaError::ReadOnly`.
+    #[allow(dead_code)]
+    pub fn read_only(data: &'static [u8]) -> Self {
+        RamDisk {
+            data: data.as_ptr() as *mut u8,
+            len: data.len() / BLOCK_SIZE * BLOCK_SIZE,
+            read_only: true,
+        }
+    }
+
+    /// Copies the first `size` bytes of `device`, e.g. a partition, to the heap, so the
+    /// filesystem can run on it without changing the device. The copy is never freed.
+    pub fn copy_of(device: &dyn Device, size: usize, read_only: bool) -> Result<Self, AtaError> {
+        let data = vec![0; size].leak();
+        for (index, chunk) in data.chunks_mut(COPY_BLOCKS * BLOCK_SIZE).enumerate() {
+            let blocks = chunk.len() / BLOCK_SIZE;
+            device.read(
+                &mut chunk[..blocks * BLOCK_SIZE],
+                index * COPY_BLOCKS * BLOCK_SIZE,
+                blocks,
+            )?;
+        }
+        let mut disk = RamDisk::new(data);
+        disk.read_only = read_only;
+        Ok(disk)
+    }
+
+    pub fn size_in_kib(&self) -> usize {
+        self.len / 1024
+    }
+
+    /// The bytes `number_of_blocks` blocks at `address` cover, checked like a partition's.
+    fn range(
+        &self,
+        buf_len: usize,
+        address: usize,
+        number_of_blocks: usize,
+    ) -> Result<Range<usize>, AtaError> {
+        if address % BLOCK_SIZE != 0 {
+            return Err(AtaError::AddressNotAligned);
+        }
+        let len = number_of_blocks
+            .checked_mul(BLOCK_SIZE)
+            .ok_or(AtaError::OutOfBounds)?;
+        if buf_len != len {
+            return Err(AtaError::WrongSizeBuffer);
+        }
+        match address.checked_add(len) {
+            Some(end) if end <= self.len => Ok(address..end),
+            _ => Err(AtaError::OutOfBounds),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    const BLOCK_SIZE: u32 = BLOCK_SIZE as u32;
+    type Error = AtaError;
+    fn read(
+        &self,
+        buf: &mut [u8],
+        address: usize,
+        number_of_blocks: usize,
+    ) -> Result<(), Self::Error> {
+        let range = self.range(buf.len(), address, number_of_blocks)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.data.add(range.start), buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+    fn write(
+        &self,
+        buf: &[u8],
+        address: usize,
+        number_of_blocks: usize,
+    ) -> Result<(), Self::Error> {
+        if self.read_only {
+            return Err(AtaError::ReadOnly);
+        }
+        let range = self.range(buf.len(), address, number_of_blocks)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), self.data.add(range.start), buf.len());
+        }
+        Ok(())
+    }
+}
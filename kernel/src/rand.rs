@@ -0,0 +1,107 @@
+use crate::{cpu, rtc};
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// How many times to retry RDRAND and RDSEED, which fail now and then when the hardware runs out
+/// of entropy. Intel recommends 10 for RDRAND.
+const RETRIES: usize = 10;
+
+static HAS_RDRAND: AtomicBool = AtomicBool::new(false);
+/// State of the fallback generator, SplitMix64. Advanced atomically, so it's safe to use from
+/// interrupt handlers too.
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack),
+            );
+        }
+        // The carry flag is clear if no random number was ready.
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn rdseed() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!(
+                "rdseed {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack),
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Seeds the fallback generator. The seed comes from RDSEED if
+/// there is one, else from the TSC and the RTC, which is good enough to vary between boots but
+/// isn't secure. Must be called after `acpi::init`, which finds the RTC's century register.
+pub fn init() {
+    let has_rdrand = cpu::has_rdrand();
+    let seed = cpu::has_rdseed().then(rdseed).flatten().unwrap_or_else(|| {
+        let now = rtc::now();
+        let time = (now.year as u64) << 40
+            | (now.month as u64) << 32
+            | (now.day as u64) << 24
+            | (now.hour as u64) << 16
+            | (now.minute as u64) << 8
+            | now.second as u64;
+        // SplitMix64 mixes its output well, so the seed only has to differ between boots.
+        let tsc = unsafe { _rdtsc() };
+        tsc ^ time
+    });
+    STATE.store(seed, Ordering::Relaxed);
+    HAS_RDRAND.store(has_rdrand, Ordering::Relaxed);
+    log::debug!(
+        "Random numbers from {}",
+        if has_rdrand { "RDRAND" } else { "SplitMix64" }
+    );
+}
+
+fn splitmix64() -> u64 {
+    const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut z = STATE
+        .fetch_add(GAMMA, Ordering::Relaxed)
+        .wrapping_add(GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A random number from RDRAND, or from the fallback generator if there's no RDRAND or it kept
+/// failing.
+pub fn u64() -> u64 {
+    if HAS_RDRAND.load(Ordering::Relaxed) {
+        if let Some(value) = rdrand() {
+            return value;
+        }
+    }
+    splitmix64()
+}
+
+/// Fills `buf` with random bytes from `u64`.
+#[allow(dead_code)]
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&u64().to_ne_bytes()[..chunk.len()]);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::acpi;
+use x86_64::instructions::{interrupts, port::Port};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Keeps NMIs enabled while a register is selected.
+const NMI_ENABLED: u8 = 0;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in the hour register for PM times in 12 hour mode.
+const HOUR_PM: u8 = 1 << 7;
+
+/// A date and time as kept by the RTC, usually local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::new(CMOS_ADDRESS).write(NMI_ENABLED | register);
+        Port::new(CMOS_DATA).read()
+    }
+}
+
+/// The raw time registers, with the century register last if there is one.
+fn read_raw(century_register: Option<u8>) -> [u8; 7] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REG_SECOND),
+        read_register(REG_MINUTE),
+        read_register(REG_HOUR),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        century_register.map_or(0, read_register),
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+/// The current date and time.
+pub fn now() -> DateTime {
+    let century_register = acpi::century_register();
+    // An update can start right after the flag is checked, so read until two reads agree.
+    let raw = interrupts::without_interrupts(|| {
+        let mut raw = read_raw(century_register);
+        loop {
+            let again = read_raw(century_register);
+            if again == raw {
+                break raw;
+            }
+            raw = again;
+        }
+    });
+    let status_b = read_register(REG_STATUS_B);
+    let convert = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    let [second, minute, hour, day, month, year, century] = raw;
+    let mut hour_value = convert(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon.
+        hour_value %= 12;
+        if hour & HOUR_PM != 0 {
+            hour_value += 12;
+        }
+    }
+    let year = convert(year) as u16;
+    let year = match century_register {
+        Some(_) => convert(century) as u16 * 100 + year,
+        // Without a century register, assume the clock isn't set before 1980, the FAT epoch.
+        None if year < 80 => 2000 + year,
+        None => 1900 + year,
+    };
+    DateTime {
+        year,
+        month: convert(month),
+        day: convert(day),
+        hour: hour_value,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
@@ -0,0 +1,611 @@
+use crate::{
+    fd::FdTable,
+    fpu::{self, FpuState},
+    memory::{self, AddressSpace},
+    sync, userspace,
+};
+use alloc::boxed::Box;
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use x86_64::{instructions::interrupts, VirtAddr};
+
+pub const MAX_PROCESSES: usize = 16;
+/// This slot runs the idle task, which is only scheduled when no process is ready.
+const IDLE_SLOT: usize = MAX_PROCESSES - 1;
+/// What a terminated process exits with, like a shell reports a process killed by SIGKILL.
+const TERMINATED_EXIT_CODE: u8 = 137;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    /// Blocked in `wait` until the process with this id exits.
+    Waiting(usize),
+    /// Blocked until `wake`, e.g. on an empty pipe.
+    Blocked,
+    Exited(u8),
+}
+
+struct Process {
+    id: usize,
+    /// The process that started this one and can `wait` for it. `None` once it exited, and for
+    /// the kernel and the idle task.
+    parent: Option<usize>,
+    state: State,
+    // Kernel stack pointer saved by `switch_context` while the process isn't running.
+    rsp: u64,
+    // Top of the slot's kernel stack. The kernel itself runs on the bootloader's stack and has
+    // none.
+    kernel_stack_top: Option<u64>,
+    // The kernel uses its own address space.
+    address_space: Option<Box<AddressSpace>>,
+    // Saved while another process owns the FPU.
+    fpu: Box<FpuState>,
+    fds: FdTable,
+    /// Set by `terminate`. The process exits the next time it would return to ring 3.
+    terminated: bool,
+}
+
+const NO_PROCESS: Option<Process> = None;
+// Slot 0 is the kernel (the shell), which is ready unless it's waiting for a program.
+static mut PROCESSES: [Option<Process>; MAX_PROCESSES] = [NO_PROCESS; MAX_PROCESSES];
+static mut CURRENT: usize = 0;
+static mut NEXT_ID: usize = 1;
+// Slot of the process whose state is in the FPU registers. They are only switched when another
+// process uses the FPU, which most never do.
+static mut FPU_OWNER: Option<usize> = None;
+// The program the shell is waiting for, which Ctrl+C terminates.
+static mut FOREGROUND: Option<usize> = None;
+
+extern "sysv64" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn kernel_thread_start() -> !;
+}
+
+// Saves the callee-saved registers and flags on the current stack, then restores the ones saved on
+// the new stack. Everything else was already saved by the caller or the interrupt handler.
+global_asm!(
+    r#"
+.globl switch_context
+switch_context:
+    pushfq
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    popfq
+    ret
+
+// Where a kernel thread starts, with the function to run in r12 and the stack aligned for a call.
+.globl kernel_thread_start
+kernel_thread_start:
+    mov rdi, r12
+    call kernel_thread_main
+    ud2
+"#
+);
+
+pub fn init() {
+    unsafe {
+        PROCESSES[0] = Some(Process {
+            id: 0,
+            parent: None,
+            state: State::Ready,
+            rsp: 0,
+            kernel_stack_top: None,
+            address_space: None,
+            fpu: Box::new(FpuState::new()),
+            fds: FdTable::new(),
+            terminated: false,
+        });
+    }
+    // Without an idle task the last process to block keeps running, checking whether it can go
+    // on, which works but never halts.
+    if let Err(err) = init_idle_task() {
+        log::warn!("No idle task: {}", err);
+    }
+}
+
+extern "sysv64" fn idle_task() -> ! {
+    crate::idle_loop()
+}
+
+fn init_idle_task() -> Result<(), &'static str> {
+    let kernel_stack_top = memory::process_kernel_stack(IDLE_SLOT)?
+        .stack_start()
+        .as_u64();
+    // The zero above the return address keeps the stack aligned like after a call.
+    let rsp = unsafe { build_kernel_stack(kernel_stack_top, [0; 6], [idle_task as u64, 0]) };
+    unsafe {
+        PROCESSES[IDLE_SLOT] = Some(Process {
+            id: NEXT_ID,
+            parent: None,
+            state: State::Ready,
+            rsp,
+            kernel_stack_top: Some(kernel_stack_top),
+            address_space: None,
+            fpu: Box::new(FpuState::new()),
+            fds: FdTable::empty(),
+            terminated: false,
+        });
+        NEXT_ID += 1;
+    }
+    Ok(())
+}
+
+/// Writes what `switch_context` restores to the kernel stack ending at `kernel_stack_top`: the
+/// `callee_saved` registers (r15, r14, r13, r12, rbp, rbx) and flags with interrupts disabled,
+/// then `start`, whose first entry it returns to. Returns the stack pointer to switch to.
+unsafe fn build_kernel_stack<const N: usize>(
+    kernel_stack_top: u64,
+    callee_saved: [u64; 6],
+    start: [u64; N],
+) -> u64 {
+    let rflags = 0x2;
+    let initial_stack = callee_saved
+        .into_iter()
+        .chain(core::iter::once(rflags))
+        .chain(start);
+    let len = callee_saved.len() + 1 + N;
+    let rsp = kernel_stack_top - (len * 8) as u64;
+    for (index, word) in initial_stack.enumerate() {
+        (rsp as *mut u64).add(index).write(word);
+    }
+    rsp
+}
+
+/// Creates a kernel thread that runs `entry` in ring 0 on its own kernel stack, in the kernel's
+/// address space, and returns its id. The timer only preempts userspace, so kernel threads run
+/// until they call `yield_now` or block, and the kernel only runs them when it does the same. When
+/// `entry` returns the thread exits and is freed by the idle task.
+#[allow(dead_code)]
+pub fn spawn_kernel_thread(entry: fn()) -> Result<usize, &'static str> {
+    add_process(
+        None,
+        None,
+        Box::new(FpuState::new()),
+        FdTable::empty(),
+        [0, 0, 0, entry as u64, 0, 0],
+        // Returned to like a called function would be, so it can realign the stack with a call.
+        [kernel_thread_start as u64],
+    )
+}
+
+#[no_mangle]
+extern "sysv64" fn kernel_thread_main(entry: u64) -> ! {
+    // Put in r12 by `spawn_kernel_thread`.
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    interrupts::enable();
+    entry();
+    exit(0)
+}
+
+/// Creates a process that starts running in ring 3 at `entry_point` in `address_space`, on the
+/// user stack at `stack_pointer` and with `args` in the first three argument registers. Returns
+/// the process id.
+pub fn spawn(
+    address_space: Box<AddressSpace>,
+    entry_point: VirtAddr,
+    stack_pointer: VirtAddr,
+    args: [u64; 3],
+) -> Result<usize, &'static str> {
+    add_process(
+        Some(address_space),
+        Some(current_id()),
+        Box::new(FpuState::new()),
+        FdTable::new(),
+        [0; 6],
+        [
+            userspace::user_entry as u64,
+            entry_point.as_u64(),
+            args[0],
+            args[1],
+            args[2],
+            stack_pointer.as_u64(),
+        ],
+    )
+}
+
+/// Creates a copy of the current process in `address_space`, which returns from the syscall it's
+/// in with the saved registers in `frame`. Returns the new process id.
+pub fn fork(
+    address_space: Box<AddressSpace>,
+    frame: &userspace::SyscallFrame,
+) -> Result<usize, &'static str> {
+    add_process(
+        Some(address_space),
+        Some(current_id()),
+        current_fpu_state(),
+        current_fds().clone(),
+        [
+            frame.r15, frame.r14, frame.r13, frame.r12, frame.rbp, frame.rbx,
+        ],
+        [
+            userspace::fork_return as u64,
+            frame.rip,
+            frame.rflags,
+            frame.rsp,
+        ],
+    )
+}
+
+/// Adds a ready process whose kernel stack is set up for `switch_context`: it restores
+/// `callee_saved` (r15, r14, r13, r12, rbp, rbx), then returns to the first entry of `start`, with
+/// the rest of `start` above it on the stack. Processes without `parent` are freed by the idle
+/// task when they exit.
+fn add_process<const N: usize>(
+    address_space: Option<Box<AddressSpace>>,
+    parent: Option<usize>,
+    fpu: Box<FpuState>,
+    fds: FdTable,
+    callee_saved: [u64; 6],
+    start: [u64; N],
+) -> Result<usize, &'static str> {
+    interrupts::without_interrupts(|| unsafe {
+        let slot = (1..IDLE_SLOT)
+            .find(|slot| PROCESSES[*slot].is_none())
+            .ok_or("too many processes")?;
+
+        let kernel_stack_top = memory::process_kernel_stack(slot)?.stack_start().as_u64();
+        // Interrupts stay disabled until sysret.
+        let rsp = build_kernel_stack(kernel_stack_top, callee_saved, start);
+
+        let id = NEXT_ID;
+        NEXT_ID += 1;
+        PROCESSES[slot] = Some(Process {
+            id,
+            parent,
+            state: State::Ready,
+            rsp,
+            kernel_stack_top: Some(kernel_stack_top),
+            address_space,
+            fpu,
+            fds,
+            terminated: false,
+        });
+        Ok(id)
+    })
+}
+
+/// Switches to the next ready process after the current one, if there is one, or else to the idle
+/// task. Interrupts must be disabled.
+unsafe fn schedule() {
+    let current = CURRENT;
+    let Some(next) = (1..=MAX_PROCESSES)
+        .map(|offset| (current + offset) % MAX_PROCESSES)
+        .filter(|slot| *slot != IDLE_SLOT)
+        .find(|slot| matches!(&PROCESSES[*slot], Some(process) if process.state == State::Ready))
+        .or(PROCESSES[IDLE_SLOT].as_ref().map(|_| IDLE_SLOT))
+    else {
+        return;
+    };
+    if next == current {
+        return;
+    }
+    let next_process = PROCESSES[next].as_mut().unwrap();
+    if let Some(kernel_stack_top) = next_process.kernel_stack_top {
+        userspace::set_kernel_stack(VirtAddr::new(kernel_stack_top));
+    }
+    match next_process.address_space.as_mut() {
+        Some(address_space) => memory::switch_address_space(address_space),
+        None => memory::switch_to_kernel_address_space(),
+    }
+    let next_rsp = next_process.rsp;
+    let old_rsp = &mut PROCESSES[current].as_mut().unwrap().rsp as *mut u64;
+    CURRENT = next;
+    fpu::trap_next_use();
+    switch_context(old_rsp, next_rsp);
+}
+
+/// A copy of the current process' FPU state, for a forked child.
+fn current_fpu_state() -> Box<FpuState> {
+    interrupts::without_interrupts(|| unsafe {
+        let process = PROCESSES[CURRENT].as_mut().unwrap();
+        if FPU_OWNER == Some(CURRENT) {
+            // The registers are newer than the saved state.
+            fpu::allow_use();
+            process.fpu.save();
+        }
+        process.fpu.clone()
+    })
+}
+
+/// Loads the current process' FPU state after its first FPU instruction since it was scheduled,
+/// saving the previous owner's. Called from the device not available handler with interrupts
+/// disabled. Returns false if the FPU isn't enabled, so the instruction can't run.
+pub fn handle_fpu_trap() -> bool {
+    if !fpu::enabled() {
+        return false;
+    }
+    fpu::allow_use();
+    unsafe {
+        if FPU_OWNER == Some(CURRENT) {
+            return true;
+        }
+        if let Some(owner) = FPU_OWNER.and_then(|slot| PROCESSES[slot].as_mut()) {
+            owner.fpu.save();
+        }
+        PROCESSES[CURRENT].as_ref().unwrap().fpu.restore();
+        FPU_OWNER = Some(CURRENT);
+    }
+    true
+}
+
+/// The id of the running process.
+pub fn current_id() -> usize {
+    unsafe { PROCESSES[CURRENT].as_ref().unwrap().id }
+}
+
+/// The running process' file descriptors.
+pub fn current_fds() -> &'static mut FdTable {
+    unsafe { &mut PROCESSES[CURRENT].as_mut().unwrap().fds }
+}
+
+/// Processes blocked until something happens that an interrupt handler can tell, e.g. a disk
+/// finishing a transfer. They are remembered by slot.
+pub struct WaitQueue {
+    waiting: AtomicU32,
+}
+
+// A bit for each slot.
+const _: () = if MAX_PROCESSES > 32 {
+    panic!("wait queues hold one bit per process slot");
+};
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiting: AtomicU32::new(0),
+        }
+    }
+
+    /// Blocks the current process until `done` returns true, checking it again each time the queue
+    /// is woken. Polls instead before the scheduler runs, and on an exception's stack, which the
+    /// next exception would overwrite while this one is blocked.
+    pub fn wait_until(&self, mut done: impl FnMut() -> bool) {
+        if unsafe { PROCESSES[0].is_none() } || memory::on_exception_stack() {
+            while !done() {
+                core::hint::spin_loop();
+            }
+            return;
+        }
+        // `done` is checked with interrupts disabled right before blocking, so a wake-up can't
+        // come in between and be missed.
+        interrupts::without_interrupts(|| {
+            while !done() {
+                self.waiting
+                    .fetch_or(1 << unsafe { CURRENT }, Ordering::Relaxed);
+                block();
+            }
+        });
+    }
+
+    /// Makes the processes in the queue ready again. Can be called from interrupt handlers.
+    pub fn wake_all(&self) {
+        let waiting = self.waiting.swap(0, Ordering::Relaxed);
+        if waiting == 0 {
+            return;
+        }
+        interrupts::without_interrupts(|| unsafe {
+            for (slot, process) in PROCESSES.iter_mut().enumerate() {
+                match process {
+                    Some(process)
+                        if waiting & 1 << slot != 0 && process.state == State::Blocked =>
+                    {
+                        process.state = State::Ready
+                    }
+                    _ => (),
+                }
+            }
+        });
+    }
+}
+
+/// A lock that blocks the processes waiting for it instead of spinning, for state its holder keeps
+/// while it waits for a device.
+pub struct SleepLock {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+}
+
+pub struct SleepLockGuard<'a> {
+    lock: &'a SleepLock,
+}
+
+impl SleepLock {
+    pub const fn new() -> Self {
+        SleepLock {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn lock(&self) -> SleepLockGuard {
+        self.waiters.wait_until(|| {
+            self.locked
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        });
+        SleepLockGuard { lock: self }
+    }
+
+    /// Whether a process holds the lock. Code that can't block, e.g. on an exception's stack,
+    /// checks this instead of spinning on a lock whose holder can't run.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for SleepLockGuard<'_> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
+
+/// Stops running the current process until `wake` is called with its id. Callers check again
+/// whatever they were waiting for after this returns.
+pub fn block() {
+    sync::assert_no_locks_held("blocking");
+    interrupts::without_interrupts(|| unsafe {
+        PROCESSES[CURRENT].as_mut().unwrap().state = State::Blocked;
+        schedule();
+        // Already ready if it was woken. Without an idle task, `schedule` can also come back
+        // right away when nothing else can run.
+        PROCESSES[CURRENT].as_mut().unwrap().state = State::Ready;
+    });
+}
+
+/// Makes process `id` ready again if it's blocked.
+pub fn wake(id: usize) {
+    interrupts::without_interrupts(|| unsafe {
+        for process in PROCESSES.iter_mut().flatten() {
+            if process.id == id && process.state == State::Blocked {
+                process.state = State::Ready;
+            }
+        }
+    });
+}
+
+/// Lets the next process run. Returns once this process is scheduled again.
+pub fn yield_now() {
+    sync::assert_no_locks_held("yielding");
+    interrupts::without_interrupts(|| unsafe { schedule() });
+}
+
+/// Makes process `id` exit with `TERMINATED_EXIT_CODE`. It's woken if it's blocked, and exits the
+/// next time it would return to ring 3 rather than right away, so whatever it was doing in the
+/// kernel is finished or given up first. The kernel and the idle task can't be terminated.
+pub fn terminate(id: usize) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| unsafe {
+        let process = PROCESSES[1..IDLE_SLOT]
+            .iter_mut()
+            .flatten()
+            .find(|process| process.id == id)
+            .ok_or("no such process")?;
+        match process.state {
+            State::Exited(_) => return Err("process already exited"),
+            State::Blocked | State::Waiting(_) => process.state = State::Ready,
+            State::Ready => (),
+        }
+        process.terminated = true;
+        Ok(())
+    })
+}
+
+/// Whether the current process was terminated. Blocking calls check this when woken and return
+/// early, so the process can exit.
+pub fn terminated() -> bool {
+    unsafe { PROCESSES[CURRENT].as_ref().unwrap().terminated }
+}
+
+/// Exits the current process if it was terminated. Called right before returning to ring 3.
+pub extern "sysv64" fn exit_if_terminated() {
+    if terminated() {
+        exit(TERMINATED_EXIT_CODE);
+    }
+}
+
+/// Sets the process that `terminate_foreground` terminates.
+pub fn set_foreground(id: Option<usize>) {
+    unsafe { FOREGROUND = id };
+}
+
+/// The process set with `set_foreground`.
+pub fn foreground() -> Option<usize> {
+    unsafe { FOREGROUND }
+}
+
+/// Terminates the process set with `set_foreground`. Returns whether there was one.
+pub fn terminate_foreground() -> bool {
+    foreground().map_or(false, |id| terminate(id).is_ok())
+}
+
+/// Ends the current process. Its resources are freed when its parent `wait`s for it, or by the
+/// idle task if the parent exited first. Its children are left to the idle task as well.
+pub fn exit(exit_code: u8) -> ! {
+    interrupts::disable();
+    unsafe {
+        // Closed now rather than when the process is freed, so blocked pipe readers see the end of
+        // the data. Dropped outside the process table, since closing a pipe wakes processes.
+        drop(core::mem::replace(current_fds(), FdTable::empty()));
+        if let Some(process) = PROCESSES[CURRENT].as_mut() {
+            // Its memory is freed now too, from the kernel's address space, so a process that's
+            // never waited for doesn't keep it.
+            if let Some(address_space) = process.address_space.take() {
+                memory::switch_to_kernel_address_space();
+                drop(address_space);
+            }
+            process.state = State::Exited(exit_code);
+            let id = process.id;
+            for other in PROCESSES.iter_mut().flatten() {
+                if other.state == State::Waiting(id) {
+                    other.state = State::Ready;
+                }
+                if other.parent == Some(id) {
+                    other.parent = None;
+                }
+            }
+        }
+        schedule();
+    }
+    unreachable!("exited process was scheduled");
+}
+
+/// Frees the exited process in `slot`. Interrupts must be disabled.
+unsafe fn reap(slot: usize) {
+    // Taken out before it's dropped, which can wake other processes.
+    drop(PROCESSES[slot].take());
+    if FPU_OWNER == Some(slot) {
+        FPU_OWNER = None;
+    }
+}
+
+/// Frees the exited processes whose parent exited before it could `wait` for them.
+pub fn reap_orphans() {
+    interrupts::without_interrupts(|| unsafe {
+        for (slot, process) in PROCESSES.iter_mut().enumerate().take(IDLE_SLOT).skip(1) {
+            if matches!(process, Some(process)
+                if process.parent.is_none() && matches!(process.state, State::Exited(_)))
+            {
+                reap(slot);
+            }
+        }
+    });
+}
+
+/// Blocks until process `id`, a child of the current process, exits, then frees it and returns
+/// its exit code. Returns `None` if there's no such child.
+pub fn wait(id: usize) -> Option<u8> {
+    interrupts::without_interrupts(|| unsafe {
+        let parent = PROCESSES[CURRENT].as_ref().unwrap().id;
+        loop {
+            let slot = PROCESSES.iter().position(|process| {
+                matches!(process, Some(process) if process.id == id && process.parent == Some(parent))
+            })?;
+            if let State::Exited(exit_code) = PROCESSES[slot].as_ref().unwrap().state {
+                reap(slot);
+                return Some(exit_code);
+            }
+            if terminated() {
+                return None;
+            }
+            // `exit` makes this process ready again. Checked again after, in case it was woken
+            // without the process exiting, or there's no idle task and `schedule` came back.
+            PROCESSES[CURRENT].as_mut().unwrap().state = State::Waiting(id);
+            schedule();
+            PROCESSES[CURRENT].as_mut().unwrap().state = State::Ready;
+        }
+    })
+}
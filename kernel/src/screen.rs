@@ -0,0 +1,328 @@
+use crate::graphics::{self, Color, GraphicsContext, Point, Rect, Texture};
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// The size of the built-in font's glyphs. `char_width` and `char_height` are the size of the
+/// font in use.
+pub const CHAR_WIDTH: u32 = 8;
+pub const CHAR_HEIGHT: u32 = 16;
+const TAB_WIDTH: i32 = 4;
+
+/// 8x16 glyphs for printable ASCII (0x20-0x7e), one byte per row with the most significant bit
+/// on the left.
+static FONT: &[u8] = include_bytes!("font8x16.data");
+/// The same for the rest of Latin-1 (0xa0-0xff).
+static LATIN1_FONT: &[u8] = include_bytes!("font8x16-latin1.data");
+/// Box drawing and block elements (0x2500-0x259f), which fill the whole cell so they join up.
+static BOX_FONT: &[u8] = include_bytes!("font8x16-box.data");
+static FONTS: [(u32, &[u8]); 3] = [(0x20, FONT), (0xa0, LATIN1_FONT), (0x2500, BOX_FONT)];
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+// PSF1 fonts have 256 glyphs, or 512 with this mode bit.
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02 | 0x04;
+const PSF1_SEPARATOR: u16 = 0xffff;
+const PSF1_START_SEQUENCE: u16 = 0xfffe;
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_FLAG_HAS_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_START_SEQUENCE: u8 = 0xfe;
+/// Larger glyphs than this are more likely a broken header than a font.
+const MAX_GLYPH_SIZE: u32 = 64;
+
+/// A font loaded from a PSF file.
+pub struct Font {
+    width: u32,
+    height: u32,
+    glyph_count: usize,
+    glyphs: Vec<u8>,
+    /// Which glyph each character uses. Without a table, a character's code is its glyph's index.
+    unicode: Option<BTreeMap<char, usize>>,
+}
+
+impl Font {
+    fn bytes_per_row(&self) -> usize {
+        (self.width as usize + 7) / 8
+    }
+    fn glyph(&self, ch: char) -> Option<Glyph> {
+        let index = match &self.unicode {
+            Some(unicode) => *unicode.get(&ch)?,
+            None => ch as usize,
+        };
+        if index >= self.glyph_count {
+            return None;
+        }
+        let size = self.bytes_per_row() * self.height as usize;
+        Some(Glyph {
+            rows: &self.glyphs[index * size..(index + 1) * size],
+            bytes_per_row: self.bytes_per_row(),
+        })
+    }
+}
+
+// Set by `set_font`. Without one, the built-in font is used.
+static mut LOADED_FONT: Option<Font> = None;
+
+/// The rows of a glyph, `bytes_per_row` bytes each with the most significant bit of the first on
+/// the left.
+struct Glyph<'a> {
+    rows: &'a [u8],
+    bytes_per_row: usize,
+}
+
+impl Glyph<'_> {
+    fn is_set(&self, row: u32, col: u32) -> bool {
+        self.rows[row as usize * self.bytes_per_row + col as usize / 8] & (0x80 >> (col % 8)) != 0
+    }
+}
+
+fn builtin_glyph(ch: char) -> Option<Glyph<'static>> {
+    FONTS.iter().find_map(|&(first, font)| {
+        let index = (ch as u32).checked_sub(first)? as usize;
+        let start = index * CHAR_HEIGHT as usize;
+        Some(Glyph {
+            rows: font.get(start..start + CHAR_HEIGHT as usize)?,
+            bytes_per_row: 1,
+        })
+    })
+}
+
+fn glyph(ch: char) -> Option<Glyph<'static>> {
+    match unsafe { LOADED_FONT.as_ref() } {
+        Some(font) => font.glyph(ch),
+        None => builtin_glyph(ch),
+    }
+}
+
+/// The width of a character cell in the font in use.
+pub fn char_width() -> u32 {
+    unsafe { LOADED_FONT.as_ref() }.map_or(CHAR_WIDTH, |font| font.width)
+}
+/// The height of a character cell in the font in use.
+pub fn char_height() -> u32 {
+    unsafe { LOADED_FONT.as_ref() }.map_or(CHAR_HEIGHT, |font| font.height)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, &'static str> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or("truncated font header")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Parses a PSF1 or PSF2 font, with its Unicode table if it has one.
+pub fn load_font(data: &[u8]) -> Result<Font, &'static str> {
+    let (width, height, glyph_count, start, has_table) = if data.starts_with(&PSF1_MAGIC) {
+        let mode = *data.get(2).ok_or("truncated font header")?;
+        let height = *data.get(3).ok_or("truncated font header")? as u32;
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let has_table = mode & PSF1_MODE_HAS_TABLE != 0;
+        (8, height, glyph_count, PSF1_HEADER_SIZE, has_table)
+    } else if data.starts_with(&PSF2_MAGIC) {
+        let header_size = read_u32(data, 8)? as usize;
+        let flags = read_u32(data, 12)?;
+        let glyph_count = read_u32(data, 16)? as usize;
+        let glyph_size = read_u32(data, 20)? as usize;
+        let height = read_u32(data, 24)?;
+        let width = read_u32(data, 28)?;
+        if header_size < PSF2_HEADER_SIZE {
+            return Err("truncated font header");
+        }
+        if glyph_size != (width as usize + 7) / 8 * height as usize {
+            return Err("glyph size doesn't match its dimensions");
+        }
+        let has_table = flags & PSF2_FLAG_HAS_TABLE != 0;
+        (width, height, glyph_count, header_size, has_table)
+    } else {
+        return Err("not a PSF font");
+    };
+    if !(1..=MAX_GLYPH_SIZE).contains(&width) || !(1..=MAX_GLYPH_SIZE).contains(&height) {
+        return Err("unsupported glyph size");
+    }
+    let glyphs_size = glyph_count
+        .checked_mul((width as usize + 7) / 8 * height as usize)
+        .ok_or("too many glyphs")?;
+    let end = start.checked_add(glyphs_size).ok_or("too many glyphs")?;
+    let glyphs = data.get(start..end).ok_or("truncated glyphs")?.to_vec();
+    let table = &data[end..];
+    let unicode = match (has_table, data.starts_with(&PSF1_MAGIC)) {
+        (false, _) => None,
+        (true, true) => Some(psf1_unicode_table(table, glyph_count)?),
+        (true, false) => Some(psf2_unicode_table(table, glyph_count)?),
+    };
+    Ok(Font {
+        width,
+        height,
+        glyph_count,
+        glyphs,
+        unicode,
+    })
+}
+
+/// Reads the UCS-2 characters each glyph stands for. Sequences of several characters for one
+/// glyph, e.g. a letter with a combining accent, come after them and are skipped.
+fn psf1_unicode_table(
+    table: &[u8],
+    glyph_count: usize,
+) -> Result<BTreeMap<char, usize>, &'static str> {
+    let mut unicode = BTreeMap::new();
+    let mut entries = table
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    for index in 0..glyph_count {
+        let mut in_sequence = false;
+        loop {
+            match entries.next().ok_or("truncated unicode table")? {
+                PSF1_SEPARATOR => break,
+                PSF1_START_SEQUENCE => in_sequence = true,
+                code if !in_sequence => {
+                    if let Some(ch) = char::from_u32(code as u32) {
+                        unicode.entry(ch).or_insert(index);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(unicode)
+}
+
+/// Reads the UTF-8 characters each glyph stands for, skipping sequences like `psf1_unicode_table`.
+fn psf2_unicode_table(
+    table: &[u8],
+    glyph_count: usize,
+) -> Result<BTreeMap<char, usize>, &'static str> {
+    let mut unicode = BTreeMap::new();
+    let mut entries = table.split(|&byte| byte == PSF2_SEPARATOR);
+    for index in 0..glyph_count {
+        let entry = entries.next().ok_or("truncated unicode table")?;
+        let singles = entry
+            .split(|&byte| byte == PSF2_START_SEQUENCE)
+            .next()
+            .unwrap_or(&[]);
+        let singles = core::str::from_utf8(singles).map_err(|_| "invalid unicode table")?;
+        for ch in singles.chars() {
+            unicode.entry(ch).or_insert(index);
+        }
+    }
+    Ok(unicode)
+}
+
+/// Draws text with `font` from now on instead of the built-in one.
+pub fn set_font(font: Font) {
+    unsafe {
+        LOADED_FONT = Some(font);
+    }
+}
+
+/// Draws a single character cell with its top-left corner at `x`, `y`. Characters missing from the
+/// font, including U+FFFD for undecodable text, are drawn as a box. Pixels outside the screen are
+/// skipped.
+pub fn draw_char(x: i32, y: i32, ch: char, fg: Color, bg: Color) {
+    let context = graphics::context();
+    let Some(mut target) = (unsafe { graphics::target() }) else {
+        return;
+    };
+    let (Some(fg), Some(bg)) = (context.encode_color(fg), context.encode_color(bg)) else {
+        return;
+    };
+    let visible = draw_char_to(&context, &mut target, x, y, ch, fg, bg);
+    graphics::mark_dirty(visible);
+}
+
+/// Draws a character cell straight into `target` with already encoded colors, without marking
+/// anything dirty. Returns the part of the cell that was drawn.
+pub fn draw_char_to<T: Texture>(
+    context: &GraphicsContext,
+    target: &mut T,
+    x: i32,
+    y: i32,
+    ch: char,
+    fg: u32,
+    bg: u32,
+) -> Rect {
+    let (width, height) = (char_width(), char_height());
+    let cell = Rect::new(x, y, width, height);
+    let visible = cell.intersect(&Rect::new(0, 0, target.width(), target.height()));
+    if visible.is_empty() {
+        return visible;
+    }
+    let glyph = glyph(ch);
+    for py in visible.y()..visible.bottom() {
+        let row = (py - y) as u32;
+        for px in visible.x()..visible.right() {
+            let col = (px - x) as u32;
+            let set = match &glyph {
+                Some(glyph) => glyph.is_set(row, col),
+                // Fallback box, inset by one pixel so neighbouring boxes stay distinct.
+                None => {
+                    (1..width.saturating_sub(1)).contains(&col)
+                        && (2..height.saturating_sub(2)).contains(&row)
+                        && (col == 1 || col == width - 2 || row == 2 || row == height - 3)
+                }
+            };
+            context.set_pixel(target, px as u32, py as u32, if set { fg } else { bg });
+        }
+    }
+    visible
+}
+
+/// Draws `text` on one line with every pixel of the built-in font as a `scale` x `scale` block,
+/// leaving the background as it is. Characters missing from the font are skipped.
+pub fn draw_text_scaled(x: i32, y: i32, text: &str, scale: u32, color: Color) {
+    let context = graphics::context();
+    let (Some(mut target), Some(color)) =
+        (unsafe { graphics::target() }, context.encode_color(color))
+    else {
+        return;
+    };
+    let char_width = CHAR_WIDTH * scale;
+    for (index, ch) in text.chars().enumerate() {
+        let Some(glyph) = builtin_glyph(ch) else {
+            continue;
+        };
+        let cx = x + (index as u32 * char_width) as i32;
+        for row in 0..CHAR_HEIGHT {
+            let py = y + (row * scale) as i32;
+            for col in 0..CHAR_WIDTH {
+                if glyph.is_set(row, col) {
+                    let px = cx + (col * scale) as i32;
+                    context.fill_rect(&mut target, Rect::new(px, py, scale, scale), color);
+                }
+            }
+        }
+    }
+    let width = text.chars().count() as u32 * char_width;
+    graphics::mark_dirty(Rect::new(x, y, width, CHAR_HEIGHT * scale));
+}
+
+/// Draws `text` starting at `x`, `y`. `\n` moves to the start of the next line and `\t` to the
+/// next tab stop; text running off the screen is clipped. Returns the position after the last
+/// character.
+#[allow(dead_code)]
+pub fn draw_text(x: i32, y: i32, text: &str, fg: Color, bg: Color) -> Point {
+    let char_width = self::char_width() as i32;
+    let (mut cx, mut cy) = (x, y);
+    for ch in text.chars() {
+        match ch {
+            '\n' => {
+                cx = x;
+                cy += char_height() as i32;
+            }
+            '\t' => {
+                let column = (cx - x) / char_width;
+                let next_stop = (column / TAB_WIDTH + 1) * TAB_WIDTH;
+                while (cx - x) / char_width < next_stop {
+                    draw_char(cx, cy, ' ', fg, bg);
+                    cx += char_width;
+                }
+            }
+            ch => {
+                draw_char(cx, cy, ch, fg, bg);
+                cx += char_width;
+            }
+        }
+    }
+    Point::new(cx, cy)
+}
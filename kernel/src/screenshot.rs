@@ -0,0 +1,31 @@
+use crate::{
+    filesystem::{self, FsError},
+    graphics,
+};
+use alloc::{format, string::String};
+
+/// Screenshots are numbered `shot000.bmp` to `shot999.bmp` in the root directory.
+const MAX_SCREENSHOTS: usize = 1000;
+
+fn free_path() -> Result<String, &'static str> {
+    for index in 0..MAX_SCREENSHOTS {
+        let path = format!("/shot{:03}.bmp", index);
+        match filesystem::open(&path) {
+            Err(FsError::NotFound) => return Ok(path),
+            Err(err) => return Err(err.as_str()),
+            Ok(_) => (),
+        }
+    }
+    Err("too many screenshots")
+}
+
+/// Saves the screen as a BMP file on the user partition, returning its path.
+pub fn save() -> Result<String, &'static str> {
+    let image = graphics::capture().ok_or("graphics not initialized")?;
+    let data = graphics::encode_bmp(&image).map_err(|_| "unsupported framebuffer format")?;
+    let path = free_path()?;
+    let mut file = filesystem::create(&path).map_err(FsError::as_str)?;
+    file.write(&data).map_err(FsError::as_str)?;
+    file.flush().map_err(FsError::as_str)?;
+    Ok(path)
+}
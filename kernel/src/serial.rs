@@ -0,0 +1,147 @@
+use crate::time;
+use core::fmt::Write;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3f8;
+
+/// A 16550 UART, used for kernel logs when running headless.
+pub struct SerialPort {
+    data: Port<u8>,
+    interrupt_enable: Port<u8>,
+    fifo_control: Port<u8>,
+    line_control: Port<u8>,
+    modem_control: Port<u8>,
+    line_status: Port<u8>,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        SerialPort {
+            data: Port::new(base),
+            interrupt_enable: Port::new(base + 1),
+            fifo_control: Port::new(base + 2),
+            line_control: Port::new(base + 3),
+            modem_control: Port::new(base + 4),
+            line_status: Port::new(base + 5),
+        }
+    }
+
+    /// Sets the port to 38400 baud, 8 data bits, no parity, one stop bit, with interrupts off.
+    pub fn init(&mut self) {
+        unsafe {
+            self.interrupt_enable.write(0x00);
+            self.line_control.write(0x80); // enable DLAB to set the baud rate divisor
+            self.data.write(0x03); // divisor lobyte (38400 baud)
+            self.interrupt_enable.write(0x00); // divisor hibyte
+            self.line_control.write(0x03); // 8 bits, no parity, one stop bit
+            self.fifo_control.write(0xc7); // enable and clear FIFOs, 14 byte threshold
+            self.modem_control.write(0x03); // DTR + RTS
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            // Wait for the transmit buffer to be empty.
+            while self.line_status.read() & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.write(byte);
+        }
+    }
+
+    /// The next received byte, if there is one.
+    pub fn read_byte(&mut self) -> Option<u8> {
+        unsafe { (self.line_status.read() & 0x01 != 0).then(|| self.data.read()) }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+static mut COM1_PORT: SerialPort = SerialPort::new(COM1);
+/// Set while a transfer protocol owns COM1.
+static mut RAW: bool = false;
+
+pub fn init_serial() {
+    unsafe {
+        COM1_PORT.init();
+    }
+}
+
+/// Writes to COM1, for code that takes a `Write`.
+pub struct SerialWriter;
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_fmt(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+pub fn write_fmt(args: core::fmt::Arguments) {
+    unsafe {
+        if !RAW {
+            let _ = COM1_PORT.write_fmt(args);
+        }
+    }
+}
+
+/// Gives COM1 to a transfer protocol: text output is dropped until `end_raw`, so logs can't
+/// corrupt the data.
+pub fn begin_raw() {
+    unsafe {
+        COM1_PORT.init();
+        // Throw away anything received before the transfer started.
+        while COM1_PORT.read_byte().is_some() {}
+        RAW = true;
+    }
+}
+
+pub fn end_raw() {
+    unsafe { RAW = false };
+}
+
+/// Drops text output or lets it through again, like `begin_raw` and `end_raw` but leaving the port
+/// and anything received alone, for a debugger stopping the kernel between packets.
+pub fn set_raw(raw: bool) {
+    unsafe { RAW = raw };
+}
+
+pub fn write_byte(byte: u8) {
+    unsafe { COM1_PORT.write_byte(byte) };
+}
+
+/// Waits up to `timeout_ms` for a byte from COM1. Spins instead of halting, because the UART
+/// interrupt is off and the FIFO would overflow between timer ticks.
+pub fn read_byte(timeout_ms: u64) -> Option<u8> {
+    let end = time::uptime_ms() + timeout_ms;
+    loop {
+        if let Some(byte) = unsafe { COM1_PORT.read_byte() } {
+            return Some(byte);
+        }
+        if time::uptime_ms() >= end {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Waits for a byte from COM1 however long it takes. Doesn't need the clock, so it works with
+/// interrupts off.
+pub fn wait_byte() -> u8 {
+    loop {
+        if let Some(byte) = unsafe { COM1_PORT.read_byte() } {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,163 @@
+use crate::{
+    acpi, console, filesystem,
+    graphics::{self, Color},
+    keyboard::{self, KeyCode},
+    mouse, program, scheduler, screenshot, speaker, userspace, watchdog, xmodem,
+};
+use alloc::{format, string::String};
+
+const PROMPT: &str = "> ";
+const ERROR_COLOR: Color = Color::new(255, 64, 64);
+/// Played when a command can't be run.
+const ERROR_BEEP_HZ: u32 = 440;
+const ERROR_BEEP_MS: u64 = 100;
+
+fn read_char() -> char {
+    loop {
+        watchdog::pet();
+        match keyboard::poll_event() {
+            Some(event) if event.pressed && event.key == KeyCode::PrintScreen => take_screenshot(),
+            Some(event) if event.pressed => {
+                if let Some(character) = event.character {
+                    return character;
+                }
+            }
+            Some(_) => (),
+            None if move_cursor() => (),
+            // Kernel threads only run when the kernel lets them.
+            None => {
+                scheduler::yield_now();
+                x86_64::instructions::hlt();
+            }
+        }
+    }
+}
+
+/// Moves the mouse cursor by any pending mouse movement. Returns whether there was any.
+fn move_cursor() -> bool {
+    let mut moved = false;
+    while let Some(event) = mouse::poll_event() {
+        graphics::cursor::move_by(event.dx as i32, event.dy as i32);
+        moved = true;
+    }
+    if moved {
+        graphics::present();
+    }
+    moved
+}
+
+fn take_screenshot() {
+    match screenshot::save() {
+        Ok(path) => console::push_line(&format!("Saved screenshot to {}", path)),
+        Err(err) => console::push_colored_line(&format!("Screenshot: {}", err), ERROR_COLOR),
+    }
+}
+
+fn show_input(line: &str) {
+    console::set_input(Some(&format!("{}{}_", PROMPT, line)));
+}
+
+fn run_command(command: &str) {
+    match command {
+        "" => (),
+        "help" => {
+            console::push_line(
+                "Commands: help, kill <id>, ls [directory], poweroff, reboot, recv <file>. Programs:",
+            );
+            for name in program::program_names() {
+                console::push_line(&format!("  {}", name));
+            }
+        }
+        "ls" => list_dir("/"),
+        "poweroff" | "shutdown" => acpi::shutdown(),
+        "reboot" => acpi::reboot(),
+        command if command.starts_with("ls ") => list_dir(command[3..].trim()),
+        command if command.starts_with("kill ") => kill(command[5..].trim()),
+        "recv" => console::push_colored_line("recv: expected a file name", ERROR_COLOR),
+        command if command.starts_with("recv ") => receive_file(command[5..].trim()),
+        name => run_program(name),
+    }
+}
+
+/// Runs the named program, with or without `.elf`, and waits for it to exit.
+pub fn run_program(name: &str) {
+    let file_name = if name.ends_with(".elf") {
+        String::from(name)
+    } else {
+        format!("{}.elf", name)
+    };
+    match program::load_program(&file_name) {
+        Ok(program) => {
+            log::info!("Running {}", file_name);
+            console::set_input(None);
+            console::set_visible(false);
+            let exit_code = userspace::enter_userspace(program);
+            console::set_visible(true);
+            log::info!("{} exited with code {}", file_name, exit_code);
+        }
+        Err(error) => {
+            console::push_colored_line(&format!("{}: {}", file_name, error), ERROR_COLOR);
+            speaker::beep(ERROR_BEEP_HZ, ERROR_BEEP_MS);
+        }
+    }
+}
+
+/// Terminates the process with the id in `id`.
+fn kill(id: &str) {
+    let result = id
+        .parse()
+        .map_err(|_| "expected a process id")
+        .and_then(scheduler::terminate);
+    if let Err(err) = result {
+        console::push_colored_line(&format!("kill: {}", err), ERROR_COLOR);
+    }
+}
+
+/// Receives a file over COM1 with XMODEM and saves it to `path`.
+pub fn receive_file(path: &str) {
+    console::push_line(&format!("Waiting for XMODEM transfer to {} on COM1", path));
+    match xmodem::receive(path) {
+        Ok(size) => console::push_line(&format!("Received {} ({} bytes)", path, size)),
+        Err(err) => {
+            console::push_colored_line(&format!("recv: {}", err), ERROR_COLOR);
+            speaker::beep(ERROR_BEEP_HZ, ERROR_BEEP_MS);
+        }
+    }
+}
+
+fn list_dir(path: &str) {
+    match filesystem::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                if entry.is_dir {
+                    console::push_line(&format!("  {}/", entry.name));
+                } else {
+                    console::push_line(&format!("  {} ({} bytes)", entry.name, entry.size));
+                }
+            }
+        }
+        Err(error) => console::push_colored_line(&format!("ls: {}", error), ERROR_COLOR),
+    }
+}
+
+/// Reads commands from the keyboard forever. Typing a program's name, with or without `.elf`,
+/// runs it.
+pub fn run() -> ! {
+    console::push_line("Type the name of a program to run it, or help.");
+    let mut line = String::new();
+    loop {
+        show_input(&line);
+        match read_char() {
+            '\n' => {
+                console::push_line(&format!("{}{}", PROMPT, line));
+                run_command(line.trim());
+                line.clear();
+            }
+            '\u{8}' => {
+                line.pop();
+            }
+            ch if !ch.is_control() => line.push(ch),
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::memory::{self, PAGE_SIZE};
+use alloc::{collections::BTreeMap, vec::Vec};
+use x86_64::{structures::paging::PhysFrame, VirtAddr};
+
+/// A region may use at most this fraction of the free frames, so one request can't take all of
+/// memory.
+const MAX_FREE_FRACTION: usize = 2;
+
+/// Physical frames that processes can map into their address spaces to share them.
+struct Region {
+    /// Each holds one reference for the region, besides one for every mapping of it.
+    frames: Vec<PhysFrame>,
+    /// Address spaces that created or mapped the region, counted once for each.
+    holds: usize,
+}
+
+static mut REGIONS: BTreeMap<u64, Region> = BTreeMap::new();
+static mut NEXT_ID: u64 = 1;
+
+/// Allocates a zeroed region of at least `size` bytes, held by the current address space until it
+/// exits, and returns its id.
+pub fn create(size: usize) -> Result<u64, &'static str> {
+    if size == 0 {
+        return Err("size is zero");
+    }
+    let pages = size / PAGE_SIZE + (size % PAGE_SIZE != 0) as usize;
+    if pages > memory::stats().free_frames / MAX_FREE_FRACTION {
+        return Err("not enough memory");
+    }
+    let mut frames = Vec::with_capacity(pages);
+    for _ in 0..pages {
+        let Some(frame) = memory::allocate_frame() else {
+            frames.into_iter().for_each(memory::free_frame);
+            return Err("out of memory");
+        };
+        unsafe {
+            core::ptr::write_bytes(
+                memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                PAGE_SIZE,
+            );
+        }
+        frames.push(frame);
+    }
+    unsafe {
+        let id = NEXT_ID;
+        NEXT_ID += 1;
+        REGIONS.insert(id, Region { frames, holds: 1 });
+        memory::current_address_space().hold_shared(id);
+        Ok(id)
+    }
+}
+
+/// Maps region `id` into the current address space and returns its page aligned address.
+pub fn map(id: u64) -> Result<VirtAddr, &'static str> {
+    let region = unsafe { REGIONS.get_mut(&id) }.ok_or("no such shared memory")?;
+    let addr = memory::current_address_space().map_shared(id, &region.frames)?;
+    region.holds += 1;
+    Ok(addr)
+}
+
+/// Unmaps the mapping of a region starting at `addr` from the current address space.
+pub fn unmap(addr: VirtAddr) -> Result<(), &'static str> {
+    let id = memory::current_address_space().unmap_shared(addr)?;
+    release(id);
+    Ok(())
+}
+
+/// Adds a hold on region `id`, for an address space that inherited one.
+pub fn retain(id: u64) {
+    if let Some(region) = unsafe { REGIONS.get_mut(&id) } {
+        region.holds += 1;
+    }
+}
+
+/// Drops a hold on region `id`, freeing it when it was the last.
+pub fn release(id: u64) {
+    unsafe {
+        let Some(region) = REGIONS.get_mut(&id) else {
+            return;
+        };
+        region.holds -= 1;
+        if region.holds == 0 {
+            let region = REGIONS.remove(&id).unwrap();
+            region.frames.into_iter().for_each(memory::free_frame);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::time::{self, PIT_FREQUENCY};
+use x86_64::instructions::{interrupts, port::Port};
+
+const PIT_COMMAND: u16 = 0x43;
+const PIT_CHANNEL_2: u16 = 0x42;
+/// Channel 2 square wave, lobyte/hibyte.
+const PIT_SQUARE_WAVE: u8 = 0b10110110;
+
+/// Also holds other board bits, so it's always read and written back with only ours changed.
+const SPEAKER_PORT: u16 = 0x61;
+const GATE_CHANNEL_2: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+
+/// Counts `periods` cycles of channel 2's output. Keeps time with interrupts disabled, where
+/// `sleep_ms` would never return.
+fn wait_periods(port: &mut Port<u8>, periods: u64) {
+    for _ in 0..periods {
+        unsafe {
+            while port.read() & CHANNEL_2_OUTPUT == 0 {
+                core::hint::spin_loop();
+            }
+            while port.read() & CHANNEL_2_OUTPUT != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// Plays a tone of about `freq_hz` on the PC speaker for `duration_ms`, then puts the speaker
+/// port back the way it was. Frequencies outside what the PIT can divide down to are clamped.
+pub fn beep(freq_hz: u32, duration_ms: u64) {
+    if duration_ms == 0 {
+        return;
+    }
+    let divider = (PIT_FREQUENCY / freq_hz.max(1) as u64).clamp(1, u16::MAX as u64);
+    let mut port = Port::<u8>::new(SPEAKER_PORT);
+    unsafe {
+        let previous = port.read();
+        Port::<u8>::new(PIT_COMMAND).write(PIT_SQUARE_WAVE);
+        let mut data_port = Port::<u8>::new(PIT_CHANNEL_2);
+        data_port.write(divider as u8);
+        data_port.write((divider >> 8) as u8);
+        port.write(previous | GATE_CHANNEL_2 | SPEAKER_ENABLE);
+        if interrupts::are_enabled() {
+            time::sleep_ms(duration_ms);
+        } else {
+            let freq = PIT_FREQUENCY / divider;
+            wait_periods(&mut port, (freq * duration_ms / 1000).max(1));
+        }
+        port.write(previous);
+    }
+}
+
+/// Three falling tones, so a panic can be told apart from other beeps without looking at the
+/// screen.
+pub fn panic_beep() {
+    for freq in [880, 660, 440] {
+        beep(freq, 150);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::graphics::{self, Color, Rect, Texture, VecBuffer};
+use crate::{cmdline, console, filesystem, screen};
+
+/// Shown instead of the built-in logo if the user partition has it.
+const LOGO_PATH: &str = "/logo.bmp";
+/// The built-in logo is the OS name in the console font, scaled up by this much.
+const NAME_SCALE: u32 = 6;
+const NAME_COLOR: Color = Color::new(255, 64, 64);
+
+const BAR_WIDTH: u32 = 256;
+const BAR_HEIGHT: u32 = 12;
+/// Space between the logo and the progress bar.
+const BAR_GAP: u32 = 24;
+const BAR_COLOR: Color = Color::WHITE;
+
+struct Splash {
+    visible: bool,
+    logo: Option<VecBuffer>,
+    done: u32,
+    total: u32,
+}
+
+static mut SPLASH: Splash = Splash {
+    visible: false,
+    logo: None,
+    done: 0,
+    total: 1,
+};
+
+/// Where the logo goes, centered on the screen with the progress bar below it.
+fn logo_rect(width: u32, height: u32) -> Rect {
+    let (screen_width, screen_height) = graphics::dimensions();
+    let x = (screen_width as i32 - width as i32) / 2;
+    let y = (screen_height as i32 - (height + BAR_GAP + BAR_HEIGHT) as i32) / 2;
+    Rect::new(x, y, width, height)
+}
+
+impl Splash {
+    fn draw(&self) {
+        let (width, height) = graphics::dimensions();
+        graphics::fill_rect(Rect::new(0, 0, width, height), Color::BLACK);
+        let logo = match &self.logo {
+            Some(logo) => {
+                let rect = logo_rect(logo.width(), logo.height());
+                graphics::draw_image(logo, rect.x(), rect.y());
+                rect
+            }
+            None => {
+                let name = crate::OS_NAME;
+                let rect = logo_rect(
+                    name.len() as u32 * screen::CHAR_WIDTH * NAME_SCALE,
+                    screen::CHAR_HEIGHT * NAME_SCALE,
+                );
+                screen::draw_text_scaled(rect.x(), rect.y(), name, NAME_SCALE, NAME_COLOR);
+                rect
+            }
+        };
+        let bar = Rect::new(
+            (width as i32 - BAR_WIDTH as i32) / 2,
+            logo.bottom() + BAR_GAP as i32,
+            BAR_WIDTH,
+            BAR_HEIGHT,
+        );
+        graphics::draw_rect(bar, BAR_COLOR);
+        // Leave a one pixel gap inside the outline.
+        let filled = (BAR_WIDTH - 4) * self.done.min(self.total) / self.total;
+        graphics::fill_rect(
+            Rect::new(bar.x() + 2, bar.y() + 2, filled, BAR_HEIGHT - 4),
+            BAR_COLOR,
+        );
+        graphics::present();
+    }
+}
+
+/// Replaces the boot log with the splash screen if `splash=on` is on the command line. `total` is
+/// the number of init steps `progress` will count. Must be called after the heap is set up, so
+/// drawing goes to the back buffer instead of flickering on the framebuffer.
+pub fn show(total: u32) {
+    match cmdline::get("splash") {
+        Some("on") => {}
+        None | Some("off") => return,
+        Some(option) => {
+            log::warn!("Unknown splash option {}, expected on or off", option);
+            return;
+        }
+    }
+    // Log lines are still recorded, and shown again when the splash is hidden.
+    console::set_visible(false);
+    unsafe {
+        SPLASH.visible = true;
+        SPLASH.total = total.max(1);
+        SPLASH.draw();
+    }
+}
+
+/// Records that `done` of the init steps are finished and redraws the progress bar.
+pub fn progress(done: u32) {
+    unsafe {
+        SPLASH.done = done;
+        if SPLASH.visible {
+            SPLASH.draw();
+        }
+    }
+}
+
+/// Switches to the logo on the user partition, now that the filesystem is up. The built-in logo
+/// stays if there is none or it can't be loaded.
+pub fn load_logo() {
+    if unsafe { !SPLASH.visible } {
+        return;
+    }
+    let Ok(file) = filesystem::open(LOGO_PATH) else {
+        return;
+    };
+    let data = match file.read_all() {
+        Ok(data) => data,
+        Err(err) => {
+            log::warn!("{}: {}", LOGO_PATH, err);
+            return;
+        }
+    };
+    match graphics::load_bmp(&data) {
+        Ok(logo) => unsafe {
+            SPLASH.logo = Some(logo);
+            SPLASH.draw();
+        },
+        Err(err) => log::warn!("{}: {}", LOGO_PATH, err),
+    }
+}
+
+/// Goes back to the console, showing everything logged while the splash was up.
+pub fn hide() {
+    unsafe {
+        if !SPLASH.visible {
+            return;
+        }
+        SPLASH.visible = false;
+        SPLASH.logo = None;
+    }
+    console::set_visible(true);
+}
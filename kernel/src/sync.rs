@@ -0,0 +1,154 @@
+use crate::kassert;
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+/// How many spin locks are held on the CPU, counted in debug builds for `assert_no_locks_held`.
+/// An atomic rather than a lock, so counting can't deadlock.
+static HELD: AtomicUsize = AtomicUsize::new(0);
+/// Set once `SpinLock::lock` warned about enabled interrupts, so the log isn't flooded.
+static WARNED_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+/// Checks in debug builds that no spin lock is held before `what`, e.g. halting or blocking. Code
+/// that would take the lock can't run until this returns, so it would deadlock.
+#[track_caller]
+pub fn assert_no_locks_held(what: &str) {
+    if cfg!(debug_assertions) {
+        let held = HELD.load(Ordering::Relaxed);
+        kassert!(held == 0, "{} with {} spin locks held", what, held);
+    }
+}
+
+/// A lock that spins until it's free. Code that takes it must not be interrupted by code that
+/// takes it too, which would spin forever; use `IrqSafeSpinLock` for data interrupt handlers use.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+    /// In debug builds, warns once if interrupts are enabled, since an interrupt handler or a
+    /// process switched to could then spin on the lock forever.
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        if cfg!(debug_assertions) {
+            if interrupts::are_enabled() && !WARNED_INTERRUPTS.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "Spin lock taken with interrupts enabled at {}",
+                    core::panic::Location::caller()
+                );
+            }
+            HELD.fetch_add(1, Ordering::Relaxed);
+        }
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        SpinLockGuard { lock: self }
+    }
+}
+
+/// Gives access to the data of a `SpinLock` and unlocks it when dropped.
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if cfg!(debug_assertions) {
+            HELD.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A `SpinLock` that disables interrupts while it's held, so an interrupt handler taking it can't
+/// find it locked by the code it interrupted.
+pub struct IrqSafeSpinLock<T> {
+    lock: SpinLock<T>,
+}
+
+impl<T> IrqSafeSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        IrqSafeSpinLock {
+            lock: SpinLock::new(data),
+        }
+    }
+    pub fn lock(&self) -> IrqSafeSpinLockGuard<T> {
+        let interrupts = InterruptsDisabled::new();
+        IrqSafeSpinLockGuard {
+            guard: self.lock.lock(),
+            _interrupts: interrupts,
+        }
+    }
+}
+
+/// Gives access to the data of an `IrqSafeSpinLock`. When dropped it unlocks it, then enables
+/// interrupts again if they were enabled before it was locked.
+pub struct IrqSafeSpinLockGuard<'a, T> {
+    // Fields are dropped in order, so the lock is released before interrupts come back.
+    guard: SpinLockGuard<'a, T>,
+    _interrupts: InterruptsDisabled,
+}
+
+impl<T> Deref for IrqSafeSpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+/// Disables interrupts until dropped. Only enables them again if they were enabled before, so it
+/// can be nested, e.g. in an interrupt handler.
+struct InterruptsDisabled {
+    were_enabled: bool,
+}
+
+impl InterruptsDisabled {
+    fn new() -> Self {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        InterruptsDisabled { were_enabled }
+    }
+}
+
+impl Drop for InterruptsDisabled {
+    fn drop(&mut self) {
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::cpu;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Input clock of the programmable interval timer.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+/// Divider programmed into PIT channel 0, giving about 1000 interrupts per second.
+const PIT_DIVIDER: u16 = 1193;
+
+// Zero until the timer is running, so early callers see time 0.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds per tick. Starts out as the PIT's period, the APIC timer replaces it.
+static TICK_NANOS: AtomicU64 = AtomicU64::new(PIT_DIVIDER as u64 * 1_000_000_000 / PIT_FREQUENCY);
+
+/// How long the TSC is counted against the PIT. Longer is more precise, but `pit_wait_ms` can't
+/// wait more than 50ms.
+const TSC_CALIBRATION_MS: u64 = 50;
+/// TSC ticks per second, or 0 if it wasn't calibrated and `now_ns` counts timer ticks instead.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Programs PIT channel 0 to fire IRQ0 at a fixed rate. Ticks are only counted once interrupts
+/// are enabled.
+pub fn init_timer() {
+    let mut command_port = Port::new(0x43);
+    let mut data_port = Port::new(0x40);
+    unsafe {
+        command_port.write(0b00110100_u8); // channel 0, lobyte/hibyte, rate generator
+        data_port.write((PIT_DIVIDER & 0xFF) as u8); // divider lobyte
+        data_port.write(((PIT_DIVIDER >> 8) & 0xFF) as u8); // divider hibyte
+    }
+}
+
+/// Busy-waits for `ms` milliseconds on PIT channel 2, which works with interrupts disabled and
+/// leaves channel 0 alone. Used to calibrate other timers; `ms` is at most 50.
+pub fn pit_wait_ms(ms: u64) {
+    let mut command_port = Port::new(0x43);
+    let mut data_port = Port::new(0x42);
+    let mut gate_port = Port::<u8>::new(0x61);
+    let count = (PIT_FREQUENCY * ms.min(50) / 1000) as u16;
+    unsafe {
+        // Gate channel 2 off and the speaker off while programming.
+        let gate = gate_port.read() & !0b11;
+        gate_port.write(gate);
+        command_port.write(0b10110000_u8); // channel 2, lobyte/hibyte, interrupt on terminal count
+        data_port.write((count & 0xFF) as u8);
+        data_port.write((count >> 8) as u8);
+        gate_port.write(gate | 1);
+        // The output goes high once the count runs out.
+        while gate_port.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        gate_port.write(gate);
+    }
+}
+
+/// Measures the TSC's frequency against the PIT, so `now_ns` can use it. CPUs without an invariant
+/// TSC change its rate with their clock speed, so `now_ns` stays on timer ticks there.
+pub fn calibrate_tsc() {
+    if !cpu::has_invariant_tsc() {
+        log::warn!("No invariant TSC, using the timer tick for nanosecond time");
+        return;
+    }
+    let start = unsafe { _rdtsc() };
+    pit_wait_ms(TSC_CALIBRATION_MS);
+    let end = unsafe { _rdtsc() };
+    let frequency = (end - start) * 1000 / TSC_CALIBRATION_MS;
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+    log::info!("TSC runs at {} MHz", frequency / 1_000_000);
+}
+
+/// Whether `now_ns` is counting yet: the TSC was calibrated, or the timer has ticked.
+pub fn clock_running() -> bool {
+    TSC_FREQUENCY.load(Ordering::Relaxed) != 0 || ticks() != 0
+}
+
+/// Nanoseconds from the TSC since the CPU was reset, or from the timer since it was started if the
+/// TSC isn't usable. Only differences between two calls mean anything.
+pub fn now_ns() -> u64 {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => ticks() * TICK_NANOS.load(Ordering::Relaxed),
+        frequency => (unsafe { _rdtsc() } as u128 * 1_000_000_000 / frequency as u128) as u64,
+    }
+}
+
+/// Sets how long each `tick` is, for timers other than the PIT.
+pub fn set_tick_length(nanos: u64) {
+    TICK_NANOS.store(nanos, Ordering::Relaxed);
+}
+
+/// Called from the timer interrupt.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer was started.
+pub fn uptime_ms() -> u64 {
+    ticks() * TICK_NANOS.load(Ordering::Relaxed) / 1_000_000
+}
+
+/// Halts until at least `ms` milliseconds have passed. Interrupts must be enabled, otherwise this
+/// never returns.
+pub fn sleep_ms(ms: u64) {
+    crate::sync::assert_no_locks_held("sleeping");
+    let end = uptime_ms() + ms;
+    while uptime_ms() < end {
+        x86_64::instructions::hlt();
+    }
+}
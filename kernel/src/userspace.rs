@@ -1,10 +1,16 @@
-use crate::memory::{KERNEL_MEMORY, USER_MEMORY};
-use core::arch::{asm, global_asm};
+use crate::{
+    cpu,
+    memory::{self, KERNEL_MEMORY, USER_SPACE_END},
+    program::LoadedProgram,
+    scheduler,
+};
+use core::arch::global_asm;
 use kernel_common::Syscall;
 use x86_64::{
-    registers::segmentation::Segment,
+    registers::{rflags::RFlags, segmentation::Segment},
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        paging::{mapper::TranslateResult, Page, PageTableFlags, Size4KiB, Translate},
         tss::TaskStateSegment,
     },
     VirtAddr,
@@ -16,6 +22,12 @@ use x86_64::{
 //   12-13: allow use of port I/O
 const USER_FLAGS: u64 = 0b11001000000010;
 
+/// Interrupt stack table entry exceptions run on, so they work whatever stack was active.
+pub const EXCEPTION_IST_INDEX: u16 = 0;
+/// Interrupt stack table entry reserved for double faults, so they still have a good stack when the
+/// exception stack is what overflowed.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 1;
+
 static mut TSS: TaskStateSegment = TaskStateSegment::new();
 static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
 
@@ -48,8 +60,10 @@ pub fn init_gdt() {
     // Setup TSS
     unsafe {
         TSS.privilege_stack_table[0] = KERNEL_MEMORY.privilege_stack.stack_start();
-        TSS.interrupt_stack_table[0] = KERNEL_MEMORY.interrupt_stack.stack_start();
-        TSS.interrupt_stack_table[1] = KERNEL_MEMORY.double_fault_stack.stack_start();
+        TSS.interrupt_stack_table[EXCEPTION_IST_INDEX as usize] =
+            KERNEL_MEMORY.interrupt_stack.stack_start();
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            KERNEL_MEMORY.double_fault_stack.stack_start();
     }
 
     // Setup GDT
@@ -65,10 +79,16 @@ pub fn init_gdt() {
 
 unsafe fn setup_userspace(segments: &Segments) {
     use x86_64::registers::model_specific::*;
-    // Enable syscall and sysret
+    // Enable syscall and sysret, and make sure the NO_EXECUTE page flag is honored
     Efer::update(|flags| {
         *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS;
+        if cpu::has_nx() {
+            *flags |= EferFlags::NO_EXECUTE_ENABLE;
+        }
     });
+    if !cpu::has_nx() {
+        log::warn!("CPU has no NX bit, data pages will be executable");
+    }
     // Setup segments
     Star::write(
         segments.user_code,
@@ -79,61 +99,274 @@ unsafe fn setup_userspace(segments: &Segments) {
     .unwrap();
     // Set jump point for when userspace executes syscall
     LStar::write(VirtAddr::from_ptr(syscall as *const ()));
+    // Disable interrupts until the syscall stub has switched to the kernel stack, and clear flags
+    // the kernel doesn't expect to be set.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    _syscall_kernel_rsp = KERNEL_MEMORY.syscall_stack.stack_start().as_u64();
 
     // Initialize function table.
     syscall_fns::init();
 }
 
-pub fn enter_userspace(entry_point: VirtAddr) -> ! {
-    let user_stack: u64 = USER_MEMORY.stack.stack_start().as_u64();
+/// Why a buffer passed in by userspace was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The address plus the length wraps around.
+    Overflow,
+    /// The buffer reaches into kernel space.
+    KernelAddress,
+    /// Part of the buffer isn't mapped, or not accessible from userspace.
+    NotMapped,
+    /// The kernel was asked to write to a buffer userspace can't write to.
+    ReadOnly,
+}
+
+/// Checks that `len` bytes at `ptr` are user memory that userspace could read itself, and returns
+/// them as a slice. A zero-length buffer is always valid, whatever `ptr` is.
+pub fn validate_user_buffer<'a>(ptr: *const u8, len: usize) -> Result<&'a [u8], Fault> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    check_user_range(ptr as u64, len, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(ptr, len) })
+}
+
+/// Like `validate_user_buffer`, but for a buffer the kernel writes to, which userspace must be
+/// able to write itself.
+pub fn validate_user_buffer_mut<'a>(ptr: *mut u8, len: usize) -> Result<&'a mut [u8], Fault> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    check_user_range(ptr as u64, len, true)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr, len) })
+}
+
+fn check_user_range(start: u64, len: usize, write: bool) -> Result<(), Fault> {
+    let end = start.checked_add(len as u64).ok_or(Fault::Overflow)?;
+    if end > USER_SPACE_END {
+        return Err(Fault::KernelAddress);
+    }
+    let address_space = memory::current_address_space();
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let last_page = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first_page, last_page) {
+        // Heap and program pages that weren't touched yet are mapped like on a page fault, so the
+        // kernel doesn't read the program's file while it's using the buffer.
+        if !address_space.handle_brk_fault(page.start_address()) {
+            address_space.handle_lazy_fault(page.start_address(), false);
+        }
+        let TranslateResult::Mapped { flags, .. } =
+            address_space.page_table().translate(page.start_address())
+        else {
+            return Err(Fault::NotMapped);
+        };
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            return Err(Fault::NotMapped);
+        }
+        // Copy-on-write pages are copied like on a page fault.
+        if write
+            && !flags.contains(PageTableFlags::WRITABLE)
+            && !address_space.handle_cow_fault(page.start_address())
+        {
+            return Err(Fault::ReadOnly);
+        }
+    }
+    Ok(())
+}
+
+/// Runs a loaded program in ring 3 until it exits, and returns its exit code.
+pub fn enter_userspace(program: LoadedProgram) -> u8 {
+    let LoadedProgram {
+        address_space,
+        entry_point,
+        stack_pointer,
+        args,
+    } = program;
+    match scheduler::spawn(address_space, entry_point, stack_pointer, args) {
+        Ok(id) => {
+            scheduler::set_foreground(Some(id));
+            let exit_code = scheduler::wait(id).unwrap_or(u8::MAX);
+            scheduler::set_foreground(None);
+            exit_code
+        }
+        Err(err) => {
+            log::error!("failed to start program: {}", err);
+            u8::MAX
+        }
+    }
+}
+
+/// Sets the stack used when a syscall or interrupt enters the kernel from ring 3.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
     unsafe {
-        asm!(
-            "mov rsp, {stack}",
-            "mov rbp, {stack}",
-            "mov r11, {flags}",
-            "sysretq",
-            in("rcx") entry_point.as_u64(),
-            stack = in(reg) user_stack,
-            flags = const USER_FLAGS,
-            options(noreturn),
-        )
+        TSS.privilege_stack_table[0] = stack_top;
+        _syscall_kernel_rsp = stack_top.as_u64();
     }
 }
 
+extern "C" {
+    /// Where a new process starts, popping the entry point, three arguments and the user stack
+    /// pointer pushed by `scheduler::spawn`.
+    pub fn user_entry() -> !;
+}
+
+global_asm!(
+    r#"
+.globl user_entry
+user_entry:
+    pop rcx
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rax
+    mov rsp, rax
+    xor ebp, ebp
+    mov r11, {flags}
+    sysretq
+"#, flags = const USER_FLAGS
+);
+
+extern "C" {
+    /// Where a forked process starts, popping the user return address, flags and stack pointer
+    /// of the syscall it returns from. The callee-saved registers were restored by
+    /// `switch_context`.
+    pub fn fork_return() -> !;
+    fn syscall_fork();
+}
+
+global_asm!(
+    r#"
+.globl fork_return
+fork_return:
+    pop rcx
+    pop r11
+    pop rax
+    mov rsp, rax
+    xor eax, eax
+    xor edi, edi
+    xor esi, esi
+    xor edx, edx
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    sysretq
+
+.globl syscall_fork
+syscall_fork:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    sub rsp, 8
+    call {fork}
+    add rsp, 56
+    ret
+"#,
+    fork = sym syscall_fns::fork,
+);
+
+/// The stack of a syscall as seen by `syscall_fork`: the callee-saved registers it pushed, then
+/// what the syscall stub pushed.
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    _return_address: u64,
+    _padding: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
 #[no_mangle]
 static mut _syscall_funcs: [u64; Syscall::NUM_SYSCALLS] = [0; Syscall::NUM_SYSCALLS];
 
 #[no_mangle]
-static mut _syscall_user_return: u64 = 0;
+static mut _syscall_kernel_rsp: u64 = 0;
+#[no_mangle]
+static mut _syscall_user_rsp: u64 = 0;
 
 extern "C" {
     fn syscall() -> !;
 }
 
+// Userspace puts the syscall number in rax and the arguments in the usual registers, except that
+// the fourth argument goes in r10 because syscall overwrites rcx with the return address.
 global_asm!(
     r#"
 .globl syscall
 syscall:
-    mov [_syscall_user_return + rip], rcx
-    lea rcx, [_syscall_funcs + rip]
-    add rax, rcx
+    mov [_syscall_user_rsp + rip], rsp
+    mov rsp, [_syscall_kernel_rsp + rip]
+    push qword ptr [_syscall_user_rsp + rip]
+    push rcx
+    push r11
+    sub rsp, 8
+    sti
+    cmp rax, {num_syscalls}
+    jae 2f
+    lea r11, [_syscall_funcs + rip]
+    mov r11, [r11 + rax * 8]
+    test r11, r11
+    jz 2f
+    mov rcx, r10
+    call r11
+    jmp 3f
+2:
+    mov rdi, rax
+    call {invalid_syscall}
+3:
+    sub rsp, 16
+    mov [rsp], rax
+    mov [rsp + 8], rdx
+    call {exit_if_terminated}
+    mov rax, [rsp]
+    mov rdx, [rsp + 8]
+    add rsp, 16
+    cli
+    add rsp, 8
+    pop r11
     pop rcx
-    call [rax]
-    mov rcx, [_syscall_user_return + rip]
-    mov r11, {flags}
+    pop rsp
     sysretq
-"#, flags = const USER_FLAGS
+"#,
+    num_syscalls = const Syscall::NUM_SYSCALLS,
+    invalid_syscall = sym syscall_fns::invalid_syscall,
+    exit_if_terminated = sym scheduler::exit_if_terminated,
 );
 
 #[allow(improper_ctypes_definitions)]
 mod syscall_fns {
-    use crate::{fatal_error, graphics, memory};
-    use alloc::string::String;
-    use core::alloc::{GlobalAlloc, Layout};
+    use super::{validate_user_buffer, validate_user_buffer_mut, SyscallFrame};
+    use crate::{
+        acpi, console, fatal_error,
+        fd::FileDescriptor,
+        filesystem, graphics,
+        memory::{self, PAGE_SIZE},
+        pipe, scheduler, shm,
+    };
+    use alloc::{rc::Rc, string::String};
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        cell::RefCell,
+    };
     use kernel_common::{
-        graphics::{FrameBuffer, GraphicsContext},
+        graphics::{FbInfo, FrameBuffer, GraphicsContext, Rect},
         Syscall,
     };
+    use x86_64::VirtAddr;
 
     pub unsafe fn init() {
         use super::_syscall_funcs as funcs;
@@ -147,6 +380,24 @@ mod syscall_fns {
         funcs[Syscall::MEM_ALLOC_ZEROED] = mem_alloc_zeroed as u64;
         funcs[Syscall::MEM_REALLOC] = mem_realloc as u64;
         funcs[Syscall::PROGRAM_PANIC] = program_panic as u64;
+        funcs[Syscall::PROGRAM_EXIT] = program_exit as u64;
+        funcs[Syscall::WRITE] = write as u64;
+        funcs[Syscall::PROGRAM_YIELD] = program_yield as u64;
+        funcs[Syscall::MEM_BRK] = mem_brk as u64;
+        funcs[Syscall::PROGRAM_FORK] = super::syscall_fork as u64;
+        funcs[Syscall::SYSTEM_POWER_OFF] = system_power_off as u64;
+        funcs[Syscall::SHM_CREATE] = shm_create as u64;
+        funcs[Syscall::SHM_MAP] = shm_map as u64;
+        funcs[Syscall::SHM_UNMAP] = shm_unmap as u64;
+        funcs[Syscall::READ] = read as u64;
+        funcs[Syscall::PIPE] = pipe as u64;
+        funcs[Syscall::CLOSE] = close as u64;
+        funcs[Syscall::OPEN] = open as u64;
+        funcs[Syscall::DUP] = dup as u64;
+        funcs[Syscall::DUP2] = dup2 as u64;
+        funcs[Syscall::PROGRAM_WAIT] = program_wait as u64;
+        funcs[Syscall::FB_INFO] = fb_info as u64;
+        funcs[Syscall::FB_BLIT] = fb_blit as u64;
     }
 
     fn copy_str_to_user_memory(input: &str) -> String {
@@ -173,12 +424,75 @@ mod syscall_fns {
     extern "sysv64" fn info_graphics_ctx() -> GraphicsContext {
         graphics::context()
     }
+    /// Stores the screen's size and pixel layout in `info`. Returns 0, or -1 if `info` isn't
+    /// writable or there's no screen.
+    extern "sysv64" fn fb_info(info: *mut FbInfo) -> i64 {
+        let Ok(out) = validate_user_buffer_mut(info as *mut u8, core::mem::size_of::<FbInfo>())
+        else {
+            return -1;
+        };
+        if unsafe { graphics::framebuffer() }.is_none() {
+            return -1;
+        }
+        let mode = graphics::mode();
+        let fb_info = FbInfo {
+            width: mode.width,
+            height: mode.height,
+            stride: mode.stride as u32,
+            bytes_per_pixel: mode.bytes_per_pixel as u32,
+            pixel_format: graphics::context().pixel_format(),
+        };
+        unsafe { (out.as_mut_ptr() as *mut FbInfo).write_unaligned(fb_info) };
+        0
+    }
+    /// Draws the image in `ptr` and `len` to `rect` on the screen and shows it, see
+    /// `graphics::blit`. Returns 0, or -1 if `rect` isn't inside the screen, the image is too
+    /// small, or the caller isn't the program the shell is running.
+    extern "sysv64" fn fb_blit(ptr: *const u8, len: usize, rect: Rect) -> i64 {
+        if scheduler::foreground() != Some(scheduler::current_id()) {
+            return -1;
+        }
+        let (width, height) = graphics::dimensions();
+        if rect.is_empty()
+            || rect.x() < 0
+            || rect.y() < 0
+            || rect.right() > width as i32
+            || rect.bottom() > height as i32
+        {
+            return -1;
+        }
+        let needed =
+            rect.width() as usize * rect.height() as usize * graphics::mode().bytes_per_pixel;
+        if len < needed {
+            return -1;
+        }
+        let Ok(src) = validate_user_buffer(ptr, needed) else {
+            return -1;
+        };
+        graphics::blit(src, rect);
+        graphics::present();
+        0
+    }
+
+    /// Whether `ptr` can be a block of `layout` from the program's heap. The allocator writes to
+    /// blocks it's given back, so anything else could make the kernel overwrite its own memory.
+    fn is_heap_block(ptr: *mut u8, layout: Layout) -> bool {
+        let heap = memory::USER_MEMORY.heap;
+        let start = ptr as u64;
+        validate_user_buffer_mut(ptr, layout.size()).is_ok()
+            && start >= heap.start().as_u64()
+            && start
+                .checked_add(layout.size() as u64)
+                .map_or(false, |end| end <= heap.end())
+    }
 
     unsafe extern "sysv64" fn mem_alloc(layout: Layout) -> *mut u8 {
         memory::user_allocator().alloc(layout)
     }
     unsafe extern "sysv64" fn mem_dealloc(ptr: *mut u8, layout: Layout) {
-        memory::user_allocator().dealloc(ptr, layout)
+        if is_heap_block(ptr, layout) {
+            memory::user_allocator().dealloc(ptr, layout)
+        }
     }
     unsafe extern "sysv64" fn mem_alloc_zeroed(layout: Layout) -> *mut u8 {
         memory::user_allocator().alloc_zeroed(layout)
@@ -188,10 +502,223 @@ mod syscall_fns {
         layout: Layout,
         new_size: usize,
     ) -> *mut u8 {
+        if !is_heap_block(ptr, layout) {
+            return core::ptr::null_mut();
+        }
         memory::user_allocator().realloc(ptr, layout, new_size)
     }
+    /// Moves the end of the program's `brk` heap and returns the new end, or the current end if
+    /// `new_break` is out of range (e.g. 0).
+    extern "sysv64" fn mem_brk(new_break: u64) -> u64 {
+        memory::current_address_space().set_brk(new_break)
+    }
+
+    extern "sysv64" fn program_panic(ptr: *const u8, len: usize) -> ! {
+        match validate_user_buffer(ptr, len) {
+            Ok(message) => {
+                fatal_error!("userspace panic:\n{}", String::from_utf8_lossy(message))
+            }
+            Err(fault) => fatal_error!("userspace panic: bad message {:?}", fault),
+        }
+    }
+    extern "sysv64" fn program_exit(exit_code: u8) -> ! {
+        scheduler::exit(exit_code)
+    }
+    extern "sysv64" fn program_yield() {
+        scheduler::yield_now();
+    }
+    /// Blocks until child process `id` exits and returns its exit code, or -1 if it isn't a child
+    /// of the caller.
+    extern "sysv64" fn program_wait(id: u64) -> i64 {
+        scheduler::wait(id as usize).map_or(-1, i64::from)
+    }
 
-    extern "sysv64" fn program_panic(message: &str) -> ! {
-        fatal_error!("userspace panic:\n{}", message);
+    extern "sysv64" fn system_power_off() -> ! {
+        acpi::shutdown()
+    }
+
+    /// Creates a zeroed shared memory region of at least `size` bytes. Returns its id, or -1 if
+    /// it's empty or too large.
+    extern "sysv64" fn shm_create(size: usize) -> i64 {
+        match shm::create(size) {
+            Ok(id) => id as i64,
+            Err(err) => {
+                log::warn!("shm_create({}) failed: {}", size, err);
+                -1
+            }
+        }
+    }
+    /// Maps shared memory region `id` and returns its address, or 0 if it couldn't be mapped.
+    extern "sysv64" fn shm_map(id: u64) -> u64 {
+        match shm::map(id) {
+            Ok(addr) => addr.as_u64(),
+            Err(err) => {
+                log::warn!("shm_map({}) failed: {}", id, err);
+                0
+            }
+        }
+    }
+    /// Unmaps the shared memory mapped at `addr`. Returns 0, or -1 if nothing was mapped there.
+    extern "sysv64" fn shm_unmap(addr: u64) -> i64 {
+        match VirtAddr::try_new(addr)
+            .map_err(|_| "bad address")
+            .and_then(shm::unmap)
+        {
+            Ok(()) => 0,
+            Err(err) => {
+                log::warn!("shm_unmap({:#x}) failed: {}", addr, err);
+                -1
+            }
+        }
+    }
+
+    /// Duplicates the calling process. Returns the child's id in the parent and 0 in the child, or
+    /// -1 if the process couldn't be copied.
+    pub extern "sysv64" fn fork(frame: &SyscallFrame) -> i64 {
+        let result = memory::current_address_space()
+            .fork()
+            .and_then(|address_space| scheduler::fork(address_space, frame));
+        match result {
+            Ok(id) => id as i64,
+            Err(err) => {
+                log::warn!("fork failed: {}", err);
+                -1
+            }
+        }
+    }
+
+    pub extern "sysv64" fn invalid_syscall(number: u64) -> i64 {
+        log::warn!("invalid syscall {}", number);
+        -1
+    }
+
+    /// Writes to the console or a pipe, blocking while the pipe is full. Returns the number of
+    /// bytes written, or -1 for a bad file descriptor or buffer, or a pipe nobody can read.
+    extern "sysv64" fn write(fd: u64, ptr: *const u8, len: usize) -> i64 {
+        // A copy, so the table isn't borrowed while blocked.
+        let Some(descriptor) = scheduler::current_fds().get(fd).cloned() else {
+            return -1;
+        };
+        let Ok(bytes) = validate_user_buffer(ptr, len) else {
+            return -1;
+        };
+        match descriptor {
+            FileDescriptor::Console(color) => {
+                console::write(bytes, color);
+                len as i64
+            }
+            FileDescriptor::PipeWriter(writer) => writer.write(bytes).map_or(-1, |len| len as i64),
+            FileDescriptor::File(_) | FileDescriptor::PipeReader(_) => -1,
+        }
+    }
+    /// Reads from a file or pipe, blocking until a pipe has data. Returns the number of bytes read,
+    /// 0 at the end of the data, or -1 for a bad file descriptor or buffer, or a disk error.
+    extern "sysv64" fn read(fd: u64, ptr: *mut u8, len: usize) -> i64 {
+        let Some(descriptor) = scheduler::current_fds().get(fd).cloned() else {
+            return -1;
+        };
+        let Ok(buf) = validate_user_buffer_mut(ptr, len) else {
+            return -1;
+        };
+        match descriptor {
+            FileDescriptor::File(file) => read_file(&file, buf),
+            FileDescriptor::PipeReader(reader) => reader.read(buf) as i64,
+            FileDescriptor::Console(_) | FileDescriptor::PipeWriter(_) => -1,
+        }
+    }
+    /// Fills `buf` from `file` a page at a time, through a kernel buffer so a large read doesn't
+    /// need a large allocation.
+    ///
+    /// The reads go through a copy of the file, since the shared one can't stay borrowed while
+    /// this blocks on the disk and a process sharing the descriptor reads from it too.
+    fn read_file(shared: &RefCell<filesystem::File>, buf: &mut [u8]) -> i64 {
+        let mut file = shared.borrow().duplicate();
+        let mut chunk = alloc::vec![0; PAGE_SIZE.min(buf.len())];
+        let mut total = 0;
+        for part in buf.chunks_mut(PAGE_SIZE) {
+            let len = part.len();
+            match file.read(&mut chunk[..len]) {
+                Ok(read) => {
+                    part[..read].copy_from_slice(&chunk[..read]);
+                    total += read as i64;
+                    if read < len {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    log::warn!("read: {}", err);
+                    total = -1;
+                    break;
+                }
+            }
+        }
+        shared.borrow_mut().seek(file.position());
+        total
+    }
+    /// Creates a pipe and stores the file descriptors of its read and write ends in `fds`, as two
+    /// u32s. Returns 0, or -1 if `fds` isn't writable.
+    extern "sysv64" fn pipe(fds: *mut u32) -> i64 {
+        let Ok(out) = validate_user_buffer_mut(fds as *mut u8, 8) else {
+            return -1;
+        };
+        let (reader, writer) = pipe::new();
+        let table = scheduler::current_fds();
+        let Some(read_fd) = table.insert(FileDescriptor::PipeReader(reader)) else {
+            return -1;
+        };
+        let Some(write_fd) = table.insert(FileDescriptor::PipeWriter(writer)) else {
+            drop(table.close(read_fd as u64));
+            return -1;
+        };
+        out[..4].copy_from_slice(&(read_fd as u32).to_ne_bytes());
+        out[4..].copy_from_slice(&(write_fd as u32).to_ne_bytes());
+        0
+    }
+    /// Opens the file at the path in `ptr` and `len` for reading. Returns the lowest free file
+    /// descriptor, or -1 if the file can't be opened or all are in use.
+    extern "sysv64" fn open(ptr: *const u8, len: usize) -> i64 {
+        let Some(path) = validate_user_buffer(ptr, len)
+            .ok()
+            .and_then(|bytes| core::str::from_utf8(bytes).ok())
+        else {
+            return -1;
+        };
+        let file = match filesystem::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                log::debug!("open {}: {}", path, err);
+                return -1;
+            }
+        };
+        let descriptor = FileDescriptor::File(Rc::new(RefCell::new(file)));
+        scheduler::current_fds()
+            .insert(descriptor)
+            .map_or(-1, |fd| fd as i64)
+    }
+    /// Makes the lowest free file descriptor refer to the same thing as `fd`. Returns it, or -1
+    /// if `fd` isn't open or all are in use.
+    extern "sysv64" fn dup(fd: u64) -> i64 {
+        scheduler::current_fds().dup(fd).map_or(-1, |fd| fd as i64)
+    }
+    /// Makes `new_fd` refer to the same thing as `fd`, closing it first if it was open. Returns
+    /// `new_fd`, or -1 if `fd` isn't open or `new_fd` is out of range.
+    extern "sysv64" fn dup2(fd: u64, new_fd: u64) -> i64 {
+        match scheduler::current_fds().dup2(fd, new_fd) {
+            Some(closed) => {
+                drop(closed);
+                new_fd as i64
+            }
+            None => -1,
+        }
+    }
+    /// Closes a file descriptor. Returns 0, or -1 if it wasn't open.
+    extern "sysv64" fn close(fd: u64) -> i64 {
+        match scheduler::current_fds().close(fd) {
+            Some(descriptor) => {
+                drop(descriptor);
+                0
+            }
+            None => -1,
+        }
     }
 }
@@ -0,0 +1,69 @@
+use crate::{acpi, cmdline, time};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// How long the kernel can go without calling `pet` before the machine is rebooted. 0 while the
+/// watchdog is off.
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+/// Uptime at which the timer reboots, unless `pet` moves it further out first.
+static DEADLINE_MS: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Nested `suspend` calls still waiting for their `resume`.
+static SUSPENDED: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts the watchdog if the command line asks for it with `watchdog=<n>s` or `watchdog=<n>ms`.
+/// It's checked on timer ticks, so it catches hangs with interrupts enabled, like a kernel loop
+/// that never finishes, but not ones with interrupts disabled.
+pub fn init() {
+    let Some(value) = cmdline::get("watchdog") else {
+        return;
+    };
+    match parse_duration(value) {
+        Some(timeout) if timeout > 0 => {
+            TIMEOUT_MS.store(timeout, Ordering::Relaxed);
+            pet();
+            log::info!("Watchdog reboots after {} ms without progress", timeout);
+        }
+        _ => log::warn!("Invalid watchdog timeout {}", value),
+    }
+}
+
+/// Milliseconds from `5s` or `500ms`. A bare number is seconds.
+fn parse_duration(value: &str) -> Option<u64> {
+    if let Some(ms) = value.strip_suffix("ms") {
+        return ms.parse().ok();
+    }
+    let seconds: u64 = value.strip_suffix('s').unwrap_or(value).parse().ok()?;
+    seconds.checked_mul(1000)
+}
+
+/// Reports progress, putting the reboot off for another timeout. Called wherever the kernel is
+/// known to be doing fine: the shell waiting for a key, the idle task, and the timer preempting
+/// userspace.
+pub fn pet() {
+    let timeout = TIMEOUT_MS.load(Ordering::Relaxed);
+    if timeout != 0 {
+        DEADLINE_MS.store(time::uptime_ms() + timeout, Ordering::Relaxed);
+    }
+}
+
+/// Stops the watchdog until `resume`, around work that can legitimately take longer than the
+/// timeout, like copying a whole partition. Calls nest.
+pub fn suspend() {
+    SUSPENDED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Undoes a `suspend`, with a full timeout from now once none are left.
+pub fn resume() {
+    SUSPENDED.fetch_sub(1, Ordering::Relaxed);
+    pet();
+}
+
+/// Reboots if the deadline has passed. Called on every timer tick.
+pub fn tick() {
+    if TIMEOUT_MS.load(Ordering::Relaxed) == 0 || SUSPENDED.load(Ordering::Relaxed) > 0 {
+        return;
+    }
+    if time::uptime_ms() >= DEADLINE_MS.load(Ordering::Relaxed) {
+        log::error!("Watchdog expired, the kernel seems to be stuck");
+        acpi::reboot();
+    }
+}
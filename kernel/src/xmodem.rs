@@ -0,0 +1,49 @@
+use crate::{
+    filesystem::{self, FsError},
+    serial, watchdog,
+};
+use kernel_common::xmodem::{self, Line, PADDING};
+
+/// COM1, which the transfer uses while serial logging is paused.
+struct Com1;
+
+impl Line for Com1 {
+    fn read_byte(&mut self, timeout_ms: u64) -> Option<u8> {
+        serial::read_byte(timeout_ms)
+    }
+    fn write_byte(&mut self, byte: u8) {
+        serial::write_byte(byte);
+    }
+}
+
+/// Replaces the file at `path` with `data`, creating it if it doesn't exist.
+fn save(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let mut file = match filesystem::open(path) {
+        Ok(mut file) => {
+            file.truncate()?;
+            file
+        }
+        Err(FsError::NotFound) => filesystem::create(path)?,
+        Err(err) => return Err(err),
+    };
+    file.write(data)?;
+    file.flush()
+}
+
+/// Receives a file over COM1 with XMODEM-CRC and writes it to `path`, replacing what was there.
+/// Returns the file's size. XMODEM pads the last block, so trailing padding bytes are removed,
+/// which would also remove any the file really ended with. Serial logging is paused meanwhile.
+pub fn receive(path: &str) -> Result<usize, &'static str> {
+    serial::begin_raw();
+    // The sender is usually started by hand, and a big file takes a while.
+    watchdog::suspend();
+    let result = xmodem::receive(&mut Com1);
+    watchdog::resume();
+    serial::end_raw();
+    let mut data = result?;
+    while data.last() == Some(&PADDING) {
+        data.pop();
+    }
+    save(path, &data).map_err(FsError::as_str)?;
+    Ok(data.len())
+}
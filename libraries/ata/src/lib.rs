@@ -8,7 +8,7 @@ use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 pub use block_device::BlockDevice;
 
 /// Implementation Courtesy of MOROS.
-/// Currently Only Supports ATA-PIO, with 24-bit LBA Addressing.
+/// Supports ATA-PIO and bus-master DMA reads, with 28-bit or 48-bit LBA Addressing.
 
 fn sleep_ticks(ticks: usize) {
     for _ in 0..=ticks {
@@ -19,10 +19,67 @@ fn sleep_ticks(ticks: usize) {
 #[repr(u16)]
 enum Command {
     Read = 0x20,
+    ReadExt = 0x24,
+    ReadDmaExt = 0x25,
     Write = 0x30,
+    WriteExt = 0x34,
+    ReadDma = 0xC8,
+    CacheFlush = 0xE7,
+    CacheFlushExt = 0xEA,
     Identify = 0xEC,
 }
 
+// The command block registers of the primary and the secondary bus.
+const IO_BASES: [u16; 2] = [0x1F0, 0x170];
+// Offset of the status register from a bus' I/O base.
+const STATUS_REGISTER: u16 = 7;
+
+/// Blocks at or past this need 48-bit addressing.
+const LBA28_LIMIT: u64 = 1 << 28;
+
+// Bus master IDE registers, as offsets from the bus' bus master base.
+const BM_COMMAND: u16 = 0;
+const BM_STATUS: u16 = 2;
+const BM_PRDT: u16 = 4;
+
+const BM_COMMAND_START: u8 = 1 << 0;
+// Set when the controller writes to memory.
+const BM_COMMAND_READ: u8 = 1 << 3;
+const BM_STATUS_ACTIVE: u8 = 1 << 0;
+const BM_STATUS_ERROR: u8 = 1 << 1;
+// Mirrors the drive's interrupt line, so completion can be polled even if the IRQ isn't routed.
+const BM_STATUS_INTERRUPT: u8 = 1 << 2;
+// Marks the last entry of a physical region descriptor table.
+const PRD_END_OF_TABLE: u32 = 1 << 31;
+const PRDT_SIZE: usize = 512;
+
+/// Physically contiguous memory the controller transfers into. The first 512 bytes hold the
+/// descriptor table, the rest is the data buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRegion {
+    pub virt: *mut u8,
+    /// Must be below 4 GiB, 4-byte aligned, and `len` bytes from it must not cross a 64 KiB
+    /// boundary.
+    pub phys: u64,
+    pub len: usize,
+}
+
+impl DmaRegion {
+    fn sectors(&self) -> usize {
+        (self.len - PRDT_SIZE) / 512
+    }
+}
+
+static mut DMA: Option<DmaRegion> = None;
+
+/// Waits until `done` returns true for a transfer on bus `bus`, which raises the bus' IRQ when
+/// `done` may have become true. Set with `set_waiter`, e.g. to let other tasks run meanwhile.
+/// Returns false if it gave up because the drive took too long.
+pub type Waiter = fn(bus: u8, done: &mut dyn FnMut() -> bool) -> bool;
+
+// Without one, transfers are polled.
+static mut WAITER: Option<Waiter> = None;
+
 #[allow(dead_code)]
 #[allow(clippy::upper_case_acronyms)]
 #[repr(usize)]
@@ -42,6 +99,8 @@ enum Status {
 pub struct Bus {
     id: u8,
     irq: u8,
+    // None when there is no bus master controller, so only PIO can be used.
+    bus_master_base: Option<u16>,
 
     data_register: Port<u16>,
     error_register: PortReadOnly<u8>,
@@ -65,6 +124,7 @@ impl Bus {
         Self {
             id,
             irq,
+            bus_master_base: None,
 
             data_register: Port::new(io_base + 0),
             error_register: PortReadOnly::new(io_base + 1),
@@ -74,7 +134,7 @@ impl Bus {
             lba1_register: Port::new(io_base + 4),
             lba2_register: Port::new(io_base + 5),
             drive_register: Port::new(io_base + 6),
-            status_register: PortReadOnly::new(io_base + 7),
+            status_register: PortReadOnly::new(io_base + STATUS_REGISTER),
             command_register: PortWriteOnly::new(io_base + 7),
 
             alternate_status_register: PortReadOnly::new(ctrl_base + 0),
@@ -129,17 +189,36 @@ impl Bus {
 
     fn busy_loop(&mut self) {
         self.wait();
-        let start = 0;
         while self.is_busy() {
-            if 0 - start > 1 {
-                // Hanged
-                return self.reset();
-            }
-
             core::hint::spin_loop();
         }
     }
 
+    /// Like `busy_loop`, but through the waiter, for commands after which the drive raises its
+    /// interrupt once it's no longer busy.
+    fn wait_for_interrupt(&mut self) -> Result<(), AtaError> {
+        self.wait();
+        self.wait_until(|bus| !bus.is_busy())
+    }
+
+    /// Resets the bus if the waiter gives up, so the next command doesn't find it still busy.
+    fn wait_until(&mut self, mut done: impl FnMut(&mut Self) -> bool) -> Result<(), AtaError> {
+        match unsafe { WAITER } {
+            Some(waiter) => {
+                if !waiter(self.id, &mut || done(self)) {
+                    self.reset();
+                    return Err(AtaError::Timeout);
+                }
+            }
+            None => {
+                while !done(self) {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn is_busy(&mut self) -> bool {
         self.status().get_bit(Status::BSY as usize)
     }
@@ -161,16 +240,35 @@ impl Bus {
         }
     }
 
-    fn setup(&mut self, drive: u8, block: u32) {
-        let drive_id = 0xE0 | (drive << 4);
+    /// Sets up a transfer of `count` blocks starting at `block`. Returns whether the transfer
+    /// needs 48-bit addressing, which takes the extended version of each command.
+    fn setup(&mut self, drive: u8, block: u64, count: u8) -> bool {
+        if block + count as u64 <= LBA28_LIMIT {
+            let drive_id = 0xE0 | (drive << 4);
+            unsafe {
+                self.drive_register
+                    .write(drive_id | ((block.get_bits(24..28) as u8) & 0x0F));
+                self.sector_count_register.write(count);
+                self.lba0_register.write(block.get_bits(0..8) as u8);
+                self.lba1_register.write(block.get_bits(8..16) as u8);
+                self.lba2_register.write(block.get_bits(16..24) as u8);
+            }
+            return false;
+        }
+        // Each register takes the high byte first, then the low byte.
+        let drive_id = 0x40 | (drive << 4);
         unsafe {
-            self.drive_register
-                .write(drive_id | ((block.get_bits(24..28) as u8) & 0x0F));
-            self.sector_count_register.write(1);
+            self.drive_register.write(drive_id);
+            self.sector_count_register.write(0);
+            self.lba0_register.write(block.get_bits(24..32) as u8);
+            self.lba1_register.write(block.get_bits(32..40) as u8);
+            self.lba2_register.write(block.get_bits(40..48) as u8);
+            self.sector_count_register.write(count);
             self.lba0_register.write(block.get_bits(0..8) as u8);
             self.lba1_register.write(block.get_bits(8..16) as u8);
             self.lba2_register.write(block.get_bits(16..24) as u8);
         }
+        true
     }
 
     pub fn identify_drive(&mut self, drive: u8) -> Option<[u16; 256]> {
@@ -186,7 +284,9 @@ impl Bus {
 
         self.write_command(Command::Identify);
 
-        if self.status() == 0 {
+        // 0 means no drive, 0xFF a floating bus with no controller behind it.
+        let status = self.status();
+        if status == 0 || status == 0xFF {
             return None;
         }
 
@@ -231,16 +331,20 @@ impl Bus {
     ///     read(0, 0, 0, &mut buffer);
     /// }
 
-    pub fn read(&mut self, drive: u8, block: u32, buf: &mut [u8]) {
+    pub fn read(&mut self, drive: u8, block: u64, buf: &mut [u8]) -> Result<(), AtaError> {
         assert_eq!(buf.len(), 512);
-        self.setup(drive, block);
-        self.write_command(Command::Read);
-        self.busy_loop();
+        if self.setup(drive, block, 1) {
+            self.write_command(Command::ReadExt);
+        } else {
+            self.write_command(Command::Read);
+        }
+        self.wait_for_interrupt()?;
         for i in 0..256 {
             let data = self.read_data();
             buf[i * 2] = data.get_bits(0..8) as u8;
             buf[i * 2 + 1] = data.get_bits(8..16) as u8;
         }
+        Ok(())
     }
 
     /// Write A single, 512-byte long slice to a given block
@@ -258,18 +362,124 @@ impl Bus {
     ///     write(0, 0, 0, &buffer);
     /// }
 
-    pub fn write(&mut self, drive: u8, block: u32, buf: &[u8]) {
+    pub fn write(&mut self, drive: u8, block: u64, buf: &[u8]) -> Result<(), AtaError> {
         assert_eq!(buf.len(), 512);
-        self.setup(drive, block);
-        self.write_command(Command::Write);
+        if self.setup(drive, block, 1) {
+            self.write_command(Command::WriteExt);
+        } else {
+            self.write_command(Command::Write);
+        }
+        // The drive asks for the data without an interrupt.
         self.busy_loop();
+        if self.is_device_error() {
+            return Err(AtaError::DeviceError);
+        }
         for i in 0..256 {
             let mut data = 0u16;
             data.set_bits(0..8, buf[i * 2] as u16);
             data.set_bits(8..16, buf[i * 2 + 1] as u16);
             self.write_data(data);
         }
+        self.wait_for_interrupt()?;
+        if self.is_device_error() {
+            return Err(AtaError::DeviceError);
+        }
+        Ok(())
+    }
+
+    /// Waits until the drive has written its cache to the disk. `lba48` drives take the extended
+    /// command.
+    pub fn flush(&mut self, drive: u8, lba48: bool) -> Result<(), AtaError> {
+        self.select_drive(drive);
+        self.wait();
+        if lba48 {
+            self.write_command(Command::CacheFlushExt);
+        } else {
+            self.write_command(Command::CacheFlush);
+        }
+        self.wait_for_interrupt()?;
+        if self.is_device_error() {
+            return Err(AtaError::DeviceError);
+        }
+        Ok(())
+    }
+
+    fn is_device_error(&mut self) -> bool {
+        let status = self.status();
+        status.get_bit(Status::ERR as usize) || status.get_bit(Status::DF as usize)
+    }
+
+    /// Whether this bus can do DMA transfers.
+    fn has_dma(&self) -> bool {
+        self.bus_master_base.is_some() && unsafe { DMA.is_some() }
+    }
+
+    fn bus_master_read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.bus_master_base.unwrap() + register).read() }
+    }
+
+    fn bus_master_write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.bus_master_base.unwrap() + register).write(value) }
+    }
+
+    /// Reads `buf.len() / 512` consecutive blocks starting at `block` with a single DMA transfer.
+    /// `buf` must fit in the DMA region.
+    fn read_dma_once(&mut self, drive: u8, block: u64, buf: &mut [u8]) -> Result<(), AtaError> {
+        let region = unsafe { DMA.ok_or(AtaError::NotInitialized)? };
+        let bytes = buf.len();
+        let data_phys = region.phys as u32 + PRDT_SIZE as u32;
+        unsafe {
+            let prdt = region.virt as *mut u32;
+            prdt.write_volatile(data_phys);
+            // A byte count of 0 means 64 KiB.
+            prdt.add(1)
+                .write_volatile(PRD_END_OF_TABLE | (bytes as u32 & 0xFFFF));
+            Port::<u32>::new(self.bus_master_base.unwrap() + BM_PRDT).write(region.phys as u32);
+        }
+        self.bus_master_write(BM_COMMAND, 0);
+        // Both bits are cleared by writing 1.
+        self.bus_master_write(BM_STATUS, BM_STATUS_ERROR | BM_STATUS_INTERRUPT);
+
+        if self.setup(drive, block, (bytes / 512) as u8) {
+            self.write_command(Command::ReadDmaExt);
+        } else {
+            self.write_command(Command::ReadDma);
+        }
+        self.bus_master_write(BM_COMMAND, BM_COMMAND_START | BM_COMMAND_READ);
+
+        let mut status = 0;
+        let done = self.wait_until(|bus| {
+            status = bus.bus_master_read(BM_STATUS);
+            status & BM_STATUS_INTERRUPT != 0 || status & BM_STATUS_ACTIVE == 0
+        });
+        self.bus_master_write(BM_COMMAND, 0);
+        done?;
+        // Reading the status register acknowledges the drive's interrupt.
         self.busy_loop();
+        if status & BM_STATUS_ERROR != 0 || self.is_error() {
+            return Err(AtaError::DmaError);
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(region.virt.add(PRDT_SIZE), buf.as_mut_ptr(), bytes);
+        }
+        Ok(())
+    }
+
+    /// Reads consecutive blocks starting at `block` into `buf`, whose length must be a multiple of
+    /// 512. A transfer that fails is retried once.
+    pub fn read_dma(&mut self, drive: u8, block: u64, buf: &mut [u8]) -> Result<(), AtaError> {
+        assert_eq!(buf.len() % 512, 0);
+        let sectors = unsafe { DMA.ok_or(AtaError::NotInitialized)? }
+            .sectors()
+            .min(255);
+        for (i, chunk) in buf.chunks_mut(sectors * 512).enumerate() {
+            let chunk_block = block + (i * sectors) as u64;
+            if self.read_dma_once(drive, chunk_block, chunk).is_err() {
+                self.reset();
+                self.read_dma_once(drive, chunk_block, chunk)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -281,6 +491,16 @@ pub enum AtaError {
     AddressNotAligned,
     OutOfBounds,
     WrongSizeBuffer,
+    /// A DMA transfer failed twice.
+    DmaError,
+    /// The DMA region doesn't meet the controller's requirements.
+    InvalidDmaRegion,
+    /// The drive reported an error or a device fault.
+    DeviceError,
+    /// The device can't be written to.
+    ReadOnly,
+    /// The drive didn't finish a command in time, e.g. because its interrupt never came.
+    Timeout,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -288,14 +508,27 @@ pub struct Drive {
     bus: usize,
     drive: u8,
     block_count: usize,
+    supports_dma: bool,
+    // Only drives with 48-bit addressing can have blocks past `LBA28_LIMIT`.
+    supports_lba48: bool,
 }
 
 impl Drive {
-    fn new(bus: u8, drive: u8, block_count: u32) -> Drive {
+    fn new(bus: u8, drive: u8, identify: &[u16; 256]) -> Drive {
+        let supports_lba48 = identify[83].get_bit(10);
+        let block_count = if supports_lba48 {
+            (0..4).fold(0u64, |count, i| {
+                count | (identify[100 + i] as u64) << (16 * i)
+            })
+        } else {
+            (identify[61] as u64) << 16 | (identify[60] as u64)
+        };
         Drive {
             bus: bus as usize,
             drive,
             block_count: block_count as usize,
+            supports_dma: identify[49].get_bit(8),
+            supports_lba48,
         }
     }
     fn byte_index_to_lba(
@@ -318,6 +551,10 @@ impl Drive {
     pub fn size_in_kib(&self) -> usize {
         self.block_count / 2
     }
+
+    pub fn block_count(&self) -> usize {
+        self.block_count
+    }
 }
 
 impl BlockDevice for Drive {
@@ -335,13 +572,16 @@ impl BlockDevice for Drive {
         }
         let address = self.byte_index_to_lba(address, number_of_blocks)?;
         let buses = unsafe { BUSES.as_mut().ok_or(AtaError::NotInitialized)? };
+        if self.supports_dma && buses[self.bus].has_dma() {
+            return buses[self.bus].read_dma(self.drive, address as u64, buf);
+        }
         for i in 0..number_of_blocks {
             let off = i * BLOCK_SIZE;
             buses[self.bus].read(
                 self.drive,
-                (address + i) as u32,
+                (address + i) as u64,
                 &mut buf[off..off + BLOCK_SIZE],
-            );
+            )?;
         }
         Ok(())
     }
@@ -361,11 +601,11 @@ impl BlockDevice for Drive {
             let off = i * BLOCK_SIZE;
             buses[self.bus].write(
                 self.drive,
-                (address + i) as u32,
+                (address + i) as u64,
                 &buf[off..off + BLOCK_SIZE],
-            );
+            )?;
         }
-        Ok(())
+        buses[self.bus].flush(self.drive, self.supports_lba48)
     }
 }
 
@@ -387,6 +627,9 @@ impl Partition {
     pub fn size_in_kib(&self) -> usize {
         self.num_bytes / 1024
     }
+    pub fn size(&self) -> usize {
+        self.num_bytes
+    }
 
     fn check_address_in_bounds(
         &self,
@@ -394,10 +637,13 @@ impl Partition {
         number_of_blocks: usize,
     ) -> Result<(), AtaError> {
         const BLOCK_SIZE: usize = Drive::BLOCK_SIZE as usize;
-        if address + (number_of_blocks * BLOCK_SIZE) > self.num_bytes {
-            Err(AtaError::OutOfBounds)
-        } else {
-            Ok(())
+        // Checked so a huge request can't wrap around into another partition.
+        let end = number_of_blocks
+            .checked_mul(BLOCK_SIZE)
+            .and_then(|len| address.checked_add(len));
+        match end {
+            Some(end) if end <= self.num_bytes => Ok(()),
+            _ => Err(AtaError::OutOfBounds),
         }
     }
 }
@@ -460,9 +706,8 @@ pub fn list() -> Result<Vec<DriveInfo>, AtaError> {
                     }
                 }
                 model = model.trim().into();
-                let block_count = (buf[61] as u32) << 16 | (buf[60] as u32);
                 res.push(DriveInfo {
-                    drive: Drive::new(bus, drive, block_count),
+                    drive: Drive::new(bus, drive, &buf),
                     model,
                     serial,
                 });
@@ -477,5 +722,46 @@ pub fn list() -> Result<Vec<DriveInfo>, AtaError> {
 // }
 
 pub unsafe fn init() {
-    BUSES = Some([Bus::new(0, 0x1F0, 0x3F6, 14), Bus::new(1, 0x170, 0x376, 15)]);
+    BUSES = Some([
+        Bus::new(0, IO_BASES[0], 0x3F6, 14),
+        Bus::new(1, IO_BASES[1], 0x376, 15),
+    ]);
+}
+
+/// Reads the status register of bus `bus`, which the drive needs before it deasserts its
+/// interrupt. For the bus' IRQ handler, so it doesn't go through a `Bus` a transfer is using.
+pub fn acknowledge_interrupt(bus: u8) -> u8 {
+    unsafe { PortReadOnly::<u8>::new(IO_BASES[bus as usize] + STATUS_REGISTER).read() }
+}
+
+/// Makes transfers wait for the drive with `waiter` instead of polling.
+///
+/// # Safety
+/// Must be called after `init`, while no transfer is in flight.
+pub unsafe fn set_waiter(waiter: Waiter) {
+    WAITER = Some(waiter);
+}
+
+/// Makes reads use bus-master DMA through `region`, with the IDE controller's bus master
+/// registers at I/O port `bus_master_base` (its BAR4). Bus mastering must be enabled on the
+/// controller.
+///
+/// # Safety
+/// Must be called after `init`. `region.virt` must map `region.phys`, and the memory must be
+/// left to the driver from then on.
+pub unsafe fn init_dma(region: DmaRegion, bus_master_base: u16) -> Result<(), AtaError> {
+    let buses = BUSES.as_mut().ok_or(AtaError::NotInitialized)?;
+    let end = region.phys + region.len as u64;
+    if region.len < PRDT_SIZE + 512
+        || region.phys % 4 != 0
+        || end > 1 << 32
+        || region.phys >> 16 != (end - 1) >> 16
+    {
+        return Err(AtaError::InvalidDmaRegion);
+    }
+    // The secondary channel's registers follow the primary's.
+    buses[0].bus_master_base = Some(bus_master_base);
+    buses[1].bus_master_base = Some(bus_master_base + 8);
+    DMA = Some(region);
+    Ok(())
 }
@@ -0,0 +1,334 @@
+use crate::graphics::{Color, GraphicsContext, Texture, VecBuffer};
+use alloc::vec::Vec;
+
+const FILE_HEADER_SIZE: usize = 14;
+/// BITMAPINFOHEADER. Later versions are longer but start with the same fields.
+const INFO_HEADER_SIZE: usize = 40;
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+/// The red, green and blue masks of a 32 bit BI_BITFIELDS image that's laid out like BI_RGB.
+const BGRX_MASKS: [u32; 3] = [0x00ff_0000, 0x0000_ff00, 0x0000_00ff];
+
+/// Larger images are rejected instead of trying to allocate them.
+const MAX_DIMENSION: u32 = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BmpError {
+    NotBmp,
+    Truncated,
+    UnsupportedCompression(u32),
+    UnsupportedBitDepth(u16),
+    InvalidSize,
+    /// The framebuffer's pixel format can't be drawn to.
+    UnsupportedFramebuffer,
+}
+
+impl core::fmt::Display for BmpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            BmpError::NotBmp => write!(f, "not a BMP file"),
+            BmpError::Truncated => write!(f, "BMP file is truncated"),
+            BmpError::UnsupportedCompression(compression) => {
+                write!(f, "unsupported BMP compression {}", compression)
+            }
+            BmpError::UnsupportedBitDepth(bits) => write!(f, "unsupported BMP bit depth {}", bits),
+            BmpError::InvalidSize => write!(f, "invalid BMP image size"),
+            BmpError::UnsupportedFramebuffer => write!(f, "unsupported framebuffer format"),
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, BmpError> {
+    let bytes = data.get(offset..offset + 2).ok_or(BmpError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, BmpError> {
+    let bytes = data.get(offset..offset + 4).ok_or(BmpError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decodes an uncompressed 24 or 32 bit BMP into a texture in `context`'s pixel format, ready to
+/// be blitted. Alpha in 32 bit images is ignored.
+pub fn decode(context: &GraphicsContext, data: &[u8]) -> Result<VecBuffer, BmpError> {
+    if data.get(..2) != Some(b"BM") {
+        return Err(BmpError::NotBmp);
+    }
+    let pixel_offset = read_u32(data, 10)? as usize;
+    if (read_u32(data, FILE_HEADER_SIZE)? as usize) < INFO_HEADER_SIZE {
+        return Err(BmpError::NotBmp);
+    }
+    let width = read_u32(data, FILE_HEADER_SIZE + 4)? as i32;
+    // Positive heights store the bottom row first.
+    let height = read_u32(data, FILE_HEADER_SIZE + 8)? as i32;
+    let bits = read_u16(data, FILE_HEADER_SIZE + 14)?;
+    let compression = read_u32(data, FILE_HEADER_SIZE + 16)?;
+
+    match (compression, bits) {
+        (BI_RGB, 24 | 32) => {}
+        (BI_RGB, _) => return Err(BmpError::UnsupportedBitDepth(bits)),
+        (BI_BITFIELDS, 32) => {
+            // The masks follow the 40 byte header, which is also where later headers keep them.
+            let masks_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+            let masks = [
+                read_u32(data, masks_offset)?,
+                read_u32(data, masks_offset + 4)?,
+                read_u32(data, masks_offset + 8)?,
+            ];
+            if masks != BGRX_MASKS {
+                return Err(BmpError::UnsupportedCompression(compression));
+            }
+        }
+        _ => return Err(BmpError::UnsupportedCompression(compression)),
+    }
+    let top_down = height < 0;
+    let (width, height) = (width.unsigned_abs(), height.unsigned_abs());
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(BmpError::InvalidSize);
+    }
+
+    let bytes_per_pixel = bits as usize / 8;
+    // Rows are padded to a multiple of 4 bytes.
+    let row_size = (width as usize * bytes_per_pixel + 3) & !3;
+    let end = pixel_offset
+        .checked_add(row_size * height as usize)
+        .ok_or(BmpError::Truncated)?;
+    let pixels = data.get(pixel_offset..end).ok_or(BmpError::Truncated)?;
+    if !context.is_supported() {
+        return Err(BmpError::UnsupportedFramebuffer);
+    }
+
+    let mut texture = VecBuffer::alloc(context, width, height);
+    for (row_index, row) in pixels.chunks_exact(row_size).enumerate() {
+        let y = if top_down {
+            row_index as u32
+        } else {
+            height - 1 - row_index as u32
+        };
+        for (x, pixel) in row
+            .chunks_exact(bytes_per_pixel)
+            .take(width as usize)
+            .enumerate()
+        {
+            let color = Color::new(pixel[2], pixel[1], pixel[0]);
+            let color = context.encode_color(color).unwrap();
+            context.set_pixel(&mut texture, x as u32, y, color);
+        }
+    }
+    Ok(texture)
+}
+
+/// Encodes `image`, in `context`'s pixel format, as a 24 bit bottom-up BMP file. Rows are read
+/// with the texture's own stride.
+pub fn encode<T: Texture>(context: &GraphicsContext, image: &T) -> Result<Vec<u8>, BmpError> {
+    if !context.is_supported() {
+        return Err(BmpError::UnsupportedFramebuffer);
+    }
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let row_size = (width * 3 + 3) & !3;
+    let pixel_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+    let file_size = pixel_offset + row_size * height;
+    let mut data = Vec::with_capacity(file_size);
+    data.extend_from_slice(b"BM");
+    data.extend_from_slice(&(file_size as u32).to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&(pixel_offset as u32).to_le_bytes());
+    data.extend_from_slice(&(INFO_HEADER_SIZE as u32).to_le_bytes());
+    data.extend_from_slice(&(width as i32).to_le_bytes());
+    data.extend_from_slice(&(height as i32).to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&24u16.to_le_bytes());
+    data.extend_from_slice(&BI_RGB.to_le_bytes());
+    data.extend_from_slice(&((row_size * height) as u32).to_le_bytes());
+    // No preferred resolution, and no palette.
+    data.extend_from_slice(&[0; 16]);
+
+    let bytes_per_pixel = context.bytes_per_pixel();
+    let row_bytes = image.stride() * bytes_per_pixel;
+    for y in (0..height).rev() {
+        let row = &image.data()[y * row_bytes..][..width * bytes_per_pixel];
+        for pixel in row.chunks_exact(bytes_per_pixel) {
+            let color = Color::from_pixel_bytes(pixel, context.pixel_format()).unwrap();
+            data.extend_from_slice(&[color.b, color.g, color.r]);
+        }
+        data.resize(data.len() + row_size - width * 3, 0);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 3] = [0xff, 0x00, 0x00];
+    const GREEN: [u8; 3] = [0x00, 0xff, 0x00];
+    const BLUE: [u8; 3] = [0x00, 0x00, 0xff];
+    const WHITE: [u8; 3] = [0xff, 0xff, 0xff];
+
+    /// A BMP file with `rows` as they are stored, padding included. A BI_BITFIELDS file gets
+    /// `masks` after the header.
+    fn file(
+        width: i32,
+        height: i32,
+        bits: u16,
+        compression: u32,
+        masks: &[u32],
+        rows: &[u8],
+    ) -> Vec<u8> {
+        let pixel_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE + masks.len() * 4;
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&((pixel_offset + rows.len()) as u32).to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(pixel_offset as u32).to_le_bytes());
+        data.extend_from_slice(&(INFO_HEADER_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&bits.to_le_bytes());
+        data.extend_from_slice(&compression.to_le_bytes());
+        data.extend_from_slice(&[0; 20]);
+        for mask in masks {
+            data.extend_from_slice(&mask.to_le_bytes());
+        }
+        data.extend_from_slice(rows);
+        data
+    }
+
+    /// A 2x2 24 bit file, its rows padded from 6 to 8 bytes.
+    fn file_24(height: i32) -> Vec<u8> {
+        #[rustfmt::skip]
+        let rows = [
+            0x00, 0x00, 0xff, 0x00, 0xff, 0x00, 0xee, 0xee,
+            0xff, 0x00, 0x00, 0xff, 0xff, 0xff, 0xee, 0xee,
+        ];
+        file(2, height, 24, BI_RGB, &[], &rows)
+    }
+
+    fn context() -> GraphicsContext {
+        GraphicsContext::const_default()
+    }
+
+    fn pixel<T: Texture>(texture: &T, x: usize, y: usize) -> [u8; 3] {
+        let offset = (y * texture.stride() + x) * context().bytes_per_pixel();
+        texture.data()[offset..offset + 3].try_into().unwrap()
+    }
+
+    fn decode_err(data: &[u8]) -> BmpError {
+        decode(&context(), data).err().unwrap()
+    }
+
+    #[test]
+    fn decodes_bottom_up_rows() {
+        let texture = decode(&context(), &file_24(2)).unwrap();
+        assert_eq!((texture.width(), texture.height()), (2, 2));
+        assert_eq!(pixel(&texture, 0, 1), RED);
+        assert_eq!(pixel(&texture, 1, 1), GREEN);
+        assert_eq!(pixel(&texture, 0, 0), BLUE);
+        assert_eq!(pixel(&texture, 1, 0), WHITE);
+    }
+
+    #[test]
+    fn decodes_top_down_rows() {
+        let texture = decode(&context(), &file_24(-2)).unwrap();
+        assert_eq!(pixel(&texture, 0, 0), RED);
+        assert_eq!(pixel(&texture, 1, 0), GREEN);
+        assert_eq!(pixel(&texture, 0, 1), BLUE);
+        assert_eq!(pixel(&texture, 1, 1), WHITE);
+    }
+
+    #[test]
+    fn decodes_32_bit_files() {
+        let rows = [0x00, 0x00, 0xff, 0x7f, 0xff, 0x00, 0x00, 0x7f];
+        for (compression, masks) in [(BI_RGB, &[][..]), (BI_BITFIELDS, &BGRX_MASKS[..])] {
+            let texture = decode(&context(), &file(2, 1, 32, compression, masks, &rows)).unwrap();
+            assert_eq!(pixel(&texture, 0, 0), RED);
+            assert_eq!(pixel(&texture, 1, 0), BLUE);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_files() {
+        let rows = [0; 8];
+        assert_eq!(decode_err(b"PNG"), BmpError::NotBmp);
+        assert_eq!(
+            decode_err(&file(2, 1, 8, BI_RGB, &[], &rows)),
+            BmpError::UnsupportedBitDepth(8)
+        );
+        assert_eq!(
+            decode_err(&file(2, 1, 24, BI_BITFIELDS, &BGRX_MASKS, &rows)),
+            BmpError::UnsupportedCompression(BI_BITFIELDS)
+        );
+        let rgbx_masks = [0x0000_00ff, 0x0000_ff00, 0x00ff_0000];
+        assert_eq!(
+            decode_err(&file(2, 1, 32, BI_BITFIELDS, &rgbx_masks, &rows)),
+            BmpError::UnsupportedCompression(BI_BITFIELDS)
+        );
+        // BI_RLE8.
+        assert_eq!(
+            decode_err(&file(2, 1, 24, 1, &[], &rows)),
+            BmpError::UnsupportedCompression(1)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        assert_eq!(
+            decode_err(&file(0, 1, 24, BI_RGB, &[], &[])),
+            BmpError::InvalidSize
+        );
+        assert_eq!(
+            decode_err(&file(1, 0, 24, BI_RGB, &[], &[])),
+            BmpError::InvalidSize
+        );
+        assert_eq!(
+            decode_err(&file(MAX_DIMENSION as i32 + 1, 1, 24, BI_RGB, &[], &[])),
+            BmpError::InvalidSize
+        );
+        assert_eq!(
+            decode_err(&file(1, i32::MIN, 24, BI_RGB, &[], &[])),
+            BmpError::InvalidSize
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = file_24(2);
+        // Missing the padding at the end of the last row.
+        assert_eq!(decode_err(&data[..data.len() - 2]), BmpError::Truncated);
+        assert_eq!(
+            decode_err(&data[..FILE_HEADER_SIZE + 10]),
+            BmpError::Truncated
+        );
+        assert_eq!(decode_err(&data[..2]), BmpError::Truncated);
+    }
+
+    /// A 2x2 texture with a stride of 3, padding filled with a color that mustn't be encoded.
+    fn padded_texture() -> VecBuffer {
+        let rows = [RED, GREEN, WHITE, BLUE, RED, WHITE].concat();
+        VecBuffer::new(2, 2, 3, rows)
+    }
+
+    #[test]
+    fn encode_reads_rows_with_the_texture_stride() {
+        let data = encode(&context(), &padded_texture()).unwrap();
+        // Bottom-up, BGR, and every row padded from 6 to 8 bytes.
+        #[rustfmt::skip]
+        let rows = [
+            0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x00, 0xff, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(data.len(), FILE_HEADER_SIZE + INFO_HEADER_SIZE + rows.len());
+        assert_eq!(data[FILE_HEADER_SIZE + INFO_HEADER_SIZE..], rows);
+    }
+
+    #[test]
+    fn encoded_images_decode_unchanged() {
+        let texture = padded_texture();
+        let decoded = decode(&context(), &encode(&context(), &texture).unwrap()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (2, 2));
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(pixel(&decoded, x, y), pixel(&texture, x, y));
+        }
+    }
+}
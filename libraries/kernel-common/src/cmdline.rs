@@ -0,0 +1,95 @@
+//! Parsing the kernel command line: `key=value` options and bare flags, separated by whitespace.
+
+/// The command line in `bytes`, without trailing NULs. Empty if it isn't valid UTF-8.
+pub fn from_bytes(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes)
+        .unwrap_or("")
+        .trim_end_matches('\0')
+}
+
+/// Each `key=value` or bare `flag` in `cmdline`, as `(key, value)`. Flags have an empty value.
+pub fn options(cmdline: &str) -> impl Iterator<Item = (&str, &str)> {
+    cmdline
+        .split_whitespace()
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+}
+
+/// The value of the last `key=value` in `cmdline`, or `Some("")` if `key` is a bare flag.
+pub fn get<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    options(cmdline)
+        .filter(|(option, _)| *option == key)
+        .map(|(_, value)| value)
+        .last()
+}
+
+/// The keys in `cmdline` that aren't in `known`, in order.
+pub fn unknown_keys<'a>(cmdline: &'a str, known: &'a [&str]) -> impl Iterator<Item = &'a str> {
+    options(cmdline)
+        .map(|(key, _)| key)
+        .filter(|key| !known.contains(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const CMDLINE: &str = "loglevel=debug  splash\tinit=shell.elf xmodem= log=serial log=screen";
+
+    #[test]
+    fn splits_options_and_flags() {
+        let options: Vec<_> = options(CMDLINE).collect();
+        assert_eq!(
+            options,
+            [
+                ("loglevel", "debug"),
+                ("splash", ""),
+                ("init", "shell.elf"),
+                ("xmodem", ""),
+                ("log", "serial"),
+                ("log", "screen"),
+            ]
+        );
+    }
+
+    #[test]
+    fn gets_values() {
+        assert_eq!(get(CMDLINE, "loglevel"), Some("debug"));
+        assert_eq!(get(CMDLINE, "init"), Some("shell.elf"));
+        assert_eq!(get(CMDLINE, "splash"), Some(""));
+        assert_eq!(get(CMDLINE, "xmodem"), Some(""));
+        // Later options win.
+        assert_eq!(get(CMDLINE, "log"), Some("screen"));
+        assert_eq!(get(CMDLINE, "gdb"), None);
+        // Keys are matched whole and with their case.
+        assert_eq!(get(CMDLINE, "lo"), None);
+        assert_eq!(get(CMDLINE, "LOG"), None);
+    }
+
+    #[test]
+    fn values_keep_later_equals_signs() {
+        assert_eq!(get("init=a=b", "init"), Some("a=b"));
+        assert_eq!(get("=value", ""), Some("value"));
+    }
+
+    #[test]
+    fn missing_command_lines_have_no_options() {
+        assert_eq!(options("").count(), 0);
+        assert_eq!(options(" \n ").count(), 0);
+        assert_eq!(get("", "init"), None);
+        assert_eq!(from_bytes(&[]), "");
+    }
+
+    #[test]
+    fn reads_bytes_up_to_the_padding() {
+        assert_eq!(from_bytes(b"init=shell.elf\0\0"), "init=shell.elf");
+        assert_eq!(from_bytes(b"init=\xff"), "");
+    }
+
+    #[test]
+    fn finds_unknown_keys() {
+        let unknown: Vec<_> = unknown_keys(CMDLINE, &["init", "log", "loglevel"]).collect();
+        assert_eq!(unknown, ["splash", "xmodem"]);
+        assert_eq!(unknown_keys("", &[]).count(), 0);
+    }
+}
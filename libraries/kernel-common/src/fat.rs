@@ -0,0 +1,532 @@
+//! The directory entries of FAT filesystems: 8.3 and long names, and finding room for new ones.
+use alloc::{format, string::String, vec, vec::Vec};
+
+pub const DIR_ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+// Marks a long filename entry, which is stored in front of the short entry it belongs to.
+const ATTR_LONG_NAME: u8 = 0x0f;
+pub const ENTRY_END: u8 = 0x00;
+pub const ENTRY_DELETED: u8 = 0xe5;
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_UNITS: usize = 13;
+// Set in byte 12 of a short entry when the base name or extension is all lowercase.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXT: u8 = 0x10;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// A file or directory found by `parse_dir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The long filename if there is one, otherwise the 8.3 name.
+    pub name: String,
+    pub short_name: String,
+    pub size: u32,
+    pub is_dir: bool,
+    pub first_cluster: u32,
+    /// Which entry of the directory the short entry is.
+    pub index: usize,
+}
+
+/// The 8.3 name in a short entry, as `NAME.EXT`, lowercased if the entry says so.
+pub fn short_name(entry: &[u8]) -> String {
+    let mut raw = [0; 11];
+    raw.copy_from_slice(&entry[0..11]);
+    // 0xe5 is stored as 0x05 so it isn't mistaken for a deleted entry.
+    if raw[0] == 0x05 {
+        raw[0] = ENTRY_DELETED;
+    }
+    let case = entry[12];
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let text = core::str::from_utf8(bytes).unwrap_or("").trim_end();
+        if lower {
+            text.to_ascii_lowercase()
+        } else {
+            String::from(text)
+        }
+    };
+    let mut name = part(&raw[0..8], case & LOWERCASE_BASE != 0);
+    let extension = part(&raw[8..11], case & LOWERCASE_EXT != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// The checksum of a short name that its long filename entries carry.
+pub fn short_name_checksum(entry: &[u8]) -> u8 {
+    entry[0..11]
+        .iter()
+        .fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte))
+}
+
+/// Long filename pieces collected in front of a short entry.
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    remaining: u8,
+}
+
+/// The entries in a directory's contents. Deleted entries and the volume label are left out, and
+/// so are `.` and `..`.
+pub fn parse_dir(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+    for (index, entry) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        match entry[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attributes = entry[11];
+        if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            let sequence = entry[0] & 0x1f;
+            if entry[0] & LAST_LONG_ENTRY != 0 {
+                long_name = Some(LongName {
+                    units: vec![0xffff; sequence as usize * LONG_NAME_UNITS],
+                    checksum: entry[13],
+                    remaining: sequence,
+                });
+            }
+            // Pieces come last to first, anything out of order drops the long name.
+            long_name = long_name.filter(|name| {
+                sequence != 0 && name.remaining == sequence && name.checksum == entry[13]
+            });
+            if let Some(name) = long_name.as_mut() {
+                let start = (sequence as usize - 1) * LONG_NAME_UNITS;
+                let offsets = (1..11)
+                    .step_by(2)
+                    .chain((14..26).step_by(2))
+                    .chain((28..32).step_by(2));
+                for (i, offset) in offsets.enumerate() {
+                    name.units[start + i] = read_u16(entry, offset);
+                }
+                name.remaining -= 1;
+            }
+            continue;
+        }
+        let long = long_name.take();
+        if attributes & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        let short_name = short_name(entry);
+        if short_name == "." || short_name == ".." {
+            continue;
+        }
+        let name = long
+            .filter(|name| name.remaining == 0 && name.checksum == short_name_checksum(entry))
+            .map(|name| {
+                let len = name
+                    .units
+                    .iter()
+                    .position(|unit| *unit == 0 || *unit == 0xffff)
+                    .unwrap_or(name.units.len());
+                char::decode_utf16(name.units[..len].iter().copied())
+                    .map(|ch| ch.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            })
+            .unwrap_or_else(|| short_name.clone());
+        entries.push(Entry {
+            name,
+            short_name,
+            size: read_u32(entry, 28),
+            is_dir: attributes & ATTR_DIRECTORY != 0,
+            first_cluster: (read_u16(entry, 20) as u32) << 16 | read_u16(entry, 26) as u32,
+            index,
+        });
+    }
+    entries
+}
+
+// Characters allowed in 8.3 names besides letters and digits.
+const SHORT_NAME_SPECIAL: &str = "!#$%&'()-@^_`{}~";
+// Characters not allowed in long names, besides control characters.
+const LONG_NAME_INVALID: &str = "\"*/:<>?\\|";
+const MAX_LONG_NAME_UNITS: usize = 255;
+
+/// Whether `name` can be stored in a directory, with a long name if needed.
+pub fn valid_name(name: &str) -> bool {
+    !(name.is_empty()
+        || name == "."
+        || name == ".."
+        || name
+            .chars()
+            .any(|ch| ch.is_control() || LONG_NAME_INVALID.contains(ch))
+        || name.encode_utf16().count() > MAX_LONG_NAME_UNITS)
+}
+
+fn short_name_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(ch)
+}
+
+/// The 8.3 form of `name` and its case flags, if it fits without a long name. Each part must be
+/// all one case, which the flags record.
+pub fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+    let mut raw = [b' '; 11];
+    let mut case = 0;
+    for (part, range, lower_flag) in [
+        (base, 0..8, LOWERCASE_BASE),
+        (extension, 8..11, LOWERCASE_EXT),
+    ] {
+        if !part.chars().all(short_name_char) {
+            return None;
+        }
+        let has_lower = part.chars().any(|ch| ch.is_ascii_lowercase());
+        let has_upper = part.chars().any(|ch| ch.is_ascii_uppercase());
+        if has_lower && has_upper {
+            return None;
+        }
+        if has_lower {
+            case |= lower_flag;
+        }
+        raw[range.start..range.start + part.len()]
+            .copy_from_slice(part.to_ascii_uppercase().as_bytes());
+    }
+    Some((raw, case))
+}
+
+/// A `BASE~N.EXT` short name for a long name, one that `taken` says isn't in use yet. None if
+/// they all are.
+pub fn generate_short_name(name: &str, taken: impl Fn(&str) -> bool) -> Option<[u8; 11]> {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, ""),
+    };
+    let clean = |part: &str, len: usize| -> String {
+        part.chars()
+            .filter(|ch| short_name_char(*ch))
+            .map(|ch| ch.to_ascii_uppercase())
+            .take(len)
+            .collect()
+    };
+    let extension = clean(extension, 3);
+    for number in 1..1000000u32 {
+        let suffix = format!("~{}", number);
+        let base = clean(base, 8 - suffix.len());
+        let base = if base.is_empty() {
+            String::from("_")
+        } else {
+            base
+        };
+        let mut raw = [b' '; 11];
+        raw[..base.len()].copy_from_slice(base.as_bytes());
+        raw[base.len()..base.len() + suffix.len()].copy_from_slice(suffix.as_bytes());
+        raw[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        entry[..11].copy_from_slice(&raw);
+        if !taken(&short_name(&entry)) {
+            return Some(raw);
+        }
+    }
+    None
+}
+
+/// The long filename entries for `name`, in the order they are stored (last piece first).
+pub fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = (units.len() + LONG_NAME_UNITS - 1) / LONG_NAME_UNITS;
+    // Terminated by a zero unless it fills the last piece exactly, then padded with 0xffff.
+    if units.len() % LONG_NAME_UNITS != 0 {
+        units.push(0);
+    }
+    units.resize(count * LONG_NAME_UNITS, 0xffff);
+    (1..=count)
+        .rev()
+        .map(|sequence| {
+            let mut entry = [0; DIR_ENTRY_SIZE];
+            entry[0] = sequence as u8
+                | if sequence == count {
+                    LAST_LONG_ENTRY
+                } else {
+                    0
+                };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let pieces = &units[(sequence - 1) * LONG_NAME_UNITS..sequence * LONG_NAME_UNITS];
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2));
+            for (unit, offset) in pieces.iter().zip(offsets) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// The index of the first run of `count` free entries in a directory's contents, if any.
+pub fn find_free_entries(data: &[u8], count: usize) -> Option<usize> {
+    let mut run = 0;
+    for (index, entry) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        match entry[0] {
+            // Everything after the end marker is free.
+            ENTRY_END => {
+                let free = data.len() / DIR_ENTRY_SIZE - index;
+                return (run + free >= count).then_some(index - run);
+            }
+            ENTRY_DELETED => run += 1,
+            _ => run = 0,
+        }
+        if run == count {
+            return Some(index + 1 - count);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn short_entry(raw: &[u8; 11], case: u8, attributes: u8, cluster: u32, size: u32) -> Vec<u8> {
+        let mut entry = vec![0; DIR_ENTRY_SIZE];
+        entry[..11].copy_from_slice(raw);
+        entry[11] = attributes;
+        entry[12] = case;
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    fn file(raw: &[u8; 11]) -> Vec<u8> {
+        short_entry(raw, 0, ATTR_ARCHIVE, 3, 1)
+    }
+
+    /// A directory cluster holding `entries`, then an end marker and free entries.
+    fn dir(entries: &[Vec<u8>], total: usize) -> Vec<u8> {
+        let mut data = entries.concat();
+        data.resize(total * DIR_ENTRY_SIZE, 0);
+        data
+    }
+
+    /// `name` stored with a long name, as `create` does.
+    fn long_file(name: &str) -> Vec<u8> {
+        let raw = generate_short_name(name, |_| false).unwrap();
+        let short = file(&raw);
+        let mut entries = long_name_entries(name, short_name_checksum(&short)).concat();
+        entries.extend_from_slice(&short);
+        entries
+    }
+
+    fn names(data: &[u8]) -> Vec<String> {
+        parse_dir(data)
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    #[test]
+    fn parses_short_entries() {
+        let data = dir(
+            &[
+                short_entry(b"README  TXT", 0, ATTR_ARCHIVE, 0x0001_2345, 100),
+                short_entry(b"BIN        ", 0, ATTR_DIRECTORY, 7, 0),
+                short_entry(b"LOGO    BMP", LOWERCASE_BASE | LOWERCASE_EXT, 0, 9, 5),
+                short_entry(b"MAKEFILE   ", LOWERCASE_EXT, 0, 10, 5),
+                short_entry(b"SHOT    BMP", LOWERCASE_EXT, 0, 11, 5),
+            ],
+            8,
+        );
+        let entries = parse_dir(&data);
+        assert_eq!(
+            entries[0],
+            Entry {
+                name: String::from("README.TXT"),
+                short_name: String::from("README.TXT"),
+                size: 100,
+                is_dir: false,
+                first_cluster: 0x0001_2345,
+                index: 0,
+            }
+        );
+        assert!(entries[1].is_dir);
+        assert_eq!(entries[1].name, "BIN");
+        assert_eq!(names(&data)[2..], ["logo.bmp", "MAKEFILE", "SHOT.bmp"]);
+    }
+
+    #[test]
+    fn skips_special_entries() {
+        let mut deleted = file(b"OLD     TXT");
+        deleted[0] = ENTRY_DELETED;
+        let data = dir(
+            &[
+                short_entry(b"MARIOS     ", 0, ATTR_VOLUME_ID, 0, 0),
+                short_entry(b".          ", 0, ATTR_DIRECTORY, 5, 0),
+                short_entry(b"..         ", 0, ATTR_DIRECTORY, 0, 0),
+                deleted,
+                file(b"A       TXT"),
+                vec![0; DIR_ENTRY_SIZE],
+                file(b"AFTER   END"),
+            ],
+            8,
+        );
+        let entries = parse_dir(&data);
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].name.as_str(), entries[0].index), ("A.TXT", 4));
+    }
+
+    #[test]
+    fn long_names_round_trip() {
+        let long = "a".repeat(MAX_LONG_NAME_UNITS);
+        for name in [
+            "a b",
+            "twelve chars",
+            "thirteen char",
+            "fourteen chars",
+            "twenty-six units long.txt",
+            "héllo wörld.txt",
+            long.as_str(),
+        ] {
+            let data = dir(&[file(b"FIRST      "), long_file(name)], 40);
+            let entries = parse_dir(&data);
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[1].name, name);
+            // The short entry comes after the long name's entries.
+            let pieces = (name.encode_utf16().count() + LONG_NAME_UNITS - 1) / LONG_NAME_UNITS;
+            assert_eq!(entries[1].index, 1 + pieces);
+        }
+    }
+
+    #[test]
+    fn broken_long_names_fall_back_to_the_short_name() {
+        let name = "a long name that needs three entries";
+        let mut wrong_checksum = long_file(name);
+        wrong_checksum[13] ^= 1;
+        let mut missing_piece = long_file(name);
+        missing_piece.drain(DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE);
+        let mut deleted_piece = long_file(name);
+        deleted_piece[DIR_ENTRY_SIZE] = ENTRY_DELETED;
+        for data in [wrong_checksum, missing_piece, deleted_piece] {
+            assert_eq!(names(&dir(&[data], 8)), ["ALONGN~1"]);
+        }
+    }
+
+    #[test]
+    fn exact_short_names() {
+        assert_eq!(exact_short_name("README.TXT"), Some((*b"README  TXT", 0)));
+        assert_eq!(
+            exact_short_name("logo.bmp"),
+            Some((*b"LOGO    BMP", LOWERCASE_BASE | LOWERCASE_EXT))
+        );
+        assert_eq!(
+            exact_short_name("kernel"),
+            Some((*b"KERNEL     ", LOWERCASE_BASE))
+        );
+        assert_eq!(
+            exact_short_name("SHOT001.bmp"),
+            Some((*b"SHOT001 BMP", LOWERCASE_EXT))
+        );
+        for name in [
+            "Readme.txt",
+            "eightplus.txt",
+            "page.html",
+            "a.b.c",
+            "name.",
+            ".hidden",
+            "with space",
+            "a+b",
+        ] {
+            assert_eq!(exact_short_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn generated_short_names_are_unique() {
+        let generate = |name, names: &[&str]| {
+            let taken = |short_name: &str| {
+                names
+                    .iter()
+                    .any(|taken| taken.eq_ignore_ascii_case(short_name))
+            };
+            generate_short_name(name, taken).unwrap()
+        };
+        assert_eq!(generate("My Long File.html", &[]), *b"MYLONG~1HTM");
+        assert_eq!(
+            generate("My Long File.html", &["MYLONG~1.HTM"]),
+            *b"MYLONG~2HTM"
+        );
+        let first_nine = [
+            "mylong~1.htm",
+            "MYLONG~2.HTM",
+            "MYLONG~3.HTM",
+            "MYLONG~4.HTM",
+            "MYLONG~5.HTM",
+            "MYLONG~6.HTM",
+            "MYLONG~7.HTM",
+            "MYLONG~8.HTM",
+            "MYLONG~9.HTM",
+        ];
+        assert_eq!(generate("My Long File.html", &first_nine), *b"MYLON~10HTM");
+        assert_eq!(generate("+++", &[]), *b"_~1        ");
+        assert_eq!(generate(".profile", &[]), *b"PROFIL~1   ");
+        assert_eq!(generate_short_name("x", |_| true), None);
+    }
+
+    #[test]
+    fn finds_free_entries() {
+        let mut deleted = file(b"OLD        ");
+        deleted[0] = ENTRY_DELETED;
+        let used = file(b"USED       ");
+        let data = dir(
+            &[used.clone(), deleted.clone(), deleted.clone(), used.clone()],
+            8,
+        );
+        assert_eq!(find_free_entries(&data, 1), Some(1));
+        assert_eq!(find_free_entries(&data, 2), Some(1));
+        // Only the four entries from the end marker on are long enough.
+        assert_eq!(find_free_entries(&data, 3), Some(4));
+        assert_eq!(find_free_entries(&data, 4), Some(4));
+        assert_eq!(find_free_entries(&data, 5), None);
+
+        // Deleted entries right before the end marker join the free ones after it.
+        let data = dir(&[used.clone(), deleted.clone()], 4);
+        assert_eq!(find_free_entries(&data, 3), Some(1));
+
+        let full = dir(&[used.clone(), deleted.clone(), used], 3);
+        assert_eq!(find_free_entries(&full, 1), Some(1));
+        assert_eq!(find_free_entries(&full, 2), None);
+    }
+
+    #[test]
+    fn validates_names() {
+        let longest = "a".repeat(MAX_LONG_NAME_UNITS);
+        for name in [
+            "hello world.txt",
+            "shot000.bmp",
+            "ünïcode",
+            longest.as_str(),
+        ] {
+            assert!(valid_name(name), "{}", name);
+        }
+        let too_long = "a".repeat(MAX_LONG_NAME_UNITS + 1);
+        for name in [
+            "",
+            ".",
+            "..",
+            "a:b",
+            "a*",
+            "tab\there",
+            "a\\b",
+            too_long.as_str(),
+        ] {
+            assert!(!valid_name(name), "{:?}", name);
+        }
+    }
+}
@@ -9,7 +9,19 @@ pub struct Point {
     y: i32,
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+impl Point {
+    pub const fn new(x: i32, y: i32) -> Self {
+        Point { x, y }
+    }
+    pub fn x(&self) -> i32 {
+        self.x
+    }
+    pub fn y(&self) -> i32 {
+        self.y
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct Rect {
     x: i32,
     y: i32,
@@ -17,6 +29,184 @@ pub struct Rect {
     height: u32,
 }
 
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+    pub fn x(&self) -> i32 {
+        self.x
+    }
+    pub fn y(&self) -> i32 {
+        self.y
+    }
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    /// One past the last column, saturating at `i32::MAX`.
+    pub fn right(&self) -> i32 {
+        (self.x as i64 + self.width as i64).min(i32::MAX as i64) as i32
+    }
+    /// One past the last row, saturating at `i32::MAX`.
+    pub fn bottom(&self) -> i32 {
+        (self.y as i64 + self.height as i64).min(i32::MAX as i64) as i32
+    }
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The smallest rectangle containing both `self` and `other`.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rect::new(x, y, (right - x) as u32, (bottom - y) as u32)
+    }
+    /// The overlapping area of `self` and `other`, which may be empty.
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= x || bottom <= y {
+            Rect::default()
+        } else {
+            Rect::new(x, y, (right - x) as u32, (bottom - y) as u32)
+        }
+    }
+    pub fn overlaps(&self, other: &Rect) -> bool {
+        !self.intersect(other).is_empty()
+    }
+}
+
+pub const MAX_DIRTY_RECTS: usize = 16;
+
+/// The areas of a back buffer drawn to since it was last copied to the screen. Overlapping rects
+/// are merged, and once there are `MAX_DIRTY_RECTS` they collapse into their bounding box. Starts
+/// out full, so the first copy covers everything.
+pub struct DirtyRects {
+    rects: [Rect; MAX_DIRTY_RECTS],
+    len: usize,
+    full: bool,
+}
+
+impl DirtyRects {
+    pub const fn new() -> Self {
+        DirtyRects {
+            rects: [Rect::new(0, 0, 0, 0); MAX_DIRTY_RECTS],
+            len: 0,
+            full: true,
+        }
+    }
+    pub fn as_slice(&self) -> &[Rect] {
+        &self.rects[..self.len]
+    }
+    /// Whether everything has to be copied, whatever `as_slice` holds.
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() || self.full {
+            return;
+        }
+        // Grow an overlapping rect instead of adding another one.
+        for existing in self.rects[..self.len].iter_mut() {
+            if existing.overlaps(&rect) {
+                *existing = existing.union(&rect);
+                return;
+            }
+        }
+        if self.len == MAX_DIRTY_RECTS {
+            // Out of slots, collapse everything into one rect.
+            let union = self.bounds().union(&rect);
+            self.rects[0] = union;
+            self.len = 1;
+        } else {
+            self.rects[self.len] = rect;
+            self.len += 1;
+        }
+    }
+    /// The smallest rect containing all dirty ones, empty if there are none.
+    pub fn bounds(&self) -> Rect {
+        self.as_slice()
+            .iter()
+            .fold(Rect::default(), |union, rect| union.union(rect))
+    }
+    pub fn invalidate(&mut self) {
+        self.full = true;
+    }
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.full = false;
+    }
+}
+
+impl Default for DirtyRects {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::new(0, 0, 0);
+    pub const WHITE: Color = Color::new(255, 255, 255);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+
+    /// Packs the color into the byte order used by a framebuffer. Only the first
+    /// `bytes_per_pixel` bytes of the result are meaningful.
+    ///
+    /// Returns `None` for formats that can't be drawn to (`U8`, `Unknown`, or a pixel size other
+    /// than 3 or 4 bytes).
+    pub fn to_pixel_bytes(&self, format: PixelFormat, bytes_per_pixel: usize) -> Option<[u8; 4]> {
+        if bytes_per_pixel != 3 && bytes_per_pixel != 4 {
+            return None;
+        }
+        match format {
+            PixelFormat::Rgb => Some([self.r, self.g, self.b, 0]),
+            PixelFormat::Bgr => Some([self.b, self.g, self.r, 0]),
+            _ => None,
+        }
+    }
+    /// The inverse of `to_pixel_bytes`.
+    pub fn from_pixel_bytes(bytes: &[u8], format: PixelFormat) -> Option<Color> {
+        match format {
+            PixelFormat::Rgb => Some(Color::new(bytes[0], bytes[1], bytes[2])),
+            PixelFormat::Bgr => Some(Color::new(bytes[2], bytes[1], bytes[0])),
+            _ => None,
+        }
+    }
+}
+
+impl From<[u8; 3]> for Color {
+    fn from(value: [u8; 3]) -> Self {
+        Color::new(value[0], value[1], value[2])
+    }
+}
+
 pub trait Texture {
     fn width(&self) -> u32;
     fn height(&self) -> u32;
@@ -50,6 +240,20 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> Texture for Buffer<T> {
     }
 }
 
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Buffer<T> {
+    pub fn new(width: u32, height: u32, stride: usize, data: T) -> Self {
+        Buffer {
+            width,
+            height,
+            stride,
+            data,
+        }
+    }
+    pub fn into_data(self) -> T {
+        self.data
+    }
+}
+
 pub type FrameBuffer = Buffer<&'static mut [u8]>;
 
 impl FrameBuffer {
@@ -127,6 +331,18 @@ impl<'a> Image<'a> {
     }
 }
 
+/// The screen's size and pixel layout, as the `FB_INFO` syscall reports it.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels from the start of one row to the next.
+    pub stride: u32,
+    pub bytes_per_pixel: u32,
+    pub pixel_format: PixelFormat,
+}
+
 #[derive(Clone)]
 pub struct GraphicsContext {
     pixel_format: PixelFormat,
@@ -155,29 +371,40 @@ impl GraphicsContext {
     pub fn image_scale(&self) -> u32 {
         self.image_scale
     }
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bytes_per_pixel
+    }
 
     fn byte_offset(&self, x: usize, y: usize, texture_stride: usize) -> isize {
         (((y * texture_stride) + x) * self.bytes_per_pixel) as isize
     }
-    fn encode_color(&self, r: u8, g: u8, b: u8) -> u32 {
-        match self.pixel_format {
-            PixelFormat::Rgb => (r as u32) | ((g as u32) << 8) | ((b as u32) << 16),
-            PixelFormat::Bgr => (b as u32) | ((g as u32) << 8) | ((r as u32) << 16),
-            PixelFormat::U8 => r as u32,
-            _ => panic!("unknown pixel format"),
-        }
+    /// Whether colors can be encoded for this framebuffer. Drawing to an unsupported framebuffer
+    /// does nothing.
+    pub fn is_supported(&self) -> bool {
+        Color::BLACK
+            .to_pixel_bytes(self.pixel_format, self.bytes_per_pixel)
+            .is_some()
+    }
+    /// Packs a color into a pixel value for `set_pixel`.
+    pub fn encode_color(&self, color: Color) -> Option<u32> {
+        color
+            .to_pixel_bytes(self.pixel_format, self.bytes_per_pixel)
+            .map(u32::from_le_bytes)
     }
-    fn get_image_pixel(&self, image: &Image, x: u32, y: u32) -> u32 {
+    fn get_image_pixel(&self, image: &Image, x: u32, y: u32) -> Option<u32> {
         let bpp = image.format.bytes_per_pixel();
         let idx = ((y * image.width) + x) as usize * bpp;
         let pixel = &image.data[idx..idx + bpp];
         match image.format {
-            ImageFormat::Rgba => self.encode_color(pixel[0], pixel[1], pixel[2]),
+            ImageFormat::Rgba => self.encode_color(Color::new(pixel[0], pixel[1], pixel[2])),
             ImageFormat::Mask(fg, bg) => {
                 if pixel[0] > 0 {
-                    self.encode_color(fg[0], fg[1], fg[2])
+                    self.encode_color(fg.into())
                 } else {
-                    self.encode_color(bg[0], bg[1], bg[2])
+                    self.encode_color(bg.into())
                 }
             }
         }
@@ -200,6 +427,211 @@ impl GraphicsContext {
             core::ptr::copy_nonoverlapping(src, dst, self.bytes_per_pixel);
         }
     }
+    /// Sets the pixel at `x`, `y` if it is inside the texture.
+    fn set_pixel_clipped<T: Texture>(&self, texture: &mut T, x: i64, y: i64, color: u32) {
+        if (0..texture.width() as i64).contains(&x) && (0..texture.height() as i64).contains(&y) {
+            self.set_pixel(texture, x as u32, y as u32, color);
+        }
+    }
+    /// The part of `rect` inside the texture. Computed in 64 bits, so rects reaching past the
+    /// range of `i32` are fine.
+    fn clip<T: Texture>(texture: &T, rect: Rect) -> Rect {
+        let x = (rect.x as i64).max(0);
+        let y = (rect.y as i64).max(0);
+        let right = (rect.x as i64 + rect.width as i64).min(texture.width() as i64);
+        let bottom = (rect.y as i64 + rect.height as i64).min(texture.height() as i64);
+        if right <= x || bottom <= y {
+            Rect::default()
+        } else {
+            Rect::new(x as i32, y as i32, (right - x) as u32, (bottom - y) as u32)
+        }
+    }
+    /// Fills the part of `rect` inside the texture. Each row is filled by doubling a copied run of
+    /// pixels, and the first row is copied to the others.
+    pub fn fill_rect<T: Texture>(&self, texture: &mut T, rect: Rect, color: u32) {
+        let rect = Self::clip(texture, rect);
+        if rect.is_empty() || !self.is_supported() {
+            return;
+        }
+        let bpp = self.bytes_per_pixel;
+        let row_stride = texture.stride() * bpp;
+        let row_bytes = rect.width as usize * bpp;
+        let start = self.byte_offset(rect.x as usize, rect.y as usize, texture.stride()) as usize;
+        let data = texture.data_mut();
+        let first_row = &mut data[start..start + row_bytes];
+        first_row[..bpp].copy_from_slice(&color.to_le_bytes()[..bpp]);
+        let mut filled = bpp;
+        while filled < row_bytes {
+            let len = filled.min(row_bytes - filled);
+            first_row.copy_within(0..len, filled);
+            filled += len;
+        }
+        for row in 1..rect.height as usize {
+            data.copy_within(start..start + row_bytes, start + row * row_stride);
+        }
+    }
+    /// Draws the one pixel wide outline of `rect`.
+    pub fn draw_rect<T: Texture>(&self, texture: &mut T, rect: Rect, color: u32) {
+        if rect.is_empty() {
+            return;
+        }
+        let last =
+            |start: i32, len: u32| (start as i64 + len as i64 - 1).min(i32::MAX as i64) as i32;
+        let (right, bottom) = (last(rect.x, rect.width), last(rect.y, rect.height));
+        self.fill_rect(texture, Rect::new(rect.x, rect.y, rect.width, 1), color);
+        self.fill_rect(texture, Rect::new(rect.x, bottom, rect.width, 1), color);
+        self.fill_rect(texture, Rect::new(rect.x, rect.y, 1, rect.height), color);
+        self.fill_rect(texture, Rect::new(right, rect.y, 1, rect.height), color);
+    }
+    /// Draws a line from `from` to `to`, both included, with Bresenham's algorithm.
+    pub fn draw_line<T: Texture>(&self, texture: &mut T, from: Point, to: Point, color: u32) {
+        if !self.is_supported() {
+            return;
+        }
+        let (mut x, mut y) = (from.x as i64, from.y as i64);
+        let (end_x, end_y) = (to.x as i64, to.y as i64);
+        let dx = (end_x - x).abs();
+        let dy = -(end_y - y).abs();
+        let step_x = if x < end_x { 1 } else { -1 };
+        let step_y = if y < end_y { 1 } else { -1 };
+        let mut error = dx + dy;
+        loop {
+            self.set_pixel_clipped(texture, x, y, color);
+            if x == end_x && y == end_y {
+                break;
+            }
+            let error2 = 2 * error;
+            if error2 >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if error2 <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+    /// Calls `f` with each point of the first octant of a circle of `radius`, using the midpoint
+    /// algorithm. The other octants are mirror images.
+    fn circle_octant(radius: u32, mut f: impl FnMut(i64, i64)) {
+        let (mut x, mut y) = (radius as i64, 0);
+        let mut error = 1 - x;
+        while x >= y {
+            f(x, y);
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+    /// Draws the outline of a circle around `center`.
+    pub fn draw_circle<T: Texture>(&self, texture: &mut T, center: Point, radius: u32, color: u32) {
+        if !self.is_supported() {
+            return;
+        }
+        let (cx, cy) = (center.x as i64, center.y as i64);
+        Self::circle_octant(radius, |x, y| {
+            for (px, py) in [
+                (x, y),
+                (y, x),
+                (-y, x),
+                (-x, y),
+                (-x, -y),
+                (-y, -x),
+                (y, -x),
+                (x, -y),
+            ] {
+                self.set_pixel_clipped(texture, cx + px, cy + py, color);
+            }
+        });
+    }
+    /// Fills a circle around `center` with one horizontal span per row.
+    pub fn fill_circle<T: Texture>(&self, texture: &mut T, center: Point, radius: u32, color: u32) {
+        let (cx, cy) = (center.x as i64, center.y as i64);
+        let mut span = |half_width: i64, dy: i64| {
+            let rect = Rect::new(
+                (cx - half_width).clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+                (cy + dy).clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+                (2 * half_width + 1).min(u32::MAX as i64) as u32,
+                1,
+            );
+            self.fill_rect(texture, rect, color);
+        };
+        Self::circle_octant(radius, |x, y| {
+            span(x, y);
+            span(x, -y);
+            span(y, x);
+            span(y, -x);
+        });
+    }
+    /// Blends an RGBA image onto `dest` at `dest_rect`, which is also the image's size. `source`
+    /// has `source_stride` pixels per row, and each pixel's alpha is scaled by `alpha`. Fully
+    /// opaque pixels are written without reading the destination, and fully transparent ones are
+    /// skipped. Rows missing from a too short `source` aren't drawn.
+    pub fn blit_blend<T: Texture>(
+        &self,
+        source: &[u8],
+        source_stride: usize,
+        dest: &mut T,
+        dest_rect: Rect,
+        alpha: u8,
+    ) {
+        let clipped = Self::clip(dest, dest_rect);
+        if clipped.is_empty() || alpha == 0 || !self.is_supported() {
+            return;
+        }
+        let (skip_x, skip_y) = (
+            (clipped.x - dest_rect.x) as usize,
+            (clipped.y - dest_rect.y) as usize,
+        );
+        let bpp = self.bytes_per_pixel;
+        let dest_stride = dest.stride();
+        let data = dest.data_mut();
+        for row in 0..clipped.height as usize {
+            let source_start = ((skip_y + row) * source_stride + skip_x) * 4;
+            let Some(source_row) =
+                source.get(source_start..source_start + clipped.width as usize * 4)
+            else {
+                return;
+            };
+            let dest_start =
+                self.byte_offset(clipped.x as usize, clipped.y as usize + row, dest_stride)
+                    as usize;
+            let dest_row = &mut data[dest_start..dest_start + clipped.width as usize * bpp];
+            for (pixel, dest_pixel) in source_row
+                .chunks_exact(4)
+                .zip(dest_row.chunks_exact_mut(bpp))
+            {
+                let pixel_alpha = (pixel[3] as u32 * alpha as u32 + 127) / 255;
+                let color = Color::new(pixel[0], pixel[1], pixel[2]);
+                let color = match pixel_alpha {
+                    0 => continue,
+                    255 => color,
+                    _ => {
+                        let Some(under) = Color::from_pixel_bytes(dest_pixel, self.pixel_format)
+                        else {
+                            return;
+                        };
+                        let mix = |over: u8, under: u8| {
+                            ((over as u32 * pixel_alpha + under as u32 * (255 - pixel_alpha) + 127)
+                                / 255) as u8
+                        };
+                        Color::new(
+                            mix(color.r, under.r),
+                            mix(color.g, under.g),
+                            mix(color.b, under.b),
+                        )
+                    }
+                };
+                if let Some(bytes) = color.to_pixel_bytes(self.pixel_format, bpp) {
+                    dest_pixel.copy_from_slice(&bytes[..bpp]);
+                }
+            }
+        }
+    }
     pub fn write<S: Texture, D: Texture>(&self, source: &S, dest: &mut D, dest_offset: usize) {
         if dest.width() < source.width() || dest.height() < source.height() {
             return;
@@ -236,7 +668,11 @@ impl GraphicsContext {
                 .unwrap_or(0);
             dest_point.y = 0;
         }
-        // TODO also clamp dest_point on the positive side
+        if dest_point.x as u32 >= dest.width() || dest_point.y as u32 >= dest.height() {
+            return;
+        }
+        source_rect.width = source_rect.width.min(dest.width() - dest_point.x as u32);
+        source_rect.height = source_rect.height.min(dest.height() - dest_point.y as u32);
         if source_rect.x < 0
             || source_rect.y < 0
             || source_rect.width == 0
@@ -271,9 +707,12 @@ impl GraphicsContext {
         {
             panic!("texture too small");
         }
+        if !self.is_supported() {
+            return;
+        }
         for y in 0..source.height {
             for x in 0..source.width {
-                let color = self.get_image_pixel(source, x, y);
+                let color = self.get_image_pixel(source, x, y).unwrap_or(0);
                 for bx in 0..self.image_scale {
                     for by in 0..self.image_scale {
                         self.set_pixel(
@@ -348,6 +787,7 @@ pub struct TextWriter<'a, T: Texture> {
     context: &'a GraphicsContext,
     texture: &'a mut T,
     start_x: i32,
+    start_y: i32,
     wrap_x: i32,
     x: i32,
     y: i32,
@@ -360,6 +800,7 @@ impl<'a, T: Texture> TextWriter<'a, T> {
             context,
             texture,
             start_x: x,
+            start_y: y,
             wrap_x,
             x,
             y,
@@ -370,6 +811,16 @@ impl<'a, T: Texture> TextWriter<'a, T> {
         self.start_x = (width as i32 / 2) - (string_width as i32 / 2);
         self.x = self.start_x;
     }
+    /// The area of the texture covered by the lines written so far.
+    pub fn drawn_rect(&self) -> Rect {
+        let char_height = unsafe { SYSTEM_FONT.char_height as i32 };
+        Rect::new(
+            self.start_x,
+            self.start_y,
+            (self.wrap_x - self.start_x).max(0) as u32,
+            (self.y + char_height - self.start_y).max(0) as u32,
+        )
+    }
 
     fn write_byte(&mut self, byte: u8) {
         let char_width = unsafe { SYSTEM_FONT.char_width as i32 };
@@ -544,3 +995,254 @@ impl<'a, T: Texture> Write for TextWriter<'a, T> {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const COLOR: [u8; 3] = [0x11, 0x22, 0x33];
+    /// What every byte starts as, so a byte that changed outside the drawn area shows up.
+    const UNTOUCHED: u8 = 0xAA;
+    const WIDTH: u32 = 8;
+    const HEIGHT: u32 = 6;
+    /// Wider than the texture, so writes into the padding at the end of each row show up too.
+    const STRIDE: usize = 10;
+
+    fn texture() -> VecBuffer {
+        Buffer::new(
+            WIDTH,
+            HEIGHT,
+            STRIDE,
+            vec![UNTOUCHED; STRIDE * HEIGHT as usize * 3],
+        )
+    }
+
+    fn color() -> u32 {
+        GraphicsContext::const_default()
+            .encode_color(Color::from(COLOR))
+            .unwrap()
+    }
+
+    /// Checks that exactly the pixels for which `drawn` returns true have the color, and that all
+    /// other bytes, including the row padding, are untouched.
+    fn assert_drawn(texture: &VecBuffer, drawn: impl Fn(u32, u32) -> bool) {
+        for y in 0..HEIGHT {
+            for x in 0..STRIDE as u32 {
+                let offset = (y as usize * STRIDE + x as usize) * 3;
+                let pixel = &texture.data()[offset..offset + 3];
+                if x < WIDTH && drawn(x, y) {
+                    assert_eq!(pixel, COLOR, "pixel {},{} wasn't drawn", x, y);
+                } else {
+                    assert_eq!(pixel, [UNTOUCHED; 3], "pixel {},{} was drawn", x, y);
+                }
+            }
+        }
+    }
+
+    fn inside(rect: Rect) -> impl Fn(u32, u32) -> bool {
+        move |x, y| {
+            (rect.x()..rect.right()).contains(&(x as i32))
+                && (rect.y()..rect.bottom()).contains(&(y as i32))
+        }
+    }
+
+    fn fill(rect: Rect) -> VecBuffer {
+        let mut texture = texture();
+        GraphicsContext::const_default().fill_rect(&mut texture, rect, color());
+        texture
+    }
+
+    fn outline(rect: Rect) -> VecBuffer {
+        let mut texture = texture();
+        GraphicsContext::const_default().draw_rect(&mut texture, rect, color());
+        texture
+    }
+
+    #[test]
+    fn clip_keeps_the_part_inside() {
+        let texture = texture();
+        let clip = |rect| GraphicsContext::clip(&texture, rect);
+        assert_eq!(clip(Rect::new(1, 2, 3, 2)), Rect::new(1, 2, 3, 2));
+        assert_eq!(clip(Rect::new(-3, -2, 5, 4)), Rect::new(0, 0, 2, 2));
+        assert_eq!(clip(Rect::new(6, 4, 10, 10)), Rect::new(6, 4, 2, 2));
+        assert_eq!(
+            clip(Rect::new(i32::MIN, i32::MIN, u32::MAX, u32::MAX)),
+            Rect::new(0, 0, WIDTH, HEIGHT)
+        );
+        assert_eq!(
+            clip(Rect::new(-5, -5, u32::MAX, u32::MAX)),
+            Rect::new(0, 0, WIDTH, HEIGHT)
+        );
+        assert!(clip(Rect::new(WIDTH as i32, 0, 3, 3)).is_empty());
+        assert!(clip(Rect::new(0, 0, 0, 3)).is_empty());
+    }
+
+    #[test]
+    fn empty_rects_draw_nothing() {
+        for rect in [
+            Rect::new(2, 2, 0, 3),
+            Rect::new(2, 2, 3, 0),
+            Rect::new(0, 0, 0, 0),
+            Rect::new(-1, -1, 0, u32::MAX),
+        ] {
+            assert_drawn(&fill(rect), |_, _| false);
+            assert_drawn(&outline(rect), |_, _| false);
+        }
+    }
+
+    #[test]
+    fn fill_rect_fills_inside() {
+        let rect = Rect::new(1, 2, 3, 2);
+        assert_drawn(&fill(rect), inside(rect));
+    }
+
+    #[test]
+    fn fill_rect_clips_negative_coordinates() {
+        assert_drawn(
+            &fill(Rect::new(-3, -2, 5, 4)),
+            inside(Rect::new(0, 0, 2, 2)),
+        );
+        assert_drawn(&fill(Rect::new(-10, 1, 5, 2)), |_, _| false);
+        assert_drawn(&fill(Rect::new(1, -10, 2, 5)), |_, _| false);
+    }
+
+    #[test]
+    fn fill_rect_clips_past_right_and_bottom() {
+        assert_drawn(
+            &fill(Rect::new(6, 4, 10, 10)),
+            inside(Rect::new(6, 4, 2, 2)),
+        );
+        assert_drawn(&fill(Rect::new(WIDTH as i32, 0, 3, 3)), |_, _| false);
+        assert_drawn(&fill(Rect::new(0, HEIGHT as i32, 3, 3)), |_, _| false);
+    }
+
+    #[test]
+    fn fill_rect_clips_huge_rects() {
+        let all = |_, _| true;
+        assert_drawn(&fill(Rect::new(0, 0, u32::MAX, u32::MAX)), all);
+        assert_drawn(
+            &fill(Rect::new(i32::MIN, i32::MIN, u32::MAX, u32::MAX)),
+            all,
+        );
+        assert_drawn(&fill(Rect::new(-5, -5, u32::MAX, u32::MAX)), all);
+        assert_drawn(
+            &fill(Rect::new(0, 0, i32::MAX as u32, i32::MAX as u32)),
+            all,
+        );
+        assert_drawn(
+            &fill(Rect::new(i32::MAX, i32::MAX, u32::MAX, u32::MAX)),
+            |_, _| false,
+        );
+    }
+
+    #[test]
+    fn draw_rect_draws_the_outline() {
+        assert_drawn(&outline(Rect::new(1, 1, 4, 3)), |x, y| {
+            (1..=4).contains(&x) && (1..=3).contains(&y) && (x == 1 || x == 4 || y == 1 || y == 3)
+        });
+    }
+
+    #[test]
+    fn draw_rect_clips_each_side() {
+        // The left side is off screen, the other three are drawn up to the edge.
+        assert_drawn(&outline(Rect::new(-1, 1, 5, 3)), |x, y| {
+            x <= 3 && (1..=3).contains(&y) && (x == 3 || y == 1 || y == 3)
+        });
+        // Only the top and left sides are on screen.
+        assert_drawn(&outline(Rect::new(0, 0, u32::MAX, u32::MAX)), |x, y| {
+            x == 0 || y == 0
+        });
+        assert_drawn(&outline(Rect::new(-1, -1, u32::MAX, u32::MAX)), |_, _| {
+            false
+        });
+        assert_drawn(
+            &outline(Rect::new(i32::MAX, i32::MAX, u32::MAX, u32::MAX)),
+            |_, _| false,
+        );
+    }
+
+    /// Draws the outlines of `rects` like the kernel does, marking each one dirty clipped to the
+    /// screen, after the first present cleared the initial full state.
+    fn draw_dirty(rects: &[Rect]) -> DirtyRects {
+        let mut texture = texture();
+        let mut dirty = DirtyRects::new();
+        dirty.clear();
+        let screen = Rect::new(0, 0, WIDTH, HEIGHT);
+        for rect in rects {
+            GraphicsContext::const_default().draw_rect(&mut texture, *rect, color());
+            dirty.add(rect.intersect(&screen));
+        }
+        // Whatever was drawn has to be copied by the next present.
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let offset = (y as usize * STRIDE + x as usize) * 3;
+                if texture.data()[offset..offset + 3] == COLOR {
+                    assert!(
+                        dirty.as_slice().iter().any(|rect| inside(*rect)(x, y)),
+                        "pixel {},{} was drawn but isn't dirty",
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+        dirty
+    }
+
+    #[test]
+    fn dirty_rects_start_full() {
+        let mut dirty = DirtyRects::new();
+        assert!(dirty.is_full());
+        dirty.add(Rect::new(1, 1, 2, 2));
+        assert!(dirty.as_slice().is_empty());
+        dirty.clear();
+        assert!(!dirty.is_full());
+        assert!(dirty.as_slice().is_empty());
+        assert!(dirty.bounds().is_empty());
+        dirty.invalidate();
+        assert!(dirty.is_full());
+    }
+
+    #[test]
+    fn dirty_region_merges_overlapping_rects() {
+        let dirty = draw_dirty(&[Rect::new(1, 1, 3, 2), Rect::new(2, 2, 3, 3)]);
+        assert_eq!(dirty.as_slice(), [Rect::new(1, 1, 4, 4)]);
+        assert_eq!(dirty.bounds(), Rect::new(1, 1, 4, 4));
+    }
+
+    #[test]
+    fn dirty_region_keeps_disjoint_rects_apart() {
+        let dirty = draw_dirty(&[Rect::new(0, 0, 2, 2), Rect::new(5, 3, 2, 2)]);
+        assert_eq!(
+            dirty.as_slice(),
+            [Rect::new(0, 0, 2, 2), Rect::new(5, 3, 2, 2)]
+        );
+        assert_eq!(dirty.bounds(), Rect::new(0, 0, 7, 5));
+    }
+
+    #[test]
+    fn dirty_region_is_clipped_and_skips_empty_rects() {
+        let dirty = draw_dirty(&[
+            Rect::new(6, 4, 5, 5),
+            Rect::new(-4, -4, 2, 2),
+            Rect::new(3, 3, 0, 2),
+        ]);
+        assert_eq!(dirty.as_slice(), [Rect::new(6, 4, 2, 2)]);
+    }
+
+    #[test]
+    fn dirty_rects_collapse_when_out_of_slots() {
+        let mut dirty = DirtyRects::new();
+        dirty.clear();
+        for i in 0..MAX_DIRTY_RECTS as i32 {
+            dirty.add(Rect::new(i * 2, 0, 1, 1));
+        }
+        assert_eq!(dirty.as_slice().len(), MAX_DIRTY_RECTS);
+        dirty.add(Rect::new(0, 10, 1, 1));
+        assert_eq!(
+            dirty.as_slice(),
+            [Rect::new(0, 0, MAX_DIRTY_RECTS as u32 * 2 - 1, 11)]
+        );
+    }
+}
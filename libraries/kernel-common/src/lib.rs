@@ -1,21 +1,83 @@
 #![no_std]
 extern crate alloc;
 
+pub mod bmp;
+pub mod cmdline;
+pub mod fat;
 pub mod graphics;
+pub mod symbols;
+pub mod xmodem;
 
+/// Syscall numbers. A program puts the number in `rax` and the arguments in `rdi`, `rsi`, `rdx`,
+/// `r10`, `r8` and `r9`, then runs `syscall`; the result is in `rax`. Unless noted otherwise,
+/// syscalls returning `i64` return -1 on failure.
 pub struct Syscall;
 
 impl Syscall {
+    /// `() -> String`, allocated in the program's heap.
     pub const INFO_OS_NAME: usize = 1;
+    /// `() -> String`.
     pub const INFO_OS_VERSION: usize = 2;
+    /// `() -> String`, empty if unknown.
     pub const INFO_BOOTLOADER_VERSION: usize = 3;
+    /// `() -> FrameBuffer`, the framebuffer itself. Prefer `FB_BLIT`, which checks what's drawn.
     pub const INFO_FRAMEBUFFER: usize = 4;
+    /// `() -> GraphicsContext`.
     pub const INFO_GRAPHICS_CTX: usize = 5;
+    /// `(layout: Layout) -> *mut u8`, null if out of memory.
     pub const MEM_ALLOC: usize = 6;
+    /// `(ptr: *mut u8, layout: Layout)`.
     pub const MEM_DEALLOC: usize = 7;
+    /// `(layout: Layout) -> *mut u8`.
     pub const MEM_ALLOC_ZEROED: usize = 8;
+    /// `(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8`.
     pub const MEM_REALLOC: usize = 9;
+    /// `(ptr: *const u8, len: usize) -> !`, with the panic message.
     pub const PROGRAM_PANIC: usize = 10;
+    /// `(code: u8) -> !`.
+    pub const PROGRAM_EXIT: usize = 11;
+    /// `(fd: u64, ptr: *const u8, len: usize) -> i64`, the number of bytes written.
+    pub const WRITE: usize = 12;
+    /// `()`, lets another process run.
+    pub const PROGRAM_YIELD: usize = 13;
+    /// `(new_break: u64) -> u64`, the new end of the heap, or the current one if out of range.
+    pub const MEM_BRK: usize = 14;
+    /// `() -> i64`, the child's id in the parent and 0 in the child.
+    pub const PROGRAM_FORK: usize = 15;
+    /// `() -> !`.
+    pub const SYSTEM_POWER_OFF: usize = 16;
+    /// `(size: usize) -> i64`, the id of a new zeroed shared memory region.
+    pub const SHM_CREATE: usize = 17;
+    /// `(id: u64) -> u64`, the address the region was mapped at, or 0.
+    pub const SHM_MAP: usize = 18;
+    /// `(addr: u64) -> i64`, 0 once unmapped.
+    pub const SHM_UNMAP: usize = 19;
+    /// `(fd: u64, ptr: *mut u8, len: usize) -> i64`, the number of bytes read, 0 at the end.
+    pub const READ: usize = 20;
+    /// `(fds: *mut [u32; 2]) -> i64`, storing the read and write ends.
+    pub const PIPE: usize = 21;
+    /// `(fd: u64) -> i64`.
+    pub const CLOSE: usize = 22;
+    /// `(ptr: *const u8, len: usize) -> i64`, a read only file descriptor for the path.
+    pub const OPEN: usize = 23;
+    /// `(fd: u64) -> i64`, the lowest free file descriptor, now a copy of `fd`.
+    pub const DUP: usize = 24;
+    /// `(fd: u64, new_fd: u64) -> i64`, `new_fd`, now a copy of `fd`.
+    pub const DUP2: usize = 25;
 
-    pub const NUM_SYSCALLS: usize = 11;
+    /// `(id: u64) -> i64`, the exit code of child process `id` once it exits.
+    pub const PROGRAM_WAIT: usize = 26;
+
+    /// `(info: *mut FbInfo) -> i64`.
+    pub const FB_INFO: usize = 27;
+    /// `(ptr: *const u8, len: usize, rect: Rect) -> i64`, drawing an image of `rect`'s size in the
+    /// screen's pixel format, with rows `rect.width()` pixels apart, and showing it.
+    pub const FB_BLIT: usize = 28;
+
+    pub const NUM_SYSCALLS: usize = 29;
 }
+
+/// Exit code of a process killed by a page fault, like a shell reports SIGSEGV.
+pub const PAGE_FAULT_EXIT_CODE: u8 = 139;
+/// Exit code of a process killed by another exception, like a shell reports SIGILL.
+pub const FAULT_EXIT_CODE: u8 = 132;
@@ -0,0 +1,192 @@
+//! The kernel's symbol table, which the build script writes and backtraces read. Each line is
+//! `start size name`, with the link address and size in hex.
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+pub struct Symbol<'a> {
+    pub start: u64,
+    pub size: u64,
+    pub name: &'a str,
+}
+
+/// Turns a legacy Rust mangled name like `_ZN6kernel6memory4init17h0123456789abcdefE` into
+/// `kernel::memory::init`. Other names are returned as they are.
+pub fn demangle(name: &str) -> String {
+    let Some(mut rest) = name
+        .strip_prefix("_ZN")
+        .and_then(|rest| rest.strip_suffix('E'))
+    else {
+        return String::from(name);
+    };
+    let mut parts = Vec::new();
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let Ok(len) = rest[..digits].parse::<usize>() else {
+            return String::from(name);
+        };
+        let Some(part) = rest.get(digits..digits + len) else {
+            return String::from(name);
+        };
+        // Parts starting with an escape get an underscore in front, e.g. `_$LT$`.
+        parts.push(part.strip_prefix("_$").map_or(part, |_| &part[1..]));
+        rest = &rest[digits + len..];
+    }
+    // The last part is a hash of the crate and signature.
+    if parts
+        .last()
+        .map_or(false, |last| last.len() == 17 && last.starts_with('h'))
+    {
+        parts.pop();
+    }
+    let mut demangled = parts.join("::");
+    for (escape, replacement) in [
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$RF$", "&"),
+        ("$BP$", "*"),
+        ("$C$", ","),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+        ("..", "::"),
+    ] {
+        demangled = demangled.replace(escape, replacement);
+    }
+    demangled
+}
+
+/// The table for `symbols`, given as start, size and name, sorted by address.
+pub fn table(mut symbols: Vec<(u64, u64, String)>) -> String {
+    symbols.sort();
+    let mut table = String::new();
+    for (start, size, name) in symbols {
+        writeln!(table, "{:x} {:x} {}", start, size, name).unwrap();
+    }
+    table
+}
+
+fn parse_symbol(line: &[u8]) -> Option<Symbol> {
+    let line = core::str::from_utf8(line).ok()?;
+    let mut fields = line.splitn(3, ' ');
+    let start = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let size = u64::from_str_radix(fields.next()?, 16).ok()?;
+    Some(Symbol {
+        start,
+        size,
+        name: fields.next()?,
+    })
+}
+
+/// The symbols in `table`, skipping lines that can't be parsed.
+pub fn symbols(table: &[u8]) -> impl Iterator<Item = Symbol> {
+    table.split(|&b| b == b'\n').filter_map(parse_symbol)
+}
+
+/// The function in `table` containing `address` and how far into it the address is. Doesn't
+/// allocate.
+pub fn lookup(table: &[u8], address: u64) -> Option<(&str, u64)> {
+    symbols(table)
+        .find(|symbol| (symbol.start..symbol.start + symbol.size).contains(&address))
+        .map(|symbol| (symbol.name, address - symbol.start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn demangles_paths() {
+        assert_eq!(
+            demangle("_ZN6kernel11kernel_main17hb920dae9c2173c5fE"),
+            "kernel::kernel_main"
+        );
+        // Without a hash at the end, the last part is kept.
+        assert_eq!(demangle("_ZN6kernel6memory4initE"), "kernel::memory::init");
+    }
+
+    #[test]
+    fn demangles_escapes() {
+        assert_eq!(
+            demangle(
+                "_ZN106_$LT$core..ops..range..Range$LT$usize$GT$$u20$as$u20$core..slice..index..\
+                 SliceIndex$LT$$u5b$T$u5d$$GT$$GT$9index_mut17h9a7040b3773df851E"
+            ),
+            "<core::ops::range::Range<usize> as core::slice::index::SliceIndex<[T]>>::index_mut"
+        );
+        assert_eq!(
+            demangle(
+                "_ZN102_$LT$core..str..iter..CharIndices$u20$as$u20$core..iter..traits..\
+                 double_ended..DoubleEndedIterator$GT$9next_back28_$u7b$$u7b$closure$u7d$$u7d$\
+                 17h64be244e5a9fe83dE"
+            ),
+            "<core::str::iter::CharIndices as core::iter::traits::double_ended::\
+             DoubleEndedIterator>::next_back::{{closure}}"
+        );
+    }
+
+    #[test]
+    fn keeps_other_names() {
+        for name in [
+            "memcpy",
+            "_start",
+            "_ZN6kernel",
+            "_ZN9kernelE",
+            "_ZNxE",
+            "_RNvC6kernel4main",
+        ] {
+            assert_eq!(demangle(name), name);
+        }
+    }
+
+    fn test_table() -> String {
+        table(vec![
+            (0x2000, 0x10, String::from("b")),
+            (0x1000, 0x20, String::from("a::<impl a::A>::f")),
+            (0x2010, 0x08, String::from("c")),
+        ])
+    }
+
+    #[test]
+    fn table_is_sorted_by_address() {
+        assert_eq!(
+            test_table(),
+            "1000 20 a::<impl a::A>::f\n2000 10 b\n2010 8 c\n"
+        );
+    }
+
+    #[test]
+    fn reads_the_table_back() {
+        let table = test_table();
+        let read: Vec<_> = symbols(table.as_bytes())
+            .map(|symbol| (symbol.start, symbol.size, symbol.name))
+            .collect();
+        assert_eq!(
+            read,
+            [
+                (0x1000, 0x20, "a::<impl a::A>::f"),
+                (0x2000, 0x10, "b"),
+                (0x2010, 0x8, "c")
+            ]
+        );
+        assert_eq!(symbols(b"1000 20\nxyz 1 a\n\n1 zz b\n2000 4 ok").count(), 1);
+    }
+
+    #[test]
+    fn looks_up_addresses() {
+        let table = test_table();
+        let table = table.as_bytes();
+        assert_eq!(lookup(table, 0x1000), Some(("a::<impl a::A>::f", 0)));
+        assert_eq!(lookup(table, 0x101f), Some(("a::<impl a::A>::f", 0x1f)));
+        assert_eq!(lookup(table, 0x2010), Some(("c", 0)));
+        // Between functions, and past the last one.
+        assert_eq!(lookup(table, 0x1020), None);
+        assert_eq!(lookup(table, 0x2018), None);
+        assert_eq!(lookup(table, 0xfff), None);
+        assert_eq!(lookup(&[], 0x1000), None);
+    }
+}
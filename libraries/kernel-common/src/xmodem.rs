@@ -0,0 +1,302 @@
+//! Receiving files with XMODEM-CRC, over any line that can send and receive bytes.
+use alloc::vec::Vec;
+
+/// What the protocol is spoken over.
+pub trait Line {
+    /// Waits up to `timeout_ms` for the next byte.
+    fn read_byte(&mut self, timeout_ms: u64) -> Option<u8>;
+    fn write_byte(&mut self, byte: u8);
+}
+
+const SOH: u8 = 0x01;
+/// Starts a 1024 byte block, which XMODEM-1K senders use once CRC mode is agreed on.
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Sent instead of NAK to ask for CRC-16 instead of the original checksum.
+const CRC_REQUEST: u8 = b'C';
+/// What the sender fills the last block up with.
+pub const PADDING: u8 = 0x1a;
+
+const BLOCK_SIZE: usize = 128;
+const LONG_BLOCK_SIZE: usize = 1024;
+
+/// How long to wait for the first block before asking again. Senders usually have to be started
+/// by hand after the receiver, so this is retried `MAX_ERRORS` times.
+const START_TIMEOUT_MS: u64 = 3000;
+/// How long to wait for the next block once the transfer is going.
+const BLOCK_TIMEOUT_MS: u64 = 10_000;
+/// How long to wait for each byte inside a block.
+const BYTE_TIMEOUT_MS: u64 = 1000;
+/// Errors in a row before the transfer is given up.
+const MAX_ERRORS: usize = 10;
+
+/// CRC-16/XMODEM: polynomial 0x1021, no reflection, starting at zero.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Reads and drops bytes until the line is quiet, so the sender is in sync again after the NAK.
+fn purge(line: &mut impl Line) {
+    while line.read_byte(BYTE_TIMEOUT_MS).is_some() {}
+}
+
+fn cancel(line: &mut impl Line) {
+    for _ in 0..3 {
+        line.write_byte(CAN);
+    }
+}
+
+/// Reads one block after its start byte. Returns its number and data, or `None` if it timed out
+/// or was damaged.
+fn read_block(line: &mut impl Line, size: usize, data: &mut [u8; LONG_BLOCK_SIZE]) -> Option<u8> {
+    let number = line.read_byte(BYTE_TIMEOUT_MS)?;
+    let complement = line.read_byte(BYTE_TIMEOUT_MS)?;
+    for byte in &mut data[..size] {
+        *byte = line.read_byte(BYTE_TIMEOUT_MS)?;
+    }
+    let crc_high = line.read_byte(BYTE_TIMEOUT_MS)?;
+    let crc_low = line.read_byte(BYTE_TIMEOUT_MS)?;
+    if number != !complement || crc16(&data[..size]) != u16::from_be_bytes([crc_high, crc_low]) {
+        return None;
+    }
+    Some(number)
+}
+
+/// Receives a whole file over `line`, padding included.
+pub fn receive(line: &mut impl Line) -> Result<Vec<u8>, &'static str> {
+    let mut received = Vec::new();
+    let mut block = [0; LONG_BLOCK_SIZE];
+    let mut expected: u8 = 1;
+    let mut started = false;
+    let mut errors = 0;
+    // Asks for the first block, and CRC mode.
+    line.write_byte(CRC_REQUEST);
+    loop {
+        if errors >= MAX_ERRORS {
+            cancel(line);
+            return Err(if started {
+                "too many errors"
+            } else {
+                "no sender"
+            });
+        }
+        let timeout = if started {
+            BLOCK_TIMEOUT_MS
+        } else {
+            START_TIMEOUT_MS
+        };
+        let size = match line.read_byte(timeout) {
+            Some(SOH) => BLOCK_SIZE,
+            Some(STX) => LONG_BLOCK_SIZE,
+            Some(EOT) => {
+                line.write_byte(ACK);
+                return Ok(received);
+            }
+            // Two in a row, so a single CAN from line noise doesn't end the transfer.
+            Some(CAN) => {
+                if line.read_byte(BYTE_TIMEOUT_MS) == Some(CAN) {
+                    return Err("cancelled by sender");
+                }
+                errors += 1;
+                continue;
+            }
+            Some(_) => {
+                purge(line);
+                line.write_byte(if started { NAK } else { CRC_REQUEST });
+                errors += 1;
+                continue;
+            }
+            None => {
+                line.write_byte(if started { NAK } else { CRC_REQUEST });
+                errors += 1;
+                continue;
+            }
+        };
+        started = true;
+        match read_block(line, size, &mut block) {
+            Some(number) if number == expected => {
+                received.extend_from_slice(&block[..size]);
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                line.write_byte(ACK);
+            }
+            // Our ACK for the last block got lost, so the sender sent it again.
+            Some(number) if number == expected.wrapping_sub(1) => line.write_byte(ACK),
+            Some(_) => {
+                cancel(line);
+                return Err("block out of sequence");
+            }
+            None => {
+                purge(line);
+                line.write_byte(NAK);
+                errors += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::vec;
+
+    /// Plays back what the sender sends, where `None` is a read that times out, and records what
+    /// the receiver answers. Once the script runs out, every read times out.
+    struct Script {
+        input: VecDeque<Option<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Line for Script {
+        fn read_byte(&mut self, _timeout_ms: u64) -> Option<u8> {
+            self.input.pop_front().flatten()
+        }
+        fn write_byte(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+    }
+
+    fn receive_script(input: &[Vec<Option<u8>>]) -> (Result<Vec<u8>, &'static str>, Vec<u8>) {
+        let mut script = Script {
+            input: input.concat().into(),
+            output: Vec::new(),
+        };
+        let result = receive(&mut script);
+        (result, script.output)
+    }
+
+    fn bytes(bytes: &[u8]) -> Vec<Option<u8>> {
+        bytes.iter().copied().map(Some).collect()
+    }
+
+    fn block_with_crc(start: u8, number: u8, data: &[u8], crc: u16) -> Vec<Option<u8>> {
+        let mut block = vec![start, number, !number];
+        block.extend_from_slice(data);
+        block.extend_from_slice(&crc.to_be_bytes());
+        bytes(&block)
+    }
+
+    fn block(number: u8, data: &[u8; BLOCK_SIZE]) -> Vec<Option<u8>> {
+        block_with_crc(SOH, number, data, crc16(data))
+    }
+
+    fn timeout() -> Vec<Option<u8>> {
+        vec![None]
+    }
+
+    #[test]
+    fn crc_matches_the_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    #[test]
+    fn receives_blocks_in_order() {
+        let (first, second) = ([1; BLOCK_SIZE], [2; BLOCK_SIZE]);
+        let (result, output) =
+            receive_script(&[block(1, &first), block(2, &second), bytes(&[EOT])]);
+        assert_eq!(result.unwrap(), [first, second].concat());
+        assert_eq!(output, [CRC_REQUEST, ACK, ACK, ACK]);
+    }
+
+    #[test]
+    fn receives_1k_blocks() {
+        let data = [3; LONG_BLOCK_SIZE];
+        let long_block = block_with_crc(STX, 1, &data, crc16(&data));
+        let (result, output) = receive_script(&[long_block, bytes(&[EOT])]);
+        assert_eq!(result.unwrap(), data);
+        assert_eq!(output, [CRC_REQUEST, ACK, ACK]);
+    }
+
+    #[test]
+    fn block_numbers_wrap_around() {
+        let blocks: Vec<_> = (1..=256)
+            .map(|i| block(i as u8, &[i as u8; BLOCK_SIZE]))
+            .collect();
+        let (result, _) = receive_script(&[blocks.concat(), bytes(&[EOT])]);
+        let expected: Vec<u8> = (1..=256).flat_map(|i| [i as u8; BLOCK_SIZE]).collect();
+        assert_eq!(result.unwrap(), expected);
+    }
+
+    #[test]
+    fn naks_damaged_blocks() {
+        let data = [4; BLOCK_SIZE];
+        let bad_crc = block_with_crc(SOH, 1, &data, crc16(&data) ^ 1);
+        let mut bad_complement = block(1, &data);
+        bad_complement[2] = Some(0);
+        let (result, output) = receive_script(&[
+            bad_crc,
+            timeout(),
+            bad_complement,
+            timeout(),
+            block(1, &data),
+            bytes(&[EOT]),
+        ]);
+        assert_eq!(result.unwrap(), data);
+        assert_eq!(output, [CRC_REQUEST, NAK, NAK, ACK, ACK]);
+    }
+
+    #[test]
+    fn acks_repeated_blocks_once() {
+        let (first, second) = ([5; BLOCK_SIZE], [6; BLOCK_SIZE]);
+        let (result, output) = receive_script(&[
+            block(1, &first),
+            block(1, &first),
+            block(2, &second),
+            bytes(&[EOT]),
+        ]);
+        assert_eq!(result.unwrap(), [first, second].concat());
+        assert_eq!(output, [CRC_REQUEST, ACK, ACK, ACK, ACK]);
+    }
+
+    #[test]
+    fn cancels_blocks_out_of_sequence() {
+        let (result, output) = receive_script(&[block(2, &[7; BLOCK_SIZE])]);
+        assert_eq!(result, Err("block out of sequence"));
+        assert_eq!(output, [CRC_REQUEST, CAN, CAN, CAN]);
+    }
+
+    #[test]
+    fn sender_can_cancel() {
+        let (result, output) = receive_script(&[block(1, &[8; BLOCK_SIZE]), bytes(&[CAN, CAN])]);
+        assert_eq!(result, Err("cancelled by sender"));
+        assert_eq!(output, [CRC_REQUEST, ACK]);
+    }
+
+    #[test]
+    fn ignores_a_single_cancel() {
+        let data = [9; BLOCK_SIZE];
+        let (result, _) =
+            receive_script(&[bytes(&[CAN]), timeout(), block(1, &data), bytes(&[EOT])]);
+        assert_eq!(result.unwrap(), data);
+    }
+
+    #[test]
+    fn gives_up_after_too_many_errors() {
+        let (result, output) = receive_script(&[]);
+        assert_eq!(result, Err("no sender"));
+        let mut expected = vec![CRC_REQUEST; MAX_ERRORS + 1];
+        expected.extend_from_slice(&[CAN; 3]);
+        assert_eq!(output, expected);
+
+        let (result, output) = receive_script(&[block(1, &[10; BLOCK_SIZE])]);
+        assert_eq!(result, Err("too many errors"));
+        assert_eq!(output[..2], [CRC_REQUEST, ACK]);
+        assert_eq!(output[2..2 + MAX_ERRORS], [NAK; MAX_ERRORS]);
+    }
+}
@@ -38,4 +38,20 @@ pub enum ErrorCause {
         /// The size of the buffer passed into the function
         actual: usize,
     },
+
+    /// The error was thrown because a GPT header did not start with `EFI PART`.
+    InvalidGptSignature,
+
+    /// The error was thrown because a GPT header's size or entry size is out of range.
+    InvalidGptHeader,
+
+    /// The error was thrown because the CRC32 of a GPT header or its partition entries
+    /// did not match the one stored in the header.
+    GptChecksumMismatch {
+        /// The checksum stored in the header
+        expected: u32,
+
+        /// The checksum of the data read
+        actual: u32,
+    },
 }
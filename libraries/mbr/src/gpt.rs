@@ -0,0 +1,394 @@
+//! GUID Partition Table parsing, for disks with a protective MBR.
+
+use crate::{ErrorCause, MbrError};
+
+/// A GUID as stored on disk, with the first three fields little-endian.
+pub type Guid = [u8; 16];
+
+/// Type GUID of Microsoft basic data partitions (EBD0A0A2-B9E5-4433-87C0-68B6B72699C7), which
+/// includes FAT32.
+pub const BASIC_DATA_PARTITION: Guid = [
+    0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7,
+];
+
+/// The block holding the primary header. The backup header is in the last block of the disk.
+pub const PRIMARY_HEADER_LBA: u64 = 1;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const MIN_HEADER_SIZE: usize = 92;
+const BLOCK_SIZE: usize = 512;
+const MIN_ENTRY_SIZE: usize = 128;
+const NAME_UNITS: usize = 36;
+
+fn read_u32_le(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(buf[..4].try_into().unwrap())
+}
+fn read_u64_le(buf: &[u8]) -> u64 {
+    u64::from_le_bytes(buf[..8].try_into().unwrap())
+}
+
+/// The CRC32 (IEEE 802.3) used by GPT.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// A GPT header, either the primary or the backup.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct GptHeader {
+    /// The block this header was read from.
+    pub current_lba: u64,
+    /// The block of the other copy of the header.
+    pub backup_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    /// The first block of the partition entry array.
+    pub entries_lba: u64,
+    pub num_entries: u32,
+    pub entry_size: u32,
+    pub entries_crc32: u32,
+}
+
+impl GptHeader {
+    /// Parses a GPT header from the block it is stored in.
+    ///
+    /// Throws an error in the following cases:
+    /// * `BufferWrongSizeError` if `bytes.len()` is less than 512
+    /// * `InvalidGptSignature` if the header doesn't start with `EFI PART`
+    /// * `InvalidGptHeader` if the header or entry size is out of range
+    /// * `GptChecksumMismatch` if the header's CRC32 doesn't match
+    pub fn from_bytes<T: AsRef<[u8]>>(bytes: &T) -> Result<GptHeader, MbrError> {
+        let buffer: &[u8] = bytes.as_ref();
+        if buffer.len() < BLOCK_SIZE {
+            return Err(MbrError::from_cause(ErrorCause::BufferWrongSizeError {
+                expected: BLOCK_SIZE,
+                actual: buffer.len(),
+            }));
+        }
+        if &buffer[0..8] != SIGNATURE {
+            return Err(MbrError::from_cause(ErrorCause::InvalidGptSignature));
+        }
+        let header_size = read_u32_le(&buffer[12..]) as usize;
+        if !(MIN_HEADER_SIZE..=BLOCK_SIZE).contains(&header_size) {
+            return Err(MbrError::from_cause(ErrorCause::InvalidGptHeader));
+        }
+        // The checksum is calculated with its own field zeroed.
+        let expected = read_u32_le(&buffer[16..]);
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..header_size].copy_from_slice(&buffer[..header_size]);
+        header[16..20].fill(0);
+        let actual = crc32(&header[..header_size]);
+        if actual != expected {
+            return Err(MbrError::from_cause(ErrorCause::GptChecksumMismatch {
+                expected,
+                actual,
+            }));
+        }
+        let entry_size = read_u32_le(&buffer[84..]);
+        if (entry_size as usize) < MIN_ENTRY_SIZE || entry_size % 8 != 0 {
+            return Err(MbrError::from_cause(ErrorCause::InvalidGptHeader));
+        }
+        Ok(GptHeader {
+            current_lba: read_u64_le(&buffer[24..]),
+            backup_lba: read_u64_le(&buffer[32..]),
+            first_usable_lba: read_u64_le(&buffer[40..]),
+            last_usable_lba: read_u64_le(&buffer[48..]),
+            disk_guid: buffer[56..72].try_into().unwrap(),
+            entries_lba: read_u64_le(&buffer[72..]),
+            num_entries: read_u32_le(&buffer[80..]),
+            entry_size,
+            entries_crc32: read_u32_le(&buffer[88..]),
+        })
+    }
+
+    /// The size of the partition entry array in bytes.
+    pub fn entries_size(&self) -> usize {
+        self.num_entries as usize * self.entry_size as usize
+    }
+
+    /// How many blocks to read from `entries_lba` to get the whole partition entry array.
+    pub fn entries_blocks(&self) -> usize {
+        (self.entries_size() + BLOCK_SIZE - 1) / BLOCK_SIZE
+    }
+
+    /// Checks the partition entry array in `bytes`, as read from `entries_lba`, and returns the
+    /// partitions in use.
+    ///
+    /// Throws an error in the following cases:
+    /// * `BufferWrongSizeError` if `bytes` is shorter than the entry array
+    /// * `GptChecksumMismatch` if the entry array's CRC32 doesn't match
+    pub fn entries<'a>(
+        &self,
+        bytes: &'a [u8],
+    ) -> Result<impl Iterator<Item = GptEntry> + 'a, MbrError> {
+        let size = self.entries_size();
+        if bytes.len() < size {
+            return Err(MbrError::from_cause(ErrorCause::BufferWrongSizeError {
+                expected: size,
+                actual: bytes.len(),
+            }));
+        }
+        let actual = crc32(&bytes[..size]);
+        if actual != self.entries_crc32 {
+            return Err(MbrError::from_cause(ErrorCause::GptChecksumMismatch {
+                expected: self.entries_crc32,
+                actual,
+            }));
+        }
+        Ok(bytes[..size]
+            .chunks_exact(self.entry_size as usize)
+            .map(GptEntry::from_bytes)
+            .filter(|entry| entry.type_guid != [0; 16]))
+    }
+}
+
+/// A partition in the GPT's entry array.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct GptEntry {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    /// The last block of the partition, inclusive.
+    pub last_lba: u64,
+    pub attributes: u64,
+    /// UTF-16LE, padded with zeros.
+    pub name: [u16; NAME_UNITS],
+}
+
+impl GptEntry {
+    fn from_bytes(buffer: &[u8]) -> GptEntry {
+        let mut name = [0u16; NAME_UNITS];
+        for (i, unit) in name.iter_mut().enumerate() {
+            *unit = u16::from_le_bytes([buffer[56 + i * 2], buffer[57 + i * 2]]);
+        }
+        GptEntry {
+            type_guid: buffer[0..16].try_into().unwrap(),
+            unique_guid: buffer[16..32].try_into().unwrap(),
+            first_lba: read_u64_le(&buffer[32..]),
+            last_lba: read_u64_le(&buffer[40..]),
+            attributes: read_u64_le(&buffer[48..]),
+            name,
+        }
+    }
+
+    /// The total number of blocks in this partition.
+    pub fn sector_count(&self) -> u64 {
+        (self.last_lba + 1).saturating_sub(self.first_lba)
+    }
+
+    /// Whether the partition's name is `name`.
+    pub fn name_is(&self, name: &str) -> bool {
+        let len = self
+            .name
+            .iter()
+            .position(|unit| *unit == 0)
+            .unwrap_or(NAME_UNITS);
+        name.encode_utf16().eq(self.name[..len].iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRIES: usize = 4;
+    const USER_GUID: Guid = [0x42; 16];
+
+    fn put_u32(buffer: &mut [u8], offset: usize, value: u32) {
+        buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    fn put_u64(buffer: &mut [u8], offset: usize, value: u64) {
+        buffer[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn entry(type_guid: Guid, first_lba: u64, last_lba: u64, name: &str) -> [u8; MIN_ENTRY_SIZE] {
+        let mut entry = [0; MIN_ENTRY_SIZE];
+        entry[0..16].copy_from_slice(&type_guid);
+        entry[16..32].copy_from_slice(&[0x11; 16]);
+        put_u64(&mut entry, 32, first_lba);
+        put_u64(&mut entry, 40, last_lba);
+        put_u64(&mut entry, 48, 1);
+        for (i, unit) in name.encode_utf16().enumerate() {
+            entry[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entry
+    }
+
+    /// An entry array with a basic data partition, an unused entry and a named one.
+    fn entries() -> [u8; ENTRIES * MIN_ENTRY_SIZE] {
+        let mut entries = [0; ENTRIES * MIN_ENTRY_SIZE];
+        entries[..MIN_ENTRY_SIZE].copy_from_slice(&entry(BASIC_DATA_PARTITION, 34, 2081, "data"));
+        entries[2 * MIN_ENTRY_SIZE..3 * MIN_ENTRY_SIZE]
+            .copy_from_slice(&entry(USER_GUID, 2082, 4129, "user"));
+        entries
+    }
+
+    /// Puts the header's CRC32 in, after changes to it.
+    fn seal(header: &mut [u8; BLOCK_SIZE]) {
+        let size = read_u32_le(&header[12..]) as usize;
+        header[16..20].fill(0);
+        let crc = crc32(&header[..size]);
+        put_u32(header, 16, crc);
+    }
+
+    /// A primary header for `entries`, with a 92 byte header followed by zeros.
+    fn header(entries: &[u8]) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[0..8].copy_from_slice(SIGNATURE);
+        put_u32(&mut header, 8, 0x0001_0000);
+        put_u32(&mut header, 12, MIN_HEADER_SIZE as u32);
+        put_u64(&mut header, 24, PRIMARY_HEADER_LBA);
+        put_u64(&mut header, 32, 8191);
+        put_u64(&mut header, 40, 34);
+        put_u64(&mut header, 48, 8158);
+        header[56..72].copy_from_slice(&[0x33; 16]);
+        put_u64(&mut header, 72, 2);
+        put_u32(&mut header, 80, ENTRIES as u32);
+        put_u32(&mut header, 84, MIN_ENTRY_SIZE as u32);
+        put_u32(&mut header, 88, crc32(entries));
+        seal(&mut header);
+        header
+    }
+
+    fn cause<T>(result: Result<T, MbrError>) -> ErrorCause {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => err.cause,
+        }
+    }
+
+    #[test]
+    fn crc_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn parses_the_header() {
+        let header = GptHeader::from_bytes(&header(&entries())).unwrap();
+        assert_eq!(
+            header,
+            GptHeader {
+                current_lba: 1,
+                backup_lba: 8191,
+                first_usable_lba: 34,
+                last_usable_lba: 8158,
+                disk_guid: [0x33; 16],
+                entries_lba: 2,
+                num_entries: ENTRIES as u32,
+                entry_size: MIN_ENTRY_SIZE as u32,
+                entries_crc32: crc32(&entries()),
+            }
+        );
+        assert_eq!(header.entries_size(), 512);
+        assert_eq!(header.entries_blocks(), 1);
+    }
+
+    #[test]
+    fn lists_used_entries() {
+        let entries = entries();
+        let header = GptHeader::from_bytes(&header(&entries)).unwrap();
+        let mut used = header.entries(&entries).unwrap();
+        let data = used.next().unwrap();
+        assert_eq!(data.type_guid, BASIC_DATA_PARTITION);
+        assert_eq!(data.unique_guid, [0x11; 16]);
+        assert_eq!(
+            (data.first_lba, data.last_lba, data.attributes),
+            (34, 2081, 1)
+        );
+        assert_eq!(data.sector_count(), 2048);
+        assert!(data.name_is("data"));
+        let user = used.next().unwrap();
+        assert_eq!(user.type_guid, USER_GUID);
+        assert!(user.name_is("user"));
+        assert!(!user.name_is("use"));
+        assert!(!user.name_is("users"));
+        assert!(used.next().is_none());
+    }
+
+    #[test]
+    fn names_can_fill_the_entry() {
+        let name = "a name that is exactly 36 units long";
+        assert_eq!(name.len(), NAME_UNITS);
+        let entry = GptEntry::from_bytes(&entry(USER_GUID, 1, 1, name));
+        assert!(entry.name_is(name));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let valid = header(&entries());
+        assert_eq!(
+            cause(GptHeader::from_bytes(&&valid[..511])),
+            ErrorCause::BufferWrongSizeError {
+                expected: 512,
+                actual: 511
+            }
+        );
+
+        let mut header = valid;
+        header[0] = b'X';
+        seal(&mut header);
+        assert_eq!(
+            cause(GptHeader::from_bytes(&header)),
+            ErrorCause::InvalidGptSignature
+        );
+
+        for (offset, value) in [(12, 91), (12, 513), (84, 120), (84, 132)] {
+            let mut header = valid;
+            put_u32(&mut header, offset, value);
+            // The header size is checked before the CRC, which it's needed for.
+            if offset != 12 {
+                seal(&mut header);
+            }
+            assert_eq!(
+                cause(GptHeader::from_bytes(&header)),
+                ErrorCause::InvalidGptHeader
+            );
+        }
+    }
+
+    #[test]
+    fn checks_the_header_crc() {
+        let mut damaged = header(&entries());
+        let expected = read_u32_le(&damaged[16..]);
+        damaged[40] ^= 1;
+        assert!(matches!(
+            cause(GptHeader::from_bytes(&damaged)),
+            ErrorCause::GptChecksumMismatch { expected: e, .. } if e == expected
+        ));
+
+        // Bytes past the header's size aren't covered.
+        let mut padded = header(&entries());
+        padded[MIN_HEADER_SIZE] = 0xff;
+        assert!(GptHeader::from_bytes(&padded).is_ok());
+    }
+
+    #[test]
+    fn checks_the_entries_crc() {
+        let header = GptHeader::from_bytes(&header(&entries())).unwrap();
+        let mut entries = entries();
+        entries[3 * MIN_ENTRY_SIZE] = 1;
+        assert_eq!(
+            cause(header.entries(&entries)),
+            ErrorCause::GptChecksumMismatch {
+                expected: header.entries_crc32,
+                actual: crc32(&entries),
+            }
+        );
+        assert_eq!(
+            cause(header.entries(&entries[..511])),
+            ErrorCause::BufferWrongSizeError {
+                expected: 512,
+                actual: 511
+            }
+        );
+    }
+}
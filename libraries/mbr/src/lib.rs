@@ -8,6 +8,8 @@ pub use error::{ErrorCause, MbrError};
 mod partition;
 pub use partition::*;
 
+pub mod gpt;
+
 fn read_u32_le(buf: &[u8]) -> u32 {
     u32::from_le_bytes(buf.try_into().unwrap())
 }
@@ -10,6 +10,8 @@ pub enum PartitionType {
     HfsPlus(u8),
     ISO9660(u8),
     NtfsExfat(u8),
+    /// Covers the whole disk to protect a GPT from tools that only know MBR.
+    GptProtective,
 }
 
 impl PartitionType {
@@ -23,6 +25,7 @@ impl PartitionType {
             0x83 => PartitionType::LinuxExt(tag),
             0x07 => PartitionType::NtfsExfat(tag),
             0xaf => PartitionType::HfsPlus(tag),
+            0xee => PartitionType::GptProtective,
             _ => PartitionType::Unknown(tag),
         }
     }
@@ -39,6 +42,7 @@ impl PartitionType {
             PartitionType::HfsPlus(t) => t,
             PartitionType::ISO9660(t) => t,
             PartitionType::NtfsExfat(t) => t,
+            PartitionType::GptProtective => 0xee,
         }
     }
 }
@@ -6,6 +6,10 @@ PROGRAM_DIR=$(dirname "$0")
 BUILD_CMD="cargo build --target ../../x86_64-user.json -Zbuild-std=core,alloc -Zbuild-std-features=compiler-builtins-mem --release"
 for prog in "$PROGRAMS"; do (cd "$PROGRAM_DIR/$prog" && $BUILD_CMD); done
 
+# The self-tests are workspace members, built like the userspace program.
+SELFTESTS="cow_fork segments write_code"
+(cd "$PROGRAM_DIR/.." && cargo build -p selftest --target x86_64-unknown-none --release)
+
 FS_IMAGE=$PROGRAM_DIR/../target/user_partition.img
 [ -f "$FS_IMAGE" ] && rm "$FS_IMAGE"
 echo Creating FAT32 filesystem
@@ -13,3 +17,10 @@ dd if=/dev/zero of="$FS_IMAGE" bs=1M count=2
 mformat -F -i "$FS_IMAGE" ::
 mmd -i "$FS_IMAGE" ::/programs
 for prog in "$PROGRAMS"; do (mcopy -i "$FS_IMAGE" target/x86_64-user/release/$prog ::/programs/$prog.elf); done
+for test in $SELFTESTS; do
+    mcopy -i "$FS_IMAGE" "$PROGRAM_DIR/../target/x86_64-unknown-none/release/$test" ::/programs/$test.elf
+done
+
+# The kernel names functions in backtraces with the symbol table from the latest kernel build.
+SYMBOLS=$(ls -t "$PROGRAM_DIR"/../target/*/build/mythos-*/out/kernel.sym 2>/dev/null | head -n 1)
+[ -s "$SYMBOLS" ] && mcopy -i "$FS_IMAGE" "$SYMBOLS" ::/kernel.sym
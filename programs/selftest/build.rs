@@ -0,0 +1,7 @@
+fn main() {
+    // `segments` is linked at a fixed address, with the segment layout it checks
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg-bin=segments=-T{}/segments.ld", dir);
+    println!("cargo:rustc-link-arg-bin=segments=--no-pie");
+    println!("cargo:rerun-if-changed=segments.ld");
+}
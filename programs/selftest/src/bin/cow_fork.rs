@@ -0,0 +1,60 @@
+//! Forks, and has the child write to memory the parent wrote before. The write has to go to the
+//! child's copy of the pages, not the parent's.
+#![no_std]
+#![no_main]
+
+use core::ptr::addr_of_mut;
+use selftest::{check, pass, run_in_child};
+
+/// Two pages of data from the program file, so the copy isn't limited to one page.
+static mut BUFFER: [u8; 8192] = [b'p'; 8192];
+
+unsafe fn fill(buf: *mut u8, len: usize, byte: u8) {
+    for i in 0..len {
+        buf.add(i).write_volatile(byte);
+    }
+}
+
+unsafe fn all(buf: *const u8, len: usize, byte: u8) -> bool {
+    (0..len).all(|i| buf.add(i).read_volatile() == byte)
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let buffer = unsafe { addr_of_mut!(BUFFER) } as *mut u8;
+    let len = unsafe { BUFFER.len() };
+    let mut stack = [b'p'; 64];
+    let stack = stack.as_mut_ptr();
+    let exit_code = run_in_child(|| unsafe {
+        check(
+            all(buffer, len, b'p'),
+            "child doesn't see the parent's data",
+        );
+        check(all(stack, 64, b'p'), "child doesn't see the parent's stack");
+        fill(buffer, len, b'c');
+        fill(stack, 64, b'c');
+        check(
+            all(buffer, len, b'c'),
+            "child's write to the buffer was lost",
+        );
+        check(all(stack, 64, b'c'), "child's write to its stack was lost");
+    });
+    check(exit_code == 0, "child failed");
+    unsafe {
+        check(
+            all(buffer, len, b'p'),
+            "child's write changed the parent's buffer",
+        );
+        check(
+            all(stack, 64, b'p'),
+            "child's write changed the parent's stack",
+        );
+        // The parent's pages were made read-only by the fork too.
+        fill(buffer, len, b'q');
+        check(
+            all(buffer, len, b'q'),
+            "parent's write after the fork was lost",
+        );
+    }
+    pass("cow_fork")
+}
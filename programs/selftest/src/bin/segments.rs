@@ -0,0 +1,108 @@
+//! Checks the layout `segments.ld` links this program with: code, read-only data sharing the
+//! code's last page, and writable data. Each segment's bytes have to be where they were linked,
+//! and each has to keep its own permissions, except that the shared page is also executable.
+#![no_std]
+#![no_main]
+
+use core::ptr::{addr_of, addr_of_mut};
+use kernel_common::PAGE_FAULT_EXIT_CODE;
+use selftest::{check, pass, run_in_child};
+
+const PAGE_SIZE: usize = 4096;
+
+#[link_section = ".text.segments"]
+static TEXT: [u8; 16] = *b"in the code segm";
+#[link_section = ".rodata.segments"]
+static RODATA: [u8; 16] = *b"in read-only dat";
+#[link_section = ".data.segments"]
+static mut DATA: [u8; 16] = *b"in writable data";
+/// Past the data in the file, so the loader has to zero it.
+static mut BSS: [u8; 2 * PAGE_SIZE] = [0; 2 * PAGE_SIZE];
+
+extern "C" {
+    /// Set by `segments.ld`.
+    static segments_text_end: u8;
+}
+
+#[inline(never)]
+fn code() -> u32 {
+    core::hint::black_box(0x1234_5678)
+}
+
+fn page(addr: usize) -> usize {
+    addr / PAGE_SIZE
+}
+
+unsafe fn bytes_are(addr: *const u8, expected: &[u8]) -> bool {
+    expected
+        .iter()
+        .enumerate()
+        .all(|(i, byte)| addr.add(i).read_volatile() == *byte)
+}
+
+/// Runs `access` in a child and checks whether the page fault handler killed it.
+fn check_faults(faults: bool, access: impl FnOnce(), reason: &str) {
+    let exit_code = run_in_child(access);
+    let expected = if faults { PAGE_FAULT_EXIT_CODE } else { 0 };
+    check(exit_code == expected, reason);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let text = addr_of!(TEXT) as *mut u8;
+    let rodata = addr_of!(RODATA) as *mut u8;
+    let data = unsafe { addr_of_mut!(DATA) } as *mut u8;
+    let bss = unsafe { addr_of_mut!(BSS) } as *mut u8;
+    let text_end = unsafe { addr_of!(segments_text_end) } as usize;
+
+    // The program has to be laid out as the test expects.
+    check(
+        page(text_end) == page(rodata as usize),
+        "read-only data doesn't share a page with the code",
+    );
+    check(
+        page(rodata as usize) != page(data as usize),
+        "writable data shares a page with read-only data",
+    );
+
+    unsafe {
+        check(bytes_are(text, b"in the code segm"), "code segment bytes");
+        check(
+            bytes_are(rodata, b"in read-only dat"),
+            "read-only data bytes",
+        );
+        check(bytes_are(data, b"in writable data"), "writable data bytes");
+        check(bytes_are(bss, &[0; 2 * PAGE_SIZE]), "zeroed data bytes");
+    }
+
+    check(code() == 0x1234_5678, "code doesn't run");
+    check_faults(
+        true,
+        || unsafe { text.write_volatile(0) },
+        "code segment is writable",
+    );
+    check_faults(
+        true,
+        || unsafe { rodata.write_volatile(0) },
+        "read-only data in the shared page is writable",
+    );
+    check_faults(
+        false,
+        || unsafe {
+            data.write_volatile(0);
+            bss.add(PAGE_SIZE).write_volatile(1);
+        },
+        "writable data can't be written",
+    );
+    // A `ret`, which only runs if the page is executable.
+    unsafe { data.write_volatile(0xC3) };
+    check_faults(
+        true,
+        || unsafe {
+            let function: extern "C" fn() = core::mem::transmute(data);
+            function();
+        },
+        "writable data is executable",
+    );
+    pass("segments")
+}
@@ -0,0 +1,41 @@
+//! Writes to the program's own code and read-only data from forked children. Loaded segments are
+//! never writable and executable, so each write has to kill the child with the page fault exit
+//! code instead of changing anything.
+#![no_std]
+#![no_main]
+
+use core::ptr::addr_of;
+use kernel_common::PAGE_FAULT_EXIT_CODE;
+use selftest::{check, pass, run_in_child};
+
+static READ_ONLY: [u8; 4] = *b"wxro";
+
+#[inline(never)]
+fn code() -> u32 {
+    core::hint::black_box(0x1234_5678)
+}
+
+/// Writes `value` to `addr` in a child and checks that the child was killed for it.
+fn check_write_faults(addr: *mut u8, value: u8, reason: &str) {
+    let before = unsafe { addr.read_volatile() };
+    let exit_code = run_in_child(|| unsafe { addr.write_volatile(value) });
+    check(exit_code == PAGE_FAULT_EXIT_CODE, reason);
+    check(unsafe { addr.read_volatile() } == before, reason);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    check(code() == 0x1234_5678, "code doesn't run");
+    check_write_faults(
+        code as usize as *mut u8,
+        0xC3,
+        "writing to the code segment didn't fault",
+    );
+    check_write_faults(
+        addr_of!(READ_ONLY) as *mut u8,
+        b'!',
+        "writing to read-only data didn't fault",
+    );
+    check(code() == 0x1234_5678, "code changed");
+    pass("write_code")
+}
@@ -0,0 +1,130 @@
+//! What the self-test programs share: syscalls, output and reporting. Each test prints a line
+//! ending in PASS or FAIL and exits with 0 if it passed.
+#![feature(alloc_error_handler)]
+#![feature(asm_const)]
+#![no_std]
+
+use core::{alloc::Layout, arch::global_asm, fmt::Write};
+use kernel_common::Syscall;
+
+#[allow(improper_ctypes)]
+extern "sysv64" {
+    fn syscall_mem_alloc(layout: Layout) -> *mut u8;
+    fn syscall_mem_dealloc(ptr: *mut u8, layout: Layout);
+
+    fn syscall_program_exit(exit_code: u8) -> !;
+    fn syscall_program_fork() -> i64;
+    fn syscall_program_wait(id: u64) -> i64;
+    fn syscall_write(fd: u64, ptr: *const u8, len: usize) -> i64;
+}
+
+macro_rules! impl_syscall {
+    ($name:expr, $id:expr) => {
+        global_asm!(concat!(".globl ", $name, "\n", $name, ":\n",
+            r#"
+                mov rax, {syscall_id}
+                mov r10, rcx
+                syscall
+                ret"#),
+            syscall_id = const $id);
+    };
+}
+
+impl_syscall!("syscall_mem_alloc", Syscall::MEM_ALLOC);
+impl_syscall!("syscall_mem_dealloc", Syscall::MEM_DEALLOC);
+impl_syscall!("syscall_program_exit", Syscall::PROGRAM_EXIT);
+impl_syscall!("syscall_program_fork", Syscall::PROGRAM_FORK);
+impl_syscall!("syscall_program_wait", Syscall::PROGRAM_WAIT);
+impl_syscall!("syscall_write", Syscall::WRITE);
+
+/// Writes to the console through file descriptor 1.
+pub struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        match unsafe { syscall_write(1, s.as_ptr(), s.len()) } {
+            -1 => Err(core::fmt::Error),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write;
+        let _ = writeln!($crate::Stdout, $($arg)*);
+    }};
+}
+
+pub fn exit(exit_code: u8) -> ! {
+    unsafe { syscall_program_exit(exit_code) }
+}
+
+/// Starts a copy of the process. Returns the child's id in the parent and 0 in the child.
+pub fn fork() -> u64 {
+    match unsafe { syscall_program_fork() } {
+        -1 => fail("fork failed"),
+        id => id as u64,
+    }
+}
+
+/// Runs `child` in a forked process that exits with 0 if `child` returns, then waits for it and
+/// returns its exit code.
+pub fn run_in_child(child: impl FnOnce()) -> u8 {
+    match fork() {
+        0 => {
+            child();
+            exit(0)
+        }
+        id => match unsafe { syscall_program_wait(id) } {
+            -1 => fail("wait failed"),
+            exit_code => exit_code as u8,
+        },
+    }
+}
+
+/// Reports that test `name` passed and exits with 0.
+pub fn pass(name: &str) -> ! {
+    println!("{}: PASS", name);
+    exit(0)
+}
+
+/// Reports that the running test failed and exits with 1.
+pub fn fail(reason: &str) -> ! {
+    println!("{}: FAIL", reason);
+    exit(1)
+}
+
+/// Fails with `reason` unless `condition` holds.
+pub fn check(condition: bool, reason: &str) {
+    if !condition {
+        fail(reason);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    println!("{}: FAIL", info);
+    exit(1)
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(_layout: Layout) -> ! {
+    fail("alloc failed")
+}
+
+// kernel-common needs an allocator, though the tests don't allocate.
+struct SystemAllocator;
+
+#[global_allocator]
+static ALLOCATOR: SystemAllocator = SystemAllocator;
+
+unsafe impl core::alloc::GlobalAlloc for SystemAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        syscall_mem_alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        syscall_mem_dealloc(ptr, layout)
+    }
+}
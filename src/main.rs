@@ -14,6 +14,19 @@ fn main() {
         cmd.arg("-drive")
             .arg(format!("format=raw,file={bios_path}"));
     }
+    // Arguments are passed on as the kernel command line, e.g. `cargo run -- loglevel=debug`.
+    // QEMU needs commas in the string doubled.
+    let cmdline = std::env::args().skip(1).collect::<Vec<_>>().join(" ");
+    if !cmdline.is_empty() {
+        cmd.arg("-fw_cfg").arg(format!(
+            "name=opt/mythos/cmdline,string={}",
+            cmdline.replace(',', ",,")
+        ));
+    }
+    // `gdb=on` makes the kernel wait for GDB on COM1, so the port is put on TCP for it to connect to.
+    if std::env::args().skip(1).any(|arg| arg == "gdb=on") {
+        cmd.arg("-serial").arg("tcp::1234,server,nowait");
+    }
     let mut child = cmd.spawn().unwrap();
     child.wait().unwrap();
 }
@@ -6,26 +6,67 @@ extern crate alloc;
 
 use alloc::{format, string::String};
 use core::{alloc::Layout, arch::global_asm, fmt::Write};
-use kernel_common::{graphics, Syscall};
+use kernel_common::{
+    graphics::{self, Texture},
+    Syscall,
+};
+
+/// Enough for the lines of text written below.
+const PANEL_HEIGHT: u32 = 256;
 
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
-    let mut framebuffer = unsafe { syscall_info_framebuffer() };
+pub extern "C" fn _start(argc: usize, argv: *const *const u8) -> ! {
+    let mut info = core::mem::MaybeUninit::<graphics::FbInfo>::uninit();
+    if unsafe { syscall_fb_info(info.as_mut_ptr()) } != 0 {
+        unsafe { syscall_program_exit(1) };
+    }
+    let info = unsafe { info.assume_init() };
+    let (width, height) = (info.width, info.height);
     let context = unsafe { syscall_info_graphics_ctx() };
     graphics::load_system_font(&context, [255, 255, 255]);
-    let mut writer = graphics::TextWriter::new(&context, &mut framebuffer, 0, 0);
+    // Drawn here and then copied to the top of the screen.
+    let mut panel = graphics::VecBuffer::alloc(&context, width, PANEL_HEIGHT.min(height));
+    context.clear(&mut panel);
+    let mut writer = graphics::TextWriter::new(&context, &mut panel, 0, 0);
     let os_name = unsafe { syscall_info_os_name() };
     let os_version = unsafe { syscall_info_os_version() };
     let bootloader_version = unsafe { syscall_info_bootloader_version() };
     let _ = writeln!(writer, "{} v{}", os_name, os_version);
     let _ = writeln!(writer, "Bootloader v{}", bootloader_version);
+    let _ = writeln!(writer, "Display {}x{}", width, height);
+    // Grow the brk heap by a page and touch it, so it gets mapped.
+    let heap_start = unsafe { syscall_mem_brk(0) };
+    let heap_end = unsafe { syscall_mem_brk(heap_start + 4096) };
+    unsafe { (heap_start as *mut u64).write_volatile(0) };
+    let _ = writeln!(writer, "Heap {:#x}-{:#x}", heap_start, heap_end);
+    for index in 0..argc {
+        let arg = unsafe { arg_str(*argv.add(index)) };
+        let _ = writeln!(writer, "argv[{}] = {}", index, arg);
+    }
 
     unsafe {
         ata::init();
     }
     let drives = ata::list().unwrap();
     let _ = writeln!(writer, "{:?}", drives[0]);
-    loop {}
+
+    let panel_rect = graphics::Rect::new(0, 0, panel.width(), panel.height());
+    unsafe { syscall_fb_blit(panel.data().as_ptr(), panel.data().len(), panel_rect) };
+
+    let message = format!("Found drive {}\n", drives[0].model);
+    unsafe {
+        syscall_write(1, message.as_ptr(), message.len());
+        syscall_program_exit(0);
+    }
+}
+
+/// Reads a NUL terminated argument string put on the stack by the kernel.
+unsafe fn arg_str(ptr: *const u8) -> &'static str {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    core::str::from_utf8(core::slice::from_raw_parts(ptr, len)).unwrap_or("?")
 }
 
 #[allow(improper_ctypes)]
@@ -33,26 +74,31 @@ extern "sysv64" {
     fn syscall_info_os_name() -> String;
     fn syscall_info_os_version() -> String;
     fn syscall_info_bootloader_version() -> String;
-    fn syscall_info_framebuffer() -> graphics::FrameBuffer;
     fn syscall_info_graphics_ctx() -> graphics::GraphicsContext;
 
     fn syscall_mem_alloc(layout: Layout) -> *mut u8;
     fn syscall_mem_dealloc(ptr: *mut u8, layout: Layout);
     fn syscall_mem_alloc_zeroed(layout: Layout) -> *mut u8;
     fn syscall_mem_realloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8;
+    fn syscall_mem_brk(new_break: u64) -> u64;
 
     fn syscall_program_panic(message: &str) -> !;
+    fn syscall_program_exit(exit_code: u8) -> !;
+    fn syscall_write(fd: u64, ptr: *const u8, len: usize) -> i64;
+
+    fn syscall_fb_info(info: *mut graphics::FbInfo) -> i64;
+    fn syscall_fb_blit(ptr: *const u8, len: usize, rect: graphics::Rect) -> i64;
 }
 
 macro_rules! impl_syscall {
     ($name:expr, $id:expr) => {
         global_asm!(concat!(".globl ", $name, "\n", $name, ":\n",
             r#"
-                mov rax, {syscall_addr}
-                push rcx
+                mov rax, {syscall_id}
+                mov r10, rcx
                 syscall
                 ret"#),
-            syscall_addr = const $id * 8);
+            syscall_id = const $id);
     };
 }
 
@@ -62,15 +108,20 @@ impl_syscall!(
     "syscall_info_bootloader_version",
     Syscall::INFO_BOOTLOADER_VERSION
 );
-impl_syscall!("syscall_info_framebuffer", Syscall::INFO_FRAMEBUFFER);
 impl_syscall!("syscall_info_graphics_ctx", Syscall::INFO_GRAPHICS_CTX);
 
 impl_syscall!("syscall_mem_alloc", Syscall::MEM_ALLOC);
 impl_syscall!("syscall_mem_dealloc", Syscall::MEM_DEALLOC);
 impl_syscall!("syscall_mem_alloc_zeroed", Syscall::MEM_ALLOC_ZEROED);
 impl_syscall!("syscall_mem_realloc", Syscall::MEM_REALLOC);
+impl_syscall!("syscall_mem_brk", Syscall::MEM_BRK);
 
 impl_syscall!("syscall_program_panic", Syscall::PROGRAM_PANIC);
+impl_syscall!("syscall_program_exit", Syscall::PROGRAM_EXIT);
+impl_syscall!("syscall_write", Syscall::WRITE);
+
+impl_syscall!("syscall_fb_info", Syscall::FB_INFO);
+impl_syscall!("syscall_fb_blit", Syscall::FB_BLIT);
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {